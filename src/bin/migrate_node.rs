@@ -0,0 +1,365 @@
+//! Migrate users' storage from a MySQL node to Spanner
+#[macro_use]
+extern crate slog_scope;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use actix_web::web::block;
+use docopt::Docopt;
+use futures::future::join_all;
+use serde_derive::{Deserialize, Serialize};
+
+use syncstorage::{
+    db::{
+        archive::{copy_user, ArchiveStats},
+        error::DbError,
+        mysql::pool::MysqlDbPool,
+        pool_from_settings, DbPool,
+    },
+    error::{ApiError, ApiResult},
+    logging::{init_logging, reset_logging},
+    server::metrics::{metrics_from_opts, Metrics},
+    settings::Settings,
+    web::extractors::HawkIdentifier,
+};
+
+const USAGE: &str = "
+Usage: migrate_node [options] --source=URL --users=FILE [<uid>...]
+
+Copies users' storage from the MySQL database --source to the Spanner
+database configured as the database_url. Collections are matched by name,
+custom collections being added to Spanner's collections table as needed.
+
+--users maps each uid to its FxA ids: a tab separated `uid fxa_uid fxa_kid`
+file, as written by tools/user_migration/gen_fxa_users.py.
+
+Without <uid>s, every user with storage within --start-uid..--end-uid is
+migrated, the range split between --workers running in parallel. Progress
+is checkpointed so an interrupted run resumes where it left off. Users that
+are migrated again are overwritten.
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+    --source=URL             The MySQL database to migrate from.
+    --users=FILE             The uid to fxa_uid and fxa_kid mapping.
+    --start-uid=UID          The first uid to migrate [default: 0].
+    --end-uid=UID            Migrate the uids before this one (default: all).
+    --workers=COUNT          Number of uid ranges migrated in parallel [default: 4].
+    --chunk-size=COUNT       Number of bsos written per transaction [default: 1000].
+    --checkpoint=FILE        Progress file to resume from [default: migrate_node.checkpoint].
+    --success-file=FILE      Report of migrated users [default: migrate_node.success].
+    --failure-file=FILE      Report of users that failed to migrate [default: migrate_node.failure].
+";
+
+/// How many user ids are fetched from the source at a time
+const USER_ID_PAGE_SIZE: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    arg_uid: Vec<u64>,
+    flag_config: Option<String>,
+    flag_source: String,
+    flag_users: String,
+    flag_start_uid: u64,
+    flag_end_uid: Option<u64>,
+    flag_workers: u64,
+    flag_chunk_size: usize,
+    flag_checkpoint: String,
+    flag_success_file: String,
+    flag_failure_file: String,
+}
+
+/// A worker's range of uids
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct UidRange {
+    start: u64,
+    end: u64,
+    /// The next uid to migrate
+    next: u64,
+}
+
+/// Each worker's progress, saved after every user
+#[derive(Debug, Deserialize, Serialize)]
+struct Checkpoint {
+    ranges: Vec<UidRange>,
+    #[serde(skip)]
+    path: String,
+}
+
+impl Checkpoint {
+    fn load(path: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+        let mut checkpoint: Checkpoint = serde_json::from_reader(File::open(path)?)?;
+        checkpoint.path = path.to_owned();
+        Ok(Some(checkpoint))
+    }
+
+    /// Split `start..end` into `workers` ranges
+    fn new(path: &str, start: u64, end: u64, workers: u64) -> Self {
+        let size = ((end.saturating_sub(start)) / workers).max(1);
+        let mut ranges = vec![];
+        let mut range_start = start;
+        while range_start < end {
+            let range_end = if ranges.len() as u64 == workers - 1 {
+                end
+            } else {
+                (range_start + size).min(end)
+            };
+            ranges.push(UidRange {
+                start: range_start,
+                end: range_end,
+                next: range_start,
+            });
+            range_start = range_end;
+        }
+        Self {
+            ranges,
+            path: path.to_owned(),
+        }
+    }
+
+    fn advance(&mut self, index: usize, next: u64) -> Result<(), DbError> {
+        self.ranges[index].next = next;
+        let tmp = format!("{}.tmp", self.path);
+        let json = serde_json::to_vec(self)
+            .map_err(|e| DbError::internal(&format!("Error encoding checkpoint: {}", e)))?;
+        fs::write(&tmp, json)
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|e| DbError::internal(&format!("Error saving checkpoint: {}", e)))
+    }
+}
+
+/// The success and failure reports, appended to across runs
+struct Report {
+    success: File,
+    failure: File,
+}
+
+impl Report {
+    fn open(success: &str, failure: &str) -> std::io::Result<Self> {
+        let append = |path| OpenOptions::new().create(true).append(true).open(path);
+        Ok(Self {
+            success: append(success)?,
+            failure: append(failure)?,
+        })
+    }
+
+    fn success(&mut self, uid: u64, stats: &ArchiveStats) {
+        if let Err(e) = writeln!(
+            self.success,
+            "{}\t{}\t{}",
+            uid, stats.collections, stats.bsos
+        ) {
+            error!("Error writing the success report: {}", e);
+        }
+    }
+
+    fn failure(&mut self, uid: u64, reason: &str) {
+        warn!("Failed to migrate user {}: {}", uid, reason);
+        if let Err(e) = writeln!(self.failure, "{}\t{}", uid, reason) {
+            error!("Error writing the failure report: {}", e);
+        }
+    }
+}
+
+struct Migration {
+    source: MysqlDbPool,
+    target: Box<dyn DbPool>,
+    /// uid -> (fxa_uid, fxa_kid)
+    users: HashMap<u64, (String, String)>,
+    chunk_size: usize,
+    report: RefCell<Report>,
+}
+
+impl Migration {
+    /// Migrate the user, reporting the outcome
+    async fn migrate_user(&self, uid: u64) {
+        let (fxa_uid, fxa_kid) = match self.users.get(&uid) {
+            Some(ids) => ids.clone(),
+            None => {
+                self.report
+                    .borrow_mut()
+                    .failure(uid, "not found in --users");
+                return;
+            }
+        };
+        let to = HawkIdentifier {
+            legacy_id: uid,
+            fxa_uid,
+            fxa_kid,
+        };
+        match self.copy(uid, &to).await {
+            Ok(stats) => {
+                debug!(
+                    "Migrated user {}: {} collections, {} bsos",
+                    uid, stats.collections, stats.bsos
+                );
+                self.report.borrow_mut().success(uid, &stats)
+            }
+            Err(e) => self.report.borrow_mut().failure(uid, &e.to_string()),
+        }
+    }
+
+    async fn copy(&self, uid: u64, to: &HawkIdentifier) -> ApiResult<ArchiveStats> {
+        let source = self.source.get().await?;
+        let target = self.target.get().await?;
+        copy_user(
+            source.as_ref(),
+            target.as_ref(),
+            &HawkIdentifier::new_legacy(uid),
+            to,
+            self.chunk_size,
+        )
+        .await
+    }
+
+    /// Migrate the users of the checkpoint's `index`th range
+    async fn migrate_range(&self, checkpoint: &RefCell<Checkpoint>, index: usize) -> ApiResult<()> {
+        loop {
+            let UidRange { end, next, .. } = checkpoint.borrow().ranges[index].clone();
+            if next >= end {
+                return Ok(());
+            }
+            let pool = self.source.clone();
+            let uids = block(move || {
+                pool.get_sync()
+                    .and_then(|db| db.get_user_ids_sync(next, end, USER_ID_PAGE_SIZE))
+                    .map_err(ApiError::from)
+            })
+            .await?;
+            if uids.is_empty() {
+                checkpoint.borrow_mut().advance(index, end)?;
+                return Ok(());
+            }
+            for uid in uids {
+                self.migrate_user(uid).await;
+                checkpoint.borrow_mut().advance(index, uid + 1)?;
+            }
+        }
+    }
+}
+
+fn read_users(path: &str) -> Result<HashMap<u64, (String, String)>, Box<dyn Error>> {
+    let mut users = HashMap::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let fields: Vec<_> = line.split('\t').map(str::trim).collect();
+        match fields.as_slice() {
+            ["uid", ..] | [""] => continue,
+            [uid, fxa_uid, fxa_kid] => {
+                let uid = uid
+                    .parse::<u64>()
+                    .map_err(|e| format!("Invalid uid in {}: {:?}: {}", path, uid, e))?;
+                users.insert(uid, ((*fxa_uid).to_owned(), (*fxa_kid).to_owned()));
+            }
+            _ => return Err(format!("Invalid line in {}: {:?}", path, line).into()),
+        }
+    }
+    Ok(users)
+}
+
+async fn run(settings: Settings, args: Args) -> Result<(), Box<dyn Error>> {
+    if !settings.uses_spanner() {
+        return Err("The database_url to migrate to must be a Spanner database".into());
+    }
+    if !args.flag_source.starts_with("mysql:") {
+        return Err("--source must be a MySQL database".into());
+    }
+    if args.flag_workers == 0 || args.flag_chunk_size == 0 {
+        return Err("--workers and --chunk-size must be positive".into());
+    }
+
+    let metrics = Metrics::from(&metrics_from_opts(&settings)?);
+    let mut source_settings = settings.clone();
+    source_settings.database_url = args.flag_source.clone();
+    let source = MysqlDbPool::new_without_migrations(&source_settings, &metrics)
+        .map_err(|e| e.to_string())?;
+    let target = pool_from_settings(&settings, &metrics)
+        .await
+        .map_err(|e| e.to_string())?;
+    let migration = Migration {
+        source,
+        target,
+        users: read_users(&args.flag_users)?,
+        chunk_size: args.flag_chunk_size,
+        report: RefCell::new(Report::open(
+            &args.flag_success_file,
+            &args.flag_failure_file,
+        )?),
+    };
+
+    if !args.arg_uid.is_empty() {
+        for uid in &args.arg_uid {
+            migration.migrate_user(*uid).await;
+        }
+        return Ok(());
+    }
+
+    let checkpoint = match Checkpoint::load(&args.flag_checkpoint)? {
+        Some(checkpoint) => {
+            info!(
+                "Resuming from {} (ignoring --start-uid, --end-uid and --workers)",
+                args.flag_checkpoint
+            );
+            checkpoint
+        }
+        None => {
+            let end = match args.flag_end_uid {
+                Some(end) => end,
+                None => {
+                    let pool = migration.source.clone();
+                    let max = block(move || {
+                        pool.get_sync()
+                            .and_then(|db| db.get_max_user_id_sync())
+                            .map_err(ApiError::from)
+                    })
+                    .await
+                    .map_err(|e| e.to_string())?;
+                    max.map_or(0, |max| max + 1)
+                }
+            };
+            Checkpoint::new(
+                &args.flag_checkpoint,
+                args.flag_start_uid,
+                end,
+                args.flag_workers,
+            )
+        }
+    };
+    for range in &checkpoint.ranges {
+        info!(
+            "Migrating uids {}..{} (from {})",
+            range.start, range.end, range.next
+        );
+    }
+    let ranges = checkpoint.ranges.len();
+    let checkpoint = RefCell::new(checkpoint);
+    let results =
+        join_all((0..ranges).map(|index| migration.migrate_range(&checkpoint, index))).await;
+    for result in results {
+        result.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(&args.flag_config)?;
+    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+
+    run(settings, args).await?;
+    info!("Completed migrate_node");
+    reset_logging();
+    Ok(())
+}
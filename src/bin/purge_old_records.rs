@@ -0,0 +1,95 @@
+//! Purge the storage of superseded Tokenserver user records
+#[macro_use]
+extern crate slog_scope;
+
+use std::error::Error;
+
+use chrono::Utc;
+use docopt::Docopt;
+use serde_derive::Deserialize;
+
+use syncstorage::{
+    db::{params, pool_from_settings},
+    error::ApiResult,
+    logging::{init_logging, reset_logging},
+    server::metrics::{metrics_from_opts, Metrics},
+    settings::Settings,
+    tokenserver::{
+        purge::{purge_old_records, PurgeParams, PurgeStats},
+        SYNC_SERVICE, SYNC_SERVICE_PATTERN,
+    },
+};
+
+const USAGE: &str = "
+Usage: purge_old_records [options]
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+    --dry-run                Report what would be purged without deleting anything.
+    --grace-period=SECONDS   Only purge records replaced this long ago [default: 86400].
+    --max-records=COUNT      Maximum number of records to purge [default: 1000].
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    flag_config: Option<String>,
+    flag_dry_run: bool,
+    flag_grace_period: u64,
+    flag_max_records: i64,
+}
+
+async fn run(settings: &Settings, args: &Args) -> ApiResult<PurgeStats> {
+    let metrics = Metrics::from(&metrics_from_opts(settings)?);
+    let db_pool = pool_from_settings(settings, &metrics).await?;
+    let db = db_pool.get().await?;
+
+    db.begin(true).await?;
+    let service_id = db
+        .post_service(params::PostService {
+            service: SYNC_SERVICE.to_owned(),
+            pattern: SYNC_SERVICE_PATTERN.to_owned(),
+        })
+        .await?;
+    db.commit().await?;
+
+    purge_old_records(
+        db.as_ref(),
+        &metrics,
+        PurgeParams {
+            service_id,
+            grace_period: args.flag_grace_period,
+            max_records: args.flag_max_records,
+            dry_run: args.flag_dry_run,
+            storage_by_uid: !settings.uses_spanner(),
+            timestamp: Utc::now().timestamp_millis(),
+        },
+    )
+    .await
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(&args.flag_config)?;
+    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+
+    let stats = run(&settings, &args).await.map_err(|e| e.to_string())?;
+    info!(
+        "{}: {} records ({} still current), {} bsos, {} user_collections, {} batches",
+        if args.flag_dry_run {
+            "Would purge"
+        } else {
+            "Purged"
+        },
+        stats.records,
+        stats.skipped,
+        stats.bsos,
+        stats.user_collections,
+        stats.batches
+    );
+    reset_logging();
+    Ok(())
+}
@@ -1,13 +1,25 @@
+#[macro_use]
+extern crate diesel;
+
+use std::collections::HashSet;
 use std::env;
 use std::error::Error;
 use std::net::UdpSocket;
 use std::sync::Arc;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use cadence::{
     BufferedUdpMetricSink, Metric, QueuingMetricSink, StatsdClient, Timed, DEFAULT_PORT,
 };
+use chrono::Utc;
+use diesel::{
+    mysql::MysqlConnection,
+    result::{DatabaseErrorKind, Error as DieselError, QueryResult},
+    sql_query,
+    sql_types::{BigInt, Integer},
+    Connection, RunQueryDsl,
+};
 use googleapis_raw::spanner::v1::{
     spanner::{
         BeginTransactionRequest, CommitRequest, CreateSessionRequest, ExecuteSqlRequest, Session,
@@ -20,9 +32,11 @@ use googleapis_raw::spanner::v1::{
 };
 use grpcio::{CallOption, ChannelBuilder, ChannelCredentials, EnvBuilder, MetadataBuilder};
 use log::{info, trace, warn};
+use syncstorage::db::BATCH_LIFETIME;
 use url::{Host, Url};
 
 const SPANNER_ADDRESS: &str = "spanner.googleapis.com:443";
+const EMULATOR_ENV_VAR: &str = "SPANNER_EMULATOR_HOST"; // Connect insecurely to an emulator
 const RETRY_ENV_VAR: &str = "PURGE_TTL_RETRY_COUNT"; // Default value = 10
 const SLEEP_ENV_VAR: &str = "PURGE_TTL_RETRY_SLEEP_MILLIS"; // Default value = 0
 
@@ -161,6 +175,28 @@ impl Iterator for SyncResultSet {
     }
 }
 
+/// An UPDATE recalculating the `count`/`total_bytes` of the `user_collections`
+/// rows matching `condition` from their bsos (all of them, expired or not, as
+/// `SpannerDb::update_user_collection_quotas` counts), excluding any further
+/// filtered out by `bso_filter`
+fn spanner_quota_update(condition: &str, bso_filter: &str) -> String {
+    let bsos = format!(
+        "FROM bsos AS b
+         WHERE b.fxa_uid = uc.fxa_uid
+           AND b.fxa_kid = uc.fxa_kid
+           AND b.collection_id = uc.collection_id{}",
+        bso_filter
+    );
+    format!(
+        "UPDATE user_collections AS uc
+            SET count = (SELECT COUNT(*) {bsos}),
+                total_bytes = (SELECT COALESCE(SUM(BYTE_LENGTH(b.payload)), 0) {bsos})
+          WHERE {condition}",
+        bsos = bsos,
+        condition = condition
+    )
+}
+
 fn delete_incremental(
     client: &SpannerClient,
     session: &Session,
@@ -190,6 +226,7 @@ fn delete_incremental(
             "DELETE FROM {} WHERE (fxa_uid, fxa_kid, collection_id, {}) IN (",
             table, column,
         );
+        let mut collections = HashSet::new();
         for row in &mut result {
             // Count starting at 1 so that i % chunk_size is false when on the first row
             let fxa_uid = row[0].get_string_value().to_owned();
@@ -209,6 +246,7 @@ fn delete_incremental(
                 "{}('{}', '{}', {}, '{}'), ",
                 delete_sql, fxa_uid, fxa_kid, collection_id, id
             );
+            collections.insert(format!("('{}', '{}', {})", fxa_uid, fxa_kid, collection_id));
 
             total += 1;
         }
@@ -219,7 +257,24 @@ fn delete_incremental(
         trace!("Deleting chunk with: {}", delete_sql);
         let mut delete_req = continue_transaction(&session, txn.clone())?;
         delete_req.set_sql(delete_sql);
+        delete_req.set_seqno(1);
         client.execute_sql(&delete_req)?;
+        if table == "bsos" {
+            // Keep the quota counters of the collections just purged from
+            // in step (within the same transaction)
+            let quota_sql = spanner_quota_update(
+                &format!(
+                    "(uc.fxa_uid, uc.fxa_kid, uc.collection_id) IN ({})",
+                    collections.into_iter().collect::<Vec<_>>().join(", ")
+                ),
+                "",
+            );
+            trace!("Updating quotas with: {}", quota_sql);
+            let mut quota_req = continue_transaction(&session, txn.clone())?;
+            quota_req.set_sql(quota_sql);
+            quota_req.set_seqno(2);
+            client.execute_sql(&quota_req)?;
+        }
         info!("{}: removed {} rows", table, total);
         commit_transaction(&client, &session, txn.clone())?;
         let (newreq, newtxn) = begin_transaction(&client, &session, RequestType::ReadWrite)?;
@@ -235,11 +290,35 @@ fn delete_all(
     session: &Session,
     table: String,
 ) -> Result<(), Box<grpcio::Error>> {
+    // Partitioned DML can't be combined with other statements in one
+    // transaction, so the expiration cutoff is fixed up front instead
+    let cutoff = format!("TIMESTAMP '{}'", Utc::now().to_rfc3339());
+    if table == "bsos" {
+        // Recalculate the quota counters of the collections about to be
+        // purged from, sans their expired bsos. A write in the meantime
+        // recalculates them as well (counting the soon to be purged bsos):
+        // `PURGE_TTL_RECALCULATE_QUOTA` repairs any such drift
+        let (mut req, _txn) = begin_transaction(client, session, RequestType::PartitionedDml)?;
+        req.set_sql(spanner_quota_update(
+            &format!(
+                "EXISTS (SELECT 1
+                           FROM bsos AS e
+                          WHERE e.fxa_uid = uc.fxa_uid
+                            AND e.fxa_kid = uc.fxa_kid
+                            AND e.collection_id = uc.collection_id
+                            AND e.expiry < {})",
+                cutoff
+            ),
+            &format!(" AND b.expiry >= {}", cutoff),
+        ));
+        let result = client.execute_sql(&req)?;
+        info!(
+            "DeleteAll: updated the quotas of {} user_collections",
+            result.get_stats().get_row_count_lower_bound()
+        );
+    }
     let (mut req, _txn) = begin_transaction(client, session, RequestType::PartitionedDml)?;
-    req.set_sql(format!(
-        "DELETE FROM {} WHERE expiry < CURRENT_TIMESTAMP()",
-        table
-    ));
+    req.set_sql(format!("DELETE FROM {} WHERE expiry < {}", table, cutoff));
     let result = client.execute_sql(&req)?;
     info!(
         "DeleteAll: {}: removed {} rows",
@@ -249,6 +328,18 @@ fn delete_all(
     Ok(())
 }
 
+/// Recalculate the quota counters of every user_collections row
+fn recalculate_quota(client: &SpannerClient, session: &Session) -> Result<(), Box<grpcio::Error>> {
+    let (mut req, _txn) = begin_transaction(client, session, RequestType::PartitionedDml)?;
+    req.set_sql(spanner_quota_update("true", ""));
+    let result = client.execute_sql(&req)?;
+    info!(
+        "RecalculateQuota: updated {} user_collections",
+        result.get_stats().get_row_count_lower_bound()
+    );
+    Ok(())
+}
+
 fn retryable(err: &grpcio::Error) -> bool {
     // if it is NOT an ABORT, we should not retry this function.
     match err {
@@ -266,50 +357,50 @@ fn retryable(err: &grpcio::Error) -> bool {
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::try_init()?;
-
-    let chunk_size: u64 = env::var("PURGE_TTL_CHUNK_SIZE")
-        .unwrap_or_else(|_| "1000".to_string())
-        .parse()
-        .unwrap();
-    let max_to_delete: u64 = env::var("PURGE_TTL_MAX_TO_DELETE")
-        .unwrap_or_else(|_| "1000".to_string())
-        .parse()
-        .unwrap();
-
-    const INCREMENTAL_ENV: &str = "PURGE_TTL_INCREMENTAL";
-    let incremental = env::var(INCREMENTAL_ENV)
-        .map(|x| x == "1" || x.to_lowercase() == "true")
-        .unwrap_or(false);
-    info!("INCREMENTAL: {:?}", incremental);
-
-    const DB_ENV: &str = "SYNC_DATABASE_URL";
-    let db_url = env::var(DB_ENV).map_err(|_| format!("Invalid or undefined {}", DB_ENV))?;
-    let url = Url::parse(&db_url).map_err(|e| format!("Invalid {}: {}", DB_ENV, e))?;
-    if url.scheme() != "spanner" || url.host() != Some(Host::Domain("projects")) {
-        return Err(format!("Invalid {}", DB_ENV).into());
-    }
-    let retries: u64 =
-        str::parse::<u64>(&env::var(RETRY_ENV_VAR).unwrap_or_else(|_| "10".to_owned()))
-            .unwrap_or(10);
-    let nap_time: Duration = Duration::from_millis(
-        str::parse::<u64>(&env::var(SLEEP_ENV_VAR).unwrap_or_else(|_| "0".to_owned())).unwrap_or(0),
-    );
+/// How expired rows are deleted, from the environment
+pub struct PurgeOptions {
+    chunk_size: u64,
+    max_to_delete: u64,
+    incremental: bool,
+    /// Only recalculate the quota counters, deleting nothing
+    recalculate_quota: bool,
+    retries: u64,
+    nap_time: Duration,
+}
 
+fn purge_spanner(
+    db_url: &str,
+    options: &PurgeOptions,
+    statsd: &StatsdClient,
+) -> Result<(), Box<dyn Error>> {
+    let PurgeOptions {
+        chunk_size,
+        max_to_delete,
+        incremental,
+        recalculate_quota: recalculate,
+        retries,
+        nap_time,
+    } = *options;
     let database = db_url["spanner://".len()..].to_owned();
-    info!("Retries: {}, sleep: {}ms", retries, nap_time.as_millis());
     info!("For {}", database);
 
     // Set up the gRPC environment.
     let env = Arc::new(EnvBuilder::new().build());
-    let creds = ChannelCredentials::google_default_credentials()?;
 
     // Create a Spanner client.
-    let chan = ChannelBuilder::new(env)
+    let builder = ChannelBuilder::new(env)
         .max_send_message_len(100 << 20)
-        .max_receive_message_len(100 << 20)
-        .secure_connect(SPANNER_ADDRESS, creds);
+        .max_receive_message_len(100 << 20);
+    let chan = match env::var(EMULATOR_ENV_VAR) {
+        Ok(emulator_host) => {
+            info!("Using the Spanner emulator at {}", emulator_host);
+            builder.connect(&emulator_host)
+        }
+        Err(_) => {
+            let creds = ChannelCredentials::google_default_credentials()?;
+            builder.secure_connect(SPANNER_ADDRESS, creds)
+        }
+    };
     let client = SpannerClient::new(chan);
 
     // Create a session
@@ -321,12 +412,32 @@ fn main() -> Result<(), Box<dyn Error>> {
     let opt = CallOption::default().headers(meta.build());
     let session = client.create_session_opt(&req, opt)?;
 
-    let statsd = statsd_from_env()?;
+    if recalculate {
+        let _timer_quota = start_timer(statsd, "purge_ttl.quota_duration");
+        for i in 0..retries {
+            match recalculate_quota(&client, &session) {
+                Ok(_) => {
+                    info!("Completed purge_ttl quota recalculation");
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Quota transaction error {}: {:?}", i, e);
+                    if !retryable(&e) {
+                        return Err(e);
+                    }
+                    if nap_time.as_millis() > 0 {
+                        thread::sleep(nap_time);
+                    }
+                }
+            }
+        }
+        panic!("Could not recalculate quotas after {} attempts", retries);
+    }
 
     {
-        let _timer_total = start_timer(&statsd, "purge_ttl.total_duration");
+        let _timer_total = start_timer(statsd, "purge_ttl.total_duration");
         {
-            let _timer_batches = start_timer(&statsd, "purge_ttl.batches_duration");
+            let _timer_batches = start_timer(statsd, "purge_ttl.batches_duration");
             let mut success = false;
             for i in 0..retries {
                 match if incremental {
@@ -364,7 +475,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         }
         {
-            let _timer_bso = start_timer(&statsd, "purge_ttl.bso_duration");
+            let _timer_bso = start_timer(statsd, "purge_ttl.bso_duration");
             let mut success = false;
             for i in 0..retries {
                 match if incremental {
@@ -400,3 +511,295 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// A (user, collection) pair with expired bsos
+#[derive(QueryableByName)]
+struct ExpiredCollection {
+    #[sql_type = "BigInt"]
+    userid: i64,
+    #[sql_type = "Integer"]
+    collection: i32,
+    #[sql_type = "BigInt"]
+    expired: i64,
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Recalculate the `count`/`total_bytes` of the user's collection from its
+/// unexpired bsos
+fn mysql_recalculate_quota(
+    conn: &MysqlConnection,
+    userid: i64,
+    collection: i32,
+    now: i64,
+) -> QueryResult<usize> {
+    sql_query(
+        "UPDATE user_collections
+            SET count = (SELECT COUNT(*)
+                           FROM bso
+                          WHERE userid = ? AND collection = ? AND ttl > ?),
+                total_bytes = (SELECT COALESCE(SUM(LENGTH(payload)), 0)
+                                 FROM bso
+                                WHERE userid = ? AND collection = ? AND ttl > ?)
+          WHERE userid = ? AND collection = ?",
+    )
+    .bind::<BigInt, _>(userid)
+    .bind::<Integer, _>(collection)
+    .bind::<BigInt, _>(now)
+    .bind::<BigInt, _>(userid)
+    .bind::<Integer, _>(collection)
+    .bind::<BigInt, _>(now)
+    .bind::<BigInt, _>(userid)
+    .bind::<Integer, _>(collection)
+    .execute(conn)
+}
+
+/// Delete up to `chunk_size` expired bsos in one transaction, keeping the
+/// affected collections' quota counters correct
+fn mysql_delete_bsos_chunk(conn: &MysqlConnection, chunk_size: u64) -> QueryResult<usize> {
+    let now = now_millis();
+    conn.transaction(|| {
+        let expired: Vec<ExpiredCollection> = sql_query(
+            "SELECT userid, collection, COUNT(*) AS expired
+               FROM (SELECT userid, collection
+                       FROM bso
+                      WHERE ttl < ?
+                      ORDER BY ttl
+                      LIMIT ?) AS chunk
+              GROUP BY userid, collection",
+        )
+        .bind::<BigInt, _>(now)
+        .bind::<BigInt, _>(chunk_size as i64)
+        .load(conn)?;
+        let mut total = 0;
+        for ExpiredCollection {
+            userid,
+            collection,
+            expired,
+        } in expired
+        {
+            total += sql_query(
+                "DELETE FROM bso
+                  WHERE userid = ? AND collection = ? AND ttl < ?
+                  LIMIT ?",
+            )
+            .bind::<BigInt, _>(userid)
+            .bind::<Integer, _>(collection)
+            .bind::<BigInt, _>(now)
+            .bind::<BigInt, _>(expired)
+            .execute(conn)?;
+            mysql_recalculate_quota(conn, userid, collection, now)?;
+        }
+        Ok(total)
+    })
+}
+
+/// Delete up to `chunk_size` rows of the batch table whose batches expired
+fn mysql_delete_batches_chunk(
+    conn: &MysqlConnection,
+    table: &str,
+    chunk_size: u64,
+) -> QueryResult<usize> {
+    sql_query(format!("DELETE FROM {} WHERE batch < ? LIMIT ?", table))
+        .bind::<BigInt, _>(now_millis() - BATCH_LIFETIME)
+        .bind::<BigInt, _>(chunk_size as i64)
+        .execute(conn)
+}
+
+/// Repeatedly delete chunks of rows until none remain (or, incrementally,
+/// `max_to_delete` were)
+fn mysql_delete_chunked(
+    table: &str,
+    options: &PurgeOptions,
+    mut delete_chunk: impl FnMut() -> QueryResult<usize>,
+) -> QueryResult<()> {
+    let mut total: u64 = 0;
+    loop {
+        let deleted = delete_chunk()? as u64;
+        total += deleted;
+        trace!("Delete: {}: removed {} rows", table, deleted);
+        if deleted < options.chunk_size || (options.incremental && total >= options.max_to_delete) {
+            break;
+        }
+    }
+    info!("{}: removed {} rows", table, total);
+    Ok(())
+}
+
+/// A page of user_collections rows
+#[derive(QueryableByName)]
+struct UserCollection {
+    #[sql_type = "BigInt"]
+    userid: i64,
+    #[sql_type = "Integer"]
+    collection: i32,
+}
+
+/// Recalculate the quota counters of every user_collections row, a page of
+/// `chunk_size` rows per transaction
+fn mysql_recalculate_all(conn: &MysqlConnection, chunk_size: u64) -> QueryResult<()> {
+    let (mut userid, mut collection) = (-1, -1);
+    let mut total = 0;
+    loop {
+        let now = now_millis();
+        let page: Vec<UserCollection> = conn.transaction(|| {
+            let page: Vec<UserCollection> = sql_query(
+                "SELECT userid, collection
+                   FROM user_collections
+                  WHERE (userid, collection) > (?, ?)
+                  ORDER BY userid, collection
+                  LIMIT ?",
+            )
+            .bind::<BigInt, _>(userid)
+            .bind::<Integer, _>(collection)
+            .bind::<BigInt, _>(chunk_size as i64)
+            .load(conn)?;
+            for row in &page {
+                mysql_recalculate_quota(conn, row.userid, row.collection, now)?;
+            }
+            Ok(page)
+        })?;
+        total += page.len();
+        match page.last() {
+            Some(last) if page.len() as u64 == chunk_size => {
+                userid = last.userid;
+                collection = last.collection;
+            }
+            _ => break,
+        }
+    }
+    info!("RecalculateQuota: updated {} user_collections", total);
+    Ok(())
+}
+
+fn mysql_retryable(err: &DieselError) -> bool {
+    // Only retry on lock contention with the server's own writes
+    match err {
+        DieselError::DatabaseError(DatabaseErrorKind::__Unknown, info) => {
+            let message = info.message();
+            message.contains("Deadlock") || message.contains("Lock wait timeout")
+        }
+        _ => false,
+    }
+}
+
+fn purge_mysql(
+    db_url: &str,
+    options: &PurgeOptions,
+    statsd: &StatsdClient,
+) -> Result<(), Box<dyn Error>> {
+    let conn = MysqlConnection::establish(db_url)?;
+    let with_retries = |name: &str, purge: &dyn Fn() -> QueryResult<()>| {
+        for i in 0..options.retries {
+            match purge() {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    warn!("{} transaction error {}: {:?}", name, i, e);
+                    if !mysql_retryable(&e) {
+                        return Err(e);
+                    }
+                    if options.nap_time.as_millis() > 0 {
+                        thread::sleep(options.nap_time);
+                    }
+                }
+            }
+        }
+        panic!(
+            "Could not purge {} after {} attempts",
+            name, options.retries
+        );
+    };
+
+    if options.recalculate_quota {
+        let _timer_quota = start_timer(statsd, "purge_ttl.quota_duration");
+        with_retries("quotas", &|| {
+            mysql_recalculate_all(&conn, options.chunk_size)
+        })?;
+        info!("Completed purge_ttl quota recalculation");
+        return Ok(());
+    }
+
+    {
+        let _timer_total = start_timer(statsd, "purge_ttl.total_duration");
+        {
+            let _timer_batches = start_timer(statsd, "purge_ttl.batches_duration");
+            with_retries("batches", &|| {
+                for table in &["batch_upload_items", "batch_uploads"] {
+                    mysql_delete_chunked(table, options, || {
+                        mysql_delete_batches_chunk(&conn, table, options.chunk_size)
+                    })?;
+                }
+                Ok(())
+            })?;
+        }
+        {
+            let _timer_bso = start_timer(statsd, "purge_ttl.bso_duration");
+            with_retries("bsos", &|| {
+                mysql_delete_chunked("bso", options, || {
+                    mysql_delete_bsos_chunk(&conn, options.chunk_size)
+                })
+            })?;
+        }
+        info!("Completed purge_ttl");
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::try_init()?;
+
+    let chunk_size: u64 = env::var("PURGE_TTL_CHUNK_SIZE")
+        .unwrap_or_else(|_| "1000".to_string())
+        .parse()
+        .unwrap();
+    let max_to_delete: u64 = env::var("PURGE_TTL_MAX_TO_DELETE")
+        .unwrap_or_else(|_| "1000".to_string())
+        .parse()
+        .unwrap();
+
+    const INCREMENTAL_ENV: &str = "PURGE_TTL_INCREMENTAL";
+    let incremental = env::var(INCREMENTAL_ENV)
+        .map(|x| x == "1" || x.to_lowercase() == "true")
+        .unwrap_or(false);
+    info!("INCREMENTAL: {:?}", incremental);
+
+    // Repair the quota counters rather than purging
+    const RECALCULATE_QUOTA_ENV: &str = "PURGE_TTL_RECALCULATE_QUOTA";
+    let recalculate_quota = env::var(RECALCULATE_QUOTA_ENV)
+        .map(|x| x == "1" || x.to_lowercase() == "true")
+        .unwrap_or(false);
+    info!("RECALCULATE_QUOTA: {:?}", recalculate_quota);
+
+    const DB_ENV: &str = "SYNC_DATABASE_URL";
+    let db_url = env::var(DB_ENV).map_err(|_| format!("Invalid or undefined {}", DB_ENV))?;
+    let url = Url::parse(&db_url).map_err(|e| format!("Invalid {}: {}", DB_ENV, e))?;
+    let retries: u64 =
+        str::parse::<u64>(&env::var(RETRY_ENV_VAR).unwrap_or_else(|_| "10".to_owned()))
+            .unwrap_or(10);
+    let nap_time: Duration = Duration::from_millis(
+        str::parse::<u64>(&env::var(SLEEP_ENV_VAR).unwrap_or_else(|_| "0".to_owned())).unwrap_or(0),
+    );
+    info!("Retries: {}, sleep: {}ms", retries, nap_time.as_millis());
+
+    let options = PurgeOptions {
+        chunk_size,
+        max_to_delete,
+        incremental,
+        recalculate_quota,
+        retries,
+        nap_time,
+    };
+    let statsd = statsd_from_env()?;
+    match url.scheme() {
+        "spanner" if url.host() == Some(Host::Domain("projects")) => {
+            purge_spanner(&db_url, &options, &statsd)
+        }
+        "mysql" => purge_mysql(&db_url, &options, &statsd),
+        _ => Err(format!("Invalid {}", DB_ENV).into()),
+    }
+}
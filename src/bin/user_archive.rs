@@ -0,0 +1,161 @@
+//! Export users' storage to, or import it from, a portable archive
+#[macro_use]
+extern crate slog_scope;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+
+use docopt::Docopt;
+use serde_derive::Deserialize;
+
+use syncstorage::{
+    db::{
+        archive::{self, ArchiveReader, ArchiveStats, ArchiveWriter},
+        error::DbError,
+        pool_from_settings, DbPool,
+    },
+    error::ApiResult,
+    logging::{init_logging, reset_logging},
+    server::metrics::{metrics_from_opts, Metrics},
+    settings::Settings,
+    web::extractors::HawkIdentifier,
+};
+
+const USAGE: &str = "
+Usage: user_archive export [options] --output=FILE [<user>...]
+       user_archive import [options] --input=FILE
+
+Users are given as UID[,FXA_UID,FXA_KID] (the latter two as used by the
+Spanner backend), either as arguments or one per line of --users.
+
+Imports aren't atomic: each chunk of bsos is committed on its own, so a
+failed import may leave users partially written. Importing the archive
+again overwrites them.
+
+Options:
+    -h, --help               Show this message.
+    --config=CONFIGFILE      Syncstorage configuration file path.
+    --output=FILE            Archive file to export to.
+    --users=FILE             File listing the users to export.
+    --input=FILE             Archive file to import.
+    --chunk-size=COUNT       Number of bsos imported per transaction [default: 500].
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_export: bool,
+    cmd_import: bool,
+    arg_user: Vec<String>,
+    flag_config: Option<String>,
+    flag_output: Option<String>,
+    flag_users: Option<String>,
+    flag_input: Option<String>,
+    flag_chunk_size: usize,
+}
+
+fn parse_user(user: &str) -> Result<HawkIdentifier, String> {
+    let parts: Vec<_> = user.trim().split(',').map(str::trim).collect();
+    let uid = parts[0]
+        .parse::<u64>()
+        .map_err(|e| format!("Invalid uid {:?}: {}", parts[0], e))?;
+    match parts.as_slice() {
+        [_] => Ok(HawkIdentifier::new_legacy(uid)),
+        [_, fxa_uid, fxa_kid] => Ok(HawkIdentifier {
+            legacy_id: uid,
+            fxa_uid: (*fxa_uid).to_owned(),
+            fxa_kid: (*fxa_kid).to_owned(),
+        }),
+        _ => Err(format!("Invalid user {:?}", user)),
+    }
+}
+
+fn users(args: &Args) -> Result<Vec<HawkIdentifier>, Box<dyn Error>> {
+    let mut users = args
+        .arg_user
+        .iter()
+        .map(|user| parse_user(user))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(path) = &args.flag_users {
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                users.push(parse_user(&line)?);
+            }
+        }
+    }
+    Ok(users)
+}
+
+async fn export(
+    db_pool: &dyn DbPool,
+    users: &[HawkIdentifier],
+    path: &str,
+) -> ApiResult<ArchiveStats> {
+    let file = File::create(path)
+        .map_err(|e| DbError::internal(&format!("Error creating {}: {}", path, e)))?;
+    let mut writer = ArchiveWriter::new(BufWriter::new(file))?;
+    let mut stats = ArchiveStats::default();
+    for user_id in users {
+        let db = db_pool.get().await?;
+        let exported = archive::export_user(db.as_ref(), &mut writer, user_id).await?;
+        info!(
+            "Exported user {}: {} collections, {} bsos",
+            user_id.legacy_id, exported.collections, exported.bsos
+        );
+        stats.users += exported.users;
+        stats.collections += exported.collections;
+        stats.bsos += exported.bsos;
+    }
+    writer.finish()?;
+    Ok(stats)
+}
+
+async fn import(db_pool: &dyn DbPool, path: &str, chunk_size: usize) -> ApiResult<ArchiveStats> {
+    let file = File::open(path)
+        .map_err(|e| DbError::internal(&format!("Error opening {}: {}", path, e)))?;
+    let reader = ArchiveReader::new(BufReader::new(file))?;
+    let db = db_pool.get().await?;
+    archive::import(db.as_ref(), reader, chunk_size).await
+}
+
+#[actix_web::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+    let settings = Settings::with_env_and_config_file(&args.flag_config)?;
+    init_logging(!settings.human_logs).expect("Logging failed to initialize");
+    if args.flag_chunk_size == 0 {
+        return Err("--chunk-size must be positive".into());
+    }
+
+    let metrics = Metrics::from(&metrics_from_opts(&settings)?);
+    let db_pool = pool_from_settings(&settings, &metrics)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (action, stats) = if args.cmd_export {
+        let users = users(&args)?;
+        if users.is_empty() {
+            return Err("No users to export".into());
+        }
+        let path = args.flag_output.as_deref().unwrap_or_default();
+        let stats = export(db_pool.as_ref(), &users, path)
+            .await
+            .map_err(|e| e.to_string())?;
+        ("Exported", stats)
+    } else {
+        debug_assert!(args.cmd_import);
+        let path = args.flag_input.as_deref().unwrap_or_default();
+        let stats = import(db_pool.as_ref(), path, args.flag_chunk_size)
+            .await
+            .map_err(|e| e.to_string())?;
+        ("Imported", stats)
+    };
+    info!(
+        "{} {} users: {} collections, {} bsos",
+        action, stats.users, stats.collections, stats.bsos
+    );
+    reset_logging();
+    Ok(())
+}
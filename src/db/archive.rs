@@ -0,0 +1,415 @@
+//! Portable per-user storage archives.
+//!
+//! An archive is a gzipped stream of JSON lines: a header naming the format
+//! and its version, then for each user a `user` record followed by each of
+//! their collections (by name, so custom collections are recreated) and the
+//! collection's bsos. Any backend can be exported to, or imported from, an
+//! archive: imports preserve the `modified` and `expiry` timestamps exactly.
+use std::future::Future;
+use std::io::{BufRead, Write};
+
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{error::DbError, params, results, util::SyncTimestamp, Db};
+use crate::error::ApiResult;
+use crate::web::extractors::{BsoQueryParams, HawkIdentifier, Offset};
+
+pub const FORMAT: &str = "syncstorage-archive";
+pub const VERSION: u32 = 1;
+
+/// How many bsos are read at a time
+const PAGE_SIZE: u32 = 1000;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Header {
+        format: String,
+        version: u32,
+    },
+    User {
+        uid: u64,
+        fxa_uid: String,
+        fxa_kid: String,
+    },
+    Collection {
+        name: String,
+        /// Milliseconds since the epoch
+        modified: i64,
+    },
+    Bso {
+        id: String,
+        sortindex: Option<i32>,
+        payload: String,
+        /// Milliseconds since the epoch
+        modified: i64,
+        /// Milliseconds since the epoch
+        expiry: i64,
+    },
+}
+
+/// What was exported or imported
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArchiveStats {
+    pub users: i64,
+    pub collections: i64,
+    pub bsos: i64,
+}
+
+fn archive_error(msg: &str, e: impl std::fmt::Display) -> DbError {
+    DbError::internal(&format!("{}: {}", msg, e))
+}
+
+/// Writes the records of an archive, beginning with its header
+pub struct ArchiveWriter<W: Write> {
+    out: GzEncoder<W>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(out: W) -> Result<Self, DbError> {
+        let mut writer = Self {
+            out: GzEncoder::new(out, Compression::default()),
+        };
+        writer.write(&Record::Header {
+            format: FORMAT.to_owned(),
+            version: VERSION,
+        })?;
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<(), DbError> {
+        serde_json::to_writer(&mut self.out, record)
+            .map_err(|e| archive_error("Error encoding archive record", e))?;
+        self.out
+            .write_all(b"\n")
+            .map_err(|e| archive_error("Error writing archive", e))
+    }
+
+    /// Flush the remaining compressed output, returning the underlying writer
+    pub fn finish(self) -> Result<W, DbError> {
+        self.out
+            .finish()
+            .map_err(|e| archive_error("Error writing archive", e))
+    }
+}
+
+/// Reads the records of an archive, checking its header
+pub struct ArchiveReader<R: BufRead> {
+    lines: std::io::Lines<std::io::BufReader<GzDecoder<R>>>,
+}
+
+impl<R: BufRead> ArchiveReader<R> {
+    pub fn new(input: R) -> Result<Self, DbError> {
+        let mut reader = Self {
+            lines: std::io::BufReader::new(GzDecoder::new(input)).lines(),
+        };
+        match reader.next().transpose()? {
+            Some(Record::Header { format, version }) if format == FORMAT => {
+                if version > VERSION {
+                    return Err(DbError::internal(&format!(
+                        "Unsupported archive version {} (expected at most {})",
+                        version, VERSION
+                    )));
+                }
+            }
+            _ => return Err(DbError::internal("Not a syncstorage archive")),
+        }
+        Ok(reader)
+    }
+}
+
+impl<R: BufRead> Iterator for ArchiveReader<R> {
+    type Item = Result<Record, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(archive_error("Error reading archive", e))),
+        };
+        Some(serde_json::from_str(&line).map_err(|e| archive_error("Invalid archive record", e)))
+    }
+}
+
+/// Write all of the user's unexpired storage to the archive, a page of bsos
+/// at a time.
+///
+/// The read is consistent: it's within a single transaction.
+pub async fn export_user<W: Write>(
+    db: &dyn Db<'_>,
+    archive: &mut ArchiveWriter<W>,
+    user_id: &HawkIdentifier,
+) -> ApiResult<ArchiveStats> {
+    let mut stats = ArchiveStats {
+        users: 1,
+        ..Default::default()
+    };
+    archive.write(&Record::User {
+        uid: user_id.legacy_id,
+        fxa_uid: user_id.fxa_uid.clone(),
+        fxa_kid: user_id.fxa_kid.clone(),
+    })?;
+
+    db.begin(false).await?;
+    let result = export_collections(db, archive, user_id, &mut stats).await;
+    match result {
+        Ok(()) => db.commit().await?,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e);
+        }
+    }
+    Ok(stats)
+}
+
+async fn export_collections<W: Write>(
+    db: &dyn Db<'_>,
+    archive: &mut ArchiveWriter<W>,
+    user_id: &HawkIdentifier,
+    stats: &mut ArchiveStats,
+) -> ApiResult<()> {
+    for (name, modified) in collection_timestamps(db, user_id).await? {
+        archive.write(&Record::Collection {
+            name: name.clone(),
+            modified: modified.as_i64(),
+        })?;
+        stats.collections += 1;
+        let mut offset = None;
+        loop {
+            let page = get_page(db, user_id, &name, offset).await?;
+            for bso in page.items {
+                archive.write(&Record::Bso {
+                    id: bso.id,
+                    sortindex: bso.sortindex,
+                    payload: bso.payload,
+                    modified: bso.modified.as_i64(),
+                    expiry: bso.expiry,
+                })?;
+                stats.bsos += 1;
+            }
+            offset = match page.offset {
+                Some(offset) => Some(offset),
+                None => break,
+            };
+        }
+    }
+    Ok(())
+}
+
+/// The user's collections and their timestamps, by name
+async fn collection_timestamps(
+    db: &dyn Db<'_>,
+    user_id: &HawkIdentifier,
+) -> ApiResult<Vec<(String, SyncTimestamp)>> {
+    let mut collections: Vec<_> = db
+        .get_collection_timestamps(user_id.clone())
+        .await?
+        .into_iter()
+        .collect();
+    collections.sort();
+    Ok(collections)
+}
+
+/// Read the page of the collection's bsos beginning at `offset` (the previous
+/// page's), which includes the offset of the next page when there is one
+async fn get_page(
+    db: &dyn Db<'_>,
+    user_id: &HawkIdentifier,
+    collection: &str,
+    offset: Option<Offset>,
+) -> ApiResult<results::GetBsos> {
+    db.get_bsos(params::GetBsos {
+        user_id: user_id.clone(),
+        collection: collection.to_owned(),
+        params: BsoQueryParams {
+            full: true,
+            limit: Some(PAGE_SIZE),
+            offset,
+            ..Default::default()
+        },
+    })
+    .await
+}
+
+/// A collection's bsos pending import
+struct PendingCollection {
+    user_id: HawkIdentifier,
+    name: String,
+    modified: SyncTimestamp,
+    bsos: Vec<params::ImportBso>,
+    /// Whether any of the collection has been written yet
+    written: bool,
+}
+
+/// Write the pending bsos (and the collection's `modified`), each chunk
+/// within its own transaction
+async fn import_pending(db: &dyn Db<'_>, pending: &mut PendingCollection) -> ApiResult<()> {
+    if pending.written && pending.bsos.is_empty() {
+        return Ok(());
+    }
+    db.begin(true).await?;
+    let result = db
+        .import_bsos(params::ImportBsos {
+            user_id: pending.user_id.clone(),
+            collection: pending.name.clone(),
+            bsos: pending.bsos.split_off(0),
+            modified: pending.modified,
+        })
+        .await;
+    match result {
+        Ok(()) => db.commit().await?,
+        Err(e) => {
+            db.rollback().await?;
+            return Err(e);
+        }
+    }
+    pending.written = true;
+    Ok(())
+}
+
+/// Import every record of the archive, writing up to `chunk_size` bsos at a
+/// time. Existing bsos of the same ids are replaced.
+///
+/// Each chunk is committed in its own transaction, so an import that fails
+/// leaves its user partially written: the failed user's records must be
+/// imported again, which overwrites what was written.
+pub async fn import<R: BufRead>(
+    db: &dyn Db<'_>,
+    archive: ArchiveReader<R>,
+    chunk_size: usize,
+) -> ApiResult<ArchiveStats> {
+    let mut stats = ArchiveStats::default();
+    let mut user_id: Option<HawkIdentifier> = None;
+    let mut pending: Option<PendingCollection> = None;
+    for record in archive {
+        match record? {
+            Record::Header { .. } => Err(DbError::internal("Unexpected archive header"))?,
+            Record::User {
+                uid,
+                fxa_uid,
+                fxa_kid,
+            } => {
+                if let Some(mut collection) = pending.take() {
+                    import_pending(db, &mut collection).await?;
+                }
+                user_id = Some(HawkIdentifier {
+                    legacy_id: uid,
+                    fxa_uid,
+                    fxa_kid,
+                });
+                stats.users += 1;
+            }
+            Record::Collection { name, modified } => {
+                if let Some(mut collection) = pending.take() {
+                    import_pending(db, &mut collection).await?;
+                }
+                let user_id = user_id
+                    .clone()
+                    .ok_or_else(|| DbError::internal("Archive collection precedes its user"))?;
+                pending = Some(PendingCollection {
+                    user_id,
+                    name,
+                    modified: SyncTimestamp::from_i64(modified)?,
+                    bsos: vec![],
+                    written: false,
+                });
+                stats.collections += 1;
+            }
+            Record::Bso {
+                id,
+                sortindex,
+                payload,
+                modified,
+                expiry,
+            } => {
+                let collection = pending
+                    .as_mut()
+                    .ok_or_else(|| DbError::internal("Archive bso precedes its collection"))?;
+                collection.bsos.push(params::ImportBso {
+                    id,
+                    sortindex,
+                    payload,
+                    modified: SyncTimestamp::from_i64(modified)?,
+                    expiry,
+                });
+                stats.bsos += 1;
+                if collection.bsos.len() >= chunk_size {
+                    import_pending(db, collection).await?;
+                }
+            }
+        }
+    }
+    if let Some(mut collection) = pending.take() {
+        import_pending(db, &mut collection).await?;
+    }
+    Ok(stats)
+}
+
+/// Copy all of a user's unexpired storage from one backend to another (as
+/// if exported then imported), writing up to `chunk_size` bsos at a time.
+///
+/// The user's storage is read a page per transaction, each page written as
+/// it's read, so unlike an export the read isn't consistent should the user
+/// be written concurrently. As with `import`, a failed copy leaves the user
+/// partially written: copying it again overwrites what was written.
+pub async fn copy_user(
+    source: &dyn Db<'_>,
+    target: &dyn Db<'_>,
+    from: &HawkIdentifier,
+    to: &HawkIdentifier,
+    chunk_size: usize,
+) -> ApiResult<ArchiveStats> {
+    let mut stats = ArchiveStats {
+        users: 1,
+        ..Default::default()
+    };
+    let collections = read(source, collection_timestamps(source, from)).await?;
+    for (name, modified) in collections {
+        let mut pending = PendingCollection {
+            user_id: to.clone(),
+            name: name.clone(),
+            modified,
+            bsos: vec![],
+            written: false,
+        };
+        stats.collections += 1;
+        let mut offset = None;
+        loop {
+            let page = read(source, get_page(source, from, &name, offset)).await?;
+            for bso in page.items {
+                pending.bsos.push(params::ImportBso {
+                    id: bso.id,
+                    sortindex: bso.sortindex,
+                    payload: bso.payload,
+                    modified: bso.modified,
+                    expiry: bso.expiry,
+                });
+                stats.bsos += 1;
+                if pending.bsos.len() >= chunk_size {
+                    import_pending(target, &mut pending).await?;
+                }
+            }
+            offset = match page.offset {
+                Some(offset) => Some(offset),
+                None => break,
+            };
+        }
+        import_pending(target, &mut pending).await?;
+    }
+    Ok(stats)
+}
+
+/// Run the `db` read within its own transaction
+async fn read<T>(db: &dyn Db<'_>, read: impl Future<Output = ApiResult<T>>) -> ApiResult<T> {
+    db.begin(false).await?;
+    match read.await {
+        Ok(result) => {
+            db.commit().await?;
+            Ok(result)
+        }
+        Err(e) => {
+            db.rollback().await?;
+            Err(e)
+        }
+    }
+}
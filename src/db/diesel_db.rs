@@ -0,0 +1,1037 @@
+//! Storage queries shared by the diesel backends (MySQL, SQLite and
+//! PostgreSQL).
+//!
+//! `diesel_db_methods!` expands them into an `impl` of a backend's Db, within
+//! its models module: the queries run against that module's `schema` tables
+//! and `conn`. The backends differ only in the SQL dialect specific methods
+//! the queries call upon:
+//!
+//! - `begin(for_write)`: starts the transaction
+//! - `locked_modified(user_id, collection_id, for_write)`: reads a
+//!   collection's modified timestamp, locking it for the transaction
+//! - `insert_collection(name)`: adds a collection unless it already exists
+//! - `upsert_sql(table, columns, key, updates)`: an INSERT updating `updates`
+//!   of the existing row of the same `key`
+//! - `PAYLOAD_BYTES_SUM`: the sum of the payloads' lengths in bytes
+
+/// Implement the storage queries for the `$db` of a diesel backend, sorting
+/// by descending sortindex (with NULLs last) via `$sortindex_desc`
+macro_rules! diesel_db_methods {
+    ($db:ident, $sortindex_desc:expr) => {
+        impl $db {
+            /// APIs for collection-level locking
+            ///
+            /// The collection is locked via `locked_modified`, within the
+            /// transaction started by `begin`.
+            pub fn lock_for_read_sync(&self, params: params::LockCollection) -> Result<()> {
+                let user_id = params.user_id.legacy_id as i64;
+                let collection_id =
+                    self.get_collection_id(&params.collection)
+                        .or_else(|e| match e.kind() {
+                            // If the collection doesn't exist, we still want to start a
+                            // transaction so it will continue to not exist.
+                            DbErrorKind::CollectionNotFound => Ok(0),
+                            _ => Err(e),
+                        })?;
+                // If we already have a read or write lock then it's safe to
+                // use it as-is.
+                if self
+                    .session
+                    .borrow()
+                    .coll_locks
+                    .get(&(user_id as u32, collection_id))
+                    .is_some()
+                {
+                    return Ok(());
+                }
+
+                // Lock the db
+                self.begin(false)?;
+                let modified = self.locked_modified(user_id, collection_id, false)?;
+                if let Some(modified) = modified {
+                    let modified = SyncTimestamp::from_i64(modified)?;
+                    self.session
+                        .borrow_mut()
+                        .coll_modified_cache
+                        .insert((user_id as u32, collection_id), modified); // why does it still expect a u32 int?
+                }
+                // XXX: who's responsible for unlocking (removing the entry)
+                self.session
+                    .borrow_mut()
+                    .coll_locks
+                    .insert((user_id as u32, collection_id), CollectionLock::Read);
+                Ok(())
+            }
+
+            pub fn lock_for_write_sync(&self, params: params::LockCollection) -> Result<()> {
+                let user_id = params.user_id.legacy_id as i64;
+                let collection_id = self.get_or_create_collection_id(&params.collection)?;
+                if let Some(CollectionLock::Read) = self
+                    .session
+                    .borrow()
+                    .coll_locks
+                    .get(&(user_id as u32, collection_id))
+                {
+                    Err(DbError::internal("Can't escalate read-lock to write-lock"))?
+                }
+
+                // Lock the db
+                self.begin(true)?;
+                let modified = self.locked_modified(user_id, collection_id, true)?;
+                if let Some(modified) = modified {
+                    let modified = SyncTimestamp::from_i64(modified)?;
+                    // Forbid the write if it would not properly incr the timestamp
+                    if modified >= self.timestamp() {
+                        Err(DbErrorKind::Conflict)?
+                    }
+                    self.session
+                        .borrow_mut()
+                        .coll_modified_cache
+                        .insert((user_id as u32, collection_id), modified);
+                }
+                self.session
+                    .borrow_mut()
+                    .coll_locks
+                    .insert((user_id as u32, collection_id), CollectionLock::Write);
+                Ok(())
+            }
+
+            pub async fn begin_async(&self, for_write: bool) -> Result<()> {
+                self.begin(for_write)
+            }
+
+            pub fn commit_sync(&self) -> Result<()> {
+                if self.session.borrow().in_transaction {
+                    self.conn
+                        .transaction_manager()
+                        .commit_transaction(&self.conn)?;
+                    self.session.borrow_mut().in_transaction = false;
+                }
+                Ok(())
+            }
+
+            pub fn rollback_sync(&self) -> Result<()> {
+                if self.session.borrow().in_transaction {
+                    self.conn
+                        .transaction_manager()
+                        .rollback_transaction(&self.conn)?;
+                    self.session.borrow_mut().in_transaction = false;
+                }
+                Ok(())
+            }
+
+            fn erect_tombstone(&self, user_id: i32) -> Result<()> {
+                sql_query(Self::upsert_sql(
+                    "user_collections",
+                    &[USER_ID, COLLECTION_ID, LAST_MODIFIED],
+                    &[USER_ID, COLLECTION_ID],
+                    &[LAST_MODIFIED],
+                ))
+                .bind::<BigInt, _>(user_id as i64)
+                .bind::<Integer, _>(TOMBSTONE)
+                .bind::<BigInt, _>(self.timestamp().as_i64())
+                .execute(&self.conn)?;
+                Ok(())
+            }
+
+            pub fn delete_storage_sync(&self, user_id: HawkIdentifier) -> Result<()> {
+                let user_id = user_id.legacy_id as i64;
+                // Delete user data.
+                delete(bso::table)
+                    .filter(bso::user_id.eq(user_id))
+                    .execute(&self.conn)?;
+                // Delete user collections.
+                delete(user_collections::table)
+                    .filter(user_collections::user_id.eq(user_id))
+                    .execute(&self.conn)?;
+                Ok(())
+            }
+
+            pub fn purge_storage_sync(
+                &self,
+                params: params::PurgeStorage,
+            ) -> Result<results::PurgeStorage> {
+                let user_id = params.user_id.legacy_id as i64;
+                if params.dry_run {
+                    return Ok(results::PurgeStorage {
+                        bsos: bso::table
+                            .filter(bso::user_id.eq(user_id))
+                            .count()
+                            .get_result(&self.conn)?,
+                        user_collections: user_collections::table
+                            .filter(user_collections::user_id.eq(user_id))
+                            .count()
+                            .get_result(&self.conn)?,
+                        batches: batch_uploads::table
+                            .filter(batch_uploads::user_id.eq(user_id))
+                            .count()
+                            .get_result(&self.conn)?,
+                    });
+                }
+
+                let bsos = delete(bso::table)
+                    .filter(bso::user_id.eq(user_id))
+                    .execute(&self.conn)?;
+                let user_collections = delete(user_collections::table)
+                    .filter(user_collections::user_id.eq(user_id))
+                    .execute(&self.conn)?;
+                let batches = delete(batch_uploads::table)
+                    .filter(batch_uploads::user_id.eq(user_id))
+                    .execute(&self.conn)?;
+                delete(batch_upload_items::table)
+                    .filter(batch_upload_items::user_id.eq(user_id))
+                    .execute(&self.conn)?;
+                Ok(results::PurgeStorage {
+                    bsos: bsos as i64,
+                    user_collections: user_collections as i64,
+                    batches: batches as i64,
+                })
+            }
+
+            pub fn expire_batches_sync(
+                &self,
+                user_id: HawkIdentifier,
+            ) -> Result<results::ExpireBatches> {
+                let user_id = user_id.legacy_id as i64;
+                let expired = delete(batch_uploads::table)
+                    .filter(batch_uploads::user_id.eq(user_id))
+                    .execute(&self.conn)?;
+                delete(batch_upload_items::table)
+                    .filter(batch_upload_items::user_id.eq(user_id))
+                    .execute(&self.conn)?;
+                Ok(expired as i64)
+            }
+
+            pub fn purge_expired_sync(
+                &self,
+                params: params::PurgeExpired,
+            ) -> Result<results::PurgeExpired> {
+                let max_user_id = match self.get_max_user_id_sync()? {
+                    Some(max_user_id) => max_user_id,
+                    None => return Ok(Default::default()),
+                };
+                let (start, end) = params.user_id_range(max_user_id);
+                let (start, end) = (start as i64, end as i64);
+                let now = self.timestamp().as_i64();
+
+                let expired: Vec<(i64, i32, String)> = bso::table
+                    .select((bso::user_id, bso::collection_id, bso::id))
+                    .filter(bso::user_id.ge(start))
+                    .filter(bso::user_id.lt(end))
+                    .filter(bso::expiry.le(now))
+                    .limit(params.limit)
+                    .load(&self.conn)?;
+                let mut by_collection: HashMap<(i64, i32), Vec<String>> = HashMap::new();
+                for (user_id, collection_id, id) in expired {
+                    by_collection
+                        .entry((user_id, collection_id))
+                        .or_default()
+                        .push(id);
+                }
+                let mut bsos = 0;
+                for ((user_id, collection_id), ids) in by_collection {
+                    bsos += delete(bso::table)
+                        .filter(bso::user_id.eq(user_id))
+                        .filter(bso::collection_id.eq(collection_id))
+                        .filter(bso::id.eq_any(ids))
+                        .filter(bso::expiry.le(now))
+                        .execute(&self.conn)?;
+                    if self.quota_enabled {
+                        // Expiring isn't a write: the collection's timestamp stays
+                        let quota = self.calc_quota_usage_sync(user_id as u32, collection_id)?;
+                        update(user_collections::table)
+                            .filter(user_collections::user_id.eq(user_id))
+                            .filter(user_collections::collection_id.eq(collection_id))
+                            .set((
+                                user_collections::count.eq(quota.count),
+                                user_collections::total_bytes.eq(quota.total_bytes as i64),
+                            ))
+                            .execute(&self.conn)?;
+                    }
+                }
+
+                let batch_ids: Vec<i64> = batch_uploads::table
+                    .select(batch_uploads::batch_id)
+                    .filter(batch_uploads::user_id.ge(start))
+                    .filter(batch_uploads::user_id.lt(end))
+                    .filter(batch_uploads::batch_id.lt(now - BATCH_LIFETIME))
+                    .limit(params.limit)
+                    .load(&self.conn)?;
+                let batches = delete(batch_uploads::table)
+                    .filter(batch_uploads::user_id.ge(start))
+                    .filter(batch_uploads::user_id.lt(end))
+                    .filter(batch_uploads::batch_id.eq_any(&batch_ids))
+                    .execute(&self.conn)?;
+                delete(batch_upload_items::table)
+                    .filter(batch_upload_items::user_id.ge(start))
+                    .filter(batch_upload_items::user_id.lt(end))
+                    .filter(batch_upload_items::batch_id.eq_any(&batch_ids))
+                    .execute(&self.conn)?;
+                Ok(results::PurgeExpired {
+                    bsos: bsos as i64,
+                    batches: batches as i64,
+                })
+            }
+
+            /// Up to `limit` ids, ascending, of the users with storage whose ids are
+            /// within `start..end` (for `migrate_node`)
+            pub fn get_user_ids_sync(&self, start: u64, end: u64, limit: i64) -> Result<Vec<u64>> {
+                let user_ids: Vec<i64> = user_collections::table
+                    .select(user_collections::user_id)
+                    .filter(user_collections::user_id.ge(start as i64))
+                    .filter(user_collections::user_id.lt(end as i64))
+                    .distinct()
+                    .order(user_collections::user_id)
+                    .limit(limit)
+                    .load(&self.conn)?;
+                Ok(user_ids.into_iter().map(|user_id| user_id as u64).collect())
+            }
+
+            /// The highest id of the users with storage
+            pub fn get_max_user_id_sync(&self) -> Result<Option<u64>> {
+                let user_id: Option<i64> = user_collections::table
+                    .select(max(user_collections::user_id))
+                    .first(&self.conn)?;
+                Ok(user_id.map(|user_id| user_id as u64))
+            }
+
+            // Deleting the collection should result in:
+            //  - collection does not appear in /info/collections
+            //  - X-Last-Modified timestamp at the storage level changing
+            pub fn delete_collection_sync(
+                &self,
+                params: params::DeleteCollection,
+            ) -> Result<SyncTimestamp> {
+                let user_id = params.user_id.legacy_id as i64;
+                let collection_id = self.get_collection_id(&params.collection)?;
+                let mut count = delete(bso::table)
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(&collection_id))
+                    .execute(&self.conn)?;
+                count += delete(user_collections::table)
+                    .filter(user_collections::user_id.eq(user_id))
+                    .filter(user_collections::collection_id.eq(&collection_id))
+                    .execute(&self.conn)?;
+                if count == 0 {
+                    Err(DbErrorKind::CollectionNotFound)?
+                } else {
+                    self.erect_tombstone(user_id as i32)?;
+                }
+                self.get_storage_timestamp_sync(params.user_id)
+            }
+
+            pub(super) fn get_or_create_collection_id(&self, name: &str) -> Result<i32> {
+                if let Some(id) = self.coll_cache.get_id(name)? {
+                    return Ok(id);
+                }
+
+                let id = self.conn.transaction(|| {
+                    self.insert_collection(name)?;
+
+                    collections::table
+                        .select(collections::id)
+                        .filter(collections::name.eq(name))
+                        .first(&self.conn)
+                })?;
+
+                if !self.session.borrow().in_write_transaction {
+                    self.coll_cache.put(id, name.to_owned())?;
+                }
+
+                Ok(id)
+            }
+
+            pub(super) fn get_collection_id(&self, name: &str) -> Result<i32> {
+                if let Some(id) = self.coll_cache.get_id(name)? {
+                    return Ok(id);
+                }
+
+                let id = collections::table
+                    .select(collections::id)
+                    .filter(collections::name.eq(name))
+                    .first::<i32>(&self.conn)
+                    .optional()?
+                    .ok_or(DbErrorKind::CollectionNotFound)?;
+                if !self.session.borrow().in_write_transaction {
+                    self.coll_cache.put(id, name.to_owned())?;
+                }
+                Ok(id)
+            }
+
+            fn _get_collection_name(&self, id: i32) -> Result<String> {
+                let name = if let Some(name) = self.coll_cache.get_name(id)? {
+                    name
+                } else {
+                    collections::table
+                        .select(collections::name)
+                        .filter(collections::id.eq(id))
+                        .first::<String>(&self.conn)
+                        .optional()?
+                        .ok_or(DbErrorKind::CollectionNotFound)?
+                };
+                Ok(name)
+            }
+
+            /// Fail with `Quota` when the user's collection is at its quota
+            pub fn check_quota(
+                &self,
+                user_id: &HawkIdentifier,
+                collection: &str,
+                collection_id: i32,
+            ) -> Result<()> {
+                if !self.quota_enabled {
+                    return Ok(());
+                }
+                let quota = match self.limits.quota_for(collection) {
+                    Some(quota) => quota as usize,
+                    None => return Ok(()),
+                };
+                let usage = self.get_quota_usage_sync(params::GetQuotaUsage {
+                    user_id: user_id.clone(),
+                    collection: collection.to_owned(),
+                    collection_id,
+                })?;
+                if usage.total_bytes >= quota {
+                    let mut tags = Tags::default();
+                    tags.tags
+                        .insert("collection".to_owned(), collection.to_owned());
+                    self.metrics
+                        .incr_with_tags("storage.quota.at_limit", Some(tags));
+                    return Err(DbErrorKind::Quota.into());
+                }
+                Ok(())
+            }
+
+            pub fn put_bso_sync(&self, bso: params::PutBso) -> Result<results::PutBso> {
+                let collection_id = self.get_or_create_collection_id(&bso.collection)?;
+                let user_id: u64 = bso.user_id.legacy_id;
+                let timestamp = self.timestamp().as_i64();
+                self.check_quota(&bso.user_id, &bso.collection, collection_id)?;
+
+                self.conn.transaction(|| {
+                    let payload = bso.payload.as_deref().unwrap_or_default();
+                    let sortindex = bso.sortindex;
+                    let ttl = bso.ttl.map_or(DEFAULT_BSO_TTL, |ttl| ttl);
+                    // Only the given fields are updated (along with modified,
+                    // when the sortindex or payload are)
+                    let mut updates = vec![];
+                    if bso.sortindex.is_some() {
+                        updates.push("sortindex");
+                    }
+                    if bso.payload.is_some() {
+                        updates.push("payload");
+                    }
+                    if bso.ttl.is_some() {
+                        updates.push(EXPIRY);
+                    }
+                    if bso.payload.is_some() || bso.sortindex.is_some() {
+                        updates.push(MODIFIED);
+                    }
+                    let q = Self::upsert_sql(
+                        "bso",
+                        &[
+                            USER_ID,
+                            COLLECTION_ID,
+                            "id",
+                            "sortindex",
+                            "payload",
+                            MODIFIED,
+                            EXPIRY,
+                        ],
+                        &[USER_ID, COLLECTION_ID, "id"],
+                        &updates,
+                    );
+                    sql_query(q)
+                        .bind::<BigInt, _>(user_id as i64) // XXX:
+                        .bind::<Integer, _>(&collection_id)
+                        .bind::<Text, _>(&bso.id)
+                        .bind::<Nullable<Integer>, _>(sortindex)
+                        .bind::<Text, _>(payload)
+                        .bind::<BigInt, _>(timestamp)
+                        .bind::<BigInt, _>(timestamp + (i64::from(ttl) * 1000))
+                        .execute(&self.conn)?;
+                    self.update_collection(user_id as u32, collection_id)
+                })
+            }
+
+            /// Write the bsos verbatim, preserving their modified and expiry, then
+            /// set the collection's modified
+            pub fn import_bsos_sync(
+                &self,
+                params: params::ImportBsos,
+            ) -> Result<results::ImportBsos> {
+                let collection_id = self.get_or_create_collection_id(&params.collection)?;
+                let user_id = params.user_id.legacy_id;
+                self.conn.transaction(|| {
+                    let q = Self::upsert_sql(
+                        "bso",
+                        &[
+                            USER_ID,
+                            COLLECTION_ID,
+                            "id",
+                            "sortindex",
+                            "payload",
+                            MODIFIED,
+                            EXPIRY,
+                        ],
+                        &[USER_ID, COLLECTION_ID, "id"],
+                        &["sortindex", "payload", MODIFIED, EXPIRY],
+                    );
+                    for bso in &params.bsos {
+                        sql_query(&q)
+                            .bind::<BigInt, _>(user_id as i64)
+                            .bind::<Integer, _>(&collection_id)
+                            .bind::<Text, _>(&bso.id)
+                            .bind::<Nullable<Integer>, _>(bso.sortindex)
+                            .bind::<Text, _>(&bso.payload)
+                            .bind::<BigInt, _>(bso.modified.as_i64())
+                            .bind::<BigInt, _>(bso.expiry)
+                            .execute(&self.conn)?;
+                    }
+                    self.upsert_user_collection(user_id as u32, collection_id, params.modified)
+                })
+            }
+
+            pub fn get_bsos_sync(&self, params: params::GetBsos) -> Result<results::GetBsos> {
+                let user_id = params.user_id.legacy_id as i64;
+                let collection_id = self.get_collection_id(&params.collection)?;
+                let BsoQueryParams {
+                    newer,
+                    older,
+                    sort,
+                    limit,
+                    offset,
+                    ids,
+                    ..
+                } = params.params;
+
+                let mut query = bso::table
+                    .select((
+                        bso::id,
+                        bso::modified,
+                        bso::payload,
+                        bso::sortindex,
+                        bso::expiry,
+                    ))
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(collection_id as i32)) // XXX:
+                    .filter(bso::expiry.gt(self.timestamp().as_i64()))
+                    .into_boxed();
+
+                if let Some(older) = older {
+                    query = query.filter(bso::modified.lt(older.as_i64()));
+                }
+                if let Some(newer) = newer {
+                    query = query.filter(bso::modified.gt(newer.as_i64()));
+                }
+
+                if !ids.is_empty() {
+                    query = query.filter(bso::id.eq_any(ids));
+                }
+
+                // Ties are broken by the id, so pages neither overlap nor skip any
+                query = match sort {
+                    Sorting::Index => query.order($sortindex_desc).then_order_by(bso::id.desc()),
+                    Sorting::Newest => query
+                        .order(bso::modified.desc())
+                        .then_order_by(bso::id.desc()),
+                    Sorting::Oldest => query
+                        .order(bso::modified.asc())
+                        .then_order_by(bso::id.asc()),
+                    Sorting::None => query.order(bso::id.asc()),
+                };
+
+                let limit = limit.map(i64::from).unwrap_or(-1);
+                if limit >= 0 {
+                    // fetch an extra row to detect if there are more rows that
+                    // match the query conditions
+                    query = query.limit(limit + 1);
+                }
+
+                let offset = offset.unwrap_or_default();
+                if let Some(key) = &offset.key {
+                    query = query.filter(Self::key_filter(key)?);
+                } else if offset.offset != 0 {
+                    if limit < 0 {
+                        // MySQL and SQLite only allow an OFFSET after a LIMIT
+                        query = query.limit(i64::MAX);
+                    }
+                    query = query.offset(offset.offset as i64);
+                }
+                let mut bsos = query.load::<results::GetBso>(&self.conn)?;
+
+                // XXX: an additional get_collection_timestamp is done here in
+                // python to trigger potential CollectionNotFoundErrors
+                //if bsos.len() == 0 {
+                //}
+
+                let next_offset = if limit >= 0 && bsos.len() > limit as usize {
+                    bsos.pop();
+                    let last = bsos
+                        .last()
+                        .map(|bso| (bso.id.as_str(), bso.modified, bso.sortindex));
+                    Some(offset.next(bsos.len(), sort, last))
+                } else {
+                    None
+                };
+
+                Ok(results::GetBsos {
+                    items: bsos,
+                    offset: next_offset,
+                })
+            }
+
+            pub fn get_bso_ids_sync(&self, params: params::GetBsos) -> Result<results::GetBsoIds> {
+                let user_id = params.user_id.legacy_id as i64;
+                let collection_id = self.get_collection_id(&params.collection)?;
+                let BsoQueryParams {
+                    newer,
+                    older,
+                    sort,
+                    limit,
+                    offset,
+                    ids,
+                    ..
+                } = params.params;
+
+                let mut query = bso::table
+                    .select((bso::id, bso::modified, bso::sortindex))
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(collection_id as i32)) // XXX:
+                    .filter(bso::expiry.gt(self.timestamp().as_i64()))
+                    .into_boxed();
+
+                if let Some(older) = older {
+                    query = query.filter(bso::modified.lt(older.as_i64()));
+                }
+                if let Some(newer) = newer {
+                    query = query.filter(bso::modified.gt(newer.as_i64()));
+                }
+
+                if !ids.is_empty() {
+                    query = query.filter(bso::id.eq_any(ids));
+                }
+
+                query = match sort {
+                    Sorting::Index => query.order($sortindex_desc).then_order_by(bso::id.desc()),
+                    Sorting::Newest => query
+                        .order(bso::modified.desc())
+                        .then_order_by(bso::id.desc()),
+                    Sorting::Oldest => query
+                        .order(bso::modified.asc())
+                        .then_order_by(bso::id.asc()),
+                    Sorting::None => query.order(bso::id.asc()),
+                };
+
+                let limit = limit.map(i64::from).unwrap_or(-1);
+                if limit >= 0 {
+                    // fetch an extra row to detect if there are more rows that
+                    // match the query conditions
+                    query = query.limit(limit + 1);
+                }
+
+                let offset = offset.unwrap_or_default();
+                if let Some(key) = &offset.key {
+                    query = query.filter(Self::key_filter(key)?);
+                } else if offset.offset != 0 {
+                    if limit < 0 {
+                        // MySQL and SQLite only allow an OFFSET after a LIMIT
+                        query = query.limit(i64::MAX);
+                    }
+                    query = query.offset(offset.offset as i64);
+                }
+                let mut rows = query.load::<(String, SyncTimestamp, Option<i32>)>(&self.conn)?;
+
+                // XXX: an additional get_collection_timestamp is done here in
+                // python to trigger potential CollectionNotFoundErrors
+                //if bsos.len() == 0 {
+                //}
+
+                let next_offset = if limit >= 0 && rows.len() > limit as usize {
+                    rows.pop();
+                    let last = rows
+                        .last()
+                        .map(|(id, modified, sortindex)| (id.as_str(), *modified, *sortindex));
+                    Some(offset.next(rows.len(), sort, last))
+                } else {
+                    None
+                };
+
+                Ok(results::GetBsoIds {
+                    items: rows.into_iter().map(|(id, _, _)| id).collect(),
+                    offset: next_offset,
+                })
+            }
+
+            /// The filter for the BSOs sorted after the one of `key`
+            fn key_filter(key: &OffsetKey) -> Result<BsoFilter> {
+                let id = key.id.clone();
+                Ok(match (key.sort, key.value) {
+                    (Sorting::None, _) => Box::new(bso::id.gt(id)),
+                    (Sorting::Newest, Some(value)) => Box::new(
+                        bso::modified
+                            .lt(value)
+                            .or(bso::modified.eq(value).and(bso::id.lt(id))),
+                    ),
+                    (Sorting::Oldest, Some(value)) => Box::new(
+                        bso::modified
+                            .gt(value)
+                            .or(bso::modified.eq(value).and(bso::id.gt(id))),
+                    ),
+                    // NULL sortindexes sort last (descending). The (integer)
+                    // sortindex is inlined as diesel's comparisons of nullable
+                    // columns aren't Bool
+                    (Sorting::Index, Some(value)) => Box::new(
+                        sql::<Bool>(&format!("sortindex < {}", value))
+                            .or(bso::sortindex.is_null())
+                            .or(sql::<Bool>(&format!("sortindex = {}", value)).and(bso::id.lt(id))),
+                    ),
+                    (Sorting::Index, None) => {
+                        Box::new(bso::sortindex.is_null().and(bso::id.lt(id)))
+                    }
+                    _ => return Err(DbErrorKind::Integrity("Invalid offset".to_owned()).into()),
+                })
+            }
+
+            pub fn get_bso_sync(&self, params: params::GetBso) -> Result<Option<results::GetBso>> {
+                let user_id = params.user_id.legacy_id as i64;
+                let collection_id = self.get_collection_id(&params.collection)?;
+                Ok(bso::table
+                    .select((
+                        bso::id,
+                        bso::modified,
+                        bso::payload,
+                        bso::sortindex,
+                        bso::expiry,
+                    ))
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(&collection_id))
+                    .filter(bso::id.eq(&params.id))
+                    .filter(bso::expiry.ge(self.timestamp().as_i64()))
+                    .get_result::<results::GetBso>(&self.conn)
+                    .optional()?)
+            }
+
+            pub fn delete_bso_sync(&self, params: params::DeleteBso) -> Result<results::DeleteBso> {
+                let user_id = params.user_id.legacy_id;
+                let collection_id = self.get_collection_id(&params.collection)?;
+                let affected_rows = delete(bso::table)
+                    .filter(bso::user_id.eq(user_id as i64))
+                    .filter(bso::collection_id.eq(&collection_id))
+                    .filter(bso::id.eq(params.id))
+                    .filter(bso::expiry.gt(&self.timestamp().as_i64()))
+                    .execute(&self.conn)?;
+                if affected_rows == 0 {
+                    Err(DbErrorKind::BsoNotFound)?
+                }
+                self.update_collection(user_id as u32, collection_id)
+            }
+
+            pub fn delete_bsos_sync(
+                &self,
+                params: params::DeleteBsos,
+            ) -> Result<results::DeleteBsos> {
+                let user_id = params.user_id.legacy_id as i64;
+                let collection_id = self.get_collection_id(&params.collection)?;
+                delete(bso::table)
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(&collection_id))
+                    .filter(bso::id.eq_any(params.ids))
+                    .execute(&self.conn)?;
+                self.update_collection(user_id as u32, collection_id)
+            }
+
+            pub fn post_bsos_sync(&self, input: params::PostBsos) -> Result<results::PostBsos> {
+                let collection_id = self.get_or_create_collection_id(&input.collection)?;
+                let mut result = results::PostBsos {
+                    modified: self.timestamp(),
+                    success: Default::default(),
+                    failed: input.failed,
+                };
+
+                for pbso in input.bsos {
+                    let id = pbso.id;
+                    let put_result = self.put_bso_sync(params::PutBso {
+                        user_id: input.user_id.clone(),
+                        collection: input.collection.clone(),
+                        id: id.clone(),
+                        payload: pbso.payload,
+                        sortindex: pbso.sortindex,
+                        ttl: pbso.ttl,
+                    });
+                    // XXX: python version doesn't report failures from db
+                    // layer.. (wouldn't db failures abort the entire transaction
+                    // anyway?)
+                    // XXX: sanitize to.to_string()?
+                    match put_result {
+                        Ok(_) => result.success.push(id),
+                        Err(e) => {
+                            result.failed.insert(id, e.to_string());
+                        }
+                    }
+                }
+                self.update_collection(input.user_id.legacy_id as u32, collection_id)?;
+                Ok(result)
+            }
+
+            pub fn get_storage_timestamp_sync(
+                &self,
+                user_id: HawkIdentifier,
+            ) -> Result<SyncTimestamp> {
+                let user_id = user_id.legacy_id as i64;
+                let modified = user_collections::table
+                    .select(max(user_collections::modified))
+                    .filter(user_collections::user_id.eq(user_id))
+                    .first::<Option<i64>>(&self.conn)?
+                    .unwrap_or_default();
+                Ok(SyncTimestamp::from_i64(modified)?)
+            }
+
+            pub fn get_collection_timestamp_sync(
+                &self,
+                params: params::GetCollectionTimestamp,
+            ) -> Result<SyncTimestamp> {
+                let user_id = params.user_id.legacy_id as u32;
+                let collection_id = self.get_collection_id(&params.collection)?;
+                if let Some(modified) = self
+                    .session
+                    .borrow()
+                    .coll_modified_cache
+                    .get(&(user_id, collection_id))
+                {
+                    return Ok(*modified);
+                }
+                user_collections::table
+                    .select(user_collections::modified)
+                    .filter(user_collections::user_id.eq(user_id as i64))
+                    .filter(user_collections::collection_id.eq(collection_id))
+                    .first(&self.conn)
+                    .optional()?
+                    .ok_or_else(|| DbErrorKind::CollectionNotFound.into())
+            }
+
+            pub fn get_bso_timestamp_sync(
+                &self,
+                params: params::GetBsoTimestamp,
+            ) -> Result<SyncTimestamp> {
+                let user_id = params.user_id.legacy_id as i64;
+                let collection_id = self.get_collection_id(&params.collection)?;
+                let modified = bso::table
+                    .select(bso::modified)
+                    .filter(bso::user_id.eq(user_id))
+                    .filter(bso::collection_id.eq(&collection_id))
+                    .filter(bso::id.eq(&params.id))
+                    .first::<i64>(&self.conn)
+                    .optional()?
+                    .unwrap_or_default();
+                Ok(SyncTimestamp::from_i64(modified)?)
+            }
+
+            pub fn get_collection_timestamps_sync(
+                &self,
+                user_id: HawkIdentifier,
+            ) -> Result<results::GetCollectionTimestamps> {
+                let modifieds = user_collections::table
+                    .select((user_collections::collection_id, user_collections::modified))
+                    .filter(user_collections::user_id.eq(user_id.legacy_id as i64))
+                    .filter(user_collections::collection_id.ne(TOMBSTONE))
+                    .load::<(i32, i64)>(&self.conn)?
+                    .into_iter()
+                    .map(|(collection_id, modified)| {
+                        SyncTimestamp::from_i64(modified).map(|ts| (collection_id, ts))
+                    })
+                    .collect::<Result<HashMap<_, _>>>()?;
+                self.map_collection_names(modifieds)
+            }
+
+            fn schema_version_sync(&self) -> Result<results::SchemaVersion> {
+                let current = self
+                    .conn
+                    .latest_run_migration_version()?
+                    .and_then(|version| version.parse::<i64>().ok())
+                    .unwrap_or_default();
+                Ok(results::SchemaVersion {
+                    current,
+                    latest: LATEST_MIGRATION_VERSION,
+                })
+            }
+
+            fn map_collection_names<T>(
+                &self,
+                by_id: HashMap<i32, T>,
+            ) -> Result<HashMap<String, T>> {
+                let mut names = self.load_collection_names(by_id.keys())?;
+                by_id
+                    .into_iter()
+                    .map(|(id, value)| {
+                        names.remove(&id).map(|name| (name, value)).ok_or_else(|| {
+                            DbError::internal("load_collection_names unknown collection id")
+                        })
+                    })
+                    .collect()
+            }
+
+            fn load_collection_names<'a>(
+                &self,
+                collection_ids: impl Iterator<Item = &'a i32>,
+            ) -> Result<HashMap<i32, String>> {
+                let mut names = HashMap::new();
+                let mut uncached = Vec::new();
+                for &id in collection_ids {
+                    if let Some(name) = self.coll_cache.get_name(id)? {
+                        names.insert(id, name);
+                    } else {
+                        uncached.push(id);
+                    }
+                }
+
+                if !uncached.is_empty() {
+                    let result = collections::table
+                        .select((collections::id, collections::name))
+                        .filter(collections::id.eq_any(uncached))
+                        .load::<(i32, String)>(&self.conn)?;
+
+                    for (id, name) in result {
+                        names.insert(id, name.clone());
+                        if !self.session.borrow().in_write_transaction {
+                            self.coll_cache.put(id, name)?;
+                        }
+                    }
+                }
+
+                Ok(names)
+            }
+
+            pub(super) fn update_collection(
+                &self,
+                user_id: u32,
+                collection_id: i32,
+            ) -> Result<SyncTimestamp> {
+                self.upsert_user_collection(user_id, collection_id, self.timestamp())?;
+                Ok(self.timestamp())
+            }
+
+            /// Set the collection's modified timestamp (and quota usage)
+            fn upsert_user_collection(
+                &self,
+                user_id: u32,
+                collection_id: i32,
+                modified: SyncTimestamp,
+            ) -> Result<()> {
+                let quota = if self.quota_enabled {
+                    self.calc_quota_usage_sync(user_id, collection_id)?
+                } else {
+                    results::GetQuotaUsage {
+                        count: 0,
+                        total_bytes: 0,
+                    }
+                };
+                let upsert = Self::upsert_sql(
+                    "user_collections",
+                    &[USER_ID, COLLECTION_ID, LAST_MODIFIED, TOTAL_BYTES, COUNT],
+                    &[USER_ID, COLLECTION_ID],
+                    &[LAST_MODIFIED, TOTAL_BYTES, COUNT],
+                );
+                sql_query(upsert)
+                    .bind::<BigInt, _>(user_id as i64)
+                    .bind::<Integer, _>(&collection_id)
+                    .bind::<BigInt, _>(&modified.as_i64())
+                    .bind::<BigInt, _>(quota.total_bytes as i64)
+                    .bind::<Integer, _>(&quota.count)
+                    .execute(&self.conn)?;
+                Ok(())
+            }
+
+            // Perform a lighter weight "read only" storage size check
+            pub fn get_storage_usage_sync(
+                &self,
+                user_id: HawkIdentifier,
+            ) -> Result<results::GetStorageUsage> {
+                let uid = user_id.legacy_id as i64;
+                let total_bytes = bso::table
+                    .select(sql::<Nullable<BigInt>>(PAYLOAD_BYTES_SUM))
+                    .filter(bso::user_id.eq(uid))
+                    .filter(bso::expiry.gt(&self.timestamp().as_i64()))
+                    .get_result::<Option<i64>>(&self.conn)?;
+                Ok(total_bytes.unwrap_or_default() as u64)
+            }
+
+            // Perform a lighter weight "read only" quota storage check
+            pub fn get_quota_usage_sync(
+                &self,
+                params: params::GetQuotaUsage,
+            ) -> Result<results::GetQuotaUsage> {
+                let uid = params.user_id.legacy_id as i64;
+                let (total_bytes, count): (i64, i32) = user_collections::table
+                    .select((user_collections::total_bytes, user_collections::count))
+                    .filter(user_collections::user_id.eq(uid))
+                    .filter(user_collections::collection_id.eq(params.collection_id))
+                    .first(&self.conn)
+                    .optional()?
+                    .unwrap_or_default();
+                Ok(results::GetQuotaUsage {
+                    total_bytes: total_bytes as usize,
+                    count,
+                })
+            }
+
+            // perform a heavier weight quota calculation
+            pub fn calc_quota_usage_sync(
+                &self,
+                user_id: u32,
+                collection_id: i32,
+            ) -> Result<results::GetQuotaUsage> {
+                let (total_bytes, count): (Option<i64>, i64) = bso::table
+                    .select((sql::<Nullable<BigInt>>(PAYLOAD_BYTES_SUM), count_star()))
+                    .filter(bso::user_id.eq(user_id as i64))
+                    .filter(bso::expiry.gt(self.timestamp().as_i64()))
+                    .filter(bso::collection_id.eq(collection_id))
+                    .get_result(&self.conn)?;
+                Ok(results::GetQuotaUsage {
+                    total_bytes: total_bytes.unwrap_or_default() as usize,
+                    count: count as i32,
+                })
+            }
+
+            pub fn get_collection_usage_sync(
+                &self,
+                user_id: HawkIdentifier,
+            ) -> Result<results::GetCollectionUsage> {
+                let counts = bso::table
+                    .select((bso::collection_id, sql::<BigInt>(PAYLOAD_BYTES_SUM)))
+                    .filter(bso::user_id.eq(user_id.legacy_id as i64))
+                    .filter(bso::expiry.gt(&self.timestamp().as_i64()))
+                    .group_by(bso::collection_id)
+                    .load(&self.conn)?
+                    .into_iter()
+                    .collect();
+                self.map_collection_names(counts)
+            }
+
+            pub fn get_collection_counts_sync(
+                &self,
+                user_id: HawkIdentifier,
+            ) -> Result<results::GetCollectionCounts> {
+                let counts = bso::table
+                    .select((
+                        bso::collection_id,
+                        sql::<BigInt>(&format!(
+                            "COUNT({collection_id})",
+                            collection_id = COLLECTION_ID
+                        )),
+                    ))
+                    .filter(bso::user_id.eq(user_id.legacy_id as i64))
+                    .filter(bso::expiry.gt(&self.timestamp().as_i64()))
+                    .group_by(bso::collection_id)
+                    .load(&self.conn)?
+                    .into_iter()
+                    .collect();
+                self.map_collection_names(counts)
+            }
+
+            pub fn timestamp(&self) -> SyncTimestamp {
+                self.session.borrow().timestamp
+            }
+        }
+    };
+}
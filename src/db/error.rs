@@ -32,6 +32,15 @@ pub enum DbErrorKind {
     #[fail(display = "Error migrating the database: {}", _0)]
     Migration(diesel_migrations::RunMigrationsError),
 
+    #[fail(display = "Error migrating the Spanner database: {}", _0)]
+    SpannerMigration(String),
+
+    #[fail(
+        display = "Database schema version {} is behind the expected {}: run `syncstorage migrate`",
+        _0, _1
+    )]
+    SchemaBehind(i64, i64),
+
     #[fail(display = "Specified collection does not exist")]
     CollectionNotFound,
 
@@ -41,6 +50,9 @@ pub enum DbErrorKind {
     #[fail(display = "Specified batch does not exist")]
     BatchNotFound,
 
+    #[fail(display = "Batch exceeds the total records or bytes limit")]
+    BatchTooLarge,
+
     #[fail(display = "An attempt at a conflicting write")]
     Conflict,
 
@@ -55,6 +67,9 @@ pub enum DbErrorKind {
 
     #[fail(display = "User over quota")]
     Quota,
+
+    #[fail(display = "No Tokenserver node available for allocation")]
+    NodeUnavailable,
 }
 
 impl DbError {
@@ -83,13 +98,15 @@ impl From<Context<DbErrorKind>> for DbError {
         let status = match inner.get_context() {
             DbErrorKind::CollectionNotFound | DbErrorKind::BsoNotFound => StatusCode::NOT_FOUND,
             // Matching the Python code here (a 400 vs 404)
-            DbErrorKind::BatchNotFound | DbErrorKind::SpannerTooLarge(_) => StatusCode::BAD_REQUEST,
+            DbErrorKind::BatchNotFound
+            | DbErrorKind::BatchTooLarge
+            | DbErrorKind::SpannerTooLarge(_) => StatusCode::BAD_REQUEST,
             // NOTE: the protocol specification states that we should return a
             // "409 Conflict" response here, but clients currently do not
             // handle these respones very well:
             //  * desktop bug: https://bugzilla.mozilla.org/show_bug.cgi?id=959034
             //  * android bug: https://bugzilla.mozilla.org/show_bug.cgi?id=959032
-            DbErrorKind::Conflict => StatusCode::SERVICE_UNAVAILABLE,
+            DbErrorKind::Conflict | DbErrorKind::NodeUnavailable => StatusCode::SERVICE_UNAVAILABLE,
             DbErrorKind::Quota => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
@@ -0,0 +1,6 @@
+pub mod models;
+pub mod pool;
+#[cfg(test)]
+mod test;
+
+pub use self::pool::MemoryDbPool;
@@ -0,0 +1,1526 @@
+use futures::future;
+
+use std::{
+    cell::RefCell,
+    cmp,
+    collections::HashMap,
+    fmt,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use crate::db::{
+    error::{DbError, DbErrorKind},
+    mysql::models::{CollectionLock, DEFAULT_BSO_TTL, TOMBSTONE},
+    params, results,
+    util::SyncTimestamp,
+    Db, DbFuture, Sorting, BATCH_LIFETIME, FIRST_CUSTOM_COLLECTION_ID, NODE_RELEASE_RATE,
+    STD_COLLS,
+};
+use crate::server::metrics::Metrics;
+use crate::settings::ServerLimits;
+use crate::web::extractors::{BsoQueryParams, HawkIdentifier};
+use crate::web::tags::Tags;
+
+pub type Result<T> = std::result::Result<T, DbError>;
+
+/// The expiry of batch committed bsos lacking a ttl (in seconds)
+const MAXTTL: i64 = 2_100_000_000;
+
+/// Source of `MemoryDb` session ids, identifying their write locks
+static NEXT_SESSION_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Everything stored by a pool, shared by all of its `MemoryDb`s
+#[derive(Debug)]
+pub struct MemoryStore {
+    collections: HashMap<String, i32>,
+    next_collection_id: i32,
+    users: HashMap<u64, UserData>,
+    /// Users write locked by a session (by its id) until it commits or rolls
+    /// back
+    write_locks: HashMap<u64, usize>,
+    tokenserver: TokenserverData,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self {
+            collections: STD_COLLS
+                .iter()
+                .map(|(id, name)| ((*name).to_owned(), *id))
+                .collect(),
+            next_collection_id: FIRST_CUSTOM_COLLECTION_ID,
+            users: Default::default(),
+            write_locks: Default::default(),
+            tokenserver: Default::default(),
+        }
+    }
+}
+
+/// A user's storage
+#[derive(Clone, Debug, Default)]
+struct UserData {
+    /// Collection modified timestamps (along with the TOMBSTONE's)
+    collections: HashMap<i32, SyncTimestamp>,
+    bsos: HashMap<i32, HashMap<String, Bso>>,
+    batches: HashMap<i64, Batch>,
+}
+
+#[derive(Clone, Debug)]
+struct Bso {
+    sortindex: Option<i32>,
+    payload: String,
+    modified: SyncTimestamp,
+    /// Expiration in milliseconds since epoch
+    expiry: i64,
+}
+
+#[derive(Clone, Debug)]
+struct Batch {
+    collection_id: i32,
+    /// Pending bsos, their fields merged over each append
+    bsos: HashMap<String, params::PostCollectionBso>,
+}
+
+impl Batch {
+    fn totals(&self) -> results::BatchTotals {
+        results::BatchTotals {
+            records: self.bsos.len() as u64,
+            bytes: self
+                .bsos
+                .values()
+                .map(|bso| bso.payload.as_ref().map_or(0, |p| p.len() as u64))
+                .sum(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct TokenserverData {
+    /// (service, pattern), ids starting from 1
+    services: Vec<(String, String)>,
+    /// ids starting from 1
+    nodes: Vec<Node>,
+    users: HashMap<i64, User>,
+    next_uid: i64,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    service_id: i32,
+    node: String,
+    available: i32,
+    current_load: i32,
+    capacity: i32,
+    downed: bool,
+    backoff: bool,
+}
+
+#[derive(Clone, Debug)]
+struct User {
+    service_id: i32,
+    email: String,
+    generation: i64,
+    keys_changed_at: Option<i64>,
+    client_state: String,
+    node_id: i64,
+    created_at: i64,
+    replaced_at: Option<i64>,
+}
+
+/// Per session Db metadata
+#[derive(Debug, Default)]
+struct MemoryDbSession {
+    /// The "current time" on the server used for this session's operations
+    timestamp: SyncTimestamp,
+    /// Currently locked collections
+    coll_locks: HashMap<(u32, i32), CollectionLock>,
+    /// Whether a transaction was started (begin() called)
+    in_transaction: bool,
+    in_write_transaction: bool,
+    /// Users' storage as of the transaction's first write to it, restored
+    /// on rollback (None when the user had none)
+    undo_users: HashMap<u64, Option<UserData>>,
+    /// Likewise for the Tokenserver data
+    undo_tokenserver: Option<TokenserverData>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryDb {
+    /// Arc<MemoryDbInner> provides a Clone impl sharing the session between
+    /// clones
+    inner: Arc<MemoryDbInner>,
+
+    pub metrics: Metrics,
+    pub limits: Arc<ServerLimits>,
+    pub quota_enabled: bool,
+}
+
+/// Despite the session being !Sync (see Arc<MemoryDbInner> above) a MemoryDb
+/// is only ever used by the one request's Futures, naturally serialized.
+unsafe impl Send for MemoryDb {}
+
+pub struct MemoryDbInner {
+    id: usize,
+    store: Arc<Mutex<MemoryStore>>,
+    session: RefCell<MemoryDbSession>,
+}
+
+impl fmt::Debug for MemoryDbInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "MemoryDbInner {{ id: {:?}, session: {:?} }}",
+            self.id, self.session
+        )
+    }
+}
+
+impl Drop for MemoryDbInner {
+    /// Abandoned transactions roll back
+    fn drop(&mut self) {
+        if let Ok(mut store) = self.store.lock() {
+            undo(&mut store, self.id, &mut self.session.borrow_mut());
+        }
+    }
+}
+
+impl Deref for MemoryDb {
+    type Target = MemoryDbInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+/// Restore the session's undo data and release its write locks
+fn undo(store: &mut MemoryStore, id: usize, session: &mut MemoryDbSession) {
+    for (user_id, user) in session.undo_users.drain() {
+        match user {
+            Some(user) => store.users.insert(user_id, user),
+            None => store.users.remove(&user_id),
+        };
+    }
+    if let Some(tokenserver) = session.undo_tokenserver.take() {
+        store.tokenserver = tokenserver;
+    }
+    store.write_locks.retain(|_, locker| *locker != id);
+}
+
+impl MemoryDb {
+    pub fn new(
+        store: Arc<Mutex<MemoryStore>>,
+        metrics: &Metrics,
+        limits: &Arc<ServerLimits>,
+        quota_enabled: bool,
+    ) -> Self {
+        let inner = MemoryDbInner {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            store,
+            session: RefCell::new(Default::default()),
+        };
+        MemoryDb {
+            inner: Arc::new(inner),
+            metrics: metrics.clone(),
+            limits: Arc::clone(limits),
+            quota_enabled,
+        }
+    }
+
+    fn store(&self) -> Result<MutexGuard<'_, MemoryStore>> {
+        self.store
+            .lock()
+            .map_err(|_| DbError::internal("MemoryStore lock poisoned"))
+    }
+
+    /// The user's storage, for writing: saving a copy to restore on rollback
+    /// when in a transaction
+    fn user_mut<'s>(&self, store: &'s mut MemoryStore, user_id: u64) -> &'s mut UserData {
+        let mut session = self.session.borrow_mut();
+        if session.in_transaction && !session.undo_users.contains_key(&user_id) {
+            session
+                .undo_users
+                .insert(user_id, store.users.get(&user_id).cloned());
+        }
+        store.users.entry(user_id).or_default()
+    }
+
+    /// The Tokenserver data, for writing (see `user_mut`)
+    fn tokenserver_mut<'s>(&self, store: &'s mut MemoryStore) -> &'s mut TokenserverData {
+        let mut session = self.session.borrow_mut();
+        if session.in_transaction && session.undo_tokenserver.is_none() {
+            session.undo_tokenserver = Some(store.tokenserver.clone());
+        }
+        &mut store.tokenserver
+    }
+
+    /// APIs for collection-level locking
+    ///
+    /// Reads aren't isolated from other sessions' writes. Write locks are
+    /// per user: a session attempting to write to a user another's locked
+    /// fails with a Conflict rather than waiting on it.
+    pub fn lock_for_read_sync(&self, params: params::LockCollection) -> Result<()> {
+        let user_id = params.user_id.legacy_id as u32;
+        let collection_id =
+            self.get_collection_id(&params.collection)
+                .or_else(|e| match e.kind() {
+                    // If the collection doesn't exist, we still want to start a
+                    // transaction so it will continue to not exist.
+                    DbErrorKind::CollectionNotFound => Ok(0),
+                    _ => Err(e),
+                })?;
+        // If we already have a read or write lock then it's safe to
+        // use it as-is.
+        if self
+            .session
+            .borrow()
+            .coll_locks
+            .get(&(user_id, collection_id))
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        self.begin(false)?;
+        self.session
+            .borrow_mut()
+            .coll_locks
+            .insert((user_id, collection_id), CollectionLock::Read);
+        Ok(())
+    }
+
+    pub fn lock_for_write_sync(&self, params: params::LockCollection) -> Result<()> {
+        let user_id = params.user_id.legacy_id;
+        let collection_id = self.get_or_create_collection_id(&params.collection)?;
+        if let Some(CollectionLock::Read) = self
+            .session
+            .borrow()
+            .coll_locks
+            .get(&(user_id as u32, collection_id))
+        {
+            Err(DbError::internal("Can't escalate read-lock to write-lock"))?
+        }
+
+        let mut store = self.store()?;
+        match store.write_locks.get(&user_id) {
+            Some(locker) if *locker != self.id => Err(DbErrorKind::Conflict)?,
+            _ => (),
+        }
+        if let Some(modified) = store
+            .users
+            .get(&user_id)
+            .and_then(|user| user.collections.get(&collection_id))
+        {
+            // Forbid the write if it would not properly incr the timestamp
+            if *modified >= self.timestamp() {
+                Err(DbErrorKind::Conflict)?
+            }
+        }
+        store.write_locks.insert(user_id, self.id);
+
+        self.begin(true)?;
+        self.session
+            .borrow_mut()
+            .coll_locks
+            .insert((user_id as u32, collection_id), CollectionLock::Write);
+        Ok(())
+    }
+
+    pub(super) fn begin(&self, for_write: bool) -> Result<()> {
+        let mut session = self.session.borrow_mut();
+        session.in_transaction = true;
+        if for_write {
+            session.in_write_transaction = true;
+        }
+        Ok(())
+    }
+
+    pub fn commit_sync(&self) -> Result<()> {
+        let mut store = self.store()?;
+        let mut session = self.session.borrow_mut();
+        session.undo_users.clear();
+        session.undo_tokenserver = None;
+        undo(&mut store, self.id, &mut session);
+        self.end_transaction(&mut session);
+        Ok(())
+    }
+
+    pub fn rollback_sync(&self) -> Result<()> {
+        let mut store = self.store()?;
+        let mut session = self.session.borrow_mut();
+        undo(&mut store, self.id, &mut session);
+        self.end_transaction(&mut session);
+        Ok(())
+    }
+
+    fn end_transaction(&self, session: &mut MemoryDbSession) {
+        session.coll_locks.clear();
+        session.in_transaction = false;
+        session.in_write_transaction = false;
+    }
+
+    pub fn delete_storage_sync(&self, user_id: HawkIdentifier) -> Result<()> {
+        let mut store = self.store()?;
+        *self.user_mut(&mut store, user_id.legacy_id) = Default::default();
+        Ok(())
+    }
+
+    pub fn purge_storage_sync(
+        &self,
+        params: params::PurgeStorage,
+    ) -> Result<results::PurgeStorage> {
+        let user_id = params.user_id.legacy_id;
+        let mut store = self.store()?;
+        let purged = match store.users.get(&user_id) {
+            Some(user) => results::PurgeStorage {
+                bsos: user.bsos.values().map(HashMap::len).sum::<usize>() as i64,
+                user_collections: user.collections.len() as i64,
+                batches: user.batches.len() as i64,
+            },
+            None => return Ok(Default::default()),
+        };
+        if !params.dry_run {
+            *self.user_mut(&mut store, user_id) = Default::default();
+        }
+        Ok(purged)
+    }
+
+    pub fn expire_batches_sync(&self, user_id: HawkIdentifier) -> Result<results::ExpireBatches> {
+        let mut store = self.store()?;
+        if !store.users.contains_key(&user_id.legacy_id) {
+            return Ok(0);
+        }
+        let user = self.user_mut(&mut store, user_id.legacy_id);
+        let expired = user.batches.len() as i64;
+        user.batches.clear();
+        Ok(expired)
+    }
+
+    pub fn purge_expired_sync(
+        &self,
+        params: params::PurgeExpired,
+    ) -> Result<results::PurgeExpired> {
+        let now = self.timestamp().as_i64();
+        let mut store = self.store()?;
+        let max_user_id = match store.users.keys().max() {
+            Some(max_user_id) => *max_user_id,
+            None => return Ok(Default::default()),
+        };
+        let (start, end) = params.user_id_range(max_user_id);
+        let mut user_ids: Vec<_> = store
+            .users
+            .keys()
+            .copied()
+            .filter(|user_id| (start..end).contains(user_id))
+            .collect();
+        user_ids.sort_unstable();
+
+        // Usage is calculated from the unexpired bsos, so there's no quota
+        // to update
+        let mut purged = results::PurgeExpired::default();
+        for user_id in user_ids {
+            let user = &store.users[&user_id];
+            let any_expired = user
+                .bsos
+                .values()
+                .any(|bsos| bsos.values().any(|bso| bso.expiry <= now))
+                || user.batches.keys().any(|id| id + BATCH_LIFETIME < now);
+            if !any_expired {
+                continue;
+            }
+            let user = self.user_mut(&mut store, user_id);
+            for bsos in user.bsos.values_mut() {
+                let expired: Vec<_> = bsos
+                    .iter()
+                    .filter(|(_, bso)| bso.expiry <= now)
+                    .map(|(id, _)| id.clone())
+                    .take((params.limit - purged.bsos) as usize)
+                    .collect();
+                for id in expired {
+                    bsos.remove(&id);
+                    purged.bsos += 1;
+                }
+            }
+            let expired: Vec<_> = user
+                .batches
+                .keys()
+                .copied()
+                .filter(|id| id + BATCH_LIFETIME < now)
+                .take((params.limit - purged.batches) as usize)
+                .collect();
+            for id in expired {
+                user.batches.remove(&id);
+                purged.batches += 1;
+            }
+            if purged.bsos >= params.limit && purged.batches >= params.limit {
+                break;
+            }
+        }
+        Ok(purged)
+    }
+
+    // Deleting the collection should result in:
+    //  - collection does not appear in /info/collections
+    //  - X-Last-Modified timestamp at the storage level changing
+    pub fn delete_collection_sync(
+        &self,
+        params: params::DeleteCollection,
+    ) -> Result<SyncTimestamp> {
+        let collection_id = self.get_collection_id(&params.collection)?;
+        {
+            let mut store = self.store()?;
+            let user = self.user_mut(&mut store, params.user_id.legacy_id);
+            let bsos = user.bsos.remove(&collection_id);
+            let modified = user.collections.remove(&collection_id);
+            if bsos.is_none() && modified.is_none() {
+                Err(DbErrorKind::CollectionNotFound)?
+            }
+            // Erect a tombstone
+            user.collections.insert(TOMBSTONE, self.timestamp());
+        }
+        self.get_storage_timestamp_sync(params.user_id)
+    }
+
+    pub(super) fn get_or_create_collection_id(&self, name: &str) -> Result<i32> {
+        let mut store = self.store()?;
+        if let Some(id) = store.collections.get(name) {
+            return Ok(*id);
+        }
+        let id = store.next_collection_id;
+        store.next_collection_id += 1;
+        store.collections.insert(name.to_owned(), id);
+        Ok(id)
+    }
+
+    pub(super) fn get_collection_id(&self, name: &str) -> Result<i32> {
+        Ok(*self
+            .store()?
+            .collections
+            .get(name)
+            .ok_or(DbErrorKind::CollectionNotFound)?)
+    }
+
+    pub fn put_bso_sync(&self, bso: params::PutBso) -> Result<results::PutBso> {
+        let collection_id = self.get_or_create_collection_id(&bso.collection)?;
+        let user_id = bso.user_id.legacy_id;
+        let timestamp = self.timestamp();
+        let quota = if self.quota_enabled {
+            self.limits.quota_for(&bso.collection)
+        } else {
+            None
+        };
+        if let Some(quota) = quota {
+            let usage = self.get_quota_usage_sync(params::GetQuotaUsage {
+                user_id: HawkIdentifier::new_legacy(user_id),
+                collection: bso.collection.clone(),
+                collection_id,
+            })?;
+            if usage.total_bytes >= quota as usize {
+                let mut tags = Tags::default();
+                tags.tags.insert("collection".to_owned(), bso.collection);
+                self.metrics
+                    .incr_with_tags("storage.quota.at_limit", Some(tags));
+                return Err(DbErrorKind::Quota.into());
+            }
+        }
+
+        let mut store = self.store()?;
+        let user = self.user_mut(&mut store, user_id);
+        let bsos = user.bsos.entry(collection_id).or_default();
+        match bsos.get_mut(&bso.id) {
+            Some(existing) => {
+                if bso.payload.is_some() || bso.sortindex.is_some() {
+                    existing.modified = timestamp;
+                }
+                if let Some(payload) = bso.payload {
+                    existing.payload = payload;
+                }
+                if bso.sortindex.is_some() {
+                    existing.sortindex = bso.sortindex;
+                }
+                if let Some(ttl) = bso.ttl {
+                    existing.expiry = timestamp.as_i64() + i64::from(ttl) * 1000;
+                }
+            }
+            None => {
+                let ttl = bso.ttl.unwrap_or(DEFAULT_BSO_TTL);
+                bsos.insert(
+                    bso.id,
+                    Bso {
+                        sortindex: bso.sortindex,
+                        payload: bso.payload.unwrap_or_default(),
+                        modified: timestamp,
+                        expiry: timestamp.as_i64() + i64::from(ttl) * 1000,
+                    },
+                );
+            }
+        }
+        user.collections.insert(collection_id, timestamp);
+        Ok(timestamp)
+    }
+
+    pub fn import_bsos_sync(&self, params: params::ImportBsos) -> Result<results::ImportBsos> {
+        let collection_id = self.get_or_create_collection_id(&params.collection)?;
+        let mut store = self.store()?;
+        let user = self.user_mut(&mut store, params.user_id.legacy_id);
+        let bsos = user.bsos.entry(collection_id).or_default();
+        for bso in params.bsos {
+            bsos.insert(
+                bso.id,
+                Bso {
+                    sortindex: bso.sortindex,
+                    payload: bso.payload,
+                    modified: bso.modified,
+                    expiry: bso.expiry,
+                },
+            );
+        }
+        user.collections.insert(collection_id, params.modified);
+        Ok(())
+    }
+
+    /// The collection's unexpired bsos matching the query, sorted
+    fn query_bsos(
+        &self,
+        user_id: u64,
+        collection_id: i32,
+        params: &BsoQueryParams,
+    ) -> Result<Vec<results::GetBso>> {
+        let now = self.timestamp().as_i64();
+        let store = self.store()?;
+        let mut bsos: Vec<_> = store
+            .users
+            .get(&user_id)
+            .and_then(|user| user.bsos.get(&collection_id))
+            .into_iter()
+            .flatten()
+            .filter(|(id, bso)| {
+                bso.expiry > now
+                    && params.older.map_or(true, |older| bso.modified < older)
+                    && params.newer.map_or(true, |newer| bso.modified > newer)
+                    && (params.ids.is_empty() || params.ids.contains(*id))
+            })
+            .map(|(id, bso)| results::GetBso {
+                id: id.clone(),
+                modified: bso.modified,
+                payload: bso.payload.clone(),
+                sortindex: bso.sortindex,
+                expiry: bso.expiry,
+            })
+            .collect();
+
+        // Ties are broken by the id, as by the SQL backends
+        bsos.sort_by(|a, b| {
+            Self::cmp_keys(
+                params.sort,
+                &Self::sort_key(params.sort, a),
+                &Self::sort_key(params.sort, b),
+            )
+        });
+        Ok(bsos)
+    }
+
+    /// The sort key of a BSO, per the ordering of `sort`
+    fn sort_key(sort: Sorting, bso: &results::GetBso) -> (Option<i64>, &str) {
+        let value = match sort {
+            Sorting::None => None,
+            Sorting::Newest | Sorting::Oldest => Some(bso.modified.as_i64()),
+            Sorting::Index => bso.sortindex.map(i64::from),
+        };
+        (value, &bso.id)
+    }
+
+    /// Order two sort keys per `sort` (NULL sortindexes sort last)
+    fn cmp_keys(sort: Sorting, a: &(Option<i64>, &str), b: &(Option<i64>, &str)) -> cmp::Ordering {
+        match sort {
+            Sorting::None => a.1.cmp(b.1),
+            Sorting::Oldest => a.cmp(b),
+            Sorting::Newest | Sorting::Index => b.cmp(a),
+        }
+    }
+
+    /// Paginate BSOs as the SQL backends do: resuming after the sort key of
+    /// an offset token, otherwise skipping a numeric offset
+    fn paginate(bsos: Vec<results::GetBso>, params: &BsoQueryParams) -> results::GetBsos {
+        let limit = params.limit.map(i64::from).unwrap_or(-1);
+        let offset = params.offset.clone().unwrap_or_default();
+        let mut bsos: Vec<_> = match &offset.key {
+            Some(key) => {
+                let key = (key.value, key.id.as_str());
+                bsos.into_iter()
+                    .filter(|bso| {
+                        Self::cmp_keys(params.sort, &Self::sort_key(params.sort, bso), &key)
+                            == cmp::Ordering::Greater
+                    })
+                    .collect()
+            }
+            None => bsos.into_iter().skip(offset.offset as usize).collect(),
+        };
+        let next_offset = if limit >= 0 && bsos.len() > limit as usize {
+            bsos.truncate(limit as usize);
+            let last = bsos
+                .last()
+                .map(|bso| (bso.id.as_str(), bso.modified, bso.sortindex));
+            Some(offset.next(bsos.len(), params.sort, last))
+        } else {
+            None
+        };
+        results::Paginated {
+            items: bsos,
+            offset: next_offset,
+        }
+    }
+
+    pub fn get_bsos_sync(&self, params: params::GetBsos) -> Result<results::GetBsos> {
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let bsos = self.query_bsos(params.user_id.legacy_id, collection_id, &params.params)?;
+        Ok(Self::paginate(bsos, &params.params))
+    }
+
+    pub fn get_bso_ids_sync(&self, params: params::GetBsos) -> Result<results::GetBsoIds> {
+        let bsos = self.get_bsos_sync(params)?;
+        Ok(results::Paginated {
+            items: bsos.items.into_iter().map(|bso| bso.id).collect(),
+            offset: bsos.offset,
+        })
+    }
+
+    pub fn get_bso_sync(&self, params: params::GetBso) -> Result<Option<results::GetBso>> {
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let now = self.timestamp().as_i64();
+        Ok(self
+            .store()?
+            .users
+            .get(&params.user_id.legacy_id)
+            .and_then(|user| user.bsos.get(&collection_id))
+            .and_then(|bsos| bsos.get(&params.id))
+            .filter(|bso| bso.expiry >= now)
+            .map(|bso| results::GetBso {
+                id: params.id,
+                modified: bso.modified,
+                payload: bso.payload.clone(),
+                sortindex: bso.sortindex,
+                expiry: bso.expiry,
+            }))
+    }
+
+    pub fn delete_bso_sync(&self, params: params::DeleteBso) -> Result<results::DeleteBso> {
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let now = self.timestamp().as_i64();
+        let mut store = self.store()?;
+        let user = self.user_mut(&mut store, params.user_id.legacy_id);
+        let bsos = user.bsos.entry(collection_id).or_default();
+        if !bsos.get(&params.id).map_or(false, |bso| bso.expiry > now) {
+            Err(DbErrorKind::BsoNotFound)?
+        }
+        bsos.remove(&params.id);
+        user.collections.insert(collection_id, self.timestamp());
+        Ok(self.timestamp())
+    }
+
+    pub fn delete_bsos_sync(&self, params: params::DeleteBsos) -> Result<results::DeleteBsos> {
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let mut store = self.store()?;
+        let user = self.user_mut(&mut store, params.user_id.legacy_id);
+        if let Some(bsos) = user.bsos.get_mut(&collection_id) {
+            for id in params.ids {
+                bsos.remove(&id);
+            }
+        }
+        user.collections.insert(collection_id, self.timestamp());
+        Ok(self.timestamp())
+    }
+
+    pub fn post_bsos_sync(&self, input: params::PostBsos) -> Result<results::PostBsos> {
+        let mut result = results::PostBsos {
+            modified: self.timestamp(),
+            success: Default::default(),
+            failed: input.failed,
+        };
+
+        for pbso in input.bsos {
+            let id = pbso.id;
+            let put_result = self.put_bso_sync(params::PutBso {
+                user_id: input.user_id.clone(),
+                collection: input.collection.clone(),
+                id: id.clone(),
+                payload: pbso.payload,
+                sortindex: pbso.sortindex,
+                ttl: pbso.ttl,
+            });
+            match put_result {
+                Ok(_) => result.success.push(id),
+                Err(e) => {
+                    result.failed.insert(id, e.to_string());
+                }
+            }
+        }
+        self.update_collection(&input.user_id, &input.collection)?;
+        Ok(result)
+    }
+
+    pub fn get_storage_timestamp_sync(&self, user_id: HawkIdentifier) -> Result<SyncTimestamp> {
+        let modified = self
+            .store()?
+            .users
+            .get(&user_id.legacy_id)
+            .and_then(|user| user.collections.values().map(|ts| ts.as_i64()).max())
+            .unwrap_or_default();
+        Ok(SyncTimestamp::from_i64(modified)?)
+    }
+
+    pub fn get_collection_timestamp_sync(
+        &self,
+        params: params::GetCollectionTimestamp,
+    ) -> Result<SyncTimestamp> {
+        let collection_id = self.get_collection_id(&params.collection)?;
+        self.store()?
+            .users
+            .get(&params.user_id.legacy_id)
+            .and_then(|user| user.collections.get(&collection_id))
+            .copied()
+            .ok_or_else(|| DbErrorKind::CollectionNotFound.into())
+    }
+
+    pub fn get_bso_timestamp_sync(&self, params: params::GetBsoTimestamp) -> Result<SyncTimestamp> {
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let modified = self
+            .store()?
+            .users
+            .get(&params.user_id.legacy_id)
+            .and_then(|user| user.bsos.get(&collection_id))
+            .and_then(|bsos| bsos.get(&params.id))
+            .map(|bso| bso.modified.as_i64())
+            .unwrap_or_default();
+        Ok(SyncTimestamp::from_i64(modified)?)
+    }
+
+    pub fn get_collection_timestamps_sync(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::GetCollectionTimestamps> {
+        let modifieds = self
+            .store()?
+            .users
+            .get(&user_id.legacy_id)
+            .map(|user| {
+                user.collections
+                    .iter()
+                    .filter(|(id, _)| **id != TOMBSTONE)
+                    .map(|(id, modified)| (*id, *modified))
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.map_collection_names(modifieds)
+    }
+
+    fn map_collection_names<T>(&self, by_id: HashMap<i32, T>) -> Result<HashMap<String, T>> {
+        let names: HashMap<_, _> = self
+            .store()?
+            .collections
+            .iter()
+            .map(|(name, id)| (*id, name.clone()))
+            .collect();
+        by_id
+            .into_iter()
+            .map(|(id, value)| {
+                names
+                    .get(&id)
+                    .map(|name| (name.clone(), value))
+                    .ok_or_else(|| DbError::internal("map_collection_names unknown collection id"))
+            })
+            .collect()
+    }
+
+    fn update_collection(
+        &self,
+        user_id: &HawkIdentifier,
+        collection: &str,
+    ) -> Result<SyncTimestamp> {
+        let collection_id = self.get_or_create_collection_id(collection)?;
+        let mut store = self.store()?;
+        self.user_mut(&mut store, user_id.legacy_id)
+            .collections
+            .insert(collection_id, self.timestamp());
+        Ok(self.timestamp())
+    }
+
+    /// The total bytes and count of each collection's unexpired bsos
+    fn collection_usage(&self, user_id: u64) -> Result<HashMap<i32, results::GetQuotaUsage>> {
+        let now = self.timestamp().as_i64();
+        Ok(self
+            .store()?
+            .users
+            .get(&user_id)
+            .map(|user| {
+                user.bsos
+                    .iter()
+                    .filter_map(|(collection_id, bsos)| {
+                        let live: Vec<_> = bsos.values().filter(|bso| bso.expiry > now).collect();
+                        if live.is_empty() {
+                            return None;
+                        }
+                        Some((
+                            *collection_id,
+                            results::GetQuotaUsage {
+                                total_bytes: live.iter().map(|bso| bso.payload.len()).sum(),
+                                count: live.len() as i32,
+                            },
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    pub fn get_storage_usage_sync(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::GetStorageUsage> {
+        Ok(self
+            .collection_usage(user_id.legacy_id)?
+            .values()
+            .map(|usage| usage.total_bytes as u64)
+            .sum())
+    }
+
+    pub fn get_quota_usage_sync(
+        &self,
+        params: params::GetQuotaUsage,
+    ) -> Result<results::GetQuotaUsage> {
+        Ok(self
+            .collection_usage(params.user_id.legacy_id)?
+            .remove(&params.collection_id)
+            .unwrap_or_default())
+    }
+
+    pub fn get_collection_usage_sync(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::GetCollectionUsage> {
+        let usage = self
+            .collection_usage(user_id.legacy_id)?
+            .into_iter()
+            .map(|(id, usage)| (id, usage.total_bytes as i64))
+            .collect();
+        self.map_collection_names(usage)
+    }
+
+    pub fn get_collection_counts_sync(
+        &self,
+        user_id: HawkIdentifier,
+    ) -> Result<results::GetCollectionCounts> {
+        let counts = self
+            .collection_usage(user_id.legacy_id)?
+            .into_iter()
+            .map(|(id, usage)| (id, i64::from(usage.count)))
+            .collect();
+        self.map_collection_names(counts)
+    }
+
+    pub fn create_batch_sync(&self, params: params::CreateBatch) -> Result<results::CreateBatch> {
+        let user_id = params.user_id.legacy_id;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        // Batch ids are millisecond timestamps (see the mysql backend)
+        let batch_id = self.timestamp().as_i64() + (user_id % 10) as i64;
+        let now = self.timestamp().as_i64();
+        let mut batch = Batch {
+            collection_id,
+            bsos: Default::default(),
+        };
+        append_bsos(&mut batch, params.bsos);
+        batch.totals().check(&self.limits, &params.collection)?;
+
+        let mut store = self.store()?;
+        let user = self.user_mut(&mut store, user_id);
+        user.batches.retain(|id, _| id + BATCH_LIFETIME >= now);
+        if user.batches.contains_key(&batch_id) {
+            // The user tried to create two batches with the same timestamp
+            Err(DbErrorKind::Conflict)?
+        }
+        user.batches.insert(batch_id, batch);
+        Ok(results::CreateBatch {
+            id: encode_id(batch_id),
+            size: None,
+        })
+    }
+
+    pub fn validate_batch_sync(&self, params: params::ValidateBatch) -> Result<bool> {
+        let batch_id = decode_id(&params.id)?;
+        // Recall that the batchid is a millisecond timestamp
+        if (batch_id + BATCH_LIFETIME) < self.timestamp().as_i64() {
+            return Ok(false);
+        }
+        let collection_id = self.get_collection_id(&params.collection)?;
+        Ok(self
+            .store()?
+            .users
+            .get(&params.user_id.legacy_id)
+            .and_then(|user| user.batches.get(&batch_id))
+            .map_or(false, |batch| batch.collection_id == collection_id))
+    }
+
+    pub fn append_to_batch_sync(
+        &self,
+        params: params::AppendToBatch,
+    ) -> Result<results::AppendToBatch> {
+        let exists = self.validate_batch_sync(params::ValidateBatch {
+            user_id: params.user_id.clone(),
+            collection: params.collection.clone(),
+            id: params.batch.id.clone(),
+        })?;
+        if !exists {
+            Err(DbErrorKind::BatchNotFound)?
+        }
+
+        let batch_id = decode_id(&params.batch.id)?;
+        let mut store = self.store()?;
+        let user = self.user_mut(&mut store, params.user_id.legacy_id);
+        let batch = user
+            .batches
+            .get_mut(&batch_id)
+            .ok_or(DbErrorKind::BatchNotFound)?;
+        // Staged on a copy, leaving the batch untouched when over its limits
+        let mut staged = batch.clone();
+        append_bsos(&mut staged, params.bsos);
+        let totals = staged.totals().check(&self.limits, &params.collection)?;
+        *batch = staged;
+        Ok(totals)
+    }
+
+    pub fn get_batch_sync(&self, params: params::GetBatch) -> Result<Option<results::GetBatch>> {
+        let is_valid = self.validate_batch_sync(params::ValidateBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.id.clone(),
+        })?;
+        Ok(if is_valid {
+            Some(results::GetBatch { id: params.id })
+        } else {
+            None
+        })
+    }
+
+    pub fn get_batch_items_sync(
+        &self,
+        params: params::GetBatchItems,
+    ) -> Result<Option<results::GetBatchItems>> {
+        let batch_id = decode_id(&params.id)?;
+        let user_id = params.user_id.legacy_id;
+        if !self.validate_batch_sync(params.into())? {
+            return Ok(None);
+        }
+        let store = self.store()?;
+        let batch = match store
+            .users
+            .get(&user_id)
+            .and_then(|user| user.batches.get(&batch_id))
+        {
+            Some(batch) => batch,
+            None => return Ok(None),
+        };
+        let mut ids: Vec<_> = batch.bsos.keys().cloned().collect();
+        ids.sort();
+        // Recall that the batchid is a millisecond timestamp with the low digit
+        // of the uid mixed in: strip it to recover the batch creation time
+        Ok(Some(results::GetBatchItems {
+            expiry: SyncTimestamp::from_milliseconds(
+                (batch_id - batch_id % 10 + BATCH_LIFETIME) as u64,
+            ),
+            count: ids.len(),
+            ids,
+        }))
+    }
+
+    pub fn delete_batch_sync(&self, params: params::DeleteBatch) -> Result<()> {
+        let batch_id = decode_id(&params.id)?;
+        let mut store = self.store()?;
+        self.user_mut(&mut store, params.user_id.legacy_id)
+            .batches
+            .remove(&batch_id);
+        Ok(())
+    }
+
+    /// Commits a batch to the bsos, deleting the batch when succesful
+    pub fn commit_batch_sync(&self, params: params::CommitBatch) -> Result<results::CommitBatch> {
+        let batch_id = decode_id(&params.batch.id)?;
+        let collection_id = self.get_collection_id(&params.collection)?;
+        let timestamp = self.timestamp();
+        let totals;
+        {
+            let mut store = self.store()?;
+            let user = self.user_mut(&mut store, params.user_id.legacy_id);
+            let batch = user
+                .batches
+                .get(&batch_id)
+                .ok_or(DbErrorKind::BatchNotFound)?;
+            totals = (batch.totals() + params.pending).check(&self.limits, &params.collection)?;
+            let batch = user
+                .batches
+                .remove(&batch_id)
+                .ok_or(DbErrorKind::BatchNotFound)?;
+            let bsos = user.bsos.entry(collection_id).or_default();
+            for (id, pending) in batch.bsos {
+                let expiry = pending
+                    .ttl
+                    .map(|ttl| timestamp.as_i64() + i64::from(ttl) * 1000);
+                match bsos.get_mut(&id) {
+                    Some(existing) => {
+                        existing.modified = timestamp;
+                        if pending.sortindex.is_some() {
+                            existing.sortindex = pending.sortindex;
+                        }
+                        if let Some(payload) = pending.payload {
+                            existing.payload = payload;
+                        }
+                        if let Some(expiry) = expiry {
+                            existing.expiry = expiry;
+                        }
+                    }
+                    None => {
+                        bsos.insert(
+                            id,
+                            Bso {
+                                sortindex: pending.sortindex,
+                                payload: pending.payload.unwrap_or_default(),
+                                modified: timestamp,
+                                expiry: expiry.unwrap_or(MAXTTL * 1000),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+        self.update_collection(&params.user_id, &params.collection)?;
+        Ok(results::CommitBatch {
+            modified: timestamp,
+            totals,
+        })
+    }
+
+    pub fn post_service_sync(&self, params: params::PostService) -> Result<results::PostService> {
+        let mut store = self.store()?;
+        if let Some(index) = store
+            .tokenserver
+            .services
+            .iter()
+            .position(|(service, _)| *service == params.service)
+        {
+            return Ok(index as i32 + 1);
+        }
+        let services = &mut self.tokenserver_mut(&mut store).services;
+        services.push((params.service, params.pattern));
+        Ok(services.len() as i32)
+    }
+
+    pub fn post_node_sync(&self, params: params::PostNode) -> Result<results::PostNode> {
+        let mut store = self.store()?;
+        if let Some(index) = store
+            .tokenserver
+            .nodes
+            .iter()
+            .position(|node| node.service_id == params.service_id && node.node == params.node)
+        {
+            return Ok(index as i64 + 1);
+        }
+        let nodes = &mut self.tokenserver_mut(&mut store).nodes;
+        nodes.push(Node {
+            service_id: params.service_id,
+            node: params.node,
+            available: params.capacity,
+            current_load: 0,
+            capacity: params.capacity,
+            downed: false,
+            backoff: false,
+        });
+        Ok(nodes.len() as i64)
+    }
+
+    pub fn allocate_node_sync(
+        &self,
+        params: params::AllocateNode,
+    ) -> Result<results::AllocateNode> {
+        let mut store = self.store()?;
+        let nodes = &mut self.tokenserver_mut(&mut store).nodes;
+        let least_loaded = |nodes: &[Node]| {
+            nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, node)| {
+                    node.service_id == params.service_id
+                        && node.available > 0
+                        && node.capacity > node.current_load
+                        && !node.downed
+                        && !node.backoff
+                })
+                .min_by(|(_, a), (_, b)| {
+                    let load =
+                        |node: &Node| f64::from(node.current_load) / f64::from(node.capacity);
+                    load(*a)
+                        .partial_cmp(&load(*b))
+                        .unwrap_or(cmp::Ordering::Equal)
+                })
+                .map(|(index, _)| index)
+        };
+
+        let index = match least_loaded(&nodes[..]) {
+            Some(index) => index,
+            None => {
+                // Every node's exhausted its available slots: release some more
+                // of their spare capacity and try again
+                let mut released = 0;
+                for node in nodes.iter_mut().filter(|node| {
+                    node.service_id == params.service_id
+                        && node.available <= 0
+                        && node.capacity > node.current_load
+                        && !node.downed
+                }) {
+                    node.available = cmp::min(
+                        (f64::from(node.capacity) * NODE_RELEASE_RATE) as i32,
+                        node.capacity - node.current_load,
+                    );
+                    released += 1;
+                }
+                if released == 0 {
+                    Err(DbErrorKind::NodeUnavailable)?
+                }
+                least_loaded(&nodes[..]).ok_or(DbErrorKind::NodeUnavailable)?
+            }
+        };
+
+        let node = &mut nodes[index];
+        node.current_load += 1;
+        node.available = cmp::max(node.available - 1, 0);
+        Ok(results::AllocateNode {
+            id: index as i64 + 1,
+            node: node.node.clone(),
+        })
+    }
+
+    pub fn get_users_sync(&self, params: params::GetUsers) -> Result<results::GetUsers> {
+        let store = self.store()?;
+        let tokenserver = &store.tokenserver;
+        let mut users: Vec<_> = tokenserver
+            .users
+            .iter()
+            .filter(|(_, user)| user.service_id == params.service_id && user.email == params.email)
+            .map(|(uid, user)| results::TokenserverUser {
+                uid: *uid,
+                node_id: user.node_id,
+                node: tokenserver
+                    .nodes
+                    .get((user.node_id - 1) as usize)
+                    .filter(|node| !node.downed)
+                    .map(|node| node.node.clone()),
+                generation: user.generation,
+                keys_changed_at: user.keys_changed_at,
+                client_state: user.client_state.clone(),
+                created_at: user.created_at,
+                replaced_at: user.replaced_at,
+            })
+            .collect();
+        users.sort_by(|a, b| (b.created_at, b.uid).cmp(&(a.created_at, a.uid)));
+        users.truncate(20);
+        Ok(users)
+    }
+
+    pub fn post_user_sync(&self, params: params::PostUser) -> Result<results::PostUser> {
+        let mut store = self.store()?;
+        let tokenserver = self.tokenserver_mut(&mut store);
+        tokenserver.next_uid += 1;
+        let uid = tokenserver.next_uid;
+        tokenserver.users.insert(
+            uid,
+            User {
+                service_id: params.service_id,
+                email: params.email,
+                generation: params.generation,
+                keys_changed_at: params.keys_changed_at,
+                client_state: params.client_state,
+                node_id: params.node_id,
+                created_at: params.created_at,
+                replaced_at: None,
+            },
+        );
+        Ok(uid)
+    }
+
+    pub fn put_user_sync(&self, params: params::PutUser) -> Result<results::PutUser> {
+        let mut store = self.store()?;
+        for user in self
+            .tokenserver_mut(&mut store)
+            .users
+            .values_mut()
+            .filter(|user| {
+                user.service_id == params.service_id
+                    && user.email == params.email
+                    && user.replaced_at.is_none()
+            })
+        {
+            user.generation = cmp::max(user.generation, params.generation);
+            user.keys_changed_at = cmp::max(user.keys_changed_at, params.keys_changed_at);
+        }
+        Ok(())
+    }
+
+    pub fn replace_users_sync(
+        &self,
+        params: params::ReplaceUsers,
+    ) -> Result<results::ReplaceUsers> {
+        let mut store = self.store()?;
+        for user in self
+            .tokenserver_mut(&mut store)
+            .users
+            .values_mut()
+            .filter(|user| {
+                user.service_id == params.service_id
+                    && user.email == params.email
+                    && user.replaced_at.is_none()
+                    && user.created_at < params.replaced_at
+            })
+        {
+            user.replaced_at = Some(params.replaced_at);
+        }
+        Ok(())
+    }
+
+    pub fn get_replaced_users_sync(
+        &self,
+        params: params::GetReplacedUsers,
+    ) -> Result<results::GetReplacedUsers> {
+        let store = self.store()?;
+        let mut users: Vec<_> = store
+            .tokenserver
+            .users
+            .iter()
+            .filter(|(_, user)| user.service_id == params.service_id)
+            .filter_map(|(uid, user)| match user.replaced_at {
+                Some(replaced_at) if replaced_at < params.replaced_before => {
+                    Some(results::ReplacedUser {
+                        uid: *uid,
+                        email: user.email.clone(),
+                        generation: user.generation,
+                        keys_changed_at: user.keys_changed_at,
+                        client_state: user.client_state.clone(),
+                        replaced_at,
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        users.sort_by_key(|user| (user.replaced_at, user.uid));
+        users.truncate(params.limit as usize);
+        Ok(users)
+    }
+
+    pub fn delete_user_sync(&self, params: params::DeleteUser) -> Result<results::DeleteUser> {
+        let mut store = self.store()?;
+        self.tokenserver_mut(&mut store).users.remove(&params.uid);
+        Ok(())
+    }
+
+    pub fn timestamp(&self) -> SyncTimestamp {
+        self.session.borrow().timestamp
+    }
+}
+
+/// Merge the bsos into the batch: fields left unset by later appends keep
+/// their earlier values
+fn append_bsos(batch: &mut Batch, bsos: Vec<params::PostCollectionBso>) {
+    for bso in bsos {
+        match batch.bsos.get_mut(&bso.id) {
+            Some(pending) => {
+                if bso.sortindex.is_some() {
+                    pending.sortindex = bso.sortindex;
+                }
+                if bso.payload.is_some() {
+                    pending.payload = bso.payload;
+                }
+                if bso.ttl.is_some() {
+                    pending.ttl = bso.ttl;
+                }
+            }
+            None => {
+                batch.bsos.insert(bso.id.clone(), bso);
+            }
+        }
+    }
+}
+
+pub fn validate_batch_id(id: &str) -> Result<()> {
+    decode_id(id).map(|_| ())
+}
+
+fn encode_id(id: i64) -> String {
+    base64::encode(&id.to_string())
+}
+
+fn decode_id(id: &str) -> Result<i64> {
+    let bytes = base64::decode(id).unwrap_or_else(|_| id.as_bytes().to_vec());
+    let decoded = std::str::from_utf8(&bytes).unwrap_or(id);
+    decoded
+        .parse::<i64>()
+        .map_err(|e| DbError::internal(&format!("Invalid batch_id: {}", e)))
+}
+
+macro_rules! memory_db_method {
+    ($name:ident, $sync_name:ident, $type:ident) => {
+        memory_db_method!($name, $sync_name, $type, results::$type);
+    };
+    ($name:ident, $sync_name:ident, $type:ident, $result:ty) => {
+        fn $name(&self, params: params::$type) -> DbFuture<'_, $result> {
+            Box::pin(future::ready(self.$sync_name(params).map_err(Into::into)))
+        }
+    };
+}
+
+impl<'a> Db<'a> for MemoryDb {
+    fn commit(&self) -> DbFuture<'_, ()> {
+        Box::pin(future::ready(self.commit_sync().map_err(Into::into)))
+    }
+
+    fn rollback(&self) -> DbFuture<'_, ()> {
+        Box::pin(future::ready(self.rollback_sync().map_err(Into::into)))
+    }
+
+    fn begin(&self, for_write: bool) -> DbFuture<'_, ()> {
+        Box::pin(future::ready(self.begin(for_write).map_err(Into::into)))
+    }
+
+    fn box_clone(&self) -> Box<dyn Db<'a>> {
+        Box::new(self.clone())
+    }
+
+    fn check(&self) -> DbFuture<'_, results::Check> {
+        Box::pin(future::ready(
+            self.store().map(|_| true).map_err(Into::into),
+        ))
+    }
+
+    /// Nothing to migrate: the store always has the current schema
+    fn schema_version(&self) -> DbFuture<'_, results::SchemaVersion> {
+        Box::pin(future::ok(Default::default()))
+    }
+
+    memory_db_method!(lock_for_read, lock_for_read_sync, LockCollection);
+    memory_db_method!(lock_for_write, lock_for_write_sync, LockCollection);
+    memory_db_method!(
+        get_collection_timestamps,
+        get_collection_timestamps_sync,
+        GetCollectionTimestamps
+    );
+    memory_db_method!(
+        get_collection_timestamp,
+        get_collection_timestamp_sync,
+        GetCollectionTimestamp
+    );
+    memory_db_method!(
+        get_collection_counts,
+        get_collection_counts_sync,
+        GetCollectionCounts
+    );
+    memory_db_method!(
+        get_collection_usage,
+        get_collection_usage_sync,
+        GetCollectionUsage
+    );
+    memory_db_method!(
+        get_storage_timestamp,
+        get_storage_timestamp_sync,
+        GetStorageTimestamp
+    );
+    memory_db_method!(get_storage_usage, get_storage_usage_sync, GetStorageUsage);
+    memory_db_method!(get_quota_usage, get_quota_usage_sync, GetQuotaUsage);
+    memory_db_method!(delete_storage, delete_storage_sync, DeleteStorage);
+    memory_db_method!(delete_collection, delete_collection_sync, DeleteCollection);
+    memory_db_method!(delete_bsos, delete_bsos_sync, DeleteBsos);
+    memory_db_method!(get_bsos, get_bsos_sync, GetBsos);
+    memory_db_method!(get_bso_ids, get_bso_ids_sync, GetBsoIds);
+    memory_db_method!(post_bsos, post_bsos_sync, PostBsos);
+    memory_db_method!(delete_bso, delete_bso_sync, DeleteBso);
+    memory_db_method!(get_bso, get_bso_sync, GetBso, Option<results::GetBso>);
+    memory_db_method!(
+        get_bso_timestamp,
+        get_bso_timestamp_sync,
+        GetBsoTimestamp,
+        results::GetBsoTimestamp
+    );
+    memory_db_method!(put_bso, put_bso_sync, PutBso);
+    memory_db_method!(import_bsos, import_bsos_sync, ImportBsos);
+    memory_db_method!(create_batch, create_batch_sync, CreateBatch);
+    memory_db_method!(validate_batch, validate_batch_sync, ValidateBatch);
+    memory_db_method!(append_to_batch, append_to_batch_sync, AppendToBatch);
+    memory_db_method!(
+        get_batch,
+        get_batch_sync,
+        GetBatch,
+        Option<results::GetBatch>
+    );
+    memory_db_method!(
+        get_batch_items,
+        get_batch_items_sync,
+        GetBatchItems,
+        Option<results::GetBatchItems>
+    );
+    memory_db_method!(delete_batch, delete_batch_sync, DeleteBatch);
+    memory_db_method!(commit_batch, commit_batch_sync, CommitBatch);
+    memory_db_method!(purge_storage, purge_storage_sync, PurgeStorage);
+    memory_db_method!(expire_batches, expire_batches_sync, ExpireBatches);
+    memory_db_method!(purge_expired, purge_expired_sync, PurgeExpired);
+    memory_db_method!(post_service, post_service_sync, PostService);
+    memory_db_method!(post_node, post_node_sync, PostNode);
+    memory_db_method!(allocate_node, allocate_node_sync, AllocateNode);
+    memory_db_method!(get_users, get_users_sync, GetUsers);
+    memory_db_method!(post_user, post_user_sync, PostUser);
+    memory_db_method!(put_user, put_user_sync, PutUser);
+    memory_db_method!(replace_users, replace_users_sync, ReplaceUsers);
+    memory_db_method!(
+        get_replaced_users,
+        get_replaced_users_sync,
+        GetReplacedUsers
+    );
+    memory_db_method!(delete_user, delete_user_sync, DeleteUser);
+
+    fn get_collection_id(&self, name: String) -> DbFuture<'_, i32> {
+        Box::pin(future::ready(
+            self.get_collection_id(&name).map_err(Into::into),
+        ))
+    }
+
+    #[cfg(test)]
+    fn create_collection(&self, name: String) -> DbFuture<'_, i32> {
+        Box::pin(future::ready(
+            self.get_or_create_collection_id(&name).map_err(Into::into),
+        ))
+    }
+
+    #[cfg(test)]
+    fn update_collection(&self, param: params::UpdateCollection) -> DbFuture<'_, SyncTimestamp> {
+        Box::pin(future::ready(
+            self.update_collection(&param.user_id, &param.collection)
+                .map_err(Into::into),
+        ))
+    }
+
+    #[cfg(test)]
+    fn timestamp(&self) -> SyncTimestamp {
+        self.timestamp()
+    }
+
+    #[cfg(test)]
+    fn set_timestamp(&self, timestamp: SyncTimestamp) {
+        self.session.borrow_mut().timestamp = timestamp;
+    }
+
+    #[cfg(test)]
+    fn clear_coll_cache(&self) {}
+
+    #[cfg(test)]
+    fn set_quota(&mut self, enabled: bool, limit: usize) {
+        Arc::make_mut(&mut self.limits).max_quota_limit = limit as u32;
+        self.quota_enabled = enabled;
+    }
+}
@@ -0,0 +1,75 @@
+use async_trait::async_trait;
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use super::models::{MemoryDb, MemoryStore, Result};
+use crate::db::{
+    results::{self, PoolState},
+    Db, DbPool,
+};
+use crate::error::ApiResult;
+use crate::server::metrics::Metrics;
+use crate::settings::{ServerLimits, Settings};
+
+/// A "pool" of sessions against one in-memory store, which lives as long as
+/// the pool (and its clones) does.
+#[derive(Clone)]
+pub struct MemoryDbPool {
+    store: Arc<Mutex<MemoryStore>>,
+
+    metrics: Metrics,
+    limits: Arc<ServerLimits>,
+    quota_enabled: bool,
+}
+
+impl MemoryDbPool {
+    /// Creates a new, empty, in-memory store.
+    pub fn new(settings: &Settings, metrics: &Metrics) -> Self {
+        Self {
+            store: Default::default(),
+            metrics: metrics.clone(),
+            limits: Arc::new(settings.limits.clone()),
+            quota_enabled: settings.enable_quota,
+        }
+    }
+
+    pub fn get_sync(&self) -> MemoryDb {
+        MemoryDb::new(
+            Arc::clone(&self.store),
+            &self.metrics,
+            &self.limits,
+            self.quota_enabled,
+        )
+    }
+}
+
+#[async_trait(?Send)]
+impl DbPool for MemoryDbPool {
+    async fn get<'a>(&'a self) -> ApiResult<Box<dyn Db<'a>>> {
+        Ok(Box::new(self.get_sync()) as Box<dyn Db<'a>>)
+    }
+
+    fn state(&self) -> results::PoolState {
+        PoolState::default()
+    }
+
+    fn validate_batch_id(&self, id: String) -> Result<()> {
+        super::models::validate_batch_id(&id)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Debug for MemoryDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("MemoryDbPool")
+            .field("limits", &self.limits)
+            .field("quota_enabled", &self.quota_enabled)
+            .finish()
+    }
+}
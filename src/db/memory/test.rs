@@ -0,0 +1,108 @@
+use crate::db::{
+    error::DbErrorKind,
+    memory::{models::Result, pool::MemoryDbPool},
+    params,
+    util::SyncTimestamp,
+    Db, BATCH_LIFETIME,
+};
+use crate::server::metrics;
+use crate::settings::test_settings;
+use crate::web::extractors::HawkIdentifier;
+
+fn pool() -> MemoryDbPool {
+    let mut settings = test_settings();
+    settings.database_url = "memory://".to_owned();
+    MemoryDbPool::new(&settings, &metrics::Metrics::noop())
+}
+
+fn gb(user_id: u32, coll: &str, id: &str) -> params::GetBso {
+    params::GetBso {
+        user_id: HawkIdentifier::new_legacy(u64::from(user_id)),
+        collection: coll.to_owned(),
+        id: id.to_owned(),
+    }
+}
+
+fn lc(user_id: u32, coll: &str) -> params::LockCollection {
+    params::LockCollection {
+        user_id: HawkIdentifier::new_legacy(u64::from(user_id)),
+        collection: coll.to_owned(),
+    }
+}
+
+fn pb(user_id: u32, coll: &str, id: &str, payload: &str) -> params::PutBso {
+    params::PutBso {
+        user_id: HawkIdentifier::new_legacy(u64::from(user_id)),
+        collection: coll.to_owned(),
+        id: id.to_owned(),
+        sortindex: None,
+        payload: Some(payload.to_owned()),
+        ttl: None,
+    }
+}
+
+#[test]
+fn rollback_restores() -> Result<()> {
+    let pool = pool();
+    let db = pool.get_sync();
+    db.put_bso_sync(pb(1, "clients", "b0", "before"))?;
+
+    let ts = db.timestamp().as_i64();
+    let db = pool.get_sync();
+    db.set_timestamp(SyncTimestamp::from_i64(ts + 10)?);
+    db.lock_for_write_sync(lc(1, "clients"))?;
+    db.put_bso_sync(pb(1, "clients", "b0", "after"))?;
+    db.put_bso_sync(pb(1, "clients", "b1", "new"))?;
+    db.rollback_sync()?;
+
+    assert_eq!(
+        db.get_bso_sync(gb(1, "clients", "b0"))?.unwrap().payload,
+        "before"
+    );
+    assert!(db.get_bso_sync(gb(1, "clients", "b1"))?.is_none());
+    Ok(())
+}
+
+#[test]
+fn write_lock_conflicts() -> Result<()> {
+    let pool = pool();
+    let db = pool.get_sync();
+    db.lock_for_write_sync(lc(1, "clients"))?;
+
+    let db2 = pool.get_sync();
+    let result = db2.lock_for_write_sync(lc(1, "clients"));
+    assert!(matches!(result.unwrap_err().kind(), DbErrorKind::Conflict));
+    // Other users are unaffected
+    db2.lock_for_write_sync(lc(2, "clients"))?;
+    db2.commit_sync()?;
+
+    // Dropping the session abandons (releasing) its lock
+    drop(db);
+    let db3 = pool.get_sync();
+    db3.lock_for_write_sync(lc(1, "clients"))?;
+    Ok(())
+}
+
+#[test]
+fn batch_expires() -> Result<()> {
+    let pool = pool();
+    let db = pool.get_sync();
+    let uid = HawkIdentifier::new_legacy(1);
+    let batch = db.create_batch_sync(params::CreateBatch {
+        user_id: uid.clone(),
+        collection: "clients".to_owned(),
+        bsos: vec![],
+    })?;
+    let vb = params::ValidateBatch {
+        user_id: uid,
+        collection: "clients".to_owned(),
+        id: batch.id,
+    };
+    assert!(db.validate_batch_sync(vb.clone())?);
+
+    let ts = db.timestamp().as_i64();
+    let db = pool.get_sync();
+    db.set_timestamp(SyncTimestamp::from_i64(ts + BATCH_LIFETIME + 11)?);
+    assert!(!db.validate_batch_sync(vb)?);
+    Ok(())
+}
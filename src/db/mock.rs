@@ -75,6 +75,10 @@ impl<'a> Db<'a> for MockDb {
         Box::pin(future::ok(true))
     }
 
+    fn schema_version(&self) -> DbFuture<'_, results::SchemaVersion> {
+        Box::pin(future::ok(Default::default()))
+    }
+
     mock_db_method!(lock_for_read, LockCollection);
     mock_db_method!(lock_for_write, LockCollection);
     mock_db_method!(get_collection_timestamps, GetCollectionTimestamps);
@@ -94,11 +98,30 @@ impl<'a> Db<'a> for MockDb {
     mock_db_method!(get_bso, GetBso, Option<results::GetBso>);
     mock_db_method!(get_bso_timestamp, GetBsoTimestamp);
     mock_db_method!(put_bso, PutBso);
+    mock_db_method!(import_bsos, ImportBsos);
     mock_db_method!(create_batch, CreateBatch);
     mock_db_method!(validate_batch, ValidateBatch);
     mock_db_method!(append_to_batch, AppendToBatch);
     mock_db_method!(get_batch, GetBatch, Option<results::GetBatch>);
+    mock_db_method!(
+        get_batch_items,
+        GetBatchItems,
+        Option<results::GetBatchItems>
+    );
+    mock_db_method!(delete_batch, DeleteBatch);
     mock_db_method!(commit_batch, CommitBatch);
+    mock_db_method!(purge_storage, PurgeStorage);
+    mock_db_method!(expire_batches, ExpireBatches);
+    mock_db_method!(purge_expired, PurgeExpired);
+    mock_db_method!(post_service, PostService);
+    mock_db_method!(post_node, PostNode);
+    mock_db_method!(allocate_node, AllocateNode);
+    mock_db_method!(get_users, GetUsers);
+    mock_db_method!(post_user, PostUser);
+    mock_db_method!(put_user, PutUser);
+    mock_db_method!(replace_users, ReplaceUsers);
+    mock_db_method!(get_replaced_users, GetReplacedUsers);
+    mock_db_method!(delete_user, DeleteUser);
 
     mock_db_method!(get_collection_id, GetCollectionId);
     #[cfg(test)]
@@ -114,9 +137,6 @@ impl<'a> Db<'a> for MockDb {
     #[cfg(test)]
     fn set_timestamp(&self, _: SyncTimestamp) {}
 
-    #[cfg(test)]
-    mock_db_method!(delete_batch, DeleteBatch);
-
     #[cfg(test)]
     fn clear_coll_cache(&self) {}
 
@@ -1,13 +1,20 @@
 //! Generic db abstration.
 
+pub mod archive;
+#[macro_use]
+mod diesel_db;
 pub mod error;
+pub mod memory;
 pub mod mock;
 pub mod mysql;
 pub mod params;
+pub mod postgres;
 pub mod results;
 pub mod spanner;
+pub mod sqlite;
 #[cfg(test)]
 mod tests;
+pub mod traced;
 pub mod transaction;
 pub mod util;
 
@@ -15,8 +22,12 @@ use std::{fmt::Debug, time::Duration};
 
 use async_trait::async_trait;
 use cadence::{Gauged, StatsdClient};
-use futures::future::{self, LocalBoxFuture, TryFutureExt};
+use futures::{
+    future::{self, LocalBoxFuture, TryFutureExt},
+    stream::{self, StreamExt},
+};
 use lazy_static::lazy_static;
+use rand::{thread_rng, Rng};
 use serde::Deserialize;
 use url::Url;
 
@@ -24,7 +35,7 @@ pub use self::error::{DbError, DbErrorKind};
 use self::util::SyncTimestamp;
 use crate::error::{ApiError, ApiResult};
 use crate::server::metrics::Metrics;
-use crate::settings::Settings;
+use crate::settings::{ReaperSettings, Settings};
 use crate::web::extractors::HawkIdentifier;
 
 lazy_static! {
@@ -54,6 +65,10 @@ lazy_static! {
 /// Non-standard collections will be allocated IDs beginning with this value
 pub const FIRST_CUSTOM_COLLECTION_ID: i32 = 101;
 
+/// The fraction of a Tokenserver node's spare capacity made available for
+/// allocation once all of its nodes are exhausted
+pub const NODE_RELEASE_RATE: f64 = 0.1;
+
 /// Rough guesstimate of the maximum reasonable life span of a batch
 pub const BATCH_LIFETIME: i64 = 2 * 60 * 60 * 1000; // 2 hours, in milliseconds
 
@@ -134,6 +149,16 @@ pub trait Db<'a>: Debug + 'a {
 
     fn get_bsos(&self, params: params::GetBsos) -> DbFuture<'_, results::GetBsos>;
 
+    /// Like `get_bsos`, but reading the BSOs from the db as the returned
+    /// stream is consumed (by backends supporting it). Only the page's BSOs
+    /// are yielded: callers track its offset from the last one.
+    fn get_bsos_stream(&self, params: params::GetBsos) -> DbFuture<'_, results::GetBsosStream<'_>> {
+        Box::pin(
+            self.get_bsos(params)
+                .map_ok(|bsos| stream::iter(bsos.items.into_iter().map(Ok)).boxed_local()),
+        )
+    }
+
     fn get_bso_ids(&self, params: params::GetBsos) -> DbFuture<'_, results::GetBsoIds>;
 
     fn post_bsos(&self, params: params::PostBsos) -> DbFuture<'_, results::PostBsos>;
@@ -149,6 +174,10 @@ pub trait Db<'a>: Debug + 'a {
 
     fn put_bso(&self, params: params::PutBso) -> DbFuture<'_, results::PutBso>;
 
+    /// Write the bsos verbatim (preserving their `modified` and `expiry`) and
+    /// set the collection's `modified`, e.g. when importing an archive.
+    fn import_bsos(&self, params: params::ImportBsos) -> DbFuture<'_, results::ImportBsos>;
+
     fn create_batch(&self, params: params::CreateBatch) -> DbFuture<'_, results::CreateBatch>;
 
     fn validate_batch(&self, params: params::ValidateBatch)
@@ -161,12 +190,73 @@ pub trait Db<'a>: Debug + 'a {
 
     fn get_batch(&self, params: params::GetBatch) -> DbFuture<'_, Option<results::GetBatch>>;
 
+    /// The expiry and staged BSO ids of a pending batch (`None` if it's
+    /// unknown or expired)
+    fn get_batch_items(
+        &self,
+        params: params::GetBatchItems,
+    ) -> DbFuture<'_, Option<results::GetBatchItems>>;
+
+    /// Abandon a pending batch, discarding everything staged in it
+    fn delete_batch(&self, params: params::DeleteBatch) -> DbFuture<'_, results::DeleteBatch>;
+
     fn commit_batch(&self, params: params::CommitBatch) -> DbFuture<'_, results::CommitBatch>;
 
+    /// Delete all of a user's bsos, collections and batches, returning how
+    /// many rows were (or on a dry run, would be) deleted.
+    fn purge_storage(&self, params: params::PurgeStorage) -> DbFuture<'_, results::PurgeStorage>;
+
+    /// Discard all of a user's outstanding (uncommitted) batches.
+    fn expire_batches(&self, params: params::ExpireBatches)
+        -> DbFuture<'_, results::ExpireBatches>;
+
+    /// Delete up to `limit` expired bsos and batches of the users within the
+    /// partition, keeping the affected collections' quota usage current.
+    fn purge_expired(&self, params: params::PurgeExpired) -> DbFuture<'_, results::PurgeExpired>;
+
+    // Tokenserver methods
+
+    /// Return the id of the named service, creating it if necessary.
+    fn post_service(&self, params: params::PostService) -> DbFuture<'_, results::PostService>;
+
+    /// Return the id of the node, creating it if necessary.
+    fn post_node(&self, params: params::PostNode) -> DbFuture<'_, results::PostNode>;
+
+    /// Reserve capacity for a new user on the service's least loaded node.
+    ///
+    /// Should run within a write transaction.
+    fn allocate_node(&self, params: params::AllocateNode) -> DbFuture<'_, results::AllocateNode>;
+
+    fn get_users(&self, params: params::GetUsers) -> DbFuture<'_, results::GetUsers>;
+
+    fn post_user(&self, params: params::PostUser) -> DbFuture<'_, results::PostUser>;
+
+    /// Advance the user's generation and keys_changed_at (never backwards).
+    fn put_user(&self, params: params::PutUser) -> DbFuture<'_, results::PutUser>;
+
+    /// Mark the user's records created before `replaced_at` as replaced.
+    fn replace_users(&self, params: params::ReplaceUsers) -> DbFuture<'_, results::ReplaceUsers>;
+
+    /// Return the service's records replaced before `replaced_before`.
+    fn get_replaced_users(
+        &self,
+        params: params::GetReplacedUsers,
+    ) -> DbFuture<'_, results::GetReplacedUsers>;
+
+    fn delete_user(&self, params: params::DeleteUser) -> DbFuture<'_, results::DeleteUser>;
+
     fn box_clone(&self) -> Box<dyn Db<'a>>;
 
+    /// Allow this session's read-only transactions to read a stale snapshot
+    /// (of a backend supporting them), provided it's no older than
+    /// `min_timestamp`.
+    fn allow_stale_reads(&self, _min_timestamp: Option<SyncTimestamp>) {}
+
     fn check(&self) -> DbFuture<'_, results::Check>;
 
+    /// Report how current the database's schema is
+    fn schema_version(&self) -> DbFuture<'_, results::SchemaVersion>;
+
     /// Retrieve the timestamp for an item/collection
     ///
     /// Modeled on the Python `get_resource_timestamp` function.
@@ -232,9 +322,6 @@ pub trait Db<'a>: Debug + 'a {
     #[cfg(test)]
     fn set_timestamp(&self, timestamp: SyncTimestamp);
 
-    #[cfg(test)]
-    fn delete_batch(&self, params: params::DeleteBatch) -> DbFuture<'_, ()>;
-
     #[cfg(test)]
     fn clear_coll_cache(&self);
 
@@ -270,13 +357,53 @@ pub async fn pool_from_settings(
 ) -> Result<Box<dyn DbPool>, DbError> {
     let url =
         Url::parse(&settings.database_url).map_err(|e| DbErrorKind::InvalidUrl(e.to_string()))?;
-    Ok(match url.scheme() {
-        "mysql" => Box::new(mysql::pool::MysqlDbPool::new(&settings, &metrics)?),
-        "spanner" => Box::new(spanner::pool::SpannerDbPool::new(&settings, &metrics).await?),
+    let pool: Box<dyn DbPool> = match url.scheme() {
+        "memory" => Box::new(memory::pool::MemoryDbPool::new(&settings, &metrics)),
+        "mysql" if settings.database_auto_migrate => {
+            Box::new(mysql::pool::MysqlDbPool::new(&settings, &metrics)?)
+        }
+        "mysql" => Box::new(mysql::pool::MysqlDbPool::new_without_migrations(
+            &settings, &metrics,
+        )?),
+        "postgres" | "postgresql" if settings.database_auto_migrate => {
+            Box::new(postgres::pool::PgDbPool::new(&settings, &metrics)?)
+        }
+        "postgres" | "postgresql" => Box::new(postgres::pool::PgDbPool::new_without_migrations(
+            &settings, &metrics,
+        )?),
+        "spanner" if settings.database_auto_migrate => {
+            Box::new(spanner::pool::SpannerDbPool::new(&settings, &metrics).await?)
+        }
+        "spanner" => Box::new(
+            spanner::pool::SpannerDbPool::new_without_migrations(&settings, &metrics).await?,
+        ),
+        "sqlite" if settings.database_auto_migrate => {
+            Box::new(sqlite::pool::SqliteDbPool::new(&settings, &metrics)?)
+        }
+        "sqlite" => Box::new(sqlite::pool::SqliteDbPool::new_without_migrations(
+            &settings, &metrics,
+        )?),
         _ => Err(DbErrorKind::InvalidUrl(settings.database_url.to_owned()))?,
+    };
+    Ok(if settings.tracing.is_enabled() {
+        Box::new(traced::TracedDbPool::new(pool))
+    } else {
+        pool
     })
 }
 
+/// Ensure the database's schema isn't behind what this server expects
+pub async fn check_schema_version(pool: &dyn DbPool) -> ApiResult<results::SchemaVersion> {
+    let version = pool.get().await?.schema_version().await?;
+    if version.current < version.latest {
+        Err(DbError::from(DbErrorKind::SchemaBehind(
+            version.current,
+            version.latest,
+        )))?
+    }
+    Ok(version)
+}
+
 /// Emit DbPool metrics periodically
 pub fn spawn_pool_periodic_reporter(
     interval: Duration,
@@ -309,3 +436,68 @@ pub fn spawn_pool_periodic_reporter(
     });
     Ok(())
 }
+
+/// Periodically purge expired bsos and batches, one randomly chosen
+/// partition of the users at a time (see `ReaperSettings`)
+pub fn spawn_expiry_reaper(
+    settings: &ReaperSettings,
+    metrics: StatsdClient,
+    pool: Box<dyn DbPool>,
+) -> Result<(), DbError> {
+    if settings.interval == 0 {
+        return Ok(());
+    }
+    if settings.partitions == 0 || settings.limit == 0 {
+        Err(DbError::internal(
+            "reaper.partitions and reaper.limit must be positive",
+        ))?
+    }
+    let settings = settings.clone();
+    actix_rt::spawn(async move {
+        loop {
+            let delay =
+                Duration::from_secs(settings.interval).mul_f64(thread_rng().gen_range(0.5, 1.5));
+            actix_rt::time::delay_for(delay).await;
+
+            let params = params::PurgeExpired {
+                partition: thread_rng().gen_range(0, settings.partitions),
+                partitions: settings.partitions,
+                limit: i64::from(settings.limit),
+            };
+            let mut sweep_metrics = Metrics::from(&metrics);
+            sweep_metrics.start_timer("storage.reaper.duration", None);
+            match purge_expired(pool.as_ref(), params.clone()).await {
+                Ok(purged) => {
+                    debug!(
+                        "Reaper purged {} bsos, {} batches from partition {}",
+                        purged.bsos, purged.batches, params.partition
+                    );
+                    sweep_metrics.count("storage.reaper.bsos", purged.bsos);
+                    sweep_metrics.count("storage.reaper.batches", purged.batches);
+                }
+                Err(e) => {
+                    warn!(
+                        "Reaper failed to purge partition {}: {}",
+                        params.partition, e
+                    );
+                    sweep_metrics.incr("storage.reaper.error");
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn purge_expired(
+    pool: &dyn DbPool,
+    params: params::PurgeExpired,
+) -> ApiResult<results::PurgeExpired> {
+    let db = pool.get().await?;
+    db.begin(true).await?;
+    let result = db.purge_expired(params).await;
+    match result {
+        Ok(_) => db.commit().await?,
+        Err(_) => db.rollback().await?,
+    }
+    result
+}
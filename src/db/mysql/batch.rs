@@ -16,7 +16,7 @@ use super::{
 };
 
 use crate::{
-    db::{params, results, DbError, DbErrorKind, BATCH_LIFETIME},
+    db::{params, results, util::SyncTimestamp, DbError, DbErrorKind, BATCH_LIFETIME},
     web::extractors::HawkIdentifier,
 };
 
@@ -25,6 +25,8 @@ const MAXTTL: i32 = 2_100_000_000;
 pub fn create(db: &MysqlDb, params: params::CreateBatch) -> Result<results::CreateBatch> {
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
+    db.check_quota(&params.user_id, &params.collection, collection_id)?;
+    results::BatchTotals::staging(&params.bsos).check(&db.limits, &params.collection)?;
     // Careful, there's some weirdness here!
     //
     // Sync timestamps are in seconds and quantized to two decimal places, so
@@ -80,7 +82,7 @@ pub fn validate(db: &MysqlDb, params: params::ValidateBatch) -> Result<bool> {
     Ok(exists.is_some())
 }
 
-pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> Result<()> {
+pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> Result<results::BatchTotals> {
     let exists = validate(
         db,
         params::ValidateBatch {
@@ -95,9 +97,11 @@ pub fn append(db: &MysqlDb, params: params::AppendToBatch) -> Result<()> {
     }
 
     let batch_id = decode_id(&params.batch.id)?;
+    let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
+    db.check_quota(&params.user_id, &params.collection, collection_id)?;
     do_append(db, batch_id, params.user_id, collection_id, params.bsos)?;
-    Ok(())
+    totals(db, batch_id, user_id)?.check(&db.limits, &params.collection)
 }
 
 pub fn get(db: &MysqlDb, params: params::GetBatch) -> Result<Option<results::GetBatch>> {
@@ -117,6 +121,32 @@ pub fn get(db: &MysqlDb, params: params::GetBatch) -> Result<Option<results::Get
     Ok(batch)
 }
 
+pub fn get_items(
+    db: &MysqlDb,
+    params: params::GetBatchItems,
+) -> Result<Option<results::GetBatchItems>> {
+    let batch_id = decode_id(&params.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    if !validate(db, params.into())? {
+        return Ok(None);
+    }
+    let ids = batch_upload_items::table
+        .select(batch_upload_items::id)
+        .filter(batch_upload_items::batch_id.eq(&batch_id))
+        .filter(batch_upload_items::user_id.eq(&user_id))
+        .order(batch_upload_items::id)
+        .load::<String>(&db.conn)?;
+    // Recall that the batchid is a millisecond timestamp with the low digit of
+    // the uid mixed in: strip it to recover the batch creation time
+    Ok(Some(results::GetBatchItems {
+        expiry: SyncTimestamp::from_milliseconds(
+            (batch_id - batch_id % 10 + BATCH_LIFETIME) as u64,
+        ),
+        count: ids.len(),
+        ids,
+    }))
+}
+
 pub fn delete(db: &MysqlDb, params: params::DeleteBatch) -> Result<()> {
     let batch_id = decode_id(&params.id)?;
     let user_id = params.user_id.legacy_id as i64;
@@ -139,6 +169,8 @@ pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> Result<results::Comm
     let user_id = params.user_id.legacy_id as i64;
     let collection_id = db.get_collection_id(&params.collection)?;
     let timestamp = db.timestamp();
+    let totals =
+        (totals(db, batch_id, user_id)? + params.pending).check(&db.limits, &params.collection)?;
     sql_query(include_str!("batch_commit.sql"))
         .bind::<BigInt, _>(user_id as i64)
         .bind::<Integer, _>(&collection_id)
@@ -161,10 +193,25 @@ pub fn commit(db: &MysqlDb, params: params::CommitBatch) -> Result<results::Comm
             id: params.batch.id,
         },
     )?;
-    Ok(results::PostBsos {
+    Ok(results::CommitBatch {
         modified: timestamp,
-        success: Default::default(),
-        failed: Default::default(),
+        totals,
+    })
+}
+
+/// The running totals of the BSOs staged in a batch
+fn totals(db: &MysqlDb, batch_id: i64, user_id: i64) -> Result<results::BatchTotals> {
+    let (records, bytes): (i64, i64) = batch_upload_items::table
+        .select((
+            sql::<BigInt>("COUNT(*)"),
+            sql::<BigInt>("COALESCE(SUM(COALESCE(payload_size, 0)), 0)"),
+        ))
+        .filter(batch_upload_items::batch_id.eq(&batch_id))
+        .filter(batch_upload_items::user_id.eq(&user_id))
+        .get_result(&db.conn)?;
+    Ok(results::BatchTotals {
+        records: records as u64,
+        bytes: bytes as u64,
     })
 }
 
@@ -1,6 +1,8 @@
 #[macro_use]
 mod batch;
 mod diesel_ext;
+#[macro_use]
+mod tokenserver;
 pub mod models;
 pub mod pool;
 mod schema;
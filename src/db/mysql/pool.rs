@@ -26,10 +26,13 @@ use crate::db::{
 };
 use crate::error::{ApiError, ApiResult};
 use crate::server::metrics::Metrics;
-use crate::settings::Settings;
+use crate::settings::{ServerLimits, Settings};
 
 embed_migrations!();
 
+/// Version of the latest embedded migration (the last in `migrations`)
+pub const LATEST_MIGRATION_VERSION: i64 = 20201017000000;
+
 /// Run the diesel embedded migrations
 ///
 /// Mysql DDL statements implicitly commit which could disrupt MysqlPool's
@@ -54,7 +57,7 @@ pub struct MysqlDbPool {
     coll_cache: Arc<CollectionCache>,
 
     metrics: Metrics,
-    quota: usize,
+    limits: Arc<ServerLimits>,
     quota_enabled: bool,
 }
 
@@ -84,7 +87,7 @@ impl MysqlDbPool {
             pool: builder.build(manager)?,
             coll_cache: Default::default(),
             metrics: metrics.clone(),
-            quota: settings.limits.max_quota_limit as usize,
+            limits: Arc::new(settings.limits.clone()),
             quota_enabled: settings.enable_quota,
         })
     }
@@ -94,7 +97,7 @@ impl MysqlDbPool {
             self.pool.get()?,
             Arc::clone(&self.coll_cache),
             &self.metrics,
-            &self.quota,
+            &self.limits,
             self.quota_enabled,
         ))
     }
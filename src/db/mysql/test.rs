@@ -10,7 +10,7 @@ use url::Url;
 
 use crate::db::mysql::{
     models::{MysqlDb, Result},
-    pool::MysqlDbPool,
+    pool::{MysqlDbPool, LATEST_MIGRATION_VERSION},
     schema::collections,
 };
 use crate::server::metrics;
@@ -83,3 +83,17 @@ fn static_collection_id() -> Result<()> {
     assert!(cid >= 100);
     Ok(())
 }
+
+#[test]
+fn latest_migration_version() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/migrations");
+    let latest = std::fs::read_dir(dir)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            let version = name.split('_').next()?.replace('-', "");
+            version.parse::<i64>().ok()
+        })
+        .max();
+    assert_eq!(latest, Some(LATEST_MIGRATION_VERSION));
+}
@@ -3,7 +3,7 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-use crate::db::results;
+use crate::db::{results, util::SyncTimestamp};
 use crate::web::extractors::{BatchBsoBody, BsoQueryParams, HawkIdentifier};
 
 macro_rules! data {
@@ -53,6 +53,7 @@ uid_data! {
     GetStorageTimestamp,
     GetStorageUsage,
     DeleteStorage,
+    ExpireBatches,
 }
 
 collection_data! {
@@ -69,6 +70,10 @@ collection_data! {
         bsos: Vec<PostCollectionBso>,
         failed: HashMap<String, String>,
     },
+    ImportBsos {
+        bsos: Vec<ImportBso>,
+        modified: SyncTimestamp,
+    },
 
     CreateBatch {
         bsos: Vec<PostCollectionBso>,
@@ -82,10 +87,16 @@ collection_data! {
     },
     CommitBatch {
         batch: Batch,
+        // Totals of the BSOs committed along with the batch (written
+        // directly rather than staged in it)
+        pending: results::BatchTotals,
     },
     GetBatch {
         id: String,
     },
+    GetBatchItems {
+        id: String,
+    },
     DeleteBatch {
         id: String,
     },
@@ -94,6 +105,16 @@ collection_data! {
     },
 }
 
+impl From<GetBatchItems> for ValidateBatch {
+    fn from(v: GetBatchItems) -> Self {
+        Self {
+            id: v.id,
+            user_id: v.user_id,
+            collection: v.collection,
+        }
+    }
+}
+
 impl From<ValidateBatch> for GetBatch {
     fn from(v: ValidateBatch) -> Self {
         Self {
@@ -148,8 +169,148 @@ impl From<BatchBsoBody> for PostCollectionBso {
     }
 }
 
+/// A bso as exported, written as is by `import_bsos`
+#[derive(Clone, Debug)]
+pub struct ImportBso {
+    pub id: String,
+    pub sortindex: Option<i32>,
+    pub payload: String,
+    pub modified: SyncTimestamp,
+    // milliseconds since the epoch
+    pub expiry: i64,
+}
+
 pub type GetCollectionId = String;
 
+data! {
+    PurgeStorage {
+        user_id: HawkIdentifier,
+        // Only count what would be purged
+        dry_run: bool,
+    }
+}
+
+data! {
+    PurgeExpired {
+        // This sweep's share of the user ids: the `partition`th of
+        // `partitions` contiguous ranges
+        partition: u32,
+        partitions: u32,
+        // The most expired bsos (and, separately, batches) to delete
+        limit: i64,
+    }
+}
+
+impl PurgeExpired {
+    /// The partition's range (`start..end`) of the legacy user ids up to
+    /// `max_user_id`
+    pub fn user_id_range(&self, max_user_id: u64) -> (u64, u64) {
+        let bound = |partition: u32| {
+            ((u128::from(max_user_id) + 1) * u128::from(partition) / u128::from(self.partitions))
+                as u64
+        };
+        (bound(self.partition), bound(self.partition + 1))
+    }
+
+    /// The partition's range (`start..end`, unbounded for the last) of the
+    /// (hex) fxa_uids, split by their first 32 bits
+    pub fn fxa_uid_range(&self) -> (String, Option<String>) {
+        let bound = |partition: u32| {
+            format!(
+                "{:08x}",
+                (1u64 << 32) * u64::from(partition) / u64::from(self.partitions)
+            )
+        };
+        let start = if self.partition == 0 {
+            String::new()
+        } else {
+            bound(self.partition)
+        };
+        let end = if self.partition + 1 >= self.partitions {
+            None
+        } else {
+            Some(bound(self.partition + 1))
+        };
+        (start, end)
+    }
+}
+
+// Tokenserver users, nodes and services
+
+data! {
+    PostService {
+        service: String,
+        pattern: String,
+    }
+}
+
+data! {
+    PostNode {
+        service_id: i32,
+        node: String,
+        capacity: i32,
+    }
+}
+
+data! {
+    AllocateNode {
+        service_id: i32,
+    }
+}
+
+data! {
+    GetUsers {
+        service_id: i32,
+        email: String,
+    }
+}
+
+data! {
+    PostUser {
+        service_id: i32,
+        email: String,
+        generation: i64,
+        keys_changed_at: Option<i64>,
+        client_state: String,
+        node_id: i64,
+        // milliseconds since the epoch
+        created_at: i64,
+    }
+}
+
+data! {
+    PutUser {
+        service_id: i32,
+        email: String,
+        generation: i64,
+        keys_changed_at: Option<i64>,
+    }
+}
+
+data! {
+    ReplaceUsers {
+        service_id: i32,
+        email: String,
+        // milliseconds since the epoch
+        replaced_at: i64,
+    }
+}
+
+data! {
+    GetReplacedUsers {
+        service_id: i32,
+        // milliseconds since the epoch
+        replaced_before: i64,
+        limit: i64,
+    }
+}
+
+data! {
+    DeleteUser {
+        uid: i64,
+    }
+}
+
 #[cfg(test)]
 pub type CreateCollection = String;
 
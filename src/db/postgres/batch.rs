@@ -0,0 +1,274 @@
+use diesel::{
+    self,
+    dsl::sql,
+    insert_into,
+    result::{DatabaseErrorKind::UniqueViolation, Error as DieselError},
+    sql_query,
+    sql_types::{BigInt, Integer, Nullable, Text},
+    ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
+};
+
+use super::{
+    models::{PgDb, Result},
+    schema::{batch_upload_items, batch_uploads},
+};
+
+use crate::{
+    db::{params, results, util::SyncTimestamp, DbError, DbErrorKind, BATCH_LIFETIME},
+    web::extractors::HawkIdentifier,
+};
+
+const MAXTTL: i32 = 2_100_000_000;
+
+pub fn create(db: &PgDb, params: params::CreateBatch) -> Result<results::CreateBatch> {
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    db.check_quota(&params.user_id, &params.collection, collection_id)?;
+    results::BatchTotals::staging(&params.bsos).check(&db.limits, &params.collection)?;
+    // Careful, there's some weirdness here!
+    //
+    // Sync timestamps are in seconds and quantized to two decimal places, so
+    // when we convert one to a bigint in milliseconds, the final digit is
+    // always zero. But we want to use the lower digits of the batchid for
+    // sharding writes via (batchid % num_tables), and leaving it as zero would
+    // skew the sharding distribution.
+    //
+    // So we mix in the lowest digit of the uid to improve the distribution
+    // while still letting us treat these ids as millisecond timestamps.  It's
+    // yuck, but it works and it keeps the weirdness contained to this single
+    // line of code.
+    let batch_id = db.timestamp().as_i64() + (user_id % 10);
+    insert_into(batch_uploads::table)
+        .values((
+            batch_uploads::batch_id.eq(&batch_id),
+            batch_uploads::user_id.eq(&user_id),
+            batch_uploads::collection_id.eq(&collection_id),
+        ))
+        .execute(&db.conn)
+        .map_err(|e| -> DbError {
+            match e {
+                // The user tried to create two batches with the same timestamp
+                DieselError::DatabaseError(UniqueViolation, _) => DbErrorKind::Conflict.into(),
+                _ => e.into(),
+            }
+        })?;
+
+    do_append(db, batch_id, params.user_id, params.bsos)?;
+    Ok(results::CreateBatch {
+        id: encode_id(batch_id),
+        size: None,
+    })
+}
+
+pub fn validate(db: &PgDb, params: params::ValidateBatch) -> Result<bool> {
+    let batch_id = decode_id(&params.id)?;
+    // Avoid hitting the db for batches that are obviously too old.  Recall
+    // that the batchid is a millisecond timestamp.
+    if (batch_id + BATCH_LIFETIME) < db.timestamp().as_i64() {
+        return Ok(false);
+    }
+
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let exists = batch_uploads::table
+        .select(sql::<Integer>("1"))
+        .filter(batch_uploads::batch_id.eq(&batch_id))
+        .filter(batch_uploads::user_id.eq(&user_id))
+        .filter(batch_uploads::collection_id.eq(&collection_id))
+        .get_result::<i32>(&db.conn)
+        .optional()?;
+    Ok(exists.is_some())
+}
+
+pub fn append(db: &PgDb, params: params::AppendToBatch) -> Result<results::BatchTotals> {
+    let exists = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id.clone(),
+            collection: params.collection.clone(),
+            id: params.batch.id.clone(),
+        },
+    )?;
+
+    if !exists {
+        Err(DbErrorKind::BatchNotFound)?
+    }
+
+    let batch_id = decode_id(&params.batch.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    db.check_quota(&params.user_id, &params.collection, collection_id)?;
+    do_append(db, batch_id, params.user_id, params.bsos)?;
+    totals(db, batch_id, user_id)?.check(&db.limits, &params.collection)
+}
+
+pub fn get(db: &PgDb, params: params::GetBatch) -> Result<Option<results::GetBatch>> {
+    let is_valid = validate(
+        db,
+        params::ValidateBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.id.clone(),
+        },
+    )?;
+    let batch = if is_valid {
+        Some(results::GetBatch { id: params.id })
+    } else {
+        None
+    };
+    Ok(batch)
+}
+
+pub fn get_items(
+    db: &PgDb,
+    params: params::GetBatchItems,
+) -> Result<Option<results::GetBatchItems>> {
+    let batch_id = decode_id(&params.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    if !validate(db, params.into())? {
+        return Ok(None);
+    }
+    let ids = batch_upload_items::table
+        .select(batch_upload_items::id)
+        .filter(batch_upload_items::batch_id.eq(&batch_id))
+        .filter(batch_upload_items::user_id.eq(&user_id))
+        .order(batch_upload_items::id)
+        .load::<String>(&db.conn)?;
+    // Recall that the batchid is a millisecond timestamp with the low digit of
+    // the uid mixed in: strip it to recover the batch creation time
+    Ok(Some(results::GetBatchItems {
+        expiry: SyncTimestamp::from_milliseconds(
+            (batch_id - batch_id % 10 + BATCH_LIFETIME) as u64,
+        ),
+        count: ids.len(),
+        ids,
+    }))
+}
+
+pub fn delete(db: &PgDb, params: params::DeleteBatch) -> Result<()> {
+    let batch_id = decode_id(&params.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    diesel::delete(batch_uploads::table)
+        .filter(batch_uploads::batch_id.eq(&batch_id))
+        .filter(batch_uploads::user_id.eq(&user_id))
+        .filter(batch_uploads::collection_id.eq(&collection_id))
+        .execute(&db.conn)?;
+    diesel::delete(batch_upload_items::table)
+        .filter(batch_upload_items::batch_id.eq(&batch_id))
+        .filter(batch_upload_items::user_id.eq(&user_id))
+        .execute(&db.conn)?;
+    Ok(())
+}
+
+/// Commits a batch to the bsos table, deleting the batch when succesful
+pub fn commit(db: &PgDb, params: params::CommitBatch) -> Result<results::CommitBatch> {
+    let batch_id = decode_id(&params.batch.id)?;
+    let user_id = params.user_id.legacy_id as i64;
+    let collection_id = db.get_collection_id(&params.collection)?;
+    let timestamp = db.timestamp();
+    let totals =
+        (totals(db, batch_id, user_id)? + params.pending).check(&db.limits, &params.collection)?;
+    // An upsert's EXCLUDED row can't distinguish a NULL in the batch from a
+    // value: update the existing bsos, then insert the new
+    sql_query(include_str!("batch_commit_update.sql"))
+        .bind::<BigInt, _>(user_id as i64)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(&batch_id)
+        .bind::<BigInt, _>(&db.timestamp().as_i64())
+        .execute(&db.conn)?;
+    sql_query(include_str!("batch_commit_insert.sql"))
+        .bind::<BigInt, _>(user_id as i64)
+        .bind::<Integer, _>(&collection_id)
+        .bind::<BigInt, _>(&batch_id)
+        .bind::<BigInt, _>(&db.timestamp().as_i64())
+        .bind::<BigInt, _>((MAXTTL as i64) * 1000) // XXX:
+        .execute(&db.conn)?;
+
+    db.update_collection(user_id as u32, collection_id)?;
+
+    delete(
+        db,
+        params::DeleteBatch {
+            user_id: params.user_id,
+            collection: params.collection,
+            id: params.batch.id,
+        },
+    )?;
+    Ok(results::CommitBatch {
+        modified: timestamp,
+        totals,
+    })
+}
+
+/// The running totals of the BSOs staged in a batch
+fn totals(db: &PgDb, batch_id: i64, user_id: i64) -> Result<results::BatchTotals> {
+    let (records, bytes): (i64, i64) = batch_upload_items::table
+        .select((
+            sql::<BigInt>("COUNT(*)"),
+            sql::<BigInt>("CAST(COALESCE(SUM(COALESCE(payload_size, 0)), 0) AS BIGINT)"),
+        ))
+        .filter(batch_upload_items::batch_id.eq(&batch_id))
+        .filter(batch_upload_items::user_id.eq(&user_id))
+        .get_result(&db.conn)?;
+    Ok(results::BatchTotals {
+        records: records as u64,
+        bytes: bytes as u64,
+    })
+}
+
+pub fn do_append(
+    db: &PgDb,
+    batch_id: i64,
+    user_id: HawkIdentifier,
+    bsos: Vec<params::PostCollectionBso>,
+) -> Result<()> {
+    // Fields left unset (NULL) by later appends keep their earlier values
+    for bso in bsos {
+        let payload_size = bso.payload.as_ref().map(|p| p.len() as i64);
+        sql_query(
+            "INSERT INTO batch_upload_items (batch, userid, id, sortindex, payload,
+                                             payload_size, ttl_offset)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 ON CONFLICT (batch, userid, id) DO UPDATE SET
+                    sortindex = COALESCE(EXCLUDED.sortindex, batch_upload_items.sortindex),
+                    payload = COALESCE(EXCLUDED.payload, batch_upload_items.payload),
+                    payload_size = COALESCE(EXCLUDED.payload_size,
+                                            batch_upload_items.payload_size),
+                    ttl_offset = COALESCE(EXCLUDED.ttl_offset, batch_upload_items.ttl_offset)",
+        )
+        .bind::<BigInt, _>(&batch_id)
+        .bind::<BigInt, _>(user_id.legacy_id as i64)
+        .bind::<Text, _>(&bso.id)
+        .bind::<Nullable<Integer>, _>(bso.sortindex)
+        .bind::<Nullable<Text>, _>(bso.payload)
+        .bind::<Nullable<BigInt>, _>(payload_size)
+        .bind::<Nullable<Integer>, _>(bso.ttl.map(|ttl| ttl as i32))
+        .execute(&db.conn)?;
+    }
+    Ok(())
+}
+
+pub fn validate_batch_id(id: &str) -> Result<()> {
+    decode_id(id).map(|_| ())
+}
+
+fn encode_id(id: i64) -> String {
+    base64::encode(&id.to_string())
+}
+
+fn decode_id(id: &str) -> Result<i64> {
+    let bytes = base64::decode(id).unwrap_or_else(|_| id.as_bytes().to_vec());
+    let decoded = std::str::from_utf8(&bytes).unwrap_or(id);
+    decoded
+        .parse::<i64>()
+        .map_err(|e| DbError::internal(&format!("Invalid batch_id: {}", e)))
+}
+
+macro_rules! batch_db_method {
+    ($name:ident, $batch_name:ident, $type:ident) => {
+        pub fn $name(&self, params: params::$type) -> Result<results::$type> {
+            batch::$batch_name(self, params)
+        }
+    };
+}
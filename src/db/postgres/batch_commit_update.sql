@@ -0,0 +1,12 @@
+UPDATE bso
+   SET modified = $4,
+       sortindex = COALESCE(batch_upload_items.sortindex, bso.sortindex),
+       ttl = COALESCE((batch_upload_items.ttl_offset::BIGINT * 1000) + $4, bso.ttl),
+       payload = COALESCE(batch_upload_items.payload, bso.payload),
+       payload_size = COALESCE(batch_upload_items.payload_size, bso.payload_size)
+  FROM batch_upload_items
+ WHERE batch_upload_items.batch = $3
+   AND batch_upload_items.userid = $1
+   AND bso.userid = $1
+   AND bso.collection = $2
+   AND bso.id = batch_upload_items.id
@@ -0,0 +1,11 @@
+#[macro_use]
+mod batch;
+#[macro_use]
+mod tokenserver;
+pub mod models;
+pub mod pool;
+mod schema;
+#[cfg(test)]
+mod test;
+
+pub use self::pool::PgDbPool;
@@ -0,0 +1,417 @@
+use actix_web::web::block;
+
+use futures::future::TryFutureExt;
+
+use std::{self, cell::RefCell, collections::HashMap, fmt, ops::Deref, sync::Arc};
+
+use diesel::{
+    connection::TransactionManager,
+    delete,
+    dsl::{count_star, max},
+    expression::{sql_literal::sql, BoxableExpression},
+    expression_methods::PgSortExpressionMethods,
+    pg::{Pg, PgConnection},
+    r2d2::{ConnectionManager, PooledConnection},
+    sql_query,
+    sql_types::{BigInt, Bool, Integer, Nullable, Text},
+    update, BoolExpressionMethods, Connection, ExpressionMethods, GroupByDsl, OptionalExtension,
+    QueryDsl, QueryResult, RunQueryDsl,
+};
+#[cfg(test)]
+use diesel_logger::LoggingConnection;
+use diesel_migrations::MigrationConnection;
+
+use super::{
+    batch,
+    pool::LATEST_MIGRATION_VERSION,
+    schema::{batch_upload_items, batch_uploads, bso, collections, user_collections},
+    tokenserver,
+};
+use crate::db::{
+    error::{DbError, DbErrorKind},
+    mysql::{
+        models::{
+            CollectionLock, COLLECTION_ID, COUNT, DEFAULT_BSO_TTL, EXPIRY, LAST_MODIFIED, MODIFIED,
+            TOMBSTONE, TOTAL_BYTES, USER_ID,
+        },
+        pool::CollectionCache,
+    },
+    params, results,
+    util::SyncTimestamp,
+    Db, DbFuture, Sorting, BATCH_LIFETIME,
+};
+use crate::server::metrics::Metrics;
+use crate::settings::ServerLimits;
+use crate::web::extractors::{BsoQueryParams, HawkIdentifier, OffsetKey};
+use crate::web::tags::Tags;
+
+pub type Result<T> = std::result::Result<T, DbError>;
+type Conn = PooledConnection<ConnectionManager<PgConnection>>;
+type BsoFilter = Box<dyn BoxableExpression<bso::table, Pg, SqlType = Bool>>;
+
+/// LENGTH counts characters: OCTET_LENGTH counts bytes
+const PAYLOAD_BYTES_SUM: &str = "SUM(OCTET_LENGTH(payload))";
+
+/// Per session Db metadata
+#[derive(Debug, Default)]
+struct PgDbSession {
+    /// The "current time" on the server used for this session's operations
+    timestamp: SyncTimestamp,
+    /// Cache of collection modified timestamps per (user_id, collection_id)
+    coll_modified_cache: HashMap<(u32, i32), SyncTimestamp>,
+    /// Currently locked collections
+    coll_locks: HashMap<(u32, i32), CollectionLock>,
+    /// Whether a transaction was started (begin() called)
+    in_transaction: bool,
+    in_write_transaction: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct PgDb {
+    /// Synchronous Diesel calls are executed in actix_web::web::block to satisfy
+    /// the Db trait's asynchronous interface.
+    ///
+    /// Arc<PgDbInner> provides a Clone impl utilized for safely moving to
+    /// the thread pool but does not provide Send as the underlying db
+    /// conn. structs are !Sync (Arc requires both for Send). See the Send impl
+    /// below.
+    pub(super) inner: Arc<PgDbInner>,
+
+    /// Pool level cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    pub metrics: Metrics,
+    pub limits: Arc<ServerLimits>,
+    pub quota_enabled: bool,
+}
+
+/// Despite the db conn structs being !Sync (see Arc<PgDbInner> above) we
+/// don't spawn multiple PgDb calls at a time in the thread pool. Calls are
+/// queued to the thread pool via Futures, naturally serialized.
+unsafe impl Send for PgDb {}
+
+pub struct PgDbInner {
+    #[cfg(not(test))]
+    pub(super) conn: Conn,
+    #[cfg(test)]
+    pub(super) conn: LoggingConnection<Conn>,
+
+    session: RefCell<PgDbSession>,
+}
+
+impl fmt::Debug for PgDbInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PgDbInner {{ session: {:?} }}", self.session)
+    }
+}
+
+impl Deref for PgDb {
+    type Target = PgDbInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl PgDb {
+    pub fn new(
+        conn: Conn,
+        coll_cache: Arc<CollectionCache>,
+        metrics: &Metrics,
+        limits: &Arc<ServerLimits>,
+        quota_enabled: bool,
+    ) -> Self {
+        let inner = PgDbInner {
+            #[cfg(not(test))]
+            conn,
+            #[cfg(test)]
+            conn: LoggingConnection::new(conn),
+            session: RefCell::new(Default::default()),
+        };
+        PgDb {
+            inner: Arc::new(inner),
+            coll_cache,
+            metrics: metrics.clone(),
+            limits: Arc::clone(limits),
+            quota_enabled,
+        }
+    }
+
+    /// Read the collection's modified timestamp, locking its row in the
+    /// user_collections table. Read locks do SELECT ... FOR SHARE and write
+    /// locks do SELECT ... FOR UPDATE.
+    ///
+    /// In theory it would be possible to use serializable transactions rather
+    /// than explicit locking, but our ops team have expressed concerns about
+    /// the efficiency of that approach at scale.
+    fn locked_modified(
+        &self,
+        user_id: i64,
+        collection_id: i32,
+        for_write: bool,
+    ) -> Result<Option<i64>> {
+        let query = user_collections::table
+            .select(user_collections::modified)
+            .filter(user_collections::user_id.eq(user_id))
+            .filter(user_collections::collection_id.eq(collection_id));
+        Ok(if for_write {
+            query.for_update().first(&self.conn).optional()?
+        } else {
+            query.for_share().first(&self.conn).optional()?
+        })
+    }
+
+    pub(super) fn begin(&self, for_write: bool) -> Result<()> {
+        // Locking further collections joins the already open transaction
+        if !self.session.borrow().in_transaction {
+            self.conn
+                .transaction_manager()
+                .begin_transaction(&self.conn)?;
+        }
+        self.session.borrow_mut().in_transaction = true;
+        if for_write {
+            self.session.borrow_mut().in_write_transaction = true;
+        }
+        Ok(())
+    }
+
+    fn insert_collection(&self, name: &str) -> QueryResult<usize> {
+        diesel::insert_into(collections::table)
+            .values(collections::name.eq(name))
+            .on_conflict_do_nothing()
+            .execute(&self.conn)
+    }
+
+    /// An INSERT of `columns` into `table`, updating the `updates` columns
+    /// of the row of a conflicting `key` instead
+    fn upsert_sql(table: &str, columns: &[&str], key: &[&str], updates: &[&str]) -> String {
+        let on_conflict = if updates.is_empty() {
+            "DO NOTHING".to_owned()
+        } else {
+            let updates: Vec<_> = updates
+                .iter()
+                .map(|column| format!("{0} = EXCLUDED.{0}", column))
+                .collect();
+            format!("DO UPDATE SET {}", updates.join(", "))
+        };
+        let placeholders: Vec<_> = (1..=columns.len()).map(|i| format!("${}", i)).collect();
+        format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) {}",
+            table,
+            columns.join(", "),
+            placeholders.join(", "),
+            key.join(", "),
+            on_conflict
+        )
+    }
+
+    fn check_sync(&self) -> Result<results::Check> {
+        // can the database be queried?
+        let result = sql_query("SELECT 1").execute(&self.conn)?;
+        Ok(result as u64 > 0)
+    }
+
+    batch_db_method!(create_batch_sync, create, CreateBatch);
+    batch_db_method!(validate_batch_sync, validate, ValidateBatch);
+    batch_db_method!(append_to_batch_sync, append, AppendToBatch);
+    batch_db_method!(commit_batch_sync, commit, CommitBatch);
+    batch_db_method!(delete_batch_sync, delete, DeleteBatch);
+
+    pub fn get_batch_sync(&self, params: params::GetBatch) -> Result<Option<results::GetBatch>> {
+        batch::get(&self, params)
+    }
+
+    pub fn get_batch_items_sync(
+        &self,
+        params: params::GetBatchItems,
+    ) -> Result<Option<results::GetBatchItems>> {
+        batch::get_items(&self, params)
+    }
+
+    tokenserver_db_method!(post_service_sync, post_service, PostService);
+    tokenserver_db_method!(post_node_sync, post_node, PostNode);
+    tokenserver_db_method!(allocate_node_sync, allocate_node, AllocateNode);
+    tokenserver_db_method!(get_users_sync, get_users, GetUsers);
+    tokenserver_db_method!(post_user_sync, post_user, PostUser);
+    tokenserver_db_method!(put_user_sync, put_user, PutUser);
+    tokenserver_db_method!(replace_users_sync, replace_users, ReplaceUsers);
+    tokenserver_db_method!(
+        get_replaced_users_sync,
+        get_replaced_users,
+        GetReplacedUsers
+    );
+    tokenserver_db_method!(delete_user_sync, delete_user, DeleteUser);
+}
+
+// Postgres sorts NULLs first when descending, unlike MySQL and SQLite
+diesel_db_methods!(PgDb, bso::sortindex.desc().nulls_last());
+
+macro_rules! sync_db_method {
+    ($name:ident, $sync_name:ident, $type:ident) => {
+        sync_db_method!($name, $sync_name, $type, results::$type);
+    };
+    ($name:ident, $sync_name:ident, $type:ident, $result:ty) => {
+        fn $name(&self, params: params::$type) -> DbFuture<'_, $result> {
+            let db = self.clone();
+            Box::pin(block(move || db.$sync_name(params).map_err(Into::into)).map_err(Into::into))
+        }
+    };
+}
+
+impl<'a> Db<'a> for PgDb {
+    fn commit(&self) -> DbFuture<'_, ()> {
+        let db = self.clone();
+        Box::pin(block(move || db.commit_sync().map_err(Into::into)).map_err(Into::into))
+    }
+
+    fn rollback(&self) -> DbFuture<'_, ()> {
+        let db = self.clone();
+        Box::pin(block(move || db.rollback_sync().map_err(Into::into)).map_err(Into::into))
+    }
+
+    fn begin(&self, for_write: bool) -> DbFuture<'_, ()> {
+        let db = self.clone();
+        Box::pin(async move { db.begin_async(for_write).map_err(Into::into).await })
+    }
+
+    fn box_clone(&self) -> Box<dyn Db<'a>> {
+        Box::new(self.clone())
+    }
+
+    fn check(&self) -> DbFuture<'_, results::Check> {
+        let db = self.clone();
+        Box::pin(block(move || db.check_sync().map_err(Into::into)).map_err(Into::into))
+    }
+
+    fn schema_version(&self) -> DbFuture<'_, results::SchemaVersion> {
+        let db = self.clone();
+        Box::pin(block(move || db.schema_version_sync().map_err(Into::into)).map_err(Into::into))
+    }
+
+    sync_db_method!(lock_for_read, lock_for_read_sync, LockCollection);
+    sync_db_method!(lock_for_write, lock_for_write_sync, LockCollection);
+    sync_db_method!(
+        get_collection_timestamps,
+        get_collection_timestamps_sync,
+        GetCollectionTimestamps
+    );
+    sync_db_method!(
+        get_collection_timestamp,
+        get_collection_timestamp_sync,
+        GetCollectionTimestamp
+    );
+    sync_db_method!(
+        get_collection_counts,
+        get_collection_counts_sync,
+        GetCollectionCounts
+    );
+    sync_db_method!(
+        get_collection_usage,
+        get_collection_usage_sync,
+        GetCollectionUsage
+    );
+    sync_db_method!(
+        get_storage_timestamp,
+        get_storage_timestamp_sync,
+        GetStorageTimestamp
+    );
+    sync_db_method!(get_storage_usage, get_storage_usage_sync, GetStorageUsage);
+    sync_db_method!(get_quota_usage, get_quota_usage_sync, GetQuotaUsage);
+    sync_db_method!(delete_storage, delete_storage_sync, DeleteStorage);
+    sync_db_method!(delete_collection, delete_collection_sync, DeleteCollection);
+    sync_db_method!(delete_bsos, delete_bsos_sync, DeleteBsos);
+    sync_db_method!(get_bsos, get_bsos_sync, GetBsos);
+
+    sync_db_method!(get_bso_ids, get_bso_ids_sync, GetBsoIds);
+    sync_db_method!(post_bsos, post_bsos_sync, PostBsos);
+    sync_db_method!(delete_bso, delete_bso_sync, DeleteBso);
+    sync_db_method!(get_bso, get_bso_sync, GetBso, Option<results::GetBso>);
+    sync_db_method!(
+        get_bso_timestamp,
+        get_bso_timestamp_sync,
+        GetBsoTimestamp,
+        results::GetBsoTimestamp
+    );
+    sync_db_method!(put_bso, put_bso_sync, PutBso);
+    sync_db_method!(import_bsos, import_bsos_sync, ImportBsos);
+    sync_db_method!(create_batch, create_batch_sync, CreateBatch);
+    sync_db_method!(validate_batch, validate_batch_sync, ValidateBatch);
+    sync_db_method!(append_to_batch, append_to_batch_sync, AppendToBatch);
+    sync_db_method!(
+        get_batch,
+        get_batch_sync,
+        GetBatch,
+        Option<results::GetBatch>
+    );
+    sync_db_method!(
+        get_batch_items,
+        get_batch_items_sync,
+        GetBatchItems,
+        Option<results::GetBatchItems>
+    );
+    sync_db_method!(delete_batch, delete_batch_sync, DeleteBatch);
+    sync_db_method!(commit_batch, commit_batch_sync, CommitBatch);
+    sync_db_method!(purge_storage, purge_storage_sync, PurgeStorage);
+    sync_db_method!(expire_batches, expire_batches_sync, ExpireBatches);
+    sync_db_method!(purge_expired, purge_expired_sync, PurgeExpired);
+    sync_db_method!(post_service, post_service_sync, PostService);
+    sync_db_method!(post_node, post_node_sync, PostNode);
+    sync_db_method!(allocate_node, allocate_node_sync, AllocateNode);
+    sync_db_method!(get_users, get_users_sync, GetUsers);
+    sync_db_method!(post_user, post_user_sync, PostUser);
+    sync_db_method!(put_user, put_user_sync, PutUser);
+    sync_db_method!(replace_users, replace_users_sync, ReplaceUsers);
+    sync_db_method!(
+        get_replaced_users,
+        get_replaced_users_sync,
+        GetReplacedUsers
+    );
+    sync_db_method!(delete_user, delete_user_sync, DeleteUser);
+
+    fn get_collection_id(&self, name: String) -> DbFuture<'_, i32> {
+        let db = self.clone();
+        Box::pin(block(move || db.get_collection_id(&name).map_err(Into::into)).map_err(Into::into))
+    }
+
+    #[cfg(test)]
+    fn create_collection(&self, name: String) -> DbFuture<'_, i32> {
+        let db = self.clone();
+        Box::pin(
+            block(move || db.get_or_create_collection_id(&name).map_err(Into::into))
+                .map_err(Into::into),
+        )
+    }
+
+    #[cfg(test)]
+    fn update_collection(&self, param: params::UpdateCollection) -> DbFuture<'_, SyncTimestamp> {
+        let db = self.clone();
+        Box::pin(
+            block(move || {
+                db.update_collection(param.user_id.legacy_id as u32, param.collection_id)
+                    .map_err(Into::into)
+            })
+            .map_err(Into::into),
+        )
+    }
+
+    #[cfg(test)]
+    fn timestamp(&self) -> SyncTimestamp {
+        self.timestamp()
+    }
+
+    #[cfg(test)]
+    fn set_timestamp(&self, timestamp: SyncTimestamp) {
+        self.session.borrow_mut().timestamp = timestamp;
+    }
+
+    #[cfg(test)]
+    fn clear_coll_cache(&self) {
+        self.coll_cache.clear();
+    }
+
+    #[cfg(test)]
+    fn set_quota(&mut self, enabled: bool, limit: usize) {
+        Arc::make_mut(&mut self.limits).max_quota_limit = limit as u32;
+        self.quota_enabled = enabled;
+    }
+}
@@ -0,0 +1,126 @@
+use actix_web::web::block;
+
+use async_trait::async_trait;
+
+use std::{fmt, sync::Arc};
+
+use diesel::{
+    pg::PgConnection,
+    r2d2::{ConnectionManager, Pool},
+    Connection,
+};
+#[cfg(test)]
+use diesel_logger::LoggingConnection;
+
+use super::models::{PgDb, Result};
+#[cfg(test)]
+use super::test::TestTransactionCustomizer;
+use crate::db::{mysql::pool::CollectionCache, results, Db, DbPool};
+use crate::error::{ApiError, ApiResult};
+use crate::server::metrics::Metrics;
+use crate::settings::{ServerLimits, Settings};
+
+embed_migrations!("src/db/postgres/migrations");
+
+/// Version of the latest embedded migration (the last in `src/db/postgres/migrations`)
+pub const LATEST_MIGRATION_VERSION: i64 = 20201022000000;
+
+/// Run the diesel embedded migrations
+///
+/// Runs on its own separate conn, outside of PgDbPool's
+/// begin_test_transaction during tests.
+pub fn run_embedded_migrations(settings: &Settings) -> Result<()> {
+    let conn = PgConnection::establish(&settings.database_url)?;
+    #[cfg(test)]
+    // XXX: this doesn't show the DDL statements
+    // https://github.com/shssoichiro/diesel-logger/issues/1
+    embedded_migrations::run(&LoggingConnection::new(conn))?;
+    #[cfg(not(test))]
+    embedded_migrations::run(&conn)?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct PgDbPool {
+    /// Pool of db connections
+    pool: Pool<ConnectionManager<PgConnection>>,
+    /// Thread Pool for running synchronous db calls
+    /// In-memory cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    metrics: Metrics,
+    limits: Arc<ServerLimits>,
+    quota_enabled: bool,
+}
+
+impl PgDbPool {
+    /// Creates a new pool of Postgres db connections.
+    ///
+    /// Also initializes the Postgres db, ensuring all migrations are ran.
+    pub fn new(settings: &Settings, metrics: &Metrics) -> Result<Self> {
+        run_embedded_migrations(settings)?;
+        Self::new_without_migrations(settings, metrics)
+    }
+
+    pub fn new_without_migrations(settings: &Settings, metrics: &Metrics) -> Result<Self> {
+        let manager = ConnectionManager::<PgConnection>::new(settings.database_url.clone());
+        let builder = Pool::builder()
+            .max_size(settings.database_pool_max_size.unwrap_or(10))
+            .min_idle(settings.database_pool_min_idle);
+
+        #[cfg(test)]
+        let builder = if settings.database_use_test_transactions {
+            builder.connection_customizer(Box::new(TestTransactionCustomizer))
+        } else {
+            builder
+        };
+
+        Ok(Self {
+            pool: builder.build(manager)?,
+            coll_cache: Default::default(),
+            metrics: metrics.clone(),
+            limits: Arc::new(settings.limits.clone()),
+            quota_enabled: settings.enable_quota,
+        })
+    }
+
+    pub fn get_sync(&self) -> Result<PgDb> {
+        Ok(PgDb::new(
+            self.pool.get()?,
+            Arc::clone(&self.coll_cache),
+            &self.metrics,
+            &self.limits,
+            self.quota_enabled,
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl DbPool for PgDbPool {
+    async fn get<'a>(&'a self) -> ApiResult<Box<dyn Db<'a>>> {
+        let pool = self.clone();
+        let db = block(move || pool.get_sync().map_err(ApiError::from)).await?;
+
+        Ok(Box::new(db) as Box<dyn Db<'a>>)
+    }
+
+    fn state(&self) -> results::PoolState {
+        self.pool.state().into()
+    }
+
+    fn validate_batch_id(&self, id: String) -> Result<()> {
+        super::batch::validate_batch_id(&id)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Debug for PgDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("PgDbPool")
+            .field("coll_cache", &self.coll_cache)
+            .finish()
+    }
+}
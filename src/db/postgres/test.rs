@@ -0,0 +1,99 @@
+use std::{collections::HashMap, result::Result as StdResult};
+
+use diesel::{
+    expression_methods::TextExpressionMethods,
+    pg::PgConnection,
+    r2d2::{CustomizeConnection, Error as PoolError},
+    Connection, ExpressionMethods, QueryDsl, RunQueryDsl,
+};
+use url::Url;
+
+use crate::db::postgres::{
+    models::{PgDb, Result},
+    pool::{PgDbPool, LATEST_MIGRATION_VERSION},
+    schema::collections,
+};
+use crate::server::metrics;
+use crate::settings::{test_settings, Settings};
+
+#[derive(Debug)]
+pub struct TestTransactionCustomizer;
+
+impl CustomizeConnection<PgConnection, PoolError> for TestTransactionCustomizer {
+    fn on_acquire(&self, conn: &mut PgConnection) -> StdResult<(), PoolError> {
+        conn.begin_test_transaction().map_err(PoolError::QueryError)
+    }
+}
+
+pub fn db(settings: &Settings) -> Result<PgDb> {
+    let _ = env_logger::try_init();
+    // inherit SYNC_DATABASE_URL from the env
+
+    let pool = PgDbPool::new(&settings, &metrics::Metrics::noop())?;
+    pool.get_sync()
+}
+
+#[test]
+fn static_collection_id() -> Result<()> {
+    let settings = test_settings();
+    if Url::parse(&settings.database_url).unwrap().scheme() != "postgres" {
+        // Skip this test if we're not using postgres
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    // ensure DB actually has predefined common collections
+    let cols: Vec<(i32, _)> = vec![
+        (1, "clients"),
+        (2, "crypto"),
+        (3, "forms"),
+        (4, "history"),
+        (5, "keys"),
+        (6, "meta"),
+        (7, "bookmarks"),
+        (8, "prefs"),
+        (9, "tabs"),
+        (10, "passwords"),
+        (11, "addons"),
+        (12, "addresses"),
+        (13, "creditcards"),
+    ];
+    // The integration tests can create collections that start
+    // with `xxx%`. We should not include those in our counts for local
+    // unit tests.
+    let results: HashMap<i32, String> = collections::table
+        .select((collections::id, collections::name))
+        .filter(collections::name.ne(""))
+        .filter(collections::name.not_like("xxx%")) // from most integration tests
+        .filter(collections::name.ne("col2")) // from older intergration tests
+        .load(&db.inner.conn)?
+        .into_iter()
+        .collect();
+    assert_eq!(results.len(), cols.len(), "mismatched columns");
+    for (id, name) in &cols {
+        assert_eq!(results.get(id).unwrap(), name);
+    }
+
+    for (id, name) in &cols {
+        let result = db.get_collection_id(name)?;
+        assert_eq!(result, *id);
+    }
+
+    let cid = db.get_or_create_collection_id("col1")?;
+    assert!(cid >= 100);
+    Ok(())
+}
+
+#[test]
+fn latest_migration_version() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/db/postgres/migrations");
+    let latest = std::fs::read_dir(dir)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            let version = name.split('_').next()?.replace('-', "");
+            version.parse::<i64>().ok()
+        })
+        .max();
+    assert_eq!(latest, Some(LATEST_MIGRATION_VERSION));
+}
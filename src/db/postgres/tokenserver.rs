@@ -0,0 +1,222 @@
+//! Tokenserver users, nodes and services.
+use diesel::{
+    sql_query,
+    sql_types::{BigInt, Double, Integer, Nullable, Text},
+    OptionalExtension, RunQueryDsl,
+};
+
+use super::models::{PgDb, Result};
+use crate::db::{params, results, DbErrorKind, NODE_RELEASE_RATE};
+
+#[derive(Debug, QueryableByName)]
+struct IdResult {
+    #[sql_type = "BigInt"]
+    id: i64,
+}
+
+pub fn post_service(db: &PgDb, params: params::PostService) -> Result<results::PostService> {
+    sql_query(
+        "INSERT INTO services (service, pattern)
+         VALUES ($1, $2)
+             ON CONFLICT DO NOTHING",
+    )
+    .bind::<Text, _>(&params.service)
+    .bind::<Text, _>(&params.pattern)
+    .execute(&db.conn)?;
+    let id = sql_query(
+        "SELECT id::BIGINT AS id
+           FROM services
+          WHERE service = $1",
+    )
+    .bind::<Text, _>(&params.service)
+    .get_result::<IdResult>(&db.conn)?
+    .id;
+    Ok(id as i32)
+}
+
+pub fn post_node(db: &PgDb, params: params::PostNode) -> Result<results::PostNode> {
+    sql_query(
+        "INSERT INTO nodes (service, node, available, current_load, capacity, downed, backoff)
+         VALUES ($1, $2, $3, 0, $4, 0, 0)
+             ON CONFLICT DO NOTHING",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.node)
+    .bind::<Integer, _>(params.capacity)
+    .bind::<Integer, _>(params.capacity)
+    .execute(&db.conn)?;
+    Ok(sql_query(
+        "SELECT id
+           FROM nodes
+          WHERE service = $1
+            AND node = $2",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.node)
+    .get_result::<IdResult>(&db.conn)?
+    .id)
+}
+
+fn least_loaded_node(db: &PgDb, service_id: i32) -> Result<Option<results::AllocateNode>> {
+    // LOG(0) is an error rather than NULL here: order by the plain ratio,
+    // which still sorts empty nodes first
+    Ok(sql_query(
+        "SELECT id, node
+           FROM nodes
+          WHERE service = $1
+            AND available > 0
+            AND capacity > current_load
+            AND downed = 0
+            AND backoff = 0
+          ORDER BY current_load::FLOAT / capacity
+          LIMIT 1
+            FOR UPDATE",
+    )
+    .bind::<Integer, _>(service_id)
+    .get_result::<results::AllocateNode>(&db.conn)
+    .optional()?)
+}
+
+pub fn allocate_node(db: &PgDb, params: params::AllocateNode) -> Result<results::AllocateNode> {
+    let node = match least_loaded_node(db, params.service_id)? {
+        Some(node) => node,
+        None => {
+            // Every node's exhausted its available slots: release some more
+            // of their spare capacity and try again
+            let released = sql_query(
+                "UPDATE nodes
+                    SET available = LEAST((capacity * $1)::INTEGER, capacity - current_load)
+                  WHERE service = $2
+                    AND available <= 0
+                    AND capacity > current_load
+                    AND downed = 0",
+            )
+            .bind::<Double, _>(NODE_RELEASE_RATE)
+            .bind::<Integer, _>(params.service_id)
+            .execute(&db.conn)?;
+            if released == 0 {
+                Err(DbErrorKind::NodeUnavailable)?
+            }
+            least_loaded_node(db, params.service_id)?.ok_or(DbErrorKind::NodeUnavailable)?
+        }
+    };
+
+    sql_query(
+        "UPDATE nodes
+            SET current_load = current_load + 1,
+                available = GREATEST(available - 1, 0)
+          WHERE id = $1",
+    )
+    .bind::<BigInt, _>(node.id)
+    .execute(&db.conn)?;
+    Ok(node)
+}
+
+pub fn get_users(db: &PgDb, params: params::GetUsers) -> Result<results::GetUsers> {
+    Ok(sql_query(
+        "SELECT users.uid, users.nodeid AS node_id, nodes.node, users.generation,
+                users.keys_changed_at, users.client_state, users.created_at,
+                users.replaced_at
+           FROM users
+           LEFT OUTER JOIN nodes
+             ON users.nodeid = nodes.id
+            AND nodes.downed = 0
+          WHERE users.email = $1
+            AND users.service = $2
+          ORDER BY users.created_at DESC, users.uid DESC
+          LIMIT 20",
+    )
+    .bind::<Text, _>(&params.email)
+    .bind::<Integer, _>(params.service_id)
+    .load::<results::TokenserverUser>(&db.conn)?)
+}
+
+pub fn post_user(db: &PgDb, params: params::PostUser) -> Result<results::PostUser> {
+    Ok(sql_query(
+        "INSERT INTO users (service, email, generation, client_state, created_at, replaced_at,
+                            nodeid, keys_changed_at)
+         VALUES ($1, $2, $3, $4, $5, NULL, $6, $7)
+         RETURNING uid AS id",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.email)
+    .bind::<BigInt, _>(params.generation)
+    .bind::<Text, _>(&params.client_state)
+    .bind::<BigInt, _>(params.created_at)
+    .bind::<BigInt, _>(params.node_id)
+    .bind::<Nullable<BigInt>, _>(params.keys_changed_at)
+    .get_result::<IdResult>(&db.conn)?
+    .id)
+}
+
+pub fn put_user(db: &PgDb, params: params::PutUser) -> Result<results::PutUser> {
+    // GREATEST ignores NULLs, keeping whichever keys_changed_at is set
+    sql_query(
+        "UPDATE users
+            SET generation = GREATEST(generation, $1),
+                keys_changed_at = GREATEST(keys_changed_at, $2)
+          WHERE service = $3
+            AND email = $4
+            AND replaced_at IS NULL",
+    )
+    .bind::<BigInt, _>(params.generation)
+    .bind::<Nullable<BigInt>, _>(params.keys_changed_at)
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.email)
+    .execute(&db.conn)?;
+    Ok(())
+}
+
+pub fn replace_users(db: &PgDb, params: params::ReplaceUsers) -> Result<results::ReplaceUsers> {
+    sql_query(
+        "UPDATE users
+            SET replaced_at = $1
+          WHERE service = $2
+            AND email = $3
+            AND replaced_at IS NULL
+            AND created_at < $4",
+    )
+    .bind::<BigInt, _>(params.replaced_at)
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.email)
+    .bind::<BigInt, _>(params.replaced_at)
+    .execute(&db.conn)?;
+    Ok(())
+}
+
+pub fn get_replaced_users(
+    db: &PgDb,
+    params: params::GetReplacedUsers,
+) -> Result<results::GetReplacedUsers> {
+    Ok(sql_query(
+        "SELECT uid, email, generation, keys_changed_at, client_state, replaced_at
+           FROM users
+          WHERE service = $1
+            AND replaced_at IS NOT NULL
+            AND replaced_at < $2
+          ORDER BY replaced_at, uid
+          LIMIT $3",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<BigInt, _>(params.replaced_before)
+    .bind::<BigInt, _>(params.limit)
+    .load::<results::ReplacedUser>(&db.conn)?)
+}
+
+pub fn delete_user(db: &PgDb, params: params::DeleteUser) -> Result<results::DeleteUser> {
+    sql_query(
+        "DELETE FROM users
+          WHERE uid = $1",
+    )
+    .bind::<BigInt, _>(params.uid)
+    .execute(&db.conn)?;
+    Ok(())
+}
+
+macro_rules! tokenserver_db_method {
+    ($name:ident, $tokenserver_name:ident, $type:ident) => {
+        pub fn $name(&self, params: params::$type) -> Result<results::$type> {
+            tokenserver::$tokenserver_name(self, params)
+        }
+    };
+}
@@ -1,11 +1,15 @@
 //! Result types for database methods.
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Add};
 
 use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+use futures::stream::LocalBoxStream;
 use serde::{Deserialize, Serialize};
 
 use super::params;
-use crate::db::util::SyncTimestamp;
+use crate::db::{util::SyncTimestamp, DbError, DbErrorKind};
+use crate::error::ApiError;
+use crate::settings::ServerLimits;
+use crate::web::extractors::Offset;
 
 pub type LockCollection = ();
 pub type GetBsoTimestamp = SyncTimestamp;
@@ -27,11 +31,73 @@ pub struct CreateBatch {
     pub size: Option<usize>,
 }
 
+/// The running totals of the BSOs of a batch
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct BatchTotals {
+    pub records: u64,
+    /// Total size of their payloads
+    pub bytes: u64,
+}
+
+impl BatchTotals {
+    /// The totals of a new batch staging `bsos`: a record posted more than
+    /// once counts once, with its last payload
+    pub fn staging<'a>(bsos: impl IntoIterator<Item = &'a params::PostCollectionBso>) -> Self {
+        let mut sizes = HashMap::new();
+        for bso in bsos {
+            let size = sizes.entry(bso.id.as_str()).or_insert(0);
+            if let Some(payload) = &bso.payload {
+                *size = payload.len() as u64;
+            }
+        }
+        Self {
+            records: sizes.len() as u64,
+            bytes: sizes.values().sum(),
+        }
+    }
+
+    /// Fail with `BatchTooLarge` when over the totals the batch's
+    /// collection is limited to
+    pub fn check(self, limits: &ServerLimits, collection: &str) -> Result<Self, DbError> {
+        if self.records > u64::from(limits.max_total_records_for(collection))
+            || self.bytes > u64::from(limits.max_total_bytes)
+        {
+            return Err(DbErrorKind::BatchTooLarge.into());
+        }
+        Ok(self)
+    }
+}
+
+impl Add for BatchTotals {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            records: self.records + other.records,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
 pub type ValidateBatch = bool;
-pub type AppendToBatch = ();
+pub type AppendToBatch = BatchTotals;
 pub type GetBatch = params::Batch;
+
+/// What a pending batch holds so far
+#[derive(Debug, Default, Serialize)]
+pub struct GetBatchItems {
+    pub expiry: SyncTimestamp,
+    pub count: usize,
+    /// Ids of the BSOs staged in the batch, in order
+    pub ids: Vec<String>,
+}
 pub type DeleteBatch = ();
-pub type CommitBatch = PostBsos;
+
+#[derive(Debug, Default)]
+pub struct CommitBatch {
+    pub modified: SyncTimestamp,
+    pub totals: BatchTotals,
+}
 pub type ValidateBatchId = ();
 pub type Check = bool;
 
@@ -67,12 +133,17 @@ where
     T: Serialize,
 {
     pub items: Vec<T>,
-    pub offset: Option<String>,
+    pub offset: Option<Offset>,
 }
 
 pub type GetBsos = Paginated<GetBso>;
 pub type GetBsoIds = Paginated<String>;
 
+/// The BSOs of `get_bsos`, read from the db as they're consumed.
+pub type GetBsosStream<'a> = LocalBoxStream<'a, Result<GetBso, ApiError>>;
+
+pub type ImportBsos = ();
+
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct PostBsos {
     pub modified: SyncTimestamp,
@@ -89,6 +160,98 @@ pub struct PoolState {
 
 pub type GetCollectionId = i32;
 
+#[derive(Debug, Default)]
+pub struct SchemaVersion {
+    /// Version of the most recently applied migration (0 when none have been)
+    pub current: i64,
+    /// Version of the latest migration known to this server
+    pub latest: i64,
+}
+
+/// Rows purged (or that would be, on a dry run) by `purge_storage`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PurgeStorage {
+    pub bsos: i64,
+    pub user_collections: i64,
+    pub batches: i64,
+}
+
+/// The number of outstanding batches expired
+pub type ExpireBatches = i64;
+
+/// Expired rows deleted by `purge_expired`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PurgeExpired {
+    pub bsos: i64,
+    pub batches: i64,
+}
+
+pub type PostService = i32;
+pub type PostNode = i64;
+pub type PutUser = ();
+pub type ReplaceUsers = ();
+
+/// The Tokenserver node a user was allocated to.
+#[derive(Debug, Default, QueryableByName)]
+pub struct AllocateNode {
+    #[sql_type = "BigInt"]
+    pub id: i64,
+    #[sql_type = "Text"]
+    pub node: String,
+}
+
+/// A Tokenserver user record.
+///
+/// A user accumulates a new record each time their client state changes or
+/// they're moved to a new node: only the newest is current, the rest are
+/// marked with `replaced_at`.
+#[derive(Clone, Debug, Default, QueryableByName)]
+pub struct TokenserverUser {
+    #[sql_type = "BigInt"]
+    pub uid: i64,
+    #[sql_type = "BigInt"]
+    pub node_id: i64,
+    /// The assigned node (None when the node's gone or is down)
+    #[sql_type = "Nullable<Text>"]
+    pub node: Option<String>,
+    #[sql_type = "BigInt"]
+    pub generation: i64,
+    #[sql_type = "Nullable<BigInt>"]
+    pub keys_changed_at: Option<i64>,
+    #[sql_type = "Text"]
+    pub client_state: String,
+    #[sql_type = "BigInt"]
+    pub created_at: i64,
+    #[sql_type = "Nullable<BigInt>"]
+    pub replaced_at: Option<i64>,
+}
+
+/// A user's records, newest first.
+pub type GetUsers = Vec<TokenserverUser>;
+pub type PostUser = i64;
+
+/// A superseded Tokenserver user record, along with the identity its
+/// storage was keyed by.
+#[derive(Clone, Debug, Default, QueryableByName)]
+pub struct ReplacedUser {
+    #[sql_type = "BigInt"]
+    pub uid: i64,
+    #[sql_type = "Text"]
+    pub email: String,
+    #[sql_type = "BigInt"]
+    pub generation: i64,
+    #[sql_type = "Nullable<BigInt>"]
+    pub keys_changed_at: Option<i64>,
+    #[sql_type = "Text"]
+    pub client_state: String,
+    #[sql_type = "BigInt"]
+    pub replaced_at: i64,
+}
+
+/// Replaced records, oldest first.
+pub type GetReplacedUsers = Vec<ReplacedUser>;
+pub type DeleteUser = ();
+
 #[cfg(test)]
 pub type CreateCollection = i32;
 
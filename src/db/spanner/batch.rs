@@ -16,10 +16,21 @@ use super::{
     support::{as_list_value, as_value},
 };
 use crate::{
-    db::{params, results, util::to_rfc3339, DbError, DbErrorKind, BATCH_LIFETIME},
+    db::{
+        params, results,
+        util::{to_rfc3339, SyncTimestamp},
+        DbError, DbErrorKind, BATCH_LIFETIME,
+    },
     web::extractors::HawkIdentifier,
 };
 
+/// The mutations of staging a new BSO: its 8 batch_bsos columns
+const STAGED_BSO_MUTATIONS: usize = 8;
+
+/// The mutations of committing a BSO, at the least: its sortindex, payload,
+/// modified and expiry columns and its BsoModified and BsoExpiry entries
+const COMMITTED_BSO_MUTATIONS: usize = 6;
+
 pub async fn create_async(
     db: &SpannerDb,
     params: params::CreateBatch,
@@ -27,6 +38,7 @@ pub async fn create_async(
     let batch_id = Uuid::new_v4().to_simple().to_string();
     let collection_id = db.get_collection_id_async(&params.collection).await?;
     let timestamp = db.timestamp()?.as_i64();
+    results::BatchTotals::staging(&params.bsos).check(&db.limits, &params.collection)?;
 
     // Ensure a parent record exists in user_collections before writing to batches
     // (INTERLEAVE IN PARENT user_collections)
@@ -73,7 +85,10 @@ pub async fn validate_async(db: &SpannerDb, params: params::ValidateBatch) -> Re
 }
 
 // Append a collection to a pending batch (`create_batch` creates a new batch)
-pub async fn append_async(db: &SpannerDb, params: params::AppendToBatch) -> Result<()> {
+pub async fn append_async(
+    db: &SpannerDb,
+    params: params::AppendToBatch,
+) -> Result<results::BatchTotals> {
     let mut metrics = db.metrics.clone();
     metrics.start_timer("storage.spanner.append_items_to_batch", None);
     let collection_id = db.get_collection_id_async(&params.collection).await?;
@@ -103,16 +118,56 @@ pub async fn append_async(db: &SpannerDb, params: params::AppendToBatch) -> Resu
     }
 
     let collection_id = db.get_collection_id_async(&params.collection).await?;
+    let batch_id = batch.id.clone();
     do_append_async(
         db,
-        params.user_id,
+        params.user_id.clone(),
         collection_id,
         batch,
         params.bsos,
         &params.collection,
     )
     .await?;
-    Ok(())
+    totals_async(db, &params.user_id, collection_id, &batch_id)
+        .await?
+        .check(&db.limits, &params.collection)
+}
+
+/// The running totals of the BSOs staged in a batch
+async fn totals_async(
+    db: &SpannerDb,
+    user_id: &HawkIdentifier,
+    collection_id: i32,
+    batch_id: &str,
+) -> Result<results::BatchTotals> {
+    let row = db
+        .sql(
+            "SELECT COUNT(*), COALESCE(SUM(BYTE_LENGTH(payload)), 0)
+               FROM batch_bsos
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id",
+        )?
+        .params(params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+            "batch_id" => batch_id.to_owned(),
+        })
+        .execute_async(&db.conn)?
+        .one()
+        .await?;
+    let total = |i: usize| {
+        row[i]
+            .get_string_value()
+            .parse::<u64>()
+            .map_err(|e| DbErrorKind::Integrity(e.to_string()))
+    };
+    Ok(results::BatchTotals {
+        records: total(0)?,
+        bytes: total(1)?,
+    })
 }
 
 pub async fn get_async(
@@ -143,6 +198,60 @@ pub async fn get_async(
     Ok(batch)
 }
 
+pub async fn get_items_async(
+    db: &SpannerDb,
+    params: params::GetBatchItems,
+) -> Result<Option<results::GetBatchItems>> {
+    let collection_id = db.get_collection_id_async(&params.collection).await?;
+    let sqlparams = params! {
+        "fxa_uid" => params.user_id.fxa_uid.clone(),
+        "fxa_kid" => params.user_id.fxa_kid.clone(),
+        "collection_id" => collection_id.to_string(),
+        "batch_id" => params.id.clone(),
+    };
+    let expiry = match db
+        .sql(
+            "SELECT expiry
+               FROM batches
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id
+                AND expiry > CURRENT_TIMESTAMP()",
+        )?
+        .params(sqlparams.clone())
+        .execute_async(&db.conn)?
+        .one_or_none()
+        .await?
+    {
+        Some(row) => SyncTimestamp::from_rfc3339(row[0].get_string_value())?,
+        None => return Ok(None),
+    };
+
+    let mut streaming = db
+        .sql(
+            "SELECT batch_bso_id
+               FROM batch_bsos
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid
+                AND collection_id = @collection_id
+                AND batch_id = @batch_id
+              ORDER BY batch_bso_id",
+        )?
+        .params(sqlparams)
+        .execute_async(&db.conn)?;
+    let mut ids = vec![];
+    while let Some(row) = streaming.next_async().await {
+        let mut row = row?;
+        ids.push(row[0].take_string_value());
+    }
+    Ok(Some(results::GetBatchItems {
+        expiry,
+        count: ids.len(),
+        ids,
+    }))
+}
+
 pub async fn delete_async(db: &SpannerDb, params: params::DeleteBatch) -> Result<()> {
     let collection_id = db.get_collection_id_async(&params.collection).await?;
     // Also deletes child batch_bsos rows (INTERLEAVE IN PARENT batches ON
@@ -172,6 +281,10 @@ pub async fn commit_async(
     let mut metrics = db.metrics.clone();
     metrics.start_timer("storage.spanner.apply_batch", None);
     let collection_id = db.get_collection_id_async(&params.collection).await?;
+    let totals = (totals_async(db, &params.user_id, collection_id, &params.batch.id).await?
+        + params.pending)
+        .check(&db.limits, &params.collection)?;
+    db.count_mutations(totals.records as usize * COMMITTED_BSO_MUTATIONS)?;
 
     // Ensure a parent record exists in user_collections before writing to bsos
     // (INTERLEAVE IN PARENT user_collections)
@@ -231,14 +344,12 @@ pub async fn commit_async(
         },
     )
     .await?;
-    // XXX: returning results::PostBsos here isn't needed
     // update the quotas for the user's collection
     db.update_user_collection_quotas(&params.user_id, collection_id)
         .await?;
-    Ok(results::PostBsos {
+    Ok(results::CommitBatch {
         modified: timestamp,
-        success: Default::default(),
-        failed: Default::default(),
+        totals,
     })
 }
 
@@ -364,8 +475,8 @@ pub async fn do_append_async(
     }
 
     if db.quota_enabled {
-        if let Some(size) = batch.size {
-            if size + running_size >= (db.quota as usize) {
+        if let (Some(size), Some(quota)) = (batch.size, db.limits.quota_for(collection)) {
+            if size + running_size >= quota as usize {
                 return Err(db.quota_error(collection));
             }
         }
@@ -396,6 +507,7 @@ pub async fn do_append_async(
     .collect();
 
     if !insert.is_empty() {
+        db.count_mutations(insert.len() * STAGED_BSO_MUTATIONS)?;
         let mut list_values = ListValue::new();
         list_values.set_values(RepeatedField::from_vec(insert));
         let mut values = Value::new();
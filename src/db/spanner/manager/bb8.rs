@@ -25,6 +25,8 @@ pub struct SpannerSessionManager<T> {
     env: Arc<Environment>,
     metrics: Metrics,
     test_transactions: bool,
+    /// Connect to this Cloud Spanner emulator instead of Spanner
+    emulator_host: Option<String>,
     phantom: PhantomData<T>,
 }
 
@@ -33,6 +35,7 @@ impl<_T> fmt::Debug for SpannerSessionManager<_T> {
         fmt.debug_struct("bb8::SpannerSessionManager")
             .field("database_name", &self.database_name)
             .field("test_transactions", &self.test_transactions)
+            .field("emulator_host", &self.emulator_host)
             .finish()
     }
 }
@@ -56,6 +59,7 @@ impl<T> SpannerSessionManager<T> {
             env,
             metrics: metrics.clone(),
             test_transactions,
+            emulator_host: settings.spanner_emulator_host.clone(),
             phantom: PhantomData,
         })
     }
@@ -72,6 +76,7 @@ impl<T: Send + Sync + 'static> ManageConnection for SpannerSessionManager<T> {
             self.metrics.clone(),
             &self.database_name,
             self.test_transactions,
+            self.emulator_host.clone(),
         )
         .await
     }
@@ -23,6 +23,8 @@ pub struct SpannerSessionManager {
     env: Arc<Environment>,
     metrics: Metrics,
     test_transactions: bool,
+    /// Connect to this Cloud Spanner emulator instead of Spanner
+    emulator_host: Option<String>,
 }
 
 impl fmt::Debug for SpannerSessionManager {
@@ -30,6 +32,7 @@ impl fmt::Debug for SpannerSessionManager {
         fmt.debug_struct("deadpool::SpannerSessionManager")
             .field("database_name", &self.database_name)
             .field("test_transactions", &self.test_transactions)
+            .field("emulator_host", &self.emulator_host)
             .finish()
     }
 }
@@ -52,6 +55,7 @@ impl SpannerSessionManager {
             env,
             metrics: metrics.clone(),
             test_transactions,
+            emulator_host: settings.spanner_emulator_host.clone(),
         })
     }
 }
@@ -64,6 +68,7 @@ impl Manager<SpannerSession, DbError> for SpannerSessionManager {
             self.metrics.clone(),
             &self.database_name,
             self.test_transactions,
+            self.emulator_host.clone(),
         )
         .await
     }
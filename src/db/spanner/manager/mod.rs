@@ -3,4 +3,4 @@ mod deadpool;
 mod session;
 
 pub use self::deadpool::{Conn, SpannerSessionManager};
-pub use self::session::SpannerSession;
+pub use self::session::{create_channel, SpannerSession};
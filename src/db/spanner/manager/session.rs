@@ -21,24 +21,39 @@ pub struct SpannerSession {
     pub(in crate::db::spanner) use_test_transactions: bool,
 }
 
+/// Create a gRPC Channel to Spanner (or the emulator at `emulator_host`)
+pub fn create_channel(
+    env: Arc<Environment>,
+    emulator_host: Option<&str>,
+) -> Result<grpcio::Channel, grpcio::Error> {
+    let builder = ChannelBuilder::new(env)
+        .max_send_message_len(100 << 20)
+        .max_receive_message_len(100 << 20);
+    if let Some(emulator_host) = emulator_host {
+        // The emulator neither needs nor supports TLS or credentials
+        return Ok(builder.connect(emulator_host));
+    }
+    // Requires
+    // GOOGLE_APPLICATION_CREDENTIALS=/path/to/service-account.json
+    let creds = ChannelCredentials::google_default_credentials()?;
+    Ok(builder.secure_connect(SPANNER_ADDRESS, creds))
+}
+
 /// Create a Session (and the underlying gRPC Channel)
 pub async fn create_spanner_session(
     env: Arc<Environment>,
     mut metrics: Metrics,
     database_name: &str,
     use_test_transactions: bool,
+    emulator_host: Option<String>,
 ) -> Result<SpannerSession, DbError> {
     // XXX: issue732: Could google_default_credentials (or
     // ChannelBuilder::secure_connect) block?!
     let chan = block(move || -> Result<grpcio::Channel, grpcio::Error> {
-        metrics.start_timer("storage.pool.grpc_auth", None);
-        // Requires
-        // GOOGLE_APPLICATION_CREDENTIALS=/path/to/service-account.json
-        let creds = ChannelCredentials::google_default_credentials()?;
-        Ok(ChannelBuilder::new(env)
-            .max_send_message_len(100 << 20)
-            .max_receive_message_len(100 << 20)
-            .secure_connect(SPANNER_ADDRESS, creds))
+        if emulator_host.is_none() {
+            metrics.start_timer("storage.pool.grpc_auth", None);
+        }
+        create_channel(env, emulator_host.as_deref())
     })
     .await
     .map_err(|e| match e {
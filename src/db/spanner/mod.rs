@@ -5,6 +5,8 @@ mod batch;
 pub mod manager;
 pub mod models;
 pub mod pool;
+pub mod schema;
 mod support;
+mod tokenserver;
 
 pub use self::pool::SpannerDbPool;
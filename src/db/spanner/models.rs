@@ -8,6 +8,7 @@ use std::{
 };
 
 use futures::future::TryFutureExt;
+use futures::stream::{self, StreamExt};
 use googleapis_raw::spanner::v1::{
     mutation::{Mutation, Mutation_Write},
     spanner::{BeginTransactionRequest, CommitRequest, ExecuteSqlRequest, RollbackRequest},
@@ -19,7 +20,7 @@ use googleapis_raw::spanner::v1::{
 };
 #[allow(unused_imports)]
 use protobuf::{
-    well_known_types::{ListValue, Value},
+    well_known_types::{Duration, ListValue, Value},
     Message, RepeatedField,
 };
 
@@ -27,12 +28,13 @@ use crate::{
     db::{
         error::{DbError, DbErrorKind},
         params, results,
-        util::SyncTimestamp,
+        util::{to_rfc3339, SyncTimestamp},
         Db, DbFuture, Sorting, FIRST_CUSTOM_COLLECTION_ID,
     },
-    server::metrics::Metrics,
+    server::{metrics::Metrics, tracing::in_span},
+    settings::ServerLimits,
     web::{
-        extractors::{BsoQueryParams, HawkIdentifier, Offset},
+        extractors::{BsoQueryParams, HawkIdentifier, OffsetKey},
         tags::Tags,
     },
 };
@@ -40,10 +42,12 @@ use crate::{
 use super::{
     batch,
     pool::{CollectionCache, Conn},
+    schema,
     support::{
-        as_list_value, as_type, as_value, bso_from_row, bso_to_insert_row, bso_to_update_row,
-        ExecuteSqlRequestBuilder, StreamedResultSetAsync,
+        as_list_value, as_type, as_value, bso_from_row, bso_to_import_row, bso_to_insert_row,
+        bso_to_update_row, ExecuteSqlRequestBuilder, StreamedResultSetAsync,
     },
+    tokenserver,
 };
 
 #[derive(Debug, Eq, PartialEq)]
@@ -64,6 +68,10 @@ pub const PRETOUCH_TS: &str = "0001-01-01T00:00:00.00Z";
 // max load size in bytes
 pub const MAX_SPANNER_LOAD_SIZE: usize = 100_000_000;
 
+/// Spanner's limit of mutations (each column written and index entry
+/// updated) per transaction
+pub const MAX_SPANNER_MUTATIONS: usize = 20_000;
+
 /// Per session Db metadata
 #[derive(Debug, Default)]
 struct SpannerDbSession {
@@ -80,8 +88,14 @@ struct SpannerDbSession {
     mutations: Option<Vec<Mutation>>,
     in_write_transaction: bool,
     execute_sql_count: u64,
-    /// Whether update_collection has already been called
-    updated_collection: bool,
+    /// Collections update_collection has already been called for
+    updated_collections: HashSet<i32>,
+    /// Whether read-only transactions may read a stale snapshot
+    stale_reads: bool,
+    /// The oldest timestamp a stale snapshot may be read at
+    min_read_timestamp: Option<SyncTimestamp>,
+    /// Mutations counted towards `MAX_SPANNER_MUTATIONS` by batch writes
+    mutation_count: usize,
 }
 
 #[derive(Clone, Debug)]
@@ -92,8 +106,11 @@ pub struct SpannerDb {
     coll_cache: Arc<CollectionCache>,
 
     pub metrics: Metrics,
-    pub quota: usize,
+    pub limits: Arc<ServerLimits>,
     pub quota_enabled: bool,
+    /// The exact staleness (in seconds) of the snapshot read-only
+    /// transactions read
+    pub exact_staleness: u32,
 }
 
 pub struct SpannerDbInner {
@@ -121,8 +138,9 @@ impl SpannerDb {
         conn: Conn,
         coll_cache: Arc<CollectionCache>,
         metrics: &Metrics,
-        quota: usize,
+        limits: &Arc<ServerLimits>,
         quota_enabled: bool,
+        exact_staleness: u32,
     ) -> Self {
         let inner = SpannerDbInner {
             conn,
@@ -132,8 +150,9 @@ impl SpannerDb {
             inner: Arc::new(inner),
             coll_cache,
             metrics: metrics.clone(),
-            quota,
+            limits: Arc::clone(limits),
             quota_enabled,
+            exact_staleness,
         }
     }
 
@@ -243,8 +262,24 @@ impl SpannerDb {
     }
 
     pub async fn lock_for_write_async(&self, params: params::LockCollection) -> Result<()> {
-        // Begin a transaction
-        self.begin_async(true).await?;
+        // Begin a transaction, unless this is a further collection locked
+        // for the same one (which then shares its timestamp)
+        let joining = {
+            let session = self.session.borrow();
+            session.transaction.is_some()
+                && session
+                    .coll_locks
+                    .values()
+                    .any(|lock| matches!(lock, CollectionLock::Write))
+        };
+        if !joining {
+            self.begin_async(true).await?;
+        }
+        let shared_timestamp = if joining {
+            self.session.borrow().timestamp
+        } else {
+            None
+        };
         let collection_id = self
             .get_or_create_collection_id_async(&params.collection)
             .await?;
@@ -282,7 +317,10 @@ impl SpannerDb {
 
         let timestamp = if let Some(result) = result {
             let modified = SyncTimestamp::from_rfc3339(result[1].get_string_value())?;
-            let now = SyncTimestamp::from_rfc3339(result[0].get_string_value())?;
+            let now = match shared_timestamp {
+                Some(timestamp) => timestamp,
+                None => SyncTimestamp::from_rfc3339(result[0].get_string_value())?,
+            };
             // Forbid the write if it would not properly incr the modified
             // timestamp
             if modified >= now {
@@ -293,6 +331,8 @@ impl SpannerDb {
                 .coll_modified_cache
                 .insert((params.user_id.clone(), collection_id), modified);
             now
+        } else if let Some(timestamp) = shared_timestamp {
+            timestamp
         } else {
             let result = self
                 .sql("SELECT CURRENT_TIMESTAMP()")?
@@ -336,6 +376,9 @@ impl SpannerDb {
     }
 
     pub(super) async fn begin_async(&self, for_write: bool) -> Result<()> {
+        if !for_write && self.begin_stale_read_async().await? {
+            return Ok(());
+        }
         let spanner = &self.conn;
         let mut options = TransactionOptions::new();
         if for_write {
@@ -355,6 +398,52 @@ impl SpannerDb {
         Ok(())
     }
 
+    /// Begin a read-only transaction reading a snapshot exactly
+    /// `exact_staleness` old, if allowed. (Spanner only bounds staleness by a
+    /// `max_staleness` for single-use transactions.)
+    ///
+    /// Returns false (in lieu of a strong read) when it's not: when this
+    /// session has written or the snapshot predates `min_read_timestamp`, as
+    /// it may lack writes the client has already seen.
+    async fn begin_stale_read_async(&self) -> Result<bool> {
+        let min_read_timestamp = {
+            let session = self.session.borrow();
+            if self.exact_staleness == 0 || !session.stale_reads || session.in_write_transaction {
+                return Ok(false);
+            }
+            session.min_read_timestamp
+        };
+        let spanner = &self.conn;
+        let mut staleness = Duration::new();
+        staleness.set_seconds(i64::from(self.exact_staleness));
+        let mut read_only = TransactionOptions_ReadOnly::new();
+        read_only.set_exact_staleness(staleness);
+        read_only.set_return_read_timestamp(true);
+        let mut options = TransactionOptions::new();
+        options.set_read_only(read_only);
+        let mut req = BeginTransactionRequest::new();
+        req.set_session(spanner.session.get_name().to_owned());
+        req.set_options(options);
+        let mut transaction = spanner.client.begin_transaction_async(&req)?.await?;
+
+        if let Some(min_read_timestamp) = min_read_timestamp {
+            let read_timestamp = transaction.get_read_timestamp();
+            let read_timestamp = SyncTimestamp::from_milliseconds(
+                read_timestamp.get_seconds() as u64 * 1000
+                    + u64::from(read_timestamp.get_nanos() as u32 / 1_000_000),
+            );
+            if read_timestamp < min_read_timestamp {
+                self.metrics.incr("storage.spanner.stale_read.fallback");
+                return Ok(false);
+            }
+        }
+
+        let mut ts = TransactionSelector::new();
+        ts.set_id(transaction.take_id());
+        self.session.borrow_mut().transaction = Some(ts);
+        Ok(true)
+    }
+
     /// Return the current transaction metadata (TransactionSelector) if one is active.
     fn get_transaction(&self) -> Result<Option<TransactionSelector>> {
         Ok(if self.session.borrow().transaction.is_some() {
@@ -414,7 +503,6 @@ impl SpannerDb {
             .push(mutation);
     }
 
-    #[allow(unused)]
     pub(super) fn insert_or_update(&self, table: &str, columns: &[&str], values: Vec<ListValue>) {
         let mut mutation = Mutation::new();
         mutation.set_insert_or_update(self.mutation_write(table, columns, values));
@@ -440,7 +528,7 @@ impl SpannerDb {
         write
     }
 
-    fn in_write_transaction(&self) -> bool {
+    pub(super) fn in_write_transaction(&self) -> bool {
         self.session.borrow().in_write_transaction
     }
 
@@ -471,6 +559,22 @@ impl SpannerDb {
         }
     }
 
+    /// Count a write's mutations towards the transaction's limit, failing
+    /// (with a 400, before Spanner would on commit) when it'd be exceeded
+    pub(super) fn count_mutations(&self, mutations: usize) -> Result<()> {
+        let mut session = self.session.borrow_mut();
+        session.mutation_count += mutations;
+        if session.mutation_count > MAX_SPANNER_MUTATIONS {
+            self.metrics.clone().incr("error.tooManyMutations");
+            return Err(DbErrorKind::SpannerTooLarge(format!(
+                "Too many mutations: {}",
+                session.mutation_count
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
     pub async fn commit_async(&self) -> Result<()> {
         if !self.in_write_transaction() {
             // read-only
@@ -491,7 +595,10 @@ impl SpannerDb {
             if let Some(mutations) = self.session.borrow_mut().mutations.take() {
                 req.set_mutations(RepeatedField::from_vec(mutations));
             }
-            spanner.client.commit_async(&req)?.await?;
+            in_span("spanner.commit", vec![], async {
+                Ok::<_, DbError>(spanner.client.commit_async(&req)?.await?)
+            })
+            .await?;
             Ok(())
         } else {
             Err(DbError::internal("No transaction to commit"))?
@@ -1007,6 +1114,211 @@ impl SpannerDb {
         Ok(())
     }
 
+    pub async fn purge_storage_async(
+        &self,
+        params: params::PurgeStorage,
+    ) -> Result<results::PurgeStorage> {
+        let sqlparams = params! {
+            "fxa_uid" => params.user_id.fxa_uid,
+            "fxa_kid" => params.user_id.fxa_kid,
+        };
+        let row = self
+            .sql(
+                "SELECT (SELECT COUNT(*)
+                           FROM bsos
+                          WHERE fxa_uid = @fxa_uid
+                            AND fxa_kid = @fxa_kid),
+                        (SELECT COUNT(*)
+                           FROM user_collections
+                          WHERE fxa_uid = @fxa_uid
+                            AND fxa_kid = @fxa_kid),
+                        (SELECT COUNT(*)
+                           FROM batches
+                          WHERE fxa_uid = @fxa_uid
+                            AND fxa_kid = @fxa_kid)",
+            )?
+            .params(sqlparams.clone())
+            .execute_async(&self.conn)?
+            .one()
+            .await?;
+        let count = |i: usize| {
+            row[i]
+                .get_string_value()
+                .parse::<i64>()
+                .map_err(|e| DbErrorKind::Integrity(e.to_string()))
+        };
+        let purged = results::PurgeStorage {
+            bsos: count(0)?,
+            user_collections: count(1)?,
+            batches: count(2)?,
+        };
+
+        if !params.dry_run {
+            // Also deletes child bsos/batch rows (INTERLEAVE IN PARENT
+            // user_collections ON DELETE CASCADE)
+            self.sql(
+                "DELETE FROM user_collections
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid",
+            )?
+            .params(sqlparams)
+            .execute_dml_async(&self.conn)
+            .await?;
+        }
+        Ok(purged)
+    }
+
+    pub async fn expire_batches_async(
+        &self,
+        user_id: params::ExpireBatches,
+    ) -> Result<results::ExpireBatches> {
+        // Also deletes the child batch_bsos rows (INTERLEAVE IN PARENT
+        // batches ON DELETE CASCADE)
+        self.sql(
+            "DELETE FROM batches
+              WHERE fxa_uid = @fxa_uid
+                AND fxa_kid = @fxa_kid",
+        )?
+        .params(params! {
+            "fxa_uid" => user_id.fxa_uid,
+            "fxa_kid" => user_id.fxa_kid,
+        })
+        .execute_dml_async(&self.conn)
+        .await
+    }
+
+    pub async fn purge_expired_async(
+        &self,
+        params: params::PurgeExpired,
+    ) -> Result<results::PurgeExpired> {
+        let (start, end) = params.fxa_uid_range();
+        let mut range_params = params! { "start" => start };
+        let range = match end {
+            Some(end) => {
+                range_params.insert("end".to_owned(), as_value(end));
+                "fxa_uid >= @start AND fxa_uid < @end"
+            }
+            None => "fxa_uid >= @start",
+        };
+        let mut purged = results::PurgeExpired::default();
+
+        let expired = self
+            .expired_keys(
+                &format!(
+                    "SELECT fxa_uid, fxa_kid, collection_id, bso_id
+                       FROM bsos
+                      WHERE {}
+                        AND expiry <= CURRENT_TIMESTAMP()
+                      LIMIT {}",
+                    range, params.limit
+                ),
+                range_params.clone(),
+            )
+            .await?;
+        for ((fxa_uid, fxa_kid, collection_id), ids) in expired {
+            let sqlparams = params! {
+                "fxa_uid" => fxa_uid,
+                "fxa_kid" => fxa_kid,
+                "collection_id" => collection_id,
+            };
+            let mut delete_params = sqlparams.clone();
+            delete_params.insert("ids".to_owned(), as_list_value(ids.into_iter()));
+            purged.bsos += self
+                .sql(
+                    "DELETE FROM bsos
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid
+                        AND collection_id = @collection_id
+                        AND bso_id IN UNNEST(@ids)",
+                )?
+                .params(delete_params)
+                .execute_dml_async(&self.conn)
+                .await?;
+            // Recount the collection's usage as `update_user_collection_quotas`
+            // would, but leave its modified timestamp: expiring isn't a write
+            self.sql(
+                "UPDATE user_collections
+                    SET count = (SELECT COUNT(*)
+                                   FROM bsos
+                                  WHERE fxa_uid = @fxa_uid
+                                    AND fxa_kid = @fxa_kid
+                                    AND collection_id = @collection_id),
+                        total_bytes = (SELECT COALESCE(SUM(BYTE_LENGTH(payload)), 0)
+                                         FROM bsos
+                                        WHERE fxa_uid = @fxa_uid
+                                          AND fxa_kid = @fxa_kid
+                                          AND collection_id = @collection_id)
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id",
+            )?
+            .params(sqlparams)
+            .execute_dml_async(&self.conn)
+            .await?;
+        }
+
+        let expired = self
+            .expired_keys(
+                &format!(
+                    "SELECT fxa_uid, fxa_kid, collection_id, batch_id
+                       FROM batches
+                      WHERE {}
+                        AND expiry <= CURRENT_TIMESTAMP()
+                      LIMIT {}",
+                    range, params.limit
+                ),
+                range_params,
+            )
+            .await?;
+        for ((fxa_uid, fxa_kid, collection_id), ids) in expired {
+            let mut sqlparams = params! {
+                "fxa_uid" => fxa_uid,
+                "fxa_kid" => fxa_kid,
+                "collection_id" => collection_id,
+            };
+            sqlparams.insert("ids".to_owned(), as_list_value(ids.into_iter()));
+            // Also deletes the child batch_bsos rows (INTERLEAVE IN PARENT
+            // batches ON DELETE CASCADE)
+            purged.batches += self
+                .sql(
+                    "DELETE FROM batches
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid
+                        AND collection_id = @collection_id
+                        AND batch_id IN UNNEST(@ids)",
+                )?
+                .params(sqlparams)
+                .execute_dml_async(&self.conn)
+                .await?;
+        }
+        Ok(purged)
+    }
+
+    /// Read the keys (`fxa_uid, fxa_kid, collection_id, <id>`) selected by
+    /// the query, grouped by collection
+    async fn expired_keys(
+        &self,
+        query: &str,
+        sqlparams: HashMap<String, Value>,
+    ) -> Result<HashMap<(String, String, String), Vec<String>>> {
+        let mut streaming = self
+            .sql(query)?
+            .params(sqlparams)
+            .execute_async(&self.conn)?;
+        let mut keys: HashMap<_, Vec<_>> = HashMap::new();
+        while let Some(row) = streaming.next_async().await {
+            let mut row = row?;
+            keys.entry((
+                row[0].take_string_value(),
+                row[1].take_string_value(),
+                row[2].take_string_value(),
+            ))
+            .or_default()
+            .push(row[3].take_string_value());
+        }
+        Ok(keys)
+    }
+
     pub fn timestamp(&self) -> Result<SyncTimestamp> {
         self.session
             .borrow()
@@ -1071,7 +1383,13 @@ impl SpannerDb {
         // buffered on the client side and only issued to Spanner in the final
         // transaction Commit.
         let timestamp = self.timestamp()?;
-        if !cfg!(test) && self.session.borrow().updated_collection {
+        if !cfg!(test)
+            && self
+                .session
+                .borrow()
+                .updated_collections
+                .contains(&collection_id)
+        {
             // No need to touch it again (except during tests where we
             // currently reuse Dbs for multiple requests)
             return Ok(timestamp);
@@ -1132,7 +1450,10 @@ impl SpannerDb {
                 .execute_dml_async(&self.conn)
                 .await?;
         }
-        self.session.borrow_mut().updated_collection = true;
+        self.session
+            .borrow_mut()
+            .updated_collections
+            .insert(collection_id);
         Ok(timestamp)
     }
 
@@ -1225,24 +1546,27 @@ impl SpannerDb {
             sqlparams.insert("ids".to_owned(), as_list_value(ids.into_iter()));
         }
 
-        // issue559: Dead code (timestamp always None)
-        /*
-        if let Some(timestamp) = offset.clone().unwrap_or_default().timestamp {
-            query = match sort {
-                Sorting::Newest => {
-                    sqlparams.insert("older_eq".to_string(), as_value(timestamp.as_rfc3339()?));
-                    sqltypes.insert("older_eq".to_string(), as_type(TypeCode::TIMESTAMP));
-                    format!("{} AND modified <= @older_eq", query)
-                }
-                Sorting::Oldest => {
-                    sqlparams.insert("newer_eq".to_string(), as_value(timestamp.as_rfc3339()?));
-                    sqltypes.insert("newer_eq".to_string(), as_type(TypeCode::TIMESTAMP));
-                    format!("{} AND modified >= @newer_eq", query)
+        let numeric_offset = match offset.as_ref().map(|offset| offset.key.as_ref()) {
+            Some(Some(key)) => {
+                query = format!("{} AND {}", query, key_filter(key)?);
+                sqlparams.insert("offset_id".to_owned(), as_value(key.id.clone()));
+                match (key.sort, key.value) {
+                    (Sorting::Newest, Some(value)) | (Sorting::Oldest, Some(value)) => {
+                        sqlparams.insert("offset_value".to_owned(), as_value(to_rfc3339(value)?));
+                        sqltypes.insert("offset_value".to_owned(), as_type(TypeCode::TIMESTAMP));
+                    }
+                    (Sorting::Index, Some(value)) => {
+                        sqlparams.insert("offset_value".to_owned(), as_value(value.to_string()));
+                        sqltypes.insert("offset_value".to_owned(), as_type(TypeCode::INT64));
+                    }
+                    _ => (),
                 }
-                _ => query,
-            };
-        }
-        */
+                // Resuming after the key rather than skipping rows
+                0
+            }
+            Some(None) => offset.as_ref().map_or(0, |offset| offset.offset),
+            None => 0,
+        };
         if let Some(older) = older {
             query = format!("{} AND modified < @older", query);
             sqlparams.insert("older".to_string(), as_value(older.as_rfc3339()?));
@@ -1253,26 +1577,20 @@ impl SpannerDb {
             sqlparams.insert("newer".to_string(), as_value(newer.as_rfc3339()?));
             sqltypes.insert("newer".to_string(), as_type(TypeCode::TIMESTAMP));
         }
+        // Ties are broken by the id, so pages neither overlap nor skip any
         query = match sort {
-            // issue559: Revert to previous sorting
-            /*
             Sorting::Index => format!("{} ORDER BY sortindex DESC, bso_id DESC", query),
-            Sorting::Newest | Sorting::None => {
-                format!("{} ORDER BY modified DESC, bso_id DESC", query)
-            }
+            Sorting::Newest => format!("{} ORDER BY modified DESC, bso_id DESC", query),
             Sorting::Oldest => format!("{} ORDER BY modified ASC, bso_id ASC", query),
-            */
-            Sorting::Index => format!("{} ORDER BY sortindex DESC", query),
-            Sorting::Newest => format!("{} ORDER BY modified DESC", query),
-            Sorting::Oldest => format!("{} ORDER BY modified ASC", query),
-            _ => query,
+            Sorting::None if limit.is_some() => format!("{} ORDER BY bso_id", query),
+            Sorting::None => query,
         };
 
         if let Some(limit) = limit {
             // fetch an extra row to detect if there are more rows that match
             // the query conditions
             query = format!("{} LIMIT {}", query, i64::from(limit) + 1);
-        } else if let Some(ref offset) = offset {
+        } else if numeric_offset > 0 {
             // Special case no limit specified but still required for an
             // offset. Spanner doesn't accept a simpler limit of -1 (common in
             // most databases) so we specify a max value with offset subtracted
@@ -1281,12 +1599,12 @@ impl SpannerDb {
             query = format!(
                 "{} LIMIT {}",
                 query,
-                i64::max_value() - offset.offset as i64
+                i64::max_value() - numeric_offset as i64
             );
         };
 
-        if let Some(offset) = offset {
-            query = format!("{} OFFSET {}", query, offset.offset);
+        if numeric_offset > 0 {
+            query = format!("{} OFFSET {}", query, numeric_offset);
         }
         self.sql(&query)?
             .params(sqlparams)
@@ -1294,59 +1612,6 @@ impl SpannerDb {
             .execute_async(&self.conn)
     }
 
-    pub fn encode_next_offset(
-        &self,
-        _sort: Sorting,
-        offset: u64,
-        _timestamp: Option<i64>,
-        modifieds: Vec<i64>,
-    ) -> Option<String> {
-        // issue559: Use a simple numeric offset everwhere as previously for
-        // now: was previously a value of "limit + offset", modifieds.len()
-        // always equals limit
-        Some(
-            Offset {
-                offset: offset + modifieds.len() as u64,
-                timestamp: None,
-            }
-            .to_string(),
-        )
-        /*
-        let mut calc_offset = 1;
-        let mut i = (modifieds.len() as i64) - 2;
-
-        let prev_bound = match sort {
-            Sorting::Index => {
-                // Use a simple numeric offset for sortindex ordering.
-                return Some(
-                    Offset {
-                        offset: offset + modifieds.len() as u64,
-                        timestamp: None,
-                    }
-                    .to_string(),
-                );
-            }
-            Sorting::None => timestamp,
-            Sorting::Newest => timestamp,
-            Sorting::Oldest => timestamp,
-        };
-        // Find an appropriate upper bound for faster timestamp ordering.
-        let bound = *modifieds.last().unwrap_or(&0);
-        // Count how many previous items have that same timestamp, and hence
-        // will need to be skipped over.  The number of matches here is limited
-        // by upload batch size.
-        while i >= 0 && modifieds[i as usize] == bound {
-            calc_offset += 1;
-            i -= 1;
-        }
-        if i < 0 && prev_bound.is_some() && prev_bound.unwrap() == bound {
-            calc_offset += offset;
-        }
-
-        Some(format!("{}:{}", bound, calc_offset))
-        */
-    }
-
     pub async fn get_bsos_async(&self, params: params::GetBsos) -> Result<results::GetBsos> {
         let query = "\
             SELECT bso_id, sortindex, payload, modified, expiry
@@ -1356,7 +1621,7 @@ impl SpannerDb {
                AND collection_id = @collection_id
                AND expiry > CURRENT_TIMESTAMP()";
         let limit = params.params.limit.map(i64::from).unwrap_or(-1);
-        let Offset { offset, timestamp } = params.params.offset.clone().unwrap_or_default();
+        let offset = params.params.offset.clone().unwrap_or_default();
         let sort = params.params.sort;
 
         let mut streaming = self.bsos_query_async(query, params).await?;
@@ -1375,8 +1640,10 @@ impl SpannerDb {
 
         let next_offset = if limit >= 0 && bsos.len() > limit as usize {
             bsos.pop();
-            let modifieds: Vec<i64> = bsos.iter().map(|r| r.modified.as_i64()).collect();
-            self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+            let last = bsos
+                .last()
+                .map(|bso| (bso.id.as_str(), bso.modified, bso.sortindex));
+            Some(offset.next(bsos.len(), sort, last))
         } else {
             None
         };
@@ -1387,13 +1654,39 @@ impl SpannerDb {
         })
     }
 
+    /// Like `get_bsos_async`, but yielding each BSO as it's streamed from
+    /// `ExecuteStreamingSql`
+    pub async fn get_bsos_stream_async(
+        &self,
+        params: params::GetBsos,
+    ) -> Result<results::GetBsosStream<'static>> {
+        let query = "\
+            SELECT bso_id, sortindex, payload, modified, expiry
+              FROM bsos
+             WHERE fxa_uid = @fxa_uid
+               AND fxa_kid = @fxa_kid
+               AND collection_id = @collection_id
+               AND expiry > CURRENT_TIMESTAMP()";
+        // Omitting the extra row read to detect a following page
+        let limit = params
+            .params
+            .limit
+            .map_or(usize::max_value(), |limit| limit as usize);
+        let rows = self.bsos_query_async(query, params).await?;
+        let items = stream::unfold(rows, |mut rows| async move {
+            let row = rows.next_async().await?;
+            Some((row.and_then(bso_from_row).map_err(Into::into), rows))
+        });
+        Ok(items.take(limit).boxed_local())
+    }
+
     pub async fn get_bso_ids_async(&self, params: params::GetBsos) -> Result<results::GetBsoIds> {
         let limit = params.params.limit.map(i64::from).unwrap_or(-1);
-        let Offset { offset, timestamp } = params.params.offset.clone().unwrap_or_default();
+        let offset = params.params.offset.clone().unwrap_or_default();
         let sort = params.params.sort;
 
         let query = "\
-            SELECT bso_id, modified
+            SELECT bso_id, modified, sortindex
               FROM bsos
              WHERE fxa_uid = @fxa_uid
                AND fxa_kid = @fxa_kid
@@ -1402,11 +1695,26 @@ impl SpannerDb {
         let mut stream = self.bsos_query_async(query, params).await?;
 
         let mut ids = vec![];
-        let mut modifieds = vec![];
+        let mut last = None;
         while let Some(row) = stream.next_async().await {
             let mut row = row?;
-            ids.push(row[0].take_string_value());
-            modifieds.push(SyncTimestamp::from_rfc3339(row[1].get_string_value())?.as_i64());
+            let id = row[0].take_string_value();
+            let modified = SyncTimestamp::from_rfc3339(row[1].get_string_value())?;
+            let sortindex = if row[2].has_null_value() {
+                None
+            } else {
+                Some(
+                    row[2]
+                        .get_string_value()
+                        .parse::<i32>()
+                        .map_err(|e| DbErrorKind::Integrity(e.to_string()))?,
+                )
+            };
+            // The sort key of the page's last id, excluding the extra row
+            if limit < 0 || ids.len() < limit as usize {
+                last = Some((modified, sortindex));
+            }
+            ids.push(id);
         }
         // NOTE: when bsos.len() == 0, server-syncstorage (the Python impl)
         // makes an additional call to get_collection_timestamp to potentially
@@ -1417,8 +1725,11 @@ impl SpannerDb {
 
         let next_offset = if limit >= 0 && ids.len() > limit as usize {
             ids.pop();
-            modifieds.pop();
-            self.encode_next_offset(sort, offset, timestamp.map(|t| t.as_i64()), modifieds)
+            let last = ids
+                .last()
+                .zip(last)
+                .map(|(id, (modified, sortindex))| (id.as_str(), modified, sortindex));
+            Some(offset.next(ids.len(), sort, last))
         } else {
             None
         };
@@ -1604,6 +1915,136 @@ impl SpannerDb {
         Ok(result)
     }
 
+    pub async fn import_bsos_async(
+        &self,
+        params: params::ImportBsos,
+    ) -> Result<results::ImportBsos> {
+        let user_id = params.user_id;
+        let collection_id = self
+            .get_or_create_collection_id_async(&params.collection)
+            .await?;
+
+        let mut sqlparams = params! {
+            "fxa_uid" => user_id.fxa_uid.clone(),
+            "fxa_kid" => user_id.fxa_kid.clone(),
+            "collection_id" => collection_id.to_string(),
+            "modified" => params.modified.as_rfc3339()?,
+        };
+        let mut sqltypes = param_types! {
+            "modified" => TypeCode::TIMESTAMP,
+        };
+        if self.quota_enabled {
+            // The bso mutations aren't visible until commit: total the
+            // already committed rows that this chunk doesn't replace
+            let mut query_params = sqlparams.clone();
+            query_params.insert(
+                "ids".to_owned(),
+                as_list_value(params.bsos.iter().map(|bso| bso.id.clone())),
+            );
+            let row = self
+                .sql(
+                    "SELECT COALESCE(SUM(BYTE_LENGTH(payload)), 0), COUNT(*)
+                       FROM bsos
+                      WHERE fxa_uid = @fxa_uid
+                        AND fxa_kid = @fxa_kid
+                        AND collection_id = @collection_id
+                        AND bso_id NOT IN UNNEST(@ids)",
+                )?
+                .params(query_params)
+                .execute_async(&self.conn)?
+                .one()
+                .await?;
+            let parse = |value: &Value| {
+                value
+                    .get_string_value()
+                    .parse::<i64>()
+                    .map_err(|e| DbErrorKind::Integrity(e.to_string()))
+            };
+            let total_bytes = parse(&row[0])?
+                + params
+                    .bsos
+                    .iter()
+                    .map(|bso| bso.payload.len() as i64)
+                    .sum::<i64>();
+            let count = parse(&row[1])? + params.bsos.len() as i64;
+            sqlparams.insert("total_bytes".to_owned(), as_value(total_bytes.to_string()));
+            sqlparams.insert("count".to_owned(), as_value(count.to_string()));
+            sqltypes.insert("total_bytes".to_owned(), as_type(TypeCode::INT64));
+            sqltypes.insert("count".to_owned(), as_type(TypeCode::INT64));
+        }
+
+        // Ensure the parent user_collections row exists (with the imported
+        // modified) before the bsos are written
+        let exists = self
+            .sql(
+                "SELECT 1
+                   FROM user_collections
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id",
+            )?
+            .params(sqlparams.clone())
+            .param_types(sqltypes.clone())
+            .execute_async(&self.conn)?
+            .one_or_none()
+            .await?
+            .is_some();
+        let set_sql = match (exists, self.quota_enabled) {
+            (true, true) => {
+                "UPDATE user_collections
+                    SET modified = @modified,
+                        count = @count,
+                        total_bytes = @total_bytes
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id"
+            }
+            (true, false) => {
+                "UPDATE user_collections
+                    SET modified = @modified
+                  WHERE fxa_uid = @fxa_uid
+                    AND fxa_kid = @fxa_kid
+                    AND collection_id = @collection_id"
+            }
+            (false, true) => {
+                "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified, count, total_bytes)
+                VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified, @count, @total_bytes)"
+            }
+            (false, false) => {
+                "INSERT INTO user_collections (fxa_uid, fxa_kid, collection_id, modified)
+                VALUES (@fxa_uid, @fxa_kid, @collection_id, @modified)"
+            }
+        };
+        self.sql(set_sql)?
+            .params(sqlparams)
+            .param_types(sqltypes)
+            .execute_dml_async(&self.conn)
+            .await?;
+
+        let rows = params
+            .bsos
+            .into_iter()
+            .map(|bso| bso_to_import_row(&user_id, collection_id, bso))
+            .collect::<Result<Vec<_>>>()?;
+        if !rows.is_empty() {
+            self.insert_or_update(
+                "bsos",
+                &[
+                    "fxa_uid",
+                    "fxa_kid",
+                    "collection_id",
+                    "bso_id",
+                    "sortindex",
+                    "payload",
+                    "modified",
+                    "expiry",
+                ],
+                rows,
+            );
+        }
+        Ok(())
+    }
+
     async fn check_async(&self) -> Result<results::Check> {
         // TODO: is there a better check than just fetching UTC?
         self.sql("SELECT CURRENT_TIMESTAMP()")?
@@ -1613,6 +2054,13 @@ impl SpannerDb {
         Ok(true)
     }
 
+    async fn schema_version_async(&self) -> Result<results::SchemaVersion> {
+        Ok(results::SchemaVersion {
+            current: schema::current_version(self).await?,
+            latest: schema::latest_version(),
+        })
+    }
+
     pub fn quota_error(&self, collection: &str) -> DbError {
         // return the over quota error.
         let mut tags = Tags::default();
@@ -1633,6 +2081,10 @@ impl SpannerDb {
         if !self.quota_enabled {
             return Ok(None);
         }
+        let quota = match self.limits.quota_for(collection) {
+            Some(quota) => quota as usize,
+            None => return Ok(None),
+        };
         let usage = self
             .get_quota_usage_async(params::GetQuotaUsage {
                 user_id: user_id.clone(),
@@ -1640,7 +2092,7 @@ impl SpannerDb {
                 collection_id,
             })
             .await?;
-        if usage.total_bytes >= self.quota {
+        if usage.total_bytes >= quota {
             return Err(self.quota_error(collection));
         }
         Ok(Some(usage.total_bytes as usize))
@@ -1847,6 +2299,27 @@ impl SpannerDb {
     }
 }
 
+/// The filter for the BSOs sorted after the one of `key` (binding
+/// `@offset_value` and `@offset_id`)
+fn key_filter(key: &OffsetKey) -> Result<&'static str> {
+    Ok(match (key.sort, key.value) {
+        (Sorting::None, _) => "bso_id > @offset_id",
+        (Sorting::Newest, Some(_)) => {
+            "(modified < @offset_value OR (modified = @offset_value AND bso_id < @offset_id))"
+        }
+        (Sorting::Oldest, Some(_)) => {
+            "(modified > @offset_value OR (modified = @offset_value AND bso_id > @offset_id))"
+        }
+        // NULL sortindexes sort last (descending)
+        (Sorting::Index, Some(_)) => {
+            "(sortindex < @offset_value OR sortindex IS NULL \
+              OR (sortindex = @offset_value AND bso_id < @offset_id))"
+        }
+        (Sorting::Index, None) => "(sortindex IS NULL AND bso_id < @offset_id)",
+        _ => return Err(DbErrorKind::Integrity("Invalid offset".to_owned()).into()),
+    })
+}
+
 impl<'a> Db<'a> for SpannerDb {
     fn commit(&self) -> DbFuture<'_, ()> {
         let db = self.clone();
@@ -1905,11 +2378,22 @@ impl<'a> Db<'a> for SpannerDb {
         Box::new(self.clone())
     }
 
+    fn allow_stale_reads(&self, min_timestamp: Option<SyncTimestamp>) {
+        let mut session = self.session.borrow_mut();
+        session.stale_reads = true;
+        session.min_read_timestamp = min_timestamp;
+    }
+
     fn check(&self) -> DbFuture<'_, results::Check> {
         let db = self.clone();
         Box::pin(async move { db.check_async().map_err(Into::into).await })
     }
 
+    fn schema_version(&self) -> DbFuture<'_, results::SchemaVersion> {
+        let db = self.clone();
+        Box::pin(async move { db.schema_version_async().map_err(Into::into).await })
+    }
+
     fn get_collection_timestamps(
         &self,
         user_id: params::GetCollectionTimestamps,
@@ -1982,6 +2466,11 @@ impl<'a> Db<'a> for SpannerDb {
         Box::pin(async move { db.get_bsos_async(param).map_err(Into::into).await })
     }
 
+    fn get_bsos_stream(&self, param: params::GetBsos) -> DbFuture<'_, results::GetBsosStream<'_>> {
+        let db = self.clone();
+        Box::pin(async move { db.get_bsos_stream_async(param).map_err(Into::into).await })
+    }
+
     fn get_bso_ids(&self, param: params::GetBsoIds) -> DbFuture<'_, results::GetBsoIds> {
         let db = self.clone();
         Box::pin(async move { db.get_bso_ids_async(param).map_err(Into::into).await })
@@ -2024,6 +2513,11 @@ impl<'a> Db<'a> for SpannerDb {
         Box::pin(async move { db.post_bsos_async_test(param).map_err(Into::into).await })
     }
 
+    fn import_bsos(&self, param: params::ImportBsos) -> DbFuture<'_, results::ImportBsos> {
+        let db = self.clone();
+        Box::pin(async move { db.import_bsos_async(param).map_err(Into::into).await })
+    }
+
     fn create_batch(&self, param: params::CreateBatch) -> DbFuture<'_, results::CreateBatch> {
         let db = self.clone();
         Box::pin(async move { batch::create_async(&db, param).map_err(Into::into).await })
@@ -2047,11 +2541,123 @@ impl<'a> Db<'a> for SpannerDb {
         Box::pin(async move { batch::get_async(&db, param).map_err(Into::into).await })
     }
 
+    fn get_batch_items(
+        &self,
+        param: params::GetBatchItems,
+    ) -> DbFuture<'_, Option<results::GetBatchItems>> {
+        let db = self.clone();
+        Box::pin(async move { batch::get_items_async(&db, param).map_err(Into::into).await })
+    }
+
+    fn delete_batch(&self, param: params::DeleteBatch) -> DbFuture<'_, results::DeleteBatch> {
+        let db = self.clone();
+        Box::pin(async move { batch::delete_async(&db, param).map_err(Into::into).await })
+    }
+
     fn commit_batch(&self, param: params::CommitBatch) -> DbFuture<'_, results::CommitBatch> {
         let db = self.clone();
         Box::pin(async move { batch::commit_async(&db, param).map_err(Into::into).await })
     }
 
+    fn purge_storage(&self, param: params::PurgeStorage) -> DbFuture<'_, results::PurgeStorage> {
+        let db = self.clone();
+        Box::pin(async move { db.purge_storage_async(param).map_err(Into::into).await })
+    }
+
+    fn expire_batches(&self, param: params::ExpireBatches) -> DbFuture<'_, results::ExpireBatches> {
+        let db = self.clone();
+        Box::pin(async move { db.expire_batches_async(param).map_err(Into::into).await })
+    }
+
+    fn purge_expired(&self, param: params::PurgeExpired) -> DbFuture<'_, results::PurgeExpired> {
+        let db = self.clone();
+        Box::pin(async move { db.purge_expired_async(param).map_err(Into::into).await })
+    }
+
+    fn post_service(&self, param: params::PostService) -> DbFuture<'_, results::PostService> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::post_service_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn post_node(&self, param: params::PostNode) -> DbFuture<'_, results::PostNode> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::post_node_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn allocate_node(&self, param: params::AllocateNode) -> DbFuture<'_, results::AllocateNode> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::allocate_node_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn get_users(&self, param: params::GetUsers) -> DbFuture<'_, results::GetUsers> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::get_users_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn post_user(&self, param: params::PostUser) -> DbFuture<'_, results::PostUser> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::post_user_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn put_user(&self, param: params::PutUser) -> DbFuture<'_, results::PutUser> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::put_user_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn replace_users(&self, param: params::ReplaceUsers) -> DbFuture<'_, results::ReplaceUsers> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::replace_users_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn get_replaced_users(
+        &self,
+        param: params::GetReplacedUsers,
+    ) -> DbFuture<'_, results::GetReplacedUsers> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::get_replaced_users_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
+    fn delete_user(&self, param: params::DeleteUser) -> DbFuture<'_, results::DeleteUser> {
+        let db = self.clone();
+        Box::pin(async move {
+            tokenserver::delete_user_async(&db, param)
+                .map_err(Into::into)
+                .await
+        })
+    }
+
     fn get_collection_id(&self, name: String) -> DbFuture<'_, i32> {
         let db = self.clone();
         Box::pin(async move { db.get_collection_id_async(&name).map_err(Into::into).await })
@@ -2084,12 +2690,6 @@ impl<'a> Db<'a> for SpannerDb {
         SpannerDb::set_timestamp(self, timestamp)
     }
 
-    #[cfg(test)]
-    fn delete_batch(&self, param: params::DeleteBatch) -> DbFuture<'_, results::DeleteBatch> {
-        let db = self.clone();
-        Box::pin(async move { batch::delete_async(&db, param).map_err(Into::into).await })
-    }
-
     #[cfg(test)]
     fn clear_coll_cache(&self) {
         self.coll_cache.clear();
@@ -2098,6 +2698,6 @@ impl<'a> Db<'a> for SpannerDb {
     #[cfg(test)]
     fn set_quota(&mut self, enabled: bool, limit: usize) {
         self.quota_enabled = enabled;
-        self.quota = limit;
+        Arc::make_mut(&mut self.limits).max_quota_limit = limit as u32;
     }
 }
@@ -10,25 +10,15 @@ use std::{
 use super::models::Result;
 use crate::db::{error::DbError, results, Db, DbPool, STD_COLLS};
 use crate::server::metrics::Metrics;
-use crate::settings::Settings;
+use crate::settings::{ServerLimits, Settings};
 
 use super::manager::{SpannerSession, SpannerSessionManager};
 use super::models::SpannerDb;
+use super::schema::run_migrations;
 use crate::error::ApiResult;
 
 pub use super::manager::Conn;
 
-embed_migrations!();
-
-/// Run the diesel embedded migrations
-///
-/// Mysql DDL statements implicitly commit which could disrupt MysqlPool's
-/// begin_test_transaction during tests. So this runs on its own separate conn.
-//pub fn run_embedded_migrations(settings: &Settings) -> Result<()> {
-//    let conn = MysqlConnection::establish(&settings.database_url)?;
-//    Ok(embedded_migrations::run(&conn)?)
-//}
-
 #[derive(Clone)]
 pub struct SpannerDbPool {
     /// Pool of db connections
@@ -37,14 +27,17 @@ pub struct SpannerDbPool {
     coll_cache: Arc<CollectionCache>,
 
     metrics: Metrics,
-    quota: usize,
+    limits: Arc<ServerLimits>,
     quota_enabled: bool,
+    exact_staleness: u32,
 }
 
 impl SpannerDbPool {
     /// Creates a new pool of Spanner db connections.
+    ///
+    /// Also applies any pending schema migrations.
     pub async fn new(settings: &Settings, metrics: &Metrics) -> Result<Self> {
-        //run_embedded_migrations(settings)?;
+        run_migrations(settings, metrics).await?;
         Self::new_without_migrations(settings, metrics).await
     }
 
@@ -58,8 +51,9 @@ impl SpannerDbPool {
             pool,
             coll_cache: Default::default(),
             metrics: metrics.clone(),
-            quota: settings.limits.max_quota_limit as usize,
+            limits: Arc::new(settings.limits.clone()),
             quota_enabled: settings.enable_quota,
+            exact_staleness: settings.spanner_exact_staleness,
         })
     }
 
@@ -74,8 +68,9 @@ impl SpannerDbPool {
             conn,
             Arc::clone(&self.coll_cache),
             &self.metrics,
-            self.quota,
+            &self.limits,
             self.quota_enabled,
+            self.exact_staleness,
         ))
     }
 }
@@ -0,0 +1,334 @@
+//! Versioned Spanner DDL migrations.
+//!
+//! Spanner schema changes are applied through the database admin API (as
+//! long-running operations) rather than over a session, so migrations are
+//! tracked in a `schema_version` table of their own.
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use googleapis_raw::{
+    longrunning::{
+        operations::{GetOperationRequest, Operation},
+        operations_grpc::OperationsClient,
+    },
+    spanner::admin::database::v1::{
+        spanner_database_admin::{GetDatabaseDdlRequest, UpdateDatabaseDdlRequest},
+        spanner_database_admin_grpc::DatabaseAdminClient,
+    },
+};
+use grpcio::EnvBuilder;
+use protobuf::RepeatedField;
+
+use super::{
+    manager::create_channel,
+    models::{Result, SpannerDb},
+    pool::SpannerDbPool,
+};
+use crate::db::{error::DbErrorKind, STD_COLLS};
+use crate::server::metrics::Metrics;
+use crate::settings::Settings;
+
+/// How often to check on a pending schema change
+const DDL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const SCHEMA_VERSION_DDL: &str = "CREATE TABLE schema_version (
+  version INT64        NOT NULL,
+  name STRING(MAX)     NOT NULL,
+  applied_at TIMESTAMP NOT NULL,
+) PRIMARY KEY(version)";
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    ddl: &'static str,
+}
+
+impl Migration {
+    /// The migration's individual DDL statements
+    fn statements(&self) -> Vec<String> {
+        let ddl: Vec<_> = self
+            .ddl
+            .lines()
+            .map(|line| line.split("--").next().unwrap_or_default())
+            .collect();
+        ddl.join("\n")
+            .split(';')
+            .map(str::trim)
+            .filter(|statement| !statement.is_empty())
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+}
+
+/// Every migration, in the order they're applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2019_10_01_000000,
+        name: "init",
+        ddl: include_str!("migrations/2019-10-01-000000_init.ddl"),
+    },
+    Migration {
+        version: 2020_08_24_000000,
+        name: "add_quota",
+        ddl: include_str!("migrations/2020-08-24-000000_add_quota.ddl"),
+    },
+    Migration {
+        version: 2020_10_17_000000,
+        name: "tokenserver",
+        ddl: include_str!("migrations/2020-10-17-000000_tokenserver.ddl"),
+    },
+];
+
+/// The version of the schema this build expects
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// The name of the table, index or column created by a DDL statement
+fn created_name(statement: &str) -> Option<&str> {
+    let words: Vec<_> = statement.split_whitespace().collect();
+    let name = match words.as_slice() {
+        ["CREATE", "TABLE", name, ..]
+        | ["CREATE", "INDEX", name, ..]
+        | ["CREATE", "UNIQUE", "INDEX", name, ..]
+        | ["CREATE", "NULL_FILTERED", "INDEX", name, ..]
+        | ["CREATE", "UNIQUE", "NULL_FILTERED", "INDEX", name, ..]
+        | ["ALTER", "TABLE", _, "ADD", "COLUMN", name, ..] => *name,
+        _ => return None,
+    };
+    Some(name.trim_end_matches('('))
+}
+
+/// Whether the statement's table, index or column already exists in the
+/// database's DDL: databases predating `schema_version` had their schema
+/// applied by hand, possibly only in part
+fn already_applied(statement: &str, ddl: &[String]) -> bool {
+    let name = match created_name(statement) {
+        Some(name) => name,
+        None => return false,
+    };
+    let words: Vec<_> = statement.split_whitespace().collect();
+    if let ["ALTER", "TABLE", table, ..] = words.as_slice() {
+        return ddl.iter().any(|existing| {
+            created_name(existing) == Some(*table)
+                && existing
+                    .lines()
+                    .skip(1)
+                    .any(|line| line.split_whitespace().next() == Some(name))
+        });
+    }
+    ddl.iter()
+        .any(|existing| created_name(existing) == Some(name))
+}
+
+/// Client of the database admin API
+struct SchemaAdmin {
+    admin: DatabaseAdminClient,
+    operations: OperationsClient,
+    database_name: String,
+}
+
+impl SchemaAdmin {
+    fn new(settings: &Settings) -> Result<Self> {
+        let database_name = settings
+            .spanner_database_name()
+            .ok_or_else(|| DbErrorKind::InvalidUrl(settings.database_url.to_owned()))?
+            .to_owned();
+        let env = Arc::new(EnvBuilder::new().build());
+        let chan = create_channel(env, settings.spanner_emulator_host.as_deref())?;
+        Ok(Self {
+            admin: DatabaseAdminClient::new(chan.clone()),
+            operations: OperationsClient::new(chan),
+            database_name,
+        })
+    }
+
+    async fn ddl(&self) -> Result<Vec<String>> {
+        let mut req = GetDatabaseDdlRequest::new();
+        req.set_database(self.database_name.clone());
+        let mut response = self.admin.get_database_ddl_async(&req)?.await?;
+        Ok(response.take_statements().into_vec())
+    }
+
+    /// Apply the statements, waiting for the schema change to complete
+    async fn update_ddl(&self, statements: Vec<String>) -> Result<()> {
+        let mut req = UpdateDatabaseDdlRequest::new();
+        req.set_database(self.database_name.clone());
+        req.set_statements(RepeatedField::from_vec(statements));
+        let mut operation = self.admin.update_database_ddl_async(&req)?.await?;
+        while !operation.get_done() {
+            actix_rt::time::delay_for(DDL_POLL_INTERVAL).await;
+            operation = self.poll(&operation).await?;
+        }
+        if operation.has_error() {
+            let error = operation.get_error();
+            Err(DbErrorKind::SpannerMigration(format!(
+                "{} ({})",
+                error.get_message(),
+                error.get_code()
+            )))?
+        }
+        Ok(())
+    }
+
+    async fn poll(&self, operation: &Operation) -> Result<Operation> {
+        let mut req = GetOperationRequest::new();
+        req.set_name(operation.get_name().to_owned());
+        Ok(self.operations.get_operation_async(&req)?.await?)
+    }
+}
+
+/// Apply any pending migrations then seed the `collections` table, returning
+/// the resulting schema version.
+pub async fn run_migrations(settings: &Settings, metrics: &Metrics) -> Result<i64> {
+    let admin = SchemaAdmin::new(settings)?;
+    let mut ddl = admin.ddl().await?;
+    if !already_applied(SCHEMA_VERSION_DDL, &ddl) {
+        info!("Creating the Spanner schema_version table");
+        admin
+            .update_ddl(vec![SCHEMA_VERSION_DDL.to_owned()])
+            .await?;
+    }
+
+    let mut settings = settings.clone();
+    #[cfg(test)]
+    {
+        // Migrations must commit
+        settings.database_use_test_transactions = false;
+    }
+    settings.database_pool_max_size = Some(1);
+    let pool = SpannerDbPool::new_without_migrations(&settings, metrics).await?;
+
+    let mut version = current_version(&pool.get_async().await?).await?;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let statements: Vec<_> = migration
+            .statements()
+            .into_iter()
+            .filter(|statement| !already_applied(statement, &ddl))
+            .collect();
+        info!(
+            "Applying Spanner migration {}_{} ({} statements)",
+            migration.version,
+            migration.name,
+            statements.len()
+        );
+        if !statements.is_empty() {
+            admin.update_ddl(statements).await?;
+            ddl = admin.ddl().await?;
+        }
+
+        let db = pool.get_async().await?;
+        db.begin_async(true).await?;
+        db.sql(
+            "INSERT INTO schema_version (version, name, applied_at)
+             VALUES (@version, @name, CURRENT_TIMESTAMP())",
+        )?
+        .params(params! {
+            "version" => migration.version.to_string(),
+            "name" => migration.name.to_owned(),
+        })
+        .execute_dml_async(&db.conn)
+        .await?;
+        db.commit_async().await?;
+        version = migration.version;
+    }
+
+    seed_collections(&pool.get_async().await?).await?;
+    Ok(version)
+}
+
+/// The version of the most recently applied migration (0 when none have been)
+pub async fn current_version(db: &SpannerDb) -> Result<i64> {
+    db.begin_async(false).await?;
+    let row = db
+        .sql("SELECT COALESCE(MAX(version), 0) FROM schema_version")?
+        .execute_async(&db.conn)?
+        .one()
+        .await?;
+    Ok(row[0]
+        .get_string_value()
+        .parse::<i64>()
+        .map_err(|e| DbErrorKind::Integrity(e.to_string()))?)
+}
+
+/// Insert any of the standard collections missing from `collections`
+async fn seed_collections(db: &SpannerDb) -> Result<()> {
+    db.begin_async(true).await?;
+    let mut streaming = db
+        .sql("SELECT collection_id, name FROM collections")?
+        .execute_async(&db.conn)?;
+    let mut existing = HashMap::new();
+    while let Some(row) = streaming.next_async().await {
+        let mut row = row?;
+        existing.insert(row[1].take_string_value(), row[0].take_string_value());
+    }
+
+    for (id, name) in STD_COLLS.iter() {
+        if existing.contains_key(*name) {
+            continue;
+        }
+        db.sql(
+            "INSERT INTO collections (collection_id, name)
+             VALUES (@collection_id, @name)",
+        )?
+        .params(params! {
+            "collection_id" => id.to_string(),
+            "name" => (*name).to_owned(),
+        })
+        .execute_dml_async(&db.conn)
+        .await?;
+    }
+    db.commit_async().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{already_applied, latest_version, MIGRATIONS, SCHEMA_VERSION_DDL};
+
+    #[test]
+    fn statements() {
+        let init = MIGRATIONS[0].statements();
+        assert!(init[0].starts_with("CREATE TABLE user_collections ("));
+        assert!(init.iter().all(|statement| !statement.contains("--")));
+        assert!(init
+            .iter()
+            .any(|statement| statement.starts_with("CREATE INDEX BsoExpiry")));
+        assert!(init
+            .iter()
+            .any(|statement| statement.starts_with("CREATE INDEX BatchExpiry")));
+        assert_eq!(latest_version(), MIGRATIONS[MIGRATIONS.len() - 1].version);
+    }
+
+    #[test]
+    fn skips_hand_applied() {
+        // As returned by GetDatabaseDdl
+        let ddl = vec![
+            "CREATE TABLE user_collections (\n  fxa_uid STRING(MAX) NOT NULL,\n  \
+             count INT64,\n) PRIMARY KEY(fxa_uid)"
+                .to_owned(),
+            "CREATE INDEX BsoExpiry ON bsos(fxa_uid, fxa_kid, collection_id, expiry), \
+             INTERLEAVE IN user_collections"
+                .to_owned(),
+        ];
+        assert!(already_applied(
+            "CREATE TABLE user_collections (\n fxa_uid STRING(MAX)\n)",
+            &ddl
+        ));
+        assert!(already_applied(
+            "CREATE INDEX BsoExpiry\n ON bsos(expiry)",
+            &ddl
+        ));
+        assert!(already_applied(
+            "ALTER TABLE user_collections ADD COLUMN count INT64",
+            &ddl
+        ));
+        assert!(!already_applied(
+            "ALTER TABLE user_collections ADD COLUMN total_bytes INT64",
+            &ddl
+        ));
+        assert!(!already_applied(
+            "CREATE INDEX BatchExpiry ON batches(expiry)",
+            &ddl
+        ));
+        assert!(!already_applied(SCHEMA_VERSION_DDL, &ddl));
+    }
+}
@@ -11,6 +11,7 @@ use googleapis_raw::spanner::v1::{
     type_pb::{StructType_Field, Type, TypeCode},
 };
 use grpcio::ClientSStreamReceiver;
+use opentelemetry::{global::BoxedSpan, KeyValue};
 use protobuf::{
     well_known_types::{ListValue, NullValue, Struct, Value},
     RepeatedField,
@@ -21,6 +22,7 @@ use crate::{
         params, results, spanner::models::DEFAULT_BSO_TTL, util::to_rfc3339, util::SyncTimestamp,
         DbError, DbErrorKind,
     },
+    server::tracing::{in_span, start_span},
     web::extractors::HawkIdentifier,
 };
 
@@ -88,6 +90,14 @@ impl ExecuteSqlRequestBuilder {
         self
     }
 
+    /// Attributes of the span around the statement's execution
+    fn span_attributes(&self) -> Vec<KeyValue> {
+        vec![
+            KeyValue::new("db.system", "spanner"),
+            KeyValue::new("db.statement", self.execute_sql.get_sql().to_owned()),
+        ]
+    }
+
     fn prepare_request(self, conn: &Conn) -> ExecuteSqlRequest {
         let mut request = self.execute_sql;
         request.set_session(conn.session.get_name().to_owned());
@@ -103,20 +113,25 @@ impl ExecuteSqlRequestBuilder {
     }
 
     /// Execute a SQL read statement but return a non-blocking streaming result
+    ///
+    /// The statement's span lasts until the result is dropped.
     pub fn execute_async(self, conn: &Conn) -> Result<StreamedResultSetAsync> {
+        let span = start_span("spanner.execute_streaming_sql", self.span_attributes());
         let stream = conn
             .client
             .execute_streaming_sql(&self.prepare_request(conn))?;
-        Ok(StreamedResultSetAsync::new(stream))
+        Ok(StreamedResultSetAsync::new(stream).with_span(span))
     }
 
     /// Execute a DML statement, returning the exact count of modified rows
     pub async fn execute_dml_async(self, conn: &Conn) -> Result<i64> {
-        let rs = conn
-            .client
-            .execute_sql_async(&self.prepare_request(conn))?
-            .await?;
-        Ok(rs.get_stats().get_row_count_exact())
+        let attributes = self.span_attributes();
+        let request = self.prepare_request(conn);
+        in_span("spanner.execute_sql", attributes, async {
+            let rs = conn.client.execute_sql_async(&request)?.await?;
+            Ok::<_, DbError>(rs.get_stats().get_row_count_exact())
+        })
+        .await
     }
 }
 
@@ -133,6 +148,9 @@ pub struct StreamedResultSetAsync {
     current_row: Vec<Value>,
     /// Incomplete value
     pending_chunk: Option<Value>,
+
+    /// Span of the statement, ended when the result is dropped
+    span: Option<BoxedSpan>,
 }
 
 impl StreamedResultSetAsync {
@@ -144,9 +162,15 @@ impl StreamedResultSetAsync {
             rows: Default::default(),
             current_row: vec![],
             pending_chunk: None,
+            span: None,
         }
     }
 
+    pub fn with_span(mut self, span: BoxedSpan) -> Self {
+        self.span = Some(span);
+        self
+    }
+
     #[allow(dead_code)]
     pub fn metadata(&self) -> Option<&ResultSetMetadata> {
         self.metadata.as_ref()
@@ -346,6 +370,31 @@ pub fn bso_to_insert_row(
     Ok(row)
 }
 
+/// A row of an imported BSO, keeping its original modified and expiry
+pub fn bso_to_import_row(
+    user_id: &HawkIdentifier,
+    collection_id: i32,
+    bso: params::ImportBso,
+) -> Result<ListValue> {
+    let sortindex = bso
+        .sortindex
+        .map(|sortindex| as_value(sortindex.to_string()))
+        .unwrap_or_else(null_value);
+
+    let mut row = ListValue::new();
+    row.set_values(RepeatedField::from_vec(vec![
+        as_value(user_id.fxa_uid.clone()),
+        as_value(user_id.fxa_kid.clone()),
+        as_value(collection_id.to_string()),
+        as_value(bso.id),
+        sortindex,
+        as_value(bso.payload),
+        as_value(bso.modified.as_rfc3339()?),
+        as_value(to_rfc3339(bso.expiry)?),
+    ]));
+    Ok(row)
+}
+
 pub fn bso_to_update_row(
     user_id: &HawkIdentifier,
     collection_id: i32,
@@ -0,0 +1,431 @@
+//! Tokenserver users, nodes and services.
+use googleapis_raw::spanner::v1::type_pb::TypeCode;
+use protobuf::well_known_types::Value;
+
+use super::{
+    models::{Result, SpannerDb},
+    support::{as_value, null_value},
+};
+use crate::db::{params, results, DbError, DbErrorKind, NODE_RELEASE_RATE};
+
+fn parse_i64(value: &Value) -> Result<i64> {
+    value
+        .get_string_value()
+        .parse::<i64>()
+        .map_err(|e| DbErrorKind::Integrity(e.to_string()).into())
+}
+
+fn parse_optional_i64(value: &Value) -> Result<Option<i64>> {
+    if value.has_null_value() {
+        Ok(None)
+    } else {
+        parse_i64(value).map(Some)
+    }
+}
+
+fn optional_i64_value(value: Option<i64>) -> Value {
+    value
+        .map(|value| as_value(value.to_string()))
+        .unwrap_or_else(null_value)
+}
+
+/// Spanner has no auto incrementing columns: ids are allocated from the
+/// current maximum, within the write transaction.
+async fn next_id(db: &SpannerDb, table: &str, column: &str) -> Result<i64> {
+    let row = db
+        .sql(&format!(
+            "SELECT COALESCE(MAX({column}), 0)
+               FROM {table}",
+            column = column,
+            table = table
+        ))?
+        .execute_async(&db.conn)?
+        .one()
+        .await?;
+    Ok(parse_i64(&row[0])? + 1)
+}
+
+fn ensure_write_transaction(db: &SpannerDb) -> Result<()> {
+    if !cfg!(test) && !db.in_write_transaction() {
+        Err(DbError::internal(
+            "Tokenserver writes require a write transaction",
+        ))?
+    }
+    Ok(())
+}
+
+pub async fn post_service_async(
+    db: &SpannerDb,
+    params: params::PostService,
+) -> Result<results::PostService> {
+    ensure_write_transaction(db)?;
+    let existing = db
+        .sql(
+            "SELECT id
+               FROM services
+              WHERE service = @service",
+        )?
+        .params(params! {"service" => params.service.clone()})
+        .execute_async(&db.conn)?
+        .one_or_none()
+        .await?;
+    if let Some(row) = existing {
+        return Ok(parse_i64(&row[0])? as i32);
+    }
+
+    let id = next_id(db, "services", "id").await?;
+    db.sql(
+        "INSERT INTO services (id, service, pattern)
+         VALUES (@id, @service, @pattern)",
+    )?
+    .params(params! {
+        "id" => id.to_string(),
+        "service" => params.service,
+        "pattern" => params.pattern,
+    })
+    .param_types(param_types! {
+        "id" => TypeCode::INT64,
+    })
+    .execute_dml_async(&db.conn)
+    .await?;
+    Ok(id as i32)
+}
+
+pub async fn post_node_async(
+    db: &SpannerDb,
+    params: params::PostNode,
+) -> Result<results::PostNode> {
+    ensure_write_transaction(db)?;
+    let existing = db
+        .sql(
+            "SELECT id
+               FROM nodes
+              WHERE service = @service
+                AND node = @node",
+        )?
+        .params(params! {
+            "service" => params.service_id.to_string(),
+            "node" => params.node.clone(),
+        })
+        .param_types(param_types! {
+            "service" => TypeCode::INT64,
+        })
+        .execute_async(&db.conn)?
+        .one_or_none()
+        .await?;
+    if let Some(row) = existing {
+        return parse_i64(&row[0]);
+    }
+
+    let id = next_id(db, "nodes", "id").await?;
+    db.sql(
+        "INSERT INTO nodes (id, service, node, available, current_load, capacity, downed,
+                            backoff)
+         VALUES (@id, @service, @node, @capacity, 0, @capacity, 0, 0)",
+    )?
+    .params(params! {
+        "id" => id.to_string(),
+        "service" => params.service_id.to_string(),
+        "node" => params.node,
+        "capacity" => params.capacity.to_string(),
+    })
+    .param_types(param_types! {
+        "id" => TypeCode::INT64,
+        "service" => TypeCode::INT64,
+        "capacity" => TypeCode::INT64,
+    })
+    .execute_dml_async(&db.conn)
+    .await?;
+    Ok(id)
+}
+
+async fn least_loaded_node(
+    db: &SpannerDb,
+    service_id: i32,
+) -> Result<Option<results::AllocateNode>> {
+    // Matches MySQL's LOG(0) being NULL: empty nodes sort first
+    let row = db
+        .sql(
+            "SELECT id, node
+               FROM nodes
+              WHERE service = @service
+                AND available > 0
+                AND capacity > current_load
+                AND downed = 0
+                AND backoff = 0
+              ORDER BY IF(current_load = 0, NULL, SAFE_DIVIDE(LN(current_load), LN(capacity)))
+              LIMIT 1",
+        )?
+        .params(params! {"service" => service_id.to_string()})
+        .param_types(param_types! {
+            "service" => TypeCode::INT64,
+        })
+        .execute_async(&db.conn)?
+        .one_or_none()
+        .await?;
+    row.map(|row| {
+        Ok(results::AllocateNode {
+            id: parse_i64(&row[0])?,
+            node: row[1].get_string_value().to_owned(),
+        })
+    })
+    .transpose()
+}
+
+pub async fn allocate_node_async(
+    db: &SpannerDb,
+    params: params::AllocateNode,
+) -> Result<results::AllocateNode> {
+    ensure_write_transaction(db)?;
+    let node = match least_loaded_node(db, params.service_id).await? {
+        Some(node) => node,
+        None => {
+            // Every node's exhausted its available slots: release some more
+            // of their spare capacity and try again
+            let released = db
+                .sql(
+                    "UPDATE nodes
+                        SET available = LEAST(CAST(capacity * @release_rate AS INT64),
+                                              capacity - current_load)
+                      WHERE service = @service
+                        AND available <= 0
+                        AND capacity > current_load
+                        AND downed = 0",
+                )?
+                .params(params! {
+                    "release_rate" => NODE_RELEASE_RATE.to_string(),
+                    "service" => params.service_id.to_string(),
+                })
+                .param_types(param_types! {
+                    "release_rate" => TypeCode::FLOAT64,
+                    "service" => TypeCode::INT64,
+                })
+                .execute_dml_async(&db.conn)
+                .await?;
+            if released == 0 {
+                Err(DbErrorKind::NodeUnavailable)?
+            }
+            least_loaded_node(db, params.service_id)
+                .await?
+                .ok_or(DbErrorKind::NodeUnavailable)?
+        }
+    };
+
+    db.sql(
+        "UPDATE nodes
+            SET current_load = current_load + 1,
+                available = GREATEST(available - 1, 0)
+          WHERE id = @id",
+    )?
+    .params(params! {"id" => node.id.to_string()})
+    .param_types(param_types! {
+        "id" => TypeCode::INT64,
+    })
+    .execute_dml_async(&db.conn)
+    .await?;
+    Ok(node)
+}
+
+pub async fn get_users_async(
+    db: &SpannerDb,
+    params: params::GetUsers,
+) -> Result<results::GetUsers> {
+    let mut streaming = db
+        .sql(
+            "SELECT users.uid, users.nodeid, nodes.node, users.generation,
+                    users.keys_changed_at, users.client_state, users.created_at,
+                    users.replaced_at
+               FROM users
+               LEFT OUTER JOIN nodes
+                 ON users.nodeid = nodes.id
+                AND nodes.downed = 0
+              WHERE users.email = @email
+                AND users.service = @service
+              ORDER BY users.created_at DESC, users.uid DESC
+              LIMIT 20",
+        )?
+        .params(params! {
+            "email" => params.email,
+            "service" => params.service_id.to_string(),
+        })
+        .param_types(param_types! {
+            "service" => TypeCode::INT64,
+        })
+        .execute_async(&db.conn)?;
+    let mut users = vec![];
+    while let Some(row) = streaming.next_async().await {
+        let row = row?;
+        users.push(results::TokenserverUser {
+            uid: parse_i64(&row[0])?,
+            node_id: parse_i64(&row[1])?,
+            node: if row[2].has_null_value() {
+                None
+            } else {
+                Some(row[2].get_string_value().to_owned())
+            },
+            generation: parse_i64(&row[3])?,
+            keys_changed_at: parse_optional_i64(&row[4])?,
+            client_state: row[5].get_string_value().to_owned(),
+            created_at: parse_i64(&row[6])?,
+            replaced_at: parse_optional_i64(&row[7])?,
+        });
+    }
+    Ok(users)
+}
+
+pub async fn post_user_async(
+    db: &SpannerDb,
+    params: params::PostUser,
+) -> Result<results::PostUser> {
+    ensure_write_transaction(db)?;
+    let uid = next_id(db, "users", "uid").await?;
+    let mut sqlparams = params! {
+        "uid" => uid.to_string(),
+        "service" => params.service_id.to_string(),
+        "email" => params.email,
+        "generation" => params.generation.to_string(),
+        "client_state" => params.client_state,
+        "created_at" => params.created_at.to_string(),
+        "nodeid" => params.node_id.to_string(),
+    };
+    sqlparams.insert(
+        "keys_changed_at".to_owned(),
+        optional_i64_value(params.keys_changed_at),
+    );
+    db.sql(
+        "INSERT INTO users (uid, service, email, generation, client_state, created_at,
+                            replaced_at, nodeid, keys_changed_at)
+         VALUES (@uid, @service, @email, @generation, @client_state, @created_at, NULL,
+                 @nodeid, @keys_changed_at)",
+    )?
+    .params(sqlparams)
+    .param_types(param_types! {
+        "uid" => TypeCode::INT64,
+        "service" => TypeCode::INT64,
+        "generation" => TypeCode::INT64,
+        "created_at" => TypeCode::INT64,
+        "nodeid" => TypeCode::INT64,
+        "keys_changed_at" => TypeCode::INT64,
+    })
+    .execute_dml_async(&db.conn)
+    .await?;
+    Ok(uid)
+}
+
+pub async fn put_user_async(db: &SpannerDb, params: params::PutUser) -> Result<results::PutUser> {
+    ensure_write_transaction(db)?;
+    let mut sqlparams = params! {
+        "generation" => params.generation.to_string(),
+        "service" => params.service_id.to_string(),
+        "email" => params.email,
+    };
+    sqlparams.insert(
+        "keys_changed_at".to_owned(),
+        optional_i64_value(params.keys_changed_at),
+    );
+    // GREATEST is NULL when any argument is: COALESCE keeps whichever
+    // keys_changed_at is set
+    db.sql(
+        "UPDATE users
+            SET generation = GREATEST(generation, @generation),
+                keys_changed_at = COALESCE(GREATEST(keys_changed_at, @keys_changed_at),
+                                           keys_changed_at, @keys_changed_at)
+          WHERE service = @service
+            AND email = @email
+            AND replaced_at IS NULL",
+    )?
+    .params(sqlparams)
+    .param_types(param_types! {
+        "generation" => TypeCode::INT64,
+        "keys_changed_at" => TypeCode::INT64,
+        "service" => TypeCode::INT64,
+    })
+    .execute_dml_async(&db.conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn replace_users_async(
+    db: &SpannerDb,
+    params: params::ReplaceUsers,
+) -> Result<results::ReplaceUsers> {
+    ensure_write_transaction(db)?;
+    db.sql(
+        "UPDATE users
+            SET replaced_at = @replaced_at
+          WHERE service = @service
+            AND email = @email
+            AND replaced_at IS NULL
+            AND created_at < @replaced_at",
+    )?
+    .params(params! {
+        "replaced_at" => params.replaced_at.to_string(),
+        "service" => params.service_id.to_string(),
+        "email" => params.email,
+    })
+    .param_types(param_types! {
+        "replaced_at" => TypeCode::INT64,
+        "service" => TypeCode::INT64,
+    })
+    .execute_dml_async(&db.conn)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_replaced_users_async(
+    db: &SpannerDb,
+    params: params::GetReplacedUsers,
+) -> Result<results::GetReplacedUsers> {
+    let mut streaming = db
+        .sql(
+            "SELECT uid, email, generation, keys_changed_at, client_state, replaced_at
+               FROM users
+              WHERE service = @service
+                AND replaced_at IS NOT NULL
+                AND replaced_at < @replaced_before
+              ORDER BY replaced_at, uid
+              LIMIT @limit",
+        )?
+        .params(params! {
+            "service" => params.service_id.to_string(),
+            "replaced_before" => params.replaced_before.to_string(),
+            "limit" => params.limit.to_string(),
+        })
+        .param_types(param_types! {
+            "service" => TypeCode::INT64,
+            "replaced_before" => TypeCode::INT64,
+            "limit" => TypeCode::INT64,
+        })
+        .execute_async(&db.conn)?;
+    let mut users = vec![];
+    while let Some(row) = streaming.next_async().await {
+        let row = row?;
+        users.push(results::ReplacedUser {
+            uid: parse_i64(&row[0])?,
+            email: row[1].get_string_value().to_owned(),
+            generation: parse_i64(&row[2])?,
+            keys_changed_at: parse_optional_i64(&row[3])?,
+            client_state: row[4].get_string_value().to_owned(),
+            replaced_at: parse_i64(&row[5])?,
+        });
+    }
+    Ok(users)
+}
+
+pub async fn delete_user_async(
+    db: &SpannerDb,
+    params: params::DeleteUser,
+) -> Result<results::DeleteUser> {
+    ensure_write_transaction(db)?;
+    db.sql(
+        "DELETE FROM users
+          WHERE uid = @uid",
+    )?
+    .params(params! {"uid" => params.uid.to_string()})
+    .param_types(param_types! {
+        "uid" => TypeCode::INT64,
+    })
+    .execute_dml_async(&db.conn)
+    .await?;
+    Ok(())
+}
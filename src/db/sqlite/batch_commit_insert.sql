@@ -0,0 +1,17 @@
+INSERT INTO bso (userid, collection, id, modified, sortindex, ttl, payload, payload_size)
+SELECT
+       ?1,
+       ?2,
+       id,
+       ?4,
+       sortindex,
+       COALESCE((ttl_offset * 1000) + ?4, ?5),
+       COALESCE(payload, ''),
+       COALESCE(payload_size, 0)
+  FROM batch_upload_items
+ WHERE batch = ?3
+   AND userid = ?1
+   AND id NOT IN (SELECT id
+                    FROM bso
+                   WHERE userid = ?1
+                     AND collection = ?2)
@@ -0,0 +1,36 @@
+UPDATE bso
+   SET modified = ?4,
+       sortindex = COALESCE(
+           (SELECT sortindex
+              FROM batch_upload_items
+             WHERE batch = ?3
+               AND userid = ?1
+               AND id = bso.id),
+           sortindex),
+       ttl = COALESCE(
+           (SELECT (ttl_offset * 1000) + ?4
+              FROM batch_upload_items
+             WHERE batch = ?3
+               AND userid = ?1
+               AND id = bso.id),
+           ttl),
+       payload = COALESCE(
+           (SELECT payload
+              FROM batch_upload_items
+             WHERE batch = ?3
+               AND userid = ?1
+               AND id = bso.id),
+           payload),
+       payload_size = COALESCE(
+           (SELECT payload_size
+              FROM batch_upload_items
+             WHERE batch = ?3
+               AND userid = ?1
+               AND id = bso.id),
+           payload_size)
+ WHERE userid = ?1
+   AND collection = ?2
+   AND id IN (SELECT id
+                FROM batch_upload_items
+               WHERE batch = ?3
+                 AND userid = ?1)
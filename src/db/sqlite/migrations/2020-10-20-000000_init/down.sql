@@ -0,0 +1,8 @@
+DROP TABLE users;
+DROP TABLE nodes;
+DROP TABLE services;
+DROP TABLE batch_upload_items;
+DROP TABLE batch_uploads;
+DROP TABLE user_collections;
+DROP TABLE collections;
+DROP TABLE bso;
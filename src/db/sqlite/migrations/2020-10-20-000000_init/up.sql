@@ -0,0 +1,102 @@
+-- The MySQL schema (as of its latest migration), column names and all
+CREATE TABLE bso (
+    userid BIGINT          NOT NULL,
+    collection INTEGER     NOT NULL,
+    id VARCHAR(64)         NOT NULL,
+
+    sortindex INTEGER,
+
+    payload TEXT           NOT NULL,
+    payload_size BIGINT    DEFAULT 0,
+
+    -- last modified time in milliseconds since epoch
+    modified BIGINT        NOT NULL,
+    -- expiration in milliseconds since epoch
+    ttl BIGINT             NOT NULL,
+
+    PRIMARY KEY (userid, collection, id)
+);
+CREATE INDEX bso_ttl_idx ON bso (ttl);
+CREATE INDEX bso_usr_col_mod_idx ON bso (userid, collection, modified);
+
+CREATE TABLE collections (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name VARCHAR(32) UNIQUE              NOT NULL
+);
+INSERT INTO collections (id, name) VALUES
+    (  1, 'clients'),
+    (  2, 'crypto'),
+    (  3, 'forms'),
+    (  4, 'history'),
+    (  5, 'keys'),
+    (  6, 'meta'),
+    (  7, 'bookmarks'),
+    (  8, 'prefs'),
+    (  9, 'tabs'),
+    ( 10, 'passwords'),
+    ( 11, 'addons'),
+    ( 12, 'addresses'),
+    ( 13, 'creditcards'),
+    -- Reserve space for additions to the standard collections
+    (100, '');
+
+CREATE TABLE user_collections (
+    userid BIGINT          NOT NULL,
+    collection INTEGER     NOT NULL,
+    -- last modified time in milliseconds since epoch
+    last_modified BIGINT   NOT NULL,
+    total_bytes BIGINT,
+    count INTEGER,
+    PRIMARY KEY (userid, collection)
+);
+
+CREATE TABLE batch_uploads (
+    batch BIGINT           NOT NULL,
+    userid BIGINT          NOT NULL,
+    collection INTEGER     NOT NULL,
+    PRIMARY KEY (batch, userid)
+);
+
+CREATE TABLE batch_upload_items (
+    batch BIGINT           NOT NULL,
+    userid BIGINT          NOT NULL,
+    id VARCHAR(64)         NOT NULL,
+    sortindex INTEGER      DEFAULT NULL,
+    payload TEXT,
+    payload_size BIGINT    DEFAULT NULL,
+    ttl_offset INTEGER     DEFAULT NULL,
+    PRIMARY KEY (batch, userid, id)
+);
+
+CREATE TABLE services (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    service VARCHAR(30) UNIQUE,
+    pattern VARCHAR(128)
+);
+
+CREATE TABLE nodes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    service INTEGER        NOT NULL,
+    node VARCHAR(64)       NOT NULL,
+    available INTEGER      NOT NULL,
+    current_load INTEGER   NOT NULL,
+    capacity INTEGER       NOT NULL,
+    downed INTEGER         NOT NULL,
+    backoff INTEGER        NOT NULL,
+    UNIQUE (service, node)
+);
+
+CREATE TABLE users (
+    uid INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    service INTEGER        NOT NULL,
+    email VARCHAR(255)     NOT NULL,
+    generation BIGINT      NOT NULL,
+    client_state VARCHAR(32) NOT NULL,
+    created_at BIGINT      NOT NULL,
+    replaced_at BIGINT     DEFAULT NULL,
+    nodeid BIGINT          NOT NULL,
+    keys_changed_at BIGINT DEFAULT NULL
+);
+CREATE INDEX lookup_idx ON users (email, service, created_at);
+CREATE INDEX replaced_at_idx ON users (service, replaced_at);
+CREATE INDEX node_idx ON users (nodeid);
@@ -0,0 +1,189 @@
+use actix_web::web::block;
+
+use async_trait::async_trait;
+
+use std::{fmt, result::Result as StdResult, sync::Arc, time::Duration};
+
+use diesel::{
+    connection::SimpleConnection,
+    r2d2::{ConnectionManager, CustomizeConnection, Error as PoolError, Pool},
+    sqlite::SqliteConnection,
+    Connection, ConnectionError,
+};
+
+use url::Url;
+
+use super::models::{Result, SqliteDb};
+use crate::db::{
+    error::DbErrorKind,
+    mysql::pool::CollectionCache,
+    results::{self, PoolState},
+    Db, DbPool,
+};
+use crate::error::{ApiError, ApiResult};
+use crate::server::metrics::Metrics;
+use crate::settings::{ServerLimits, Settings};
+
+embed_migrations!("src/db/sqlite/migrations");
+
+/// Version of the latest embedded migration (the last in `src/db/sqlite/migrations`)
+pub const LATEST_MIGRATION_VERSION: i64 = 20201020000000;
+
+/// How long a connection waits on another's lock of the database before
+/// failing with SQLITE_BUSY.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Path of the database file from a `sqlite:` url, e.g.
+/// `sqlite:///var/lib/syncstorage.db`, or `:memory:` for `sqlite::memory:`.
+pub fn database_path(database_url: &str) -> Result<String> {
+    let url = Url::parse(database_url).map_err(|e| DbErrorKind::InvalidUrl(e.to_string()))?;
+    if url.path().is_empty() {
+        Err(DbErrorKind::InvalidUrl(database_url.to_owned()))?
+    }
+    Ok(url.path().to_owned())
+}
+
+/// Run the diesel embedded migrations
+pub fn run_embedded_migrations(conn: &SqliteConnection) -> Result<()> {
+    embedded_migrations::run(conn)?;
+    Ok(())
+}
+
+/// Configures each new connection.
+///
+/// In-memory databases live and die with their connection, so they're
+/// migrated here (and pools of them are limited to the one connection).
+#[derive(Debug)]
+struct SqliteConnectionCustomizer {
+    in_memory: bool,
+    #[cfg(test)]
+    use_test_transactions: bool,
+}
+
+impl CustomizeConnection<SqliteConnection, PoolError> for SqliteConnectionCustomizer {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> StdResult<(), PoolError> {
+        // WAL lets readers continue alongside the (single) writer
+        conn.batch_execute(&format!(
+            "PRAGMA busy_timeout = {};
+             PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = NORMAL;",
+            BUSY_TIMEOUT.as_millis()
+        ))
+        .map_err(PoolError::QueryError)?;
+        if self.in_memory {
+            run_embedded_migrations(conn).map_err(|e| {
+                PoolError::ConnectionError(ConnectionError::BadConnection(e.to_string()))
+            })?;
+        }
+        #[cfg(test)]
+        {
+            if self.use_test_transactions {
+                conn.begin_test_transaction()
+                    .map_err(PoolError::QueryError)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteDbPool {
+    /// Pool of db connections
+    pool: Pool<ConnectionManager<SqliteConnection>>,
+    /// In-memory cache of collection_ids and their names
+    coll_cache: Arc<CollectionCache>,
+
+    metrics: Metrics,
+    limits: Arc<ServerLimits>,
+    quota_enabled: bool,
+}
+
+impl SqliteDbPool {
+    /// Creates a new pool of SQLite db connections.
+    ///
+    /// Also initializes the SQLite db, ensuring all migrations are ran.
+    pub fn new(settings: &Settings, metrics: &Metrics) -> Result<Self> {
+        let path = database_path(&settings.database_url)?;
+        if path != ":memory:" {
+            run_embedded_migrations(&SqliteConnection::establish(&path)?)?;
+        }
+        Self::new_without_migrations(settings, metrics)
+    }
+
+    /// Creates a new pool of SQLite db connections without migrating a
+    /// database file (in-memory databases are always migrated).
+    pub fn new_without_migrations(settings: &Settings, metrics: &Metrics) -> Result<Self> {
+        let path = database_path(&settings.database_url)?;
+        let in_memory = path == ":memory:";
+
+        let manager = ConnectionManager::<SqliteConnection>::new(path);
+        let builder = if in_memory {
+            // Never let go of the one connection holding the database
+            Pool::builder()
+                .max_size(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            Pool::builder()
+                .max_size(settings.database_pool_max_size.unwrap_or(10))
+                .min_idle(settings.database_pool_min_idle)
+        };
+        let builder = builder.connection_customizer(Box::new(SqliteConnectionCustomizer {
+            in_memory,
+            #[cfg(test)]
+            use_test_transactions: settings.database_use_test_transactions,
+        }));
+
+        Ok(Self {
+            pool: builder.build(manager)?,
+            coll_cache: Default::default(),
+            metrics: metrics.clone(),
+            limits: Arc::new(settings.limits.clone()),
+            quota_enabled: settings.enable_quota,
+        })
+    }
+
+    pub fn get_sync(&self) -> Result<SqliteDb> {
+        Ok(SqliteDb::new(
+            self.pool.get()?,
+            Arc::clone(&self.coll_cache),
+            &self.metrics,
+            &self.limits,
+            self.quota_enabled,
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl DbPool for SqliteDbPool {
+    async fn get<'a>(&'a self) -> ApiResult<Box<dyn Db<'a>>> {
+        let pool = self.clone();
+        let db = block(move || pool.get_sync().map_err(ApiError::from)).await?;
+
+        Ok(Box::new(db) as Box<dyn Db<'a>>)
+    }
+
+    fn state(&self) -> results::PoolState {
+        let state = self.pool.state();
+        PoolState {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+        }
+    }
+
+    fn validate_batch_id(&self, id: String) -> Result<()> {
+        super::batch::validate_batch_id(&id)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(self.clone())
+    }
+}
+
+impl fmt::Debug for SqliteDbPool {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("SqliteDbPool")
+            .field("coll_cache", &self.coll_cache)
+            .finish()
+    }
+}
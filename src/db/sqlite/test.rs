@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use diesel::{expression_methods::TextExpressionMethods, ExpressionMethods, QueryDsl, RunQueryDsl};
+use url::Url;
+
+use crate::db::{
+    params,
+    sqlite::{
+        models::{Result, SqliteDb},
+        pool::{SqliteDbPool, LATEST_MIGRATION_VERSION},
+        schema::collections,
+    },
+};
+use crate::server::metrics;
+use crate::settings::{test_settings, Settings};
+use crate::web::extractors::HawkIdentifier;
+
+pub fn db(settings: &Settings) -> Result<SqliteDb> {
+    let _ = env_logger::try_init();
+    // inherit SYNC_DATABASE_URL from the env
+
+    let pool = SqliteDbPool::new(&settings, &metrics::Metrics::noop())?;
+    pool.get_sync()
+}
+
+#[test]
+fn static_collection_id() -> Result<()> {
+    let settings = test_settings();
+    if Url::parse(&settings.database_url).unwrap().scheme() != "sqlite" {
+        // Skip this test if we're not using sqlite
+        return Ok(());
+    }
+    let db = db(&settings)?;
+
+    // ensure DB actually has predefined common collections
+    let cols: Vec<(i32, _)> = vec![
+        (1, "clients"),
+        (2, "crypto"),
+        (3, "forms"),
+        (4, "history"),
+        (5, "keys"),
+        (6, "meta"),
+        (7, "bookmarks"),
+        (8, "prefs"),
+        (9, "tabs"),
+        (10, "passwords"),
+        (11, "addons"),
+        (12, "addresses"),
+        (13, "creditcards"),
+    ];
+    // The integration tests can create collections that start
+    // with `xxx%`. We should not include those in our counts for local
+    // unit tests.
+    let results: HashMap<i32, String> = collections::table
+        .select((collections::id, collections::name))
+        .filter(collections::name.ne(""))
+        .filter(collections::name.not_like("xxx%")) // from most integration tests
+        .filter(collections::name.ne("col2")) // from older intergration tests
+        .load(&db.inner.conn)?
+        .into_iter()
+        .collect();
+    assert_eq!(results.len(), cols.len(), "mismatched columns");
+    for (id, name) in &cols {
+        assert_eq!(results.get(id).unwrap(), name);
+    }
+
+    for (id, name) in &cols {
+        let result = db.get_collection_id(name)?;
+        assert_eq!(result, *id);
+    }
+
+    let cid = db.get_or_create_collection_id("col1")?;
+    assert!(cid >= 100);
+    Ok(())
+}
+
+#[test]
+fn write_after_read_transaction() -> Result<()> {
+    let settings = test_settings();
+    if Url::parse(&settings.database_url).unwrap().scheme() != "sqlite" {
+        // Skip this test if we're not using sqlite
+        return Ok(());
+    }
+    let db = db(&settings)?;
+    let lock = |collection: &str| params::LockCollection {
+        user_id: HawkIdentifier::new_legacy(1),
+        collection: collection.to_owned(),
+    };
+
+    // The read transaction can't be escalated to take the write lock
+    db.lock_for_read_sync(lock("clients"))?;
+    assert!(db.lock_for_write_sync(lock("bookmarks")).is_err());
+    db.rollback_sync()?;
+
+    db.lock_for_write_sync(lock("bookmarks"))?;
+    db.lock_for_read_sync(lock("clients"))?;
+    db.rollback_sync()
+}
+
+#[test]
+fn latest_migration_version() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/db/sqlite/migrations");
+    let latest = std::fs::read_dir(dir)
+        .unwrap()
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            let version = name.split('_').next()?.replace('-', "");
+            version.parse::<i64>().ok()
+        })
+        .max();
+    assert_eq!(latest, Some(LATEST_MIGRATION_VERSION));
+}
@@ -0,0 +1,223 @@
+//! Tokenserver users, nodes and services.
+use diesel::{
+    sql_query,
+    sql_types::{BigInt, Double, Integer, Nullable, Text},
+    OptionalExtension, RunQueryDsl,
+};
+
+use super::models::{Result, SqliteDb};
+use crate::db::{params, results, DbErrorKind, NODE_RELEASE_RATE};
+
+#[derive(Debug, QueryableByName)]
+struct IdResult {
+    #[sql_type = "BigInt"]
+    id: i64,
+}
+
+pub fn post_service(db: &SqliteDb, params: params::PostService) -> Result<results::PostService> {
+    sql_query(
+        "INSERT OR IGNORE INTO services (service, pattern)
+         VALUES (?, ?)",
+    )
+    .bind::<Text, _>(&params.service)
+    .bind::<Text, _>(&params.pattern)
+    .execute(&db.conn)?;
+    let id = sql_query(
+        "SELECT id
+           FROM services
+          WHERE service = ?",
+    )
+    .bind::<Text, _>(&params.service)
+    .get_result::<IdResult>(&db.conn)?
+    .id;
+    Ok(id as i32)
+}
+
+pub fn post_node(db: &SqliteDb, params: params::PostNode) -> Result<results::PostNode> {
+    sql_query(
+        "INSERT OR IGNORE INTO nodes (service, node, available, current_load, capacity,
+                                      downed, backoff)
+         VALUES (?, ?, ?, 0, ?, 0, 0)",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.node)
+    .bind::<Integer, _>(params.capacity)
+    .bind::<Integer, _>(params.capacity)
+    .execute(&db.conn)?;
+    Ok(sql_query(
+        "SELECT id
+           FROM nodes
+          WHERE service = ?
+            AND node = ?",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.node)
+    .get_result::<IdResult>(&db.conn)?
+    .id)
+}
+
+fn least_loaded_node(db: &SqliteDb, service_id: i32) -> Result<Option<results::AllocateNode>> {
+    // Without LOG, order by the plain ratio: empty nodes still sort first.
+    // No FOR UPDATE either: the write transaction holds the database lock
+    Ok(sql_query(
+        "SELECT id, node
+           FROM nodes
+          WHERE service = ?
+            AND available > 0
+            AND capacity > current_load
+            AND downed = 0
+            AND backoff = 0
+          ORDER BY CAST(current_load AS REAL) / capacity
+          LIMIT 1",
+    )
+    .bind::<Integer, _>(service_id)
+    .get_result::<results::AllocateNode>(&db.conn)
+    .optional()?)
+}
+
+pub fn allocate_node(db: &SqliteDb, params: params::AllocateNode) -> Result<results::AllocateNode> {
+    let node = match least_loaded_node(db, params.service_id)? {
+        Some(node) => node,
+        None => {
+            // Every node's exhausted its available slots: release some more
+            // of their spare capacity and try again
+            let released = sql_query(
+                "UPDATE nodes
+                    SET available = MIN(CAST(capacity * ? AS INTEGER), capacity - current_load)
+                  WHERE service = ?
+                    AND available <= 0
+                    AND capacity > current_load
+                    AND downed = 0",
+            )
+            .bind::<Double, _>(NODE_RELEASE_RATE)
+            .bind::<Integer, _>(params.service_id)
+            .execute(&db.conn)?;
+            if released == 0 {
+                Err(DbErrorKind::NodeUnavailable)?
+            }
+            least_loaded_node(db, params.service_id)?.ok_or(DbErrorKind::NodeUnavailable)?
+        }
+    };
+
+    sql_query(
+        "UPDATE nodes
+            SET current_load = current_load + 1,
+                available = MAX(available - 1, 0)
+          WHERE id = ?",
+    )
+    .bind::<BigInt, _>(node.id)
+    .execute(&db.conn)?;
+    Ok(node)
+}
+
+pub fn get_users(db: &SqliteDb, params: params::GetUsers) -> Result<results::GetUsers> {
+    Ok(sql_query(
+        "SELECT users.uid, users.nodeid AS node_id, nodes.node, users.generation,
+                users.keys_changed_at, users.client_state, users.created_at,
+                users.replaced_at
+           FROM users
+           LEFT OUTER JOIN nodes
+             ON users.nodeid = nodes.id
+            AND nodes.downed = 0
+          WHERE users.email = ?
+            AND users.service = ?
+          ORDER BY users.created_at DESC, users.uid DESC
+          LIMIT 20",
+    )
+    .bind::<Text, _>(&params.email)
+    .bind::<Integer, _>(params.service_id)
+    .load::<results::TokenserverUser>(&db.conn)?)
+}
+
+pub fn post_user(db: &SqliteDb, params: params::PostUser) -> Result<results::PostUser> {
+    sql_query(
+        "INSERT INTO users (service, email, generation, client_state, created_at, replaced_at,
+                            nodeid, keys_changed_at)
+         VALUES (?, ?, ?, ?, ?, NULL, ?, ?)",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.email)
+    .bind::<BigInt, _>(params.generation)
+    .bind::<Text, _>(&params.client_state)
+    .bind::<BigInt, _>(params.created_at)
+    .bind::<BigInt, _>(params.node_id)
+    .bind::<Nullable<BigInt>, _>(params.keys_changed_at)
+    .execute(&db.conn)?;
+    Ok(sql_query("SELECT last_insert_rowid() AS id")
+        .get_result::<IdResult>(&db.conn)?
+        .id)
+}
+
+pub fn put_user(db: &SqliteDb, params: params::PutUser) -> Result<results::PutUser> {
+    // Multi-argument MAX is NULL when any argument is: COALESCE keeps
+    // whichever keys_changed_at is set
+    sql_query(
+        "UPDATE users
+            SET generation = MAX(generation, ?),
+                keys_changed_at = COALESCE(MAX(keys_changed_at, ?), keys_changed_at, ?)
+          WHERE service = ?
+            AND email = ?
+            AND replaced_at IS NULL",
+    )
+    .bind::<BigInt, _>(params.generation)
+    .bind::<Nullable<BigInt>, _>(params.keys_changed_at)
+    .bind::<Nullable<BigInt>, _>(params.keys_changed_at)
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.email)
+    .execute(&db.conn)?;
+    Ok(())
+}
+
+pub fn replace_users(db: &SqliteDb, params: params::ReplaceUsers) -> Result<results::ReplaceUsers> {
+    sql_query(
+        "UPDATE users
+            SET replaced_at = ?
+          WHERE service = ?
+            AND email = ?
+            AND replaced_at IS NULL
+            AND created_at < ?",
+    )
+    .bind::<BigInt, _>(params.replaced_at)
+    .bind::<Integer, _>(params.service_id)
+    .bind::<Text, _>(&params.email)
+    .bind::<BigInt, _>(params.replaced_at)
+    .execute(&db.conn)?;
+    Ok(())
+}
+
+pub fn get_replaced_users(
+    db: &SqliteDb,
+    params: params::GetReplacedUsers,
+) -> Result<results::GetReplacedUsers> {
+    Ok(sql_query(
+        "SELECT uid, email, generation, keys_changed_at, client_state, replaced_at
+           FROM users
+          WHERE service = ?
+            AND replaced_at IS NOT NULL
+            AND replaced_at < ?
+          ORDER BY replaced_at, uid
+          LIMIT ?",
+    )
+    .bind::<Integer, _>(params.service_id)
+    .bind::<BigInt, _>(params.replaced_before)
+    .bind::<BigInt, _>(params.limit)
+    .load::<results::ReplacedUser>(&db.conn)?)
+}
+
+pub fn delete_user(db: &SqliteDb, params: params::DeleteUser) -> Result<results::DeleteUser> {
+    sql_query(
+        "DELETE FROM users
+          WHERE uid = ?",
+    )
+    .bind::<BigInt, _>(params.uid)
+    .execute(&db.conn)?;
+    Ok(())
+}
+
+macro_rules! tokenserver_db_method {
+    ($name:ident, $tokenserver_name:ident, $type:ident) => {
+        pub fn $name(&self, params: params::$type) -> Result<results::$type> {
+            tokenserver::$tokenserver_name(self, params)
+        }
+    };
+}
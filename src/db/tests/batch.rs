@@ -4,6 +4,7 @@ use super::support::{db_pool, gbso, hid, pbso, postbso, test_db, Result};
 use crate::{
     db::{error::DbErrorKind, params, results, util::SyncTimestamp, BATCH_LIFETIME},
     error::ApiErrorKind,
+    settings::{CollectionLimits, Settings},
 };
 
 fn cb(user_id: u32, coll: &str, bsos: Vec<params::PostCollectionBso>) -> params::CreateBatch {
@@ -141,6 +142,7 @@ async fn append_commit() -> Result<()> {
             user_id: hid(uid),
             collection: coll.to_owned(),
             batch,
+            pending: Default::default(),
         })
         .await?;
 
@@ -163,13 +165,16 @@ async fn append_commit() -> Result<()> {
 
 #[tokio::test]
 async fn quota_test_create_batch() -> Result<()> {
-    let mut settings = crate::settings::test_settings();
+    let settings = crate::settings::test_settings();
 
     if !settings.enable_quota {
         debug!("[test] Skipping test");
         return Ok(());
     }
+    create_batch_over_quota(settings).await
+}
 
+async fn create_batch_over_quota(mut settings: Settings) -> Result<()> {
     let limit = 300;
     settings.limits.max_quota_limit = limit;
 
@@ -190,6 +195,7 @@ async fn quota_test_create_batch() -> Result<()> {
         user_id: hid(uid),
         collection: coll.to_owned(),
         batch,
+        pending: Default::default(),
     })
     .await?;
 
@@ -200,13 +206,16 @@ async fn quota_test_create_batch() -> Result<()> {
 
 #[tokio::test]
 async fn quota_test_append_batch() -> Result<()> {
-    let mut settings = crate::settings::test_settings();
+    let settings = crate::settings::test_settings();
 
     if !settings.enable_quota {
         debug!("[test] Skipping test");
         return Ok(());
     }
+    append_batch_over_quota(settings).await
+}
 
+async fn append_batch_over_quota(mut settings: Settings) -> Result<()> {
     let limit = 300;
     settings.limits.max_quota_limit = limit;
 
@@ -231,6 +240,7 @@ async fn quota_test_append_batch() -> Result<()> {
         user_id: hid(uid),
         collection: coll.to_owned(),
         batch,
+        pending: Default::default(),
     })
     .await?;
     let id2 = db.create_batch(cb(uid, coll, bsos2)).await?;
@@ -242,6 +252,81 @@ async fn quota_test_append_batch() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn quota_test_collection_overrides() -> Result<()> {
+    let settings = crate::settings::test_settings();
+
+    if !settings.enable_quota {
+        debug!("[test] Skipping test");
+        return Ok(());
+    }
+    collection_quota_overrides(settings).await
+}
+
+async fn collection_quota_overrides(mut settings: Settings) -> Result<()> {
+    let limit = 300;
+    settings.limits.max_quota_limit = limit;
+    // meta has no quota
+    settings.limits.collections.insert(
+        "meta".to_owned(),
+        CollectionLimits {
+            max_quota_limit: Some(0),
+            ..Default::default()
+        },
+    );
+
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let filler = (0..limit - 10).map(|_| "#").collect::<Vec<_>>().concat();
+    for coll in &["clients", "meta"] {
+        let bsos = vec![postbso("b0", Some(filler.as_ref()), None, None)];
+        let new_batch = db.create_batch(cb(uid, coll, bsos)).await?;
+        let batch = db.get_batch(gb(uid, coll, new_batch.id)).await?.unwrap();
+        db.commit_batch(params::CommitBatch {
+            user_id: hid(uid),
+            collection: (*coll).to_owned(),
+            batch,
+            pending: Default::default(),
+        })
+        .await?;
+    }
+
+    let bsos = vec![postbso("b1", Some(filler.as_ref()), None, None)];
+    assert!(db.create_batch(cb(uid, "clients", bsos)).await.is_err());
+    let bsos = vec![postbso("b1", Some(filler.as_ref()), None, None)];
+    assert!(db.create_batch(cb(uid, "meta", bsos)).await.is_ok());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_quota_test_batches() -> Result<()> {
+    let mut settings = crate::settings::test_settings();
+    if !settings.uses_sqlite() {
+        debug!("[test] Skipping test: SQLite only");
+        return Ok(());
+    }
+    settings.enable_quota = true;
+    create_batch_over_quota(settings.clone()).await?;
+    append_batch_over_quota(settings.clone()).await?;
+    collection_quota_overrides(settings).await
+}
+
+#[tokio::test]
+async fn postgres_quota_test_batches() -> Result<()> {
+    let mut settings = crate::settings::test_settings();
+    if !settings.uses_postgres() {
+        debug!("[test] Skipping test: PostgreSQL only");
+        return Ok(());
+    }
+    settings.enable_quota = true;
+    create_batch_over_quota(settings.clone()).await?;
+    append_batch_over_quota(settings.clone()).await?;
+    collection_quota_overrides(settings).await
+}
+
 #[tokio::test]
 async fn test_append_async_w_null() -> Result<()> {
     let settings = crate::settings::test_settings();
@@ -285,6 +370,7 @@ async fn test_append_async_w_null() -> Result<()> {
         user_id: hid(uid),
         collection: coll.to_owned(),
         batch,
+        pending: Default::default(),
     })
     .await?;
     let bso = db.get_bso(gbso(uid, coll, "b0")).await?.unwrap();
@@ -294,3 +380,174 @@ async fn test_append_async_w_null() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn batch_total_limits() -> Result<()> {
+    let mut settings = crate::settings::test_settings();
+    // clients may stage at most 3 records per batch
+    settings.limits.collections.insert(
+        "clients".to_owned(),
+        CollectionLimits {
+            max_total_records: Some(3),
+            ..Default::default()
+        },
+    );
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let coll = "clients";
+    let bsos = (0..4)
+        .map(|i| postbso(&format!("b{}", i), Some("payload"), None, None))
+        .collect();
+    let result = db.create_batch(cb(uid, coll, bsos)).await;
+    let is_too_large = match result.unwrap_err().kind() {
+        ApiErrorKind::Db(dbe) => matches!(dbe.kind(), DbErrorKind::BatchTooLarge),
+        _ => false,
+    };
+    assert!(is_too_large, "Expected BatchTooLarge");
+
+    let bsos = vec![
+        postbso("b0", Some("payload 0"), None, None),
+        postbso("b1", Some("payload 1"), None, None),
+    ];
+    let new_batch = db.create_batch(cb(uid, coll, bsos)).await?;
+    // re-appending a staged record doesn't count twice
+    let totals = db
+        .append_to_batch(ab(
+            uid,
+            coll,
+            new_batch.clone(),
+            vec![postbso("b1", Some("payload 1!"), None, None)],
+        ))
+        .await?;
+    assert_eq!(totals.records, 2);
+    assert_eq!(totals.bytes, 19);
+
+    let result = db
+        .append_to_batch(ab(
+            uid,
+            coll,
+            new_batch.clone(),
+            vec![
+                postbso("b2", Some("payload 2"), None, None),
+                postbso("b3", Some("payload 3"), None, None),
+            ],
+        ))
+        .await;
+    let is_too_large = match result.unwrap_err().kind() {
+        ApiErrorKind::Db(dbe) => matches!(dbe.kind(), DbErrorKind::BatchTooLarge),
+        _ => false,
+    };
+    assert!(is_too_large, "Expected BatchTooLarge");
+
+    // records written directly at commit time count towards the limit too
+    let batch = db
+        .get_batch(gb(uid, coll, new_batch.id.clone()))
+        .await?
+        .unwrap();
+    let result = db
+        .commit_batch(params::CommitBatch {
+            user_id: hid(uid),
+            collection: coll.to_owned(),
+            batch,
+            pending: results::BatchTotals {
+                records: 2,
+                bytes: 18,
+            },
+        })
+        .await;
+    assert!(result.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn get_items_delete() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let coll = "clients";
+    let bsos = vec![
+        postbso("b1", Some("payload 1"), None, None),
+        postbso("b0", Some("payload 0"), None, None),
+    ];
+    let new_batch = db.create_batch(cb(uid, coll, bsos)).await?;
+    let bsos = vec![
+        postbso("b2", Some("payload 2"), None, None),
+        postbso("b0", None, Some(5), None),
+    ];
+    db.append_to_batch(ab(uid, coll, new_batch.clone(), bsos))
+        .await?;
+
+    let gbi = || params::GetBatchItems {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        id: new_batch.id.clone(),
+    };
+    let items = db.get_batch_items(gbi()).await?.unwrap();
+    assert_eq!(items.count, 3);
+    assert_eq!(items.ids, vec!["b0", "b1", "b2"]);
+    assert_eq!(
+        items.expiry.as_i64(),
+        db.timestamp().as_i64() + BATCH_LIFETIME
+    );
+
+    db.delete_batch(params::DeleteBatch {
+        user_id: hid(uid),
+        collection: coll.to_owned(),
+        id: new_batch.id.clone(),
+    })
+    .await?;
+    assert!(db.get_batch_items(gbi()).await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn commit_across_collections() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = 1;
+    let colls = ["bookmarks", "meta"];
+    let mut batches = vec![];
+    for coll in &colls {
+        let bsos = vec![postbso("b0", Some("payload 0"), None, None)];
+        batches.push(db.create_batch(cb(uid, coll, bsos)).await?);
+    }
+
+    // Both collections are locked within the one transaction
+    for coll in &colls {
+        db.lock_for_write(params::LockCollection {
+            user_id: hid(uid),
+            collection: (*coll).to_owned(),
+        })
+        .await?;
+    }
+    let mut modified = vec![];
+    for (coll, new_batch) in colls.iter().zip(batches) {
+        let batch = db.get_batch(gb(uid, coll, new_batch.id)).await?.unwrap();
+        let result = db
+            .commit_batch(params::CommitBatch {
+                user_id: hid(uid),
+                collection: (*coll).to_owned(),
+                batch,
+                pending: Default::default(),
+            })
+            .await?;
+        modified.push(result.modified);
+    }
+    assert_eq!(modified[0], modified[1]);
+
+    for coll in &colls {
+        assert!(db.get_bso(gbso(uid, coll, "b0")).await?.is_some());
+        let ts = db
+            .get_collection_timestamp(params::GetCollectionTimestamp {
+                user_id: hid(uid),
+                collection: (*coll).to_owned(),
+            })
+            .await?;
+        assert_eq!(ts, modified[0]);
+    }
+    Ok(())
+}
@@ -5,9 +5,13 @@ use lazy_static::lazy_static;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
 use super::support::{db_pool, dbso, dbsos, gbso, gbsos, hid, pbso, postbso, test_db, Result};
-use crate::db::{mysql::models::DEFAULT_BSO_TTL, params, util::SyncTimestamp, Sorting};
-use crate::settings::test_settings;
-use crate::web::extractors::HawkIdentifier;
+use crate::db::{
+    archive, mysql::models::DEFAULT_BSO_TTL, params, pool_from_settings, results,
+    util::SyncTimestamp, Sorting,
+};
+use crate::server::metrics::Metrics;
+use crate::settings::{test_settings, Settings};
+use crate::web::extractors::{HawkIdentifier, Offset};
 
 // distant future (year 2099) timestamp for tests
 const MAX_TIMESTAMP: u64 = 4_070_937_600_000;
@@ -132,11 +136,11 @@ async fn get_bsos_limit_offset() -> Result<()> {
             0,
             Sorting::Index,
             0,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert!(bsos.items.is_empty());
-    assert_eq!(bsos.offset, Some("0".to_string()));
+    assert_eq!(bsos.offset, Some(Offset::default()));
 
     let bsos = db
         .get_bsos(gbsos(
@@ -147,7 +151,7 @@ async fn get_bsos_limit_offset() -> Result<()> {
             0,
             Sorting::Index,
             -1,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), size as usize);
@@ -155,7 +159,7 @@ async fn get_bsos_limit_offset() -> Result<()> {
 
     let newer = 0;
     let limit = 5;
-    let offset = "0".to_owned();
+    let offset = None;
     // XXX: validation?
     /*
     let bsos = db.get_bsos_sync(gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, Sorting::Index, -1, 0))?;
@@ -171,15 +175,12 @@ async fn get_bsos_limit_offset() -> Result<()> {
             newer,
             Sorting::Newest,
             limit,
-            &offset,
+            offset,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 5 as usize);
-    if let Some(ref offset) = bsos.offset {
-        if offset.chars().position(|c| c == ':').is_none() {
-            assert_eq!(offset, &"5".to_string());
-        }
-    }
+    let next = bsos.offset.clone().unwrap();
+    assert_eq!(next.offset, 5);
 
     assert_eq!(bsos.items[0].id, "11");
     assert_eq!(bsos.items[4].id, "7");
@@ -193,15 +194,12 @@ async fn get_bsos_limit_offset() -> Result<()> {
             newer,
             Sorting::Newest,
             limit,
-            &bsos.offset.unwrap(),
+            bsos.offset,
         ))
         .await?;
     assert_eq!(bsos2.items.len(), 5 as usize);
-    if let Some(ref offset) = bsos2.offset {
-        if offset.chars().position(|c| c == ':').is_none() {
-            assert_eq!(offset, &"10".to_owned());
-        }
-    }
+    let next = bsos2.offset.clone().unwrap();
+    assert_eq!(next.offset, 10);
     assert_eq!(bsos2.items[0].id, "6");
     assert_eq!(bsos2.items[4].id, "2");
 
@@ -214,7 +212,7 @@ async fn get_bsos_limit_offset() -> Result<()> {
             newer,
             Sorting::Newest,
             limit,
-            &bsos2.offset.unwrap(),
+            bsos2.offset,
         ))
         .await?;
     assert_eq!(bsos3.items.len(), 2 as usize);
@@ -224,6 +222,65 @@ async fn get_bsos_limit_offset() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_bsos_offset_survives_deletes() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    for i in 0..5 {
+        let bso = pbso(uid, coll, &i.to_string(), Some("a"), Some(i), None);
+        with_delta!(&db, i64::from(i) * 10, { db.put_bso(bso).await })?;
+    }
+
+    for sort in &[Sorting::Newest, Sorting::Index] {
+        let bsos = db
+            .get_bsos(gbsos(uid, coll, &[], MAX_TIMESTAMP, 0, *sort, 2, None))
+            .await?;
+        let ids: Vec<_> = bsos.items.iter().map(|bso| bso.id.as_str()).collect();
+        assert_eq!(ids, vec!["4", "3"]);
+
+        // A numeric offset would now skip "2"
+        let page1 = bsos.offset.unwrap();
+        with_delta!(&db, 100, { db.delete_bso(dbso(uid, coll, "4")).await })?;
+        let bsos = db
+            .get_bsos(gbsos(
+                uid,
+                coll,
+                &[],
+                MAX_TIMESTAMP,
+                0,
+                *sort,
+                2,
+                Some(page1),
+            ))
+            .await?;
+        let ids: Vec<_> = bsos.items.iter().map(|bso| bso.id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "1"]);
+
+        let bsos = db
+            .get_bsos(gbsos(
+                uid,
+                coll,
+                &[],
+                MAX_TIMESTAMP,
+                0,
+                *sort,
+                2,
+                bsos.offset,
+            ))
+            .await?;
+        let ids: Vec<_> = bsos.items.iter().map(|bso| bso.id.as_str()).collect();
+        assert_eq!(ids, vec!["0"]);
+        assert_eq!(bsos.offset, None);
+
+        let bso = pbso(uid, coll, "4", Some("a"), Some(4), None);
+        with_delta!(&db, 40, { db.put_bso(bso).await })?;
+    }
+    Ok(())
+}
+
 #[tokio::test]
 async fn get_bsos_newer() -> Result<()> {
     let pool = db_pool(None).await?;
@@ -254,7 +311,7 @@ async fn get_bsos_newer() -> Result<()> {
             timestamp as u64 - 30,
             Sorting::Newest,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 3);
@@ -271,7 +328,7 @@ async fn get_bsos_newer() -> Result<()> {
             timestamp as u64 - 20,
             Sorting::Newest,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 2);
@@ -287,7 +344,7 @@ async fn get_bsos_newer() -> Result<()> {
             timestamp as u64 - 10,
             Sorting::Newest,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 1);
@@ -302,7 +359,7 @@ async fn get_bsos_newer() -> Result<()> {
             timestamp as u64,
             Sorting::Newest,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 0);
@@ -338,7 +395,7 @@ async fn get_bsos_sort() -> Result<()> {
             0,
             Sorting::Newest,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 3);
@@ -355,7 +412,7 @@ async fn get_bsos_sort() -> Result<()> {
             0,
             Sorting::Oldest,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 3);
@@ -372,7 +429,7 @@ async fn get_bsos_sort() -> Result<()> {
             0,
             Sorting::Index,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 3);
@@ -670,7 +727,33 @@ async fn test_quota() -> Result<()> {
         return Ok(());
     }
 
-    let pool = db_pool(None).await?;
+    put_bso_over_quota(settings).await
+}
+
+#[tokio::test]
+async fn sqlite_test_quota() -> Result<()> {
+    let mut settings = crate::settings::test_settings();
+    if !settings.uses_sqlite() {
+        debug!("[test] Skipping test: SQLite only");
+        return Ok(());
+    }
+    settings.enable_quota = true;
+    put_bso_over_quota(settings).await
+}
+
+#[tokio::test]
+async fn postgres_test_quota() -> Result<()> {
+    let mut settings = crate::settings::test_settings();
+    if !settings.uses_postgres() {
+        debug!("[test] Skipping test: PostgreSQL only");
+        return Ok(());
+    }
+    settings.enable_quota = true;
+    put_bso_over_quota(settings).await
+}
+
+async fn put_bso_over_quota(settings: Settings) -> Result<()> {
+    let pool = db_pool(Some(settings)).await?;
     let mut db = test_db(pool.as_ref()).await?;
 
     let uid = 5;
@@ -855,6 +938,73 @@ async fn get_bso() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn stale_reads_see_own_writes() -> Result<()> {
+    let mut settings = test_settings();
+    if !settings.uses_spanner() {
+        debug!("[test] Skipping test: only Spanner reads stale snapshots");
+        return Ok(());
+    }
+    settings.spanner_exact_staleness = 10;
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    let bid = "b0";
+    db.allow_stale_reads(None);
+    db.put_bso(pbso(uid, coll, bid, Some("a"), None, None))
+        .await?;
+
+    // A session that's written keeps reading strongly
+    let bso = db.get_bso(gbso(uid, coll, bid)).await?.unwrap();
+    assert_eq!(bso.payload, "a");
+    Ok(())
+}
+
+#[tokio::test]
+async fn stale_reads_honor_if_modified_since() -> Result<()> {
+    let mut settings = test_settings();
+    if !settings.uses_spanner() {
+        debug!("[test] Skipping test: only Spanner reads stale snapshots");
+        return Ok(());
+    }
+    settings.spanner_exact_staleness = 10;
+    // The write must be visible to other sessions
+    settings.database_use_test_transactions = false;
+    let pool = pool_from_settings(&settings, &Metrics::noop()).await?;
+
+    let uid = thread_rng().gen_range(10_000, 20_000);
+    let coll = "clients";
+    let bid = "b0";
+    let db = test_db(pool.as_ref()).await?;
+    db.begin(true).await?;
+    let modified = db
+        .put_bso(pbso(uid, coll, bid, Some("a"), None, None))
+        .await?;
+    db.commit().await?;
+
+    // The snapshot predates the write
+    let db = test_db(pool.as_ref()).await?;
+    db.allow_stale_reads(None);
+    assert!(db.get_bso(gbso(uid, coll, bid)).await?.is_none());
+
+    // Unless the client's already seen it: then it's a strong read
+    let db = test_db(pool.as_ref()).await?;
+    db.allow_stale_reads(Some(modified));
+    let bso = db.get_bso(gbso(uid, coll, bid)).await?;
+
+    let cleanup = test_db(pool.as_ref()).await?;
+    cleanup.begin(true).await?;
+    cleanup
+        .delete_storage(params::DeleteStorage { user_id: hid(uid) })
+        .await?;
+    cleanup.commit().await?;
+
+    assert_eq!(bso.unwrap().payload, "a");
+    Ok(())
+}
+
 #[tokio::test]
 async fn get_bsos() -> Result<()> {
     let pool = db_pool(None).await?;
@@ -885,7 +1035,7 @@ async fn get_bsos() -> Result<()> {
             0,
             Sorting::Newest,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(ids.items, vec!["b0", "b1", "b2", "b3", "b4"]);
@@ -899,7 +1049,7 @@ async fn get_bsos() -> Result<()> {
             0,
             Sorting::Newest,
             10,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 3);
@@ -916,11 +1066,12 @@ async fn get_bsos() -> Result<()> {
             0,
             Sorting::Index,
             2,
-            &"0".to_owned(),
+            None,
         ))
         .await?;
     assert_eq!(bsos.items.len(), 2);
-    assert_eq!(bsos.offset, Some("2".to_string()));
+    let next = bsos.offset.clone().unwrap();
+    assert_eq!(next.offset, 2);
     assert_eq!(bsos.items[0].id, "b2");
     assert_eq!(bsos.items[1].id, "b1");
     Ok(())
@@ -1006,19 +1157,71 @@ async fn usage_stats() -> Result<()> {
 }
 
 #[tokio::test]
-async fn purge_expired() -> Result<()> {
+async fn optimize() -> Result<()> {
     let pool = db_pool(None).await?;
     let db = test_db(pool.as_ref()).await?;
     Ok(())
 }
+*/
 
 #[tokio::test]
-async fn optimize() -> Result<()> {
+async fn purge_expired() -> Result<()> {
     let pool = db_pool(None).await?;
     let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    let bso = pbso(uid, coll, "expired", Some("a"), None, Some(1));
+    with_delta!(db, -10_000, { db.put_bso(bso).await })?;
+    db.put_bso(pbso(uid, coll, "live", Some("b"), None, None))
+        .await?;
+
+    let purged = db
+        .purge_expired(params::PurgeExpired {
+            partition: 0,
+            partitions: 1,
+            limit: 1000,
+        })
+        .await?;
+    assert!(purged.bsos >= 1);
+    let bsos = db
+        .get_bsos(gbsos(
+            uid,
+            coll,
+            &[],
+            MAX_TIMESTAMP,
+            0,
+            Sorting::None,
+            10,
+            "0",
+        ))
+        .await?;
+    assert_eq!(bsos.items.len(), 1);
+    assert_eq!(bsos.items[0].id, "live");
     Ok(())
 }
-*/
+
+#[test]
+fn purge_expired_partitions() {
+    let partition = |partition| params::PurgeExpired {
+        partition,
+        partitions: 4,
+        limit: 1,
+    };
+    assert_eq!(partition(0).user_id_range(99), (0, 25));
+    assert_eq!(partition(3).user_id_range(99), (75, 100));
+    assert_eq!(partition(0).user_id_range(2), (0, 0));
+    assert_eq!(partition(3).user_id_range(2), (2, 3));
+    assert_eq!(
+        partition(0).fxa_uid_range(),
+        ("".to_owned(), Some("40000000".to_owned()))
+    );
+    assert_eq!(
+        partition(1).fxa_uid_range(),
+        ("40000000".to_owned(), Some("80000000".to_owned()))
+    );
+    assert_eq!(partition(3).fxa_uid_range(), ("c0000000".to_owned(), None));
+}
 
 #[tokio::test]
 async fn delete_storage() -> Result<()> {
@@ -1046,6 +1249,45 @@ async fn delete_storage() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn purge_storage() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let coll = "clients";
+    db.put_bso(pbso(uid, coll, "b0", Some("test"), None, None))
+        .await?;
+    db.put_bso(pbso(uid, coll, "b1", Some("test"), None, None))
+        .await?;
+
+    let expected = results::PurgeStorage {
+        bsos: 2,
+        user_collections: 1,
+        batches: 0,
+    };
+    let purged = db
+        .purge_storage(params::PurgeStorage {
+            user_id: hid(uid),
+            dry_run: true,
+        })
+        .await?;
+    assert_eq!(purged, expected);
+    assert!(db.get_bso(gbso(uid, coll, "b0")).await?.is_some());
+
+    let purged = db
+        .purge_storage(params::PurgeStorage {
+            user_id: hid(uid),
+            dry_run: false,
+        })
+        .await?;
+    assert_eq!(purged, expected);
+    assert!(db.get_bso(gbso(uid, coll, "b0")).await?.is_none());
+    let collections = db.get_collection_counts(hid(uid)).await?;
+    assert!(collections.is_empty());
+    Ok(())
+}
+
 #[tokio::test]
 async fn collection_cache() -> Result<()> {
     let pool = db_pool(None).await?;
@@ -1111,3 +1353,95 @@ async fn heartbeat() -> Result<()> {
     assert!(db.check().await?);
     Ok(())
 }
+
+#[tokio::test]
+async fn archive_round_trip() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let bsos = [
+        ("bookmarks", "b0", Some(1)),
+        ("bookmarks", "b1", None),
+        ("my_collection", "m0", Some(2)),
+    ];
+    for (i, (coll, bid, sortindex)) in bsos.iter().enumerate() {
+        with_delta!(db, i as i64 * 10_000, {
+            db.put_bso(pbso(uid, coll, bid, Some(bid), *sortindex, Some(3600)))
+                .await?;
+        });
+    }
+    let timestamps = db.get_collection_timestamps(hid(uid)).await?;
+    let mut expected = vec![];
+    for (coll, bid, _) in &bsos {
+        expected.push(db.get_bso(gbso(uid, coll, bid)).await?.unwrap());
+    }
+
+    let mut writer = archive::ArchiveWriter::new(vec![])?;
+    let exported = archive::export_user(db.as_ref(), &mut writer, &hid(uid)).await?;
+    assert_eq!(exported.collections, 2);
+    assert_eq!(exported.bsos, 3);
+    let data = writer.finish()?;
+
+    db.delete_storage(hid(uid)).await?;
+    let reader = archive::ArchiveReader::new(&data[..])?;
+    let imported = archive::import(db.as_ref(), reader, 1).await?;
+    assert_eq!(imported, exported);
+
+    assert_eq!(db.get_collection_timestamps(hid(uid)).await?, timestamps);
+    for ((coll, bid, _), expected) in bsos.iter().zip(expected) {
+        let bso = db.get_bso(gbso(uid, coll, bid)).await?.unwrap();
+        assert_eq!(bso.payload, expected.payload);
+        assert_eq!(bso.sortindex, expected.sortindex);
+        assert_eq!(bso.modified, expected.modified);
+        assert_eq!(bso.expiry, expected.expiry);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn copy_user() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let uid = *UID;
+    let to = uid + 1;
+    db.put_bso(pbso(
+        uid,
+        "clients",
+        "c0",
+        Some("clients"),
+        None,
+        Some(3600),
+    ))
+    .await?;
+    with_delta!(db, 10_000, {
+        db.put_bso(pbso(
+            uid,
+            "my_collection",
+            "m0",
+            Some("custom"),
+            Some(3),
+            None,
+        ))
+        .await?;
+    });
+
+    let copied = archive::copy_user(db.as_ref(), db.as_ref(), &hid(uid), &hid(to), 1).await?;
+    assert_eq!(copied.collections, 2);
+    assert_eq!(copied.bsos, 2);
+
+    assert_eq!(
+        db.get_collection_timestamps(hid(to)).await?,
+        db.get_collection_timestamps(hid(uid)).await?
+    );
+    for (coll, bid) in &[("clients", "c0"), ("my_collection", "m0")] {
+        let expected = db.get_bso(gbso(uid, coll, bid)).await?.unwrap();
+        let bso = db.get_bso(gbso(to, coll, bid)).await?.unwrap();
+        assert_eq!(bso.payload, expected.payload);
+        assert_eq!(bso.sortindex, expected.sortindex);
+        assert_eq!(bso.modified, expected.modified);
+        assert_eq!(bso.expiry, expected.expiry);
+    }
+    Ok(())
+}
@@ -6,3 +6,5 @@ pub mod support;
 pub mod batch;
 #[cfg(test)]
 mod db;
+#[cfg(test)]
+mod tokenserver;
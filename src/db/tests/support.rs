@@ -1,5 +1,3 @@
-use std::str::FromStr;
-
 use crate::db::DbPool;
 use crate::error::ApiResult;
 use crate::{
@@ -99,7 +97,7 @@ pub fn gbsos(
     newer: u64,
     sort: Sorting,
     limit: i64,
-    offset: &str,
+    offset: Option<Offset>,
 ) -> params::GetBsos {
     params::GetBsos {
         user_id: hid(user_id),
@@ -110,7 +108,7 @@ pub fn gbsos(
             newer: Some(SyncTimestamp::from_milliseconds(newer)),
             sort,
             limit: Some(limit as u32),
-            offset: Some(Offset::from_str(offset).unwrap_or_default()),
+            offset,
             full: true,
         },
     }
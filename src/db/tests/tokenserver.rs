@@ -0,0 +1,306 @@
+use super::support::{db_pool, gbso, pbso, test_db, Result};
+use crate::{
+    db::{error::DbErrorKind, params, Db},
+    error::ApiErrorKind,
+    settings::test_settings,
+    tokenserver::{
+        format_key_id,
+        purge::{purge_user, PurgeParams},
+        users::{get_or_create_user, UserParams},
+    },
+    web::extractors::HawkIdentifier,
+};
+
+async fn post_service(db: &dyn Db<'_>, service: &str) -> Result<i32> {
+    db.post_service(params::PostService {
+        service: service.to_owned(),
+        pattern: "{node}/1.5/{uid}".to_owned(),
+    })
+    .await
+}
+
+async fn post_node(db: &dyn Db<'_>, service_id: i32, node: &str, capacity: i32) -> Result<i64> {
+    db.post_node(params::PostNode {
+        service_id,
+        node: node.to_owned(),
+        capacity,
+    })
+    .await
+}
+
+fn pu(service_id: i32, email: &str, node_id: i64, client_state: &str, ts: i64) -> params::PostUser {
+    params::PostUser {
+        service_id,
+        email: email.to_owned(),
+        generation: 1,
+        keys_changed_at: Some(1),
+        client_state: client_state.to_owned(),
+        node_id,
+        created_at: ts,
+    }
+}
+
+fn gu(service_id: i32, email: &str) -> params::GetUsers {
+    params::GetUsers {
+        service_id,
+        email: email.to_owned(),
+    }
+}
+
+#[tokio::test]
+async fn post_service_and_node_idempotent() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let service_id = post_service(&db, "test-idempotent").await?;
+    assert_eq!(post_service(&db, "test-idempotent").await?, service_id);
+    assert_ne!(post_service(&db, "test-idempotent-2").await?, service_id);
+
+    let node_id = post_node(&db, service_id, "https://a.example.com", 10).await?;
+    assert_eq!(
+        post_node(&db, service_id, "https://a.example.com", 10).await?,
+        node_id
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn allocate_least_loaded_node() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let service_id = post_service(&db, "test-allocate").await?;
+    post_node(&db, service_id, "https://a.example.com", 10).await?;
+    post_node(&db, service_id, "https://b.example.com", 10).await?;
+
+    let an = params::AllocateNode { service_id };
+    let first = db.allocate_node(an.clone()).await?;
+    let second = db.allocate_node(an).await?;
+    assert_ne!(first.id, second.id);
+    assert_ne!(first.node, second.node);
+    Ok(())
+}
+
+#[tokio::test]
+async fn allocate_node_at_capacity() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let service_id = post_service(&db, "test-capacity").await?;
+    let node_id = post_node(&db, service_id, "https://a.example.com", 1).await?;
+
+    let an = params::AllocateNode { service_id };
+    assert_eq!(db.allocate_node(an.clone()).await?.id, node_id);
+    let result = db.allocate_node(an).await;
+    let is_node_unavailable = match result.unwrap_err().kind() {
+        ApiErrorKind::Db(dbe) => matches!(dbe.kind(), DbErrorKind::NodeUnavailable),
+        _ => false,
+    };
+    assert!(is_node_unavailable, "Expected NodeUnavailable");
+    Ok(())
+}
+
+#[tokio::test]
+async fn users_replaced() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let service_id = post_service(&db, "test-users").await?;
+    let node_id = post_node(&db, service_id, "https://a.example.com", 10).await?;
+    let email = "test@example.com";
+    assert!(db.get_users(gu(service_id, email)).await?.is_empty());
+
+    let uid1 = db
+        .post_user(pu(service_id, email, node_id, "aaaa", 1_000))
+        .await?;
+    let users = db.get_users(gu(service_id, email)).await?;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].uid, uid1);
+    assert_eq!(users[0].node.as_deref(), Some("https://a.example.com"));
+    assert_eq!(users[0].replaced_at, None);
+
+    let uid2 = db
+        .post_user(pu(service_id, email, node_id, "bbbb", 2_000))
+        .await?;
+    db.replace_users(params::ReplaceUsers {
+        service_id,
+        email: email.to_owned(),
+        replaced_at: 2_000,
+    })
+    .await?;
+
+    // Most recent record first, the previous one replaced
+    let users = db.get_users(gu(service_id, email)).await?;
+    assert_eq!(users.len(), 2);
+    assert_eq!(users[0].uid, uid2);
+    assert_eq!(users[0].client_state, "bbbb");
+    assert_eq!(users[0].replaced_at, None);
+    assert_eq!(users[1].uid, uid1);
+    assert_eq!(users[1].replaced_at, Some(2_000));
+    Ok(())
+}
+
+#[tokio::test]
+async fn put_user_never_regresses() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let service_id = post_service(&db, "test-put-user").await?;
+    let node_id = post_node(&db, service_id, "https://a.example.com", 10).await?;
+    let email = "test@example.com";
+    db.post_user(pu(service_id, email, node_id, "aaaa", 1_000))
+        .await?;
+
+    let put = |generation, keys_changed_at| params::PutUser {
+        service_id,
+        email: email.to_owned(),
+        generation,
+        keys_changed_at: Some(keys_changed_at),
+    };
+    db.put_user(put(5, 10)).await?;
+    db.put_user(put(3, 7)).await?;
+
+    let users = db.get_users(gu(service_id, email)).await?;
+    assert_eq!(users[0].generation, 5);
+    assert_eq!(users[0].keys_changed_at, Some(10));
+    Ok(())
+}
+
+#[tokio::test]
+async fn keys_changed_at_requires_new_client_state() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let service_id = post_service(&db, "test-keys-changed-at").await?;
+    post_node(&db, service_id, "https://a.example.com", 10).await?;
+    let up = |keys_changed_at, client_state: &str, timestamp| UserParams {
+        service_id,
+        email: "test@example.com".to_owned(),
+        generation: 1,
+        keys_changed_at,
+        client_state: client_state.to_owned(),
+        timestamp,
+    };
+    let user = get_or_create_user(db.as_ref(), up(1, "aaaa", 1_000)).await?;
+
+    let result = get_or_create_user(db.as_ref(), up(2, "aaaa", 2_000)).await;
+    let is_invalid = match result.unwrap_err().kind() {
+        ApiErrorKind::InvalidCredentials(reason) => reason == "invalid-keysChangedAt",
+        _ => false,
+    };
+    assert!(is_invalid, "Expected invalid-keysChangedAt");
+
+    // New keys along with the bump are fine
+    let replaced = get_or_create_user(db.as_ref(), up(2, "bbbb", 2_000)).await?;
+    assert_ne!(replaced.uid, user.uid);
+    assert_eq!(replaced.keys_changed_at, Some(2));
+    Ok(())
+}
+
+#[tokio::test]
+async fn replaced_users() -> Result<()> {
+    let pool = db_pool(None).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let service_id = post_service(&db, "test-replaced").await?;
+    let node_id = post_node(&db, service_id, "https://a.example.com", 10).await?;
+    let email = "test@example.com";
+    let uid1 = db
+        .post_user(pu(service_id, email, node_id, "aaaa", 1_000))
+        .await?;
+    let uid2 = db
+        .post_user(pu(service_id, email, node_id, "bbbb", 2_000))
+        .await?;
+    db.replace_users(params::ReplaceUsers {
+        service_id,
+        email: email.to_owned(),
+        replaced_at: 2_000,
+    })
+    .await?;
+
+    let gru = |replaced_before| params::GetReplacedUsers {
+        service_id,
+        replaced_before,
+        limit: 10,
+    };
+    assert!(db.get_replaced_users(gru(2_000)).await?.is_empty());
+    let replaced = db.get_replaced_users(gru(2_001)).await?;
+    assert_eq!(replaced.len(), 1);
+    assert_eq!(replaced[0].uid, uid1);
+    assert_eq!(replaced[0].email, email);
+    assert_eq!(replaced[0].client_state, "aaaa");
+    assert_eq!(replaced[0].keys_changed_at, Some(1));
+    assert_eq!(replaced[0].replaced_at, 2_000);
+
+    db.delete_user(params::DeleteUser { uid: uid1 }).await?;
+    assert!(db.get_replaced_users(gru(2_001)).await?.is_empty());
+    let users = db.get_users(gu(service_id, email)).await?;
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].uid, uid2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn purge_user_storage() -> Result<()> {
+    let settings = test_settings();
+    let storage_by_uid = !settings.uses_spanner();
+    let pool = db_pool(Some(settings)).await?;
+    let db = test_db(pool.as_ref()).await?;
+
+    let service_id = post_service(&db, "test-purge").await?;
+    let node_id = post_node(&db, service_id, "https://a.example.com", 10).await?;
+    let email = "test@example.com";
+    // A node reassignment: a new record (and uid) with the same keys
+    let uid1 = db
+        .post_user(pu(service_id, email, node_id, "aaaa", 1_000))
+        .await?;
+    db.post_user(pu(service_id, email, node_id, "aaaa", 2_000))
+        .await?;
+    db.replace_users(params::ReplaceUsers {
+        service_id,
+        email: email.to_owned(),
+        replaced_at: 2_000,
+    })
+    .await?;
+
+    let user_id = HawkIdentifier {
+        legacy_id: uid1 as u64,
+        fxa_uid: "test".to_owned(),
+        fxa_kid: format_key_id(1, "aaaa")?,
+    };
+    db.put_bso(params::PutBso {
+        user_id: user_id.clone(),
+        ..pbso(0, "clients", "b0", Some("payload"), None, None)
+    })
+    .await?;
+
+    let replaced = db
+        .get_replaced_users(params::GetReplacedUsers {
+            service_id,
+            replaced_before: 2_001,
+            limit: 10,
+        })
+        .await?;
+    assert_eq!(replaced.len(), 1);
+    let params = PurgeParams {
+        service_id,
+        grace_period: 0,
+        max_records: 10,
+        dry_run: false,
+        storage_by_uid,
+        timestamp: 2_001,
+    };
+    let purged = purge_user(db.as_ref(), &params, &replaced[0]).await?;
+
+    // Spanner storage is shared by both records (keyed by the unchanged
+    // fxa_kid), otherwise it belonged to the replaced uid alone
+    assert_eq!(purged.is_some(), storage_by_uid);
+    let bso = db
+        .get_bso(params::GetBso {
+            user_id,
+            ..gbso(0, "clients", "b0")
+        })
+        .await?;
+    assert_eq!(bso.is_none(), storage_by_uid);
+    Ok(())
+}
@@ -0,0 +1,170 @@
+//! Db wrapper tracing each call in a span (a child of the request's span).
+use async_trait::async_trait;
+
+use super::*;
+use crate::server::tracing::in_span;
+
+#[derive(Debug)]
+pub struct TracedDbPool {
+    pool: Box<dyn DbPool>,
+}
+
+impl TracedDbPool {
+    pub fn new(pool: Box<dyn DbPool>) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait(?Send)]
+impl DbPool for TracedDbPool {
+    async fn get<'a>(&'a self) -> ApiResult<Box<dyn Db<'a>>> {
+        let db = in_span("db.get", vec![], self.pool.get()).await?;
+        Ok(Box::new(TracedDb { db }) as Box<dyn Db<'a>>)
+    }
+
+    fn state(&self) -> results::PoolState {
+        self.pool.state()
+    }
+
+    fn validate_batch_id(&self, params: params::ValidateBatchId) -> Result<(), DbError> {
+        self.pool.validate_batch_id(params)
+    }
+
+    fn box_clone(&self) -> Box<dyn DbPool> {
+        Box::new(Self::new(self.pool.clone()))
+    }
+}
+
+#[derive(Debug)]
+pub struct TracedDb<'a> {
+    db: Box<dyn Db<'a>>,
+}
+
+macro_rules! traced_db_method {
+    ($name:ident, $type:ident) => {
+        traced_db_method!($name, $type, results::$type);
+    };
+    ($name:ident, $type:ident, $result:ty) => {
+        fn $name(&self, params: params::$type) -> DbFuture<'_, $result> {
+            Box::pin(in_span(
+                concat!("db.", stringify!($name)),
+                vec![],
+                self.db.$name(params),
+            ))
+        }
+    };
+}
+
+impl<'a> Db<'a> for TracedDb<'a> {
+    fn commit(&self) -> DbFuture<'_, ()> {
+        Box::pin(in_span("db.commit", vec![], self.db.commit()))
+    }
+
+    fn rollback(&self) -> DbFuture<'_, ()> {
+        Box::pin(in_span("db.rollback", vec![], self.db.rollback()))
+    }
+
+    fn begin(&self, for_write: bool) -> DbFuture<'_, ()> {
+        Box::pin(in_span("db.begin", vec![], self.db.begin(for_write)))
+    }
+
+    fn box_clone(&self) -> Box<dyn Db<'a>> {
+        Box::new(TracedDb {
+            db: self.db.box_clone(),
+        })
+    }
+
+    fn allow_stale_reads(&self, min_timestamp: Option<SyncTimestamp>) {
+        self.db.allow_stale_reads(min_timestamp)
+    }
+
+    fn check(&self) -> DbFuture<'_, results::Check> {
+        Box::pin(in_span("db.check", vec![], self.db.check()))
+    }
+
+    fn schema_version(&self) -> DbFuture<'_, results::SchemaVersion> {
+        Box::pin(in_span(
+            "db.schema_version",
+            vec![],
+            self.db.schema_version(),
+        ))
+    }
+
+    traced_db_method!(lock_for_read, LockCollection);
+    traced_db_method!(lock_for_write, LockCollection);
+    traced_db_method!(get_collection_timestamps, GetCollectionTimestamps);
+    traced_db_method!(get_collection_timestamp, GetCollectionTimestamp);
+    traced_db_method!(get_collection_counts, GetCollectionCounts);
+    traced_db_method!(get_collection_usage, GetCollectionUsage);
+    traced_db_method!(get_storage_timestamp, GetStorageTimestamp);
+    traced_db_method!(get_storage_usage, GetStorageUsage);
+    traced_db_method!(get_quota_usage, GetQuotaUsage);
+    traced_db_method!(delete_storage, DeleteStorage);
+    traced_db_method!(delete_collection, DeleteCollection);
+    traced_db_method!(delete_bsos, DeleteBsos);
+    traced_db_method!(get_bsos, GetBsos);
+
+    fn get_bsos_stream(&self, params: params::GetBsos) -> DbFuture<'_, results::GetBsosStream<'_>> {
+        Box::pin(in_span(
+            "db.get_bsos_stream",
+            vec![],
+            self.db.get_bsos_stream(params),
+        ))
+    }
+    traced_db_method!(get_bso_ids, GetBsoIds);
+    traced_db_method!(post_bsos, PostBsos);
+    traced_db_method!(delete_bso, DeleteBso);
+    traced_db_method!(get_bso, GetBso, Option<results::GetBso>);
+    traced_db_method!(get_bso_timestamp, GetBsoTimestamp);
+    traced_db_method!(put_bso, PutBso);
+    traced_db_method!(import_bsos, ImportBsos);
+    traced_db_method!(create_batch, CreateBatch);
+    traced_db_method!(validate_batch, ValidateBatch);
+    traced_db_method!(append_to_batch, AppendToBatch);
+    traced_db_method!(get_batch, GetBatch, Option<results::GetBatch>);
+    traced_db_method!(
+        get_batch_items,
+        GetBatchItems,
+        Option<results::GetBatchItems>
+    );
+    traced_db_method!(delete_batch, DeleteBatch);
+    traced_db_method!(commit_batch, CommitBatch);
+    traced_db_method!(purge_storage, PurgeStorage);
+    traced_db_method!(expire_batches, ExpireBatches);
+    traced_db_method!(purge_expired, PurgeExpired);
+    traced_db_method!(post_service, PostService);
+    traced_db_method!(post_node, PostNode);
+    traced_db_method!(allocate_node, AllocateNode);
+    traced_db_method!(get_users, GetUsers);
+    traced_db_method!(post_user, PostUser);
+    traced_db_method!(put_user, PutUser);
+    traced_db_method!(replace_users, ReplaceUsers);
+    traced_db_method!(get_replaced_users, GetReplacedUsers);
+    traced_db_method!(delete_user, DeleteUser);
+
+    traced_db_method!(get_collection_id, GetCollectionId);
+    #[cfg(test)]
+    traced_db_method!(create_collection, CreateCollection);
+    #[cfg(test)]
+    traced_db_method!(update_collection, UpdateCollection);
+
+    #[cfg(test)]
+    fn timestamp(&self) -> SyncTimestamp {
+        self.db.timestamp()
+    }
+
+    #[cfg(test)]
+    fn set_timestamp(&self, timestamp: SyncTimestamp) {
+        self.db.set_timestamp(timestamp)
+    }
+
+    #[cfg(test)]
+    fn clear_coll_cache(&self) {
+        self.db.clear_coll_cache()
+    }
+
+    #[cfg(test)]
+    fn set_quota(&mut self, enabled: bool, limit: usize) {
+        self.db.set_quota(enabled, limit)
+    }
+}
@@ -3,7 +3,8 @@ use crate::error::{ApiError, ApiErrorKind};
 use crate::server::metrics::Metrics;
 use crate::server::ServerState;
 use crate::web::extractors::{
-    BsoParam, CollectionParam, HawkIdentifier, PreConditionHeader, PreConditionHeaderOpt,
+    BatchParams, BsoParam, CollectionParam, HawkIdentifier, PreConditionHeader,
+    PreConditionHeaderOpt,
 };
 use crate::web::middleware::SyncServerRequest;
 use crate::web::tags::Tags;
@@ -12,7 +13,7 @@ use actix_http::http::{HeaderValue, Method, StatusCode};
 use actix_http::Error;
 use actix_web::dev::{Payload, PayloadStream};
 use actix_web::http::header;
-use actix_web::web::Data;
+use actix_web::web::{Data, Query};
 use actix_web::{FromRequest, HttpRequest, HttpResponse};
 use futures::future::LocalBoxFuture;
 use futures::FutureExt;
@@ -22,6 +23,7 @@ use std::future::Future;
 pub struct DbTransactionPool {
     pool: Box<dyn DbPool>,
     is_read: bool,
+    stale_reads: bool,
     tags: Tags,
     user_id: HawkIdentifier,
     collection: Option<String>,
@@ -46,6 +48,16 @@ impl DbTransactionPool {
         let db = self.pool.get().await?;
         let db2 = db.clone();
 
+        if self.stale_reads {
+            // Reads may be served from a slightly stale snapshot, but not
+            // one older than what the client has already seen
+            let min_timestamp = match self.precondition.opt {
+                Some(PreConditionHeader::IfModifiedSince(ts)) => Some(ts),
+                _ => None,
+            };
+            db.allow_stale_reads(min_timestamp);
+        }
+
         // Lock for transaction
         let result = match (self.get_lock_collection(), self.is_read) {
             (Some(lc), true) => db.lock_for_read(lc).await,
@@ -222,10 +234,16 @@ impl FromRequest for DbTransactionPool {
             let bso_opt = bso.map(|b| b.bso);
 
             let is_read = matches!(method, Method::GET | Method::HEAD);
+            // Pending batches are inspected right after being appended to,
+            // so they're always read from a strong snapshot
+            let stale_reads = is_read
+                && Query::<BatchParams>::from_query(req.query_string())
+                    .map_or(true, |params| params.batch.is_none());
             let precondition = PreConditionHeaderOpt::extrude(&req.headers(), Some(tags.clone()))?;
             let pool = Self {
                 pool: state.db_pool.clone(),
                 is_read,
+                stale_reads,
                 tags,
                 user_id,
                 collection,
@@ -68,9 +68,15 @@ pub enum ApiErrorKind {
     #[fail(display = "HAWK authentication error: {}", _0)]
     Hawk(#[cause] HawkError),
 
+    #[fail(display = "Invalid credentials: {}", _0)]
+    InvalidCredentials(String),
+
     #[fail(display = "No app_data ServerState")]
     NoServerState,
 
+    #[fail(display = "Rate limit exceeded, retry after {} seconds", _0)]
+    RateLimited(u64),
+
     #[fail(display = "{}", _0)]
     Internal(String),
 
@@ -83,6 +89,10 @@ impl ApiErrorKind {
         match self {
             ApiErrorKind::Db(err) => err.metric_label(),
             ApiErrorKind::Hawk(err) => err.metric_label(),
+            ApiErrorKind::InvalidCredentials(_) => {
+                Some("tokenserver.error.invalid_credentials".to_owned())
+            }
+            ApiErrorKind::RateLimited(_) => Some("storage.rate_limited".to_owned()),
             _ => None,
         }
     }
@@ -181,6 +191,7 @@ impl ApiError {
             },
             ApiErrorKind::Db(dber) => match dber.kind() {
                 DbErrorKind::Quota => WeaveError::OverQuota,
+                DbErrorKind::BatchTooLarge => WeaveError::SizeLimitExceeded,
                 _ => WeaveError::UnknownError,
             },
             _ => WeaveError::UnknownError,
@@ -231,10 +242,11 @@ impl From<Context<ApiErrorKind>> for ApiError {
     fn from(inner: Context<ApiErrorKind>) -> Self {
         let status = match inner.get_context() {
             ApiErrorKind::Db(error) => error.status,
-            ApiErrorKind::Hawk(_) => StatusCode::UNAUTHORIZED,
+            ApiErrorKind::Hawk(_) | ApiErrorKind::InvalidCredentials(_) => StatusCode::UNAUTHORIZED,
             ApiErrorKind::NoServerState | ApiErrorKind::Internal(_) => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
+            ApiErrorKind::RateLimited(_) => StatusCode::SERVICE_UNAVAILABLE,
             ApiErrorKind::Validation(error) => error.status,
         };
 
@@ -250,9 +262,14 @@ impl ResponseError for ApiError {
         // HttpResponse::build(self.status).json(self)
         //
         // So instead we translate our error to a backwards compatible one
+        let retry_after = match self.kind() {
+            ApiErrorKind::RateLimited(retry_after) => Some(*retry_after),
+            _ if self.is_conflict() => Some(u64::from(RETRY_AFTER)),
+            _ => None,
+        };
         HttpResponse::build(self.status)
-            .if_true(self.is_conflict(), |resp| {
-                resp.header("Retry-After", RETRY_AFTER.to_string());
+            .if_some(retry_after, |retry_after, resp| {
+                resp.header("Retry-After", retry_after.to_string());
             })
             .json(self.weave_error_code() as i32)
     }
@@ -289,9 +306,13 @@ impl Serialize for ApiErrorKind {
         match *self {
             ApiErrorKind::Db(ref error) => serialize_string_to_array(serializer, error),
             ApiErrorKind::Hawk(ref error) => serialize_string_to_array(serializer, error),
+            ApiErrorKind::InvalidCredentials(ref description) => {
+                serialize_string_to_array(serializer, description)
+            }
             ApiErrorKind::Internal(ref description) => {
                 serialize_string_to_array(serializer, description)
             }
+            ApiErrorKind::RateLimited(_) => serialize_string_to_array(serializer, self),
             ApiErrorKind::Validation(ref error) => Serialize::serialize(error, serializer),
             ApiErrorKind::NoServerState => {
                 Serialize::serialize("No State information found", serializer)
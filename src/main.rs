@@ -8,10 +8,20 @@ use docopt::Docopt;
 use serde_derive::Deserialize;
 
 use logging::init_logging;
-use syncstorage::{logging, server, settings};
+use syncstorage::{
+    db, logging, server,
+    server::metrics::{metrics_from_opts, Metrics},
+    server::tracing::init_tracing,
+    settings,
+};
 
 const USAGE: &str = "
-Usage: syncstorage [options]
+Usage:
+    syncstorage [options]
+    syncstorage migrate [options]
+
+Commands:
+    migrate                  Apply any pending database migrations and exit.
 
 Options:
     -h, --help               Show this message.
@@ -20,9 +30,24 @@ Options:
 
 #[derive(Debug, Deserialize)]
 struct Args {
+    cmd_migrate: bool,
     flag_config: Option<String>,
 }
 
+/// Apply any pending migrations, returning the resulting schema version
+async fn migrate(settings: &settings::Settings) -> Result<i64, Box<dyn Error>> {
+    let mut settings = settings.clone();
+    settings.database_auto_migrate = true;
+    let metrics = Metrics::from(&metrics_from_opts(&settings).map_err(|e| e.to_string())?);
+    let pool = db::pool_from_settings(&settings, &metrics)
+        .await
+        .map_err(|e| e.to_string())?;
+    let version = db::check_schema_version(pool.as_ref())
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(version.current)
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args: Args = Docopt::new(USAGE)
@@ -30,6 +55,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .unwrap_or_else(|e| e.exit());
     let settings = settings::Settings::with_env_and_config_file(&args.flag_config)?;
     init_logging(!settings.human_logs).expect("Logging failed to initialize");
+    if args.cmd_migrate {
+        let version = migrate(&settings).await?;
+        info!("Migrations complete, schema version: {}", version);
+        logging::reset_logging();
+        return Ok(());
+    }
     debug!("Starting up...");
     // Set SENTRY_DSN environment variable to enable Sentry.
     // Avoid its default reqwest transport for now due to issues w/
@@ -44,6 +75,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
         release: sentry::release_name!(),
         ..sentry::ClientOptions::default()
     });
+    // Set SYNC_TRACING__EXPORTER to enable OpenTelemetry tracing.
+    let _tracing = init_tracing(&settings.tracing).map_err(|e| e.to_string())?;
 
     // Setup and run the server
     let banner = settings.banner();
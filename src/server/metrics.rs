@@ -1,12 +1,19 @@
+use std::collections::HashMap;
+use std::io;
 use std::net::UdpSocket;
+use std::sync::Arc;
 use std::time::Instant;
 
 use actix_web::{error::ErrorInternalServerError, web::Data, Error, HttpRequest};
 use cadence::{
-    BufferedUdpMetricSink, Counted, Metric, NopMetricSink, QueuingMetricSink, StatsdClient, Timed,
+    BufferedUdpMetricSink, Counted, Metric, MetricSink, NopMetricSink, QueuingMetricSink,
+    StatsdClient, StatsdClientBuilder, Timed,
+};
+use prometheus::{
+    CounterVec, Encoder, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry, TextEncoder,
 };
 
-use crate::error::ApiError;
+use crate::error::{ApiError, ApiErrorKind};
 use crate::server::ServerState;
 use crate::settings::Settings;
 use crate::web::tags::Tags;
@@ -126,8 +133,17 @@ impl Metrics {
     }
 
     pub fn incr_with_tags(&self, label: &str, tags: Option<Tags>) {
+        self.count_with_tags(label, 1, tags)
+    }
+
+    // add to a counter with no tags data.
+    pub fn count(&self, label: &str, count: i64) {
+        self.count_with_tags(label, count, None)
+    }
+
+    pub fn count_with_tags(&self, label: &str, count: i64, tags: Option<Tags>) {
         if let Some(client) = self.client.as_ref() {
-            let mut tagged = client.incr_with_tags(label);
+            let mut tagged = client.count_with_tags(label, count);
             let mut mtags = self.tags.clone().unwrap_or_default();
             if let Some(tags) = tags {
                 mtags.extend(tags.tags);
@@ -137,8 +153,6 @@ impl Metrics {
                     tagged = tagged.with_tag(&key, val.as_ref());
                 }
             }
-            // Include any "hard coded" tags.
-            // incr = incr.with_tag("version", env!("CARGO_PKG_VERSION"));
             match tagged.try_send() {
                 Err(e) => {
                     // eat the metric, but log the error
@@ -161,6 +175,35 @@ pub fn metrics_from_req(req: &HttpRequest) -> Result<Box<StatsdClient>, Error> {
 
 /// Create a cadence StatsdClient from the given options
 pub fn metrics_from_opts(opts: &Settings) -> Result<StatsdClient, ApiError> {
+    metrics_from_opts_with_prometheus(opts, None)
+}
+
+/// Create a cadence StatsdClient from the given options, also recording
+/// everything it sends to `prometheus` (if any)
+pub fn metrics_from_opts_with_prometheus(
+    opts: &Settings,
+    prometheus: Option<&PrometheusMetrics>,
+) -> Result<StatsdClient, ApiError> {
+    fn builder<T>(
+        prefix: &str,
+        sink: T,
+        prometheus: Option<&PrometheusMetrics>,
+    ) -> StatsdClientBuilder
+    where
+        T: MetricSink + Sync + Send + std::panic::RefUnwindSafe + 'static,
+    {
+        match prometheus {
+            Some(prometheus) => StatsdClient::builder(
+                prefix,
+                PrometheusSink {
+                    inner: sink,
+                    prometheus: prometheus.clone(),
+                },
+            ),
+            None => StatsdClient::builder(prefix, sink),
+        }
+    }
+
     let builder = if let Some(statsd_host) = opts.statsd_host.as_ref() {
         let socket = UdpSocket::bind("0.0.0.0:0")?;
         socket.set_nonblocking(true)?;
@@ -168,9 +211,9 @@ pub fn metrics_from_opts(opts: &Settings) -> Result<StatsdClient, ApiError> {
         let host = (statsd_host.as_str(), opts.statsd_port);
         let udp_sink = BufferedUdpMetricSink::from(host, socket)?;
         let sink = QueuingMetricSink::from(udp_sink);
-        StatsdClient::builder(opts.statsd_label.as_ref(), sink)
+        builder(opts.statsd_label.as_ref(), sink, prometheus)
     } else {
-        StatsdClient::builder(opts.statsd_label.as_ref(), NopMetricSink)
+        builder(opts.statsd_label.as_ref(), NopMetricSink, prometheus)
     };
     Ok(builder
         .with_error_handler(|err| {
@@ -179,10 +222,438 @@ pub fn metrics_from_opts(opts: &Settings) -> Result<StatsdClient, ApiError> {
         .build())
 }
 
+/// Buckets of the timer histograms (timers are in milliseconds)
+const TIMER_BUCKETS: &[f64] = &[
+    1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+];
+
+#[derive(Clone)]
+enum Collector {
+    Counter(CounterVec),
+    Gauge(GaugeVec),
+    Histogram(HistogramVec),
+}
+
+/// The Prometheus metric type mirroring a statsd one
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    /// statsd counters
+    Counter,
+    /// statsd gauges
+    Gauge,
+    /// statsd timers (and histograms)
+    Histogram,
+}
+
+/// The tags of request scoped metrics (see `Tags::from_request_head`), less
+/// the browser and OS versions: too many distinct values for labels
+const REQUEST: &[&str] = &["ua_browser_family", "ua_name", "ua_os_family", "uri_method"];
+
+/// `REQUEST` along with the collection
+const REQUEST_COLLECTION: &[&str] = &[
+    "collection",
+    "ua_browser_family",
+    "ua_name",
+    "ua_os_family",
+    "uri_method",
+];
+
+/// The metrics mirrored to Prometheus: their statsd names (less the prefix),
+/// types and label names. Tags outside of a metric's labels are dropped and
+/// any missing left empty, while metrics not declared here aren't mirrored.
+const METRICS: &[(&str, Kind, &[&str])] = &[
+    ("admin.delete_batches", Kind::Counter, REQUEST),
+    ("admin.delete_storage", Kind::Counter, REQUEST),
+    ("admin.get_collection", Kind::Counter, REQUEST),
+    ("admin.get_collections", Kind::Counter, REQUEST),
+    ("error.rejectua", Kind::Counter, REQUEST),
+    ("error.tooManyMutations", Kind::Counter, REQUEST),
+    ("error.tooMuchData", Kind::Counter, REQUEST),
+    ("request.delete_all", Kind::Counter, REQUEST),
+    ("request.delete_bso", Kind::Counter, REQUEST),
+    ("request.delete_bsos", Kind::Counter, REQUEST),
+    ("request.delete_collection", Kind::Counter, REQUEST),
+    ("request.delete_collection_batch", Kind::Counter, REQUEST),
+    ("request.error.db.conflict", Kind::Counter, REQUEST),
+    ("request.error.hawk.decode_error", Kind::Counter, REQUEST),
+    ("request.error.hawk.expired", Kind::Counter, REQUEST),
+    ("request.error.hawk.header", Kind::Counter, REQUEST),
+    ("request.error.hawk.hmac", Kind::Counter, REQUEST),
+    ("request.error.hawk.id_too_short", Kind::Counter, REQUEST),
+    ("request.error.hawk.invalid_header", Kind::Counter, REQUEST),
+    ("request.error.hawk.invalid_json", Kind::Counter, REQUEST),
+    ("request.error.hawk.missing_header", Kind::Counter, REQUEST),
+    ("request.error.hawk.missing_id", Kind::Counter, REQUEST),
+    ("request.error.hawk.missing_prefix", Kind::Counter, REQUEST),
+    ("request.error.hawk.parse_error", Kind::Counter, REQUEST),
+    ("request.error.invalid_content_type", Kind::Counter, REQUEST),
+    ("request.error.invalid_json", Kind::Counter, REQUEST),
+    ("request.get_bso", Kind::Counter, REQUEST),
+    ("request.get_collection", Kind::Counter, REQUEST),
+    ("request.get_collection_batch", Kind::Counter, REQUEST),
+    ("request.get_collection_counts", Kind::Counter, REQUEST),
+    ("request.get_collection_usage", Kind::Counter, REQUEST),
+    ("request.get_collections", Kind::Counter, REQUEST),
+    ("request.get_quota", Kind::Counter, REQUEST),
+    ("request.post_collection", Kind::Counter, REQUEST),
+    ("request.post_collection_batch", Kind::Counter, REQUEST),
+    ("request.post_storage_batch", Kind::Counter, REQUEST),
+    ("request.put_bso", Kind::Counter, REQUEST),
+    (
+        "self.storage.delete_bsos",
+        Kind::Counter,
+        REQUEST_COLLECTION,
+    ),
+    ("storage.confict", Kind::Counter, REQUEST),
+    (
+        "storage.pool.connections.active",
+        Kind::Gauge,
+        &["hostname"],
+    ),
+    ("storage.pool.connections.idle", Kind::Gauge, &["hostname"]),
+    ("storage.pool.grpc_auth", Kind::Histogram, REQUEST),
+    ("storage.quota.at_limit", Kind::Counter, REQUEST_COLLECTION),
+    (
+        "storage.quota.init_totals",
+        Kind::Histogram,
+        REQUEST_COLLECTION,
+    ),
+    (
+        "storage.quota.update_existing_totals",
+        Kind::Histogram,
+        REQUEST,
+    ),
+    ("storage.rate_limited", Kind::Counter, REQUEST_COLLECTION),
+    ("storage.reaper.batches", Kind::Counter, REQUEST),
+    ("storage.reaper.bsos", Kind::Counter, REQUEST),
+    ("storage.reaper.duration", Kind::Histogram, REQUEST),
+    ("storage.reaper.error", Kind::Counter, REQUEST),
+    (
+        "storage.spanner.adding_updates_to_batch_bsos",
+        Kind::Counter,
+        REQUEST,
+    ),
+    (
+        "storage.spanner.append_items_to_batch",
+        Kind::Histogram,
+        REQUEST,
+    ),
+    ("storage.spanner.apply_batch", Kind::Histogram, REQUEST),
+    (
+        "storage.spanner.apply_batch_insert",
+        Kind::Histogram,
+        REQUEST,
+    ),
+    (
+        "storage.spanner.apply_batch_update",
+        Kind::Histogram,
+        REQUEST,
+    ),
+    ("storage.spanner.delete_bso", Kind::Counter, REQUEST),
+    (
+        "storage.spanner.delete_collection",
+        Kind::Counter,
+        REQUEST_COLLECTION,
+    ),
+    (
+        "storage.spanner.stale_read.fallback",
+        Kind::Counter,
+        REQUEST,
+    ),
+    ("sync.error.collectionParam", Kind::Counter, REQUEST),
+    (
+        "tokenserver.error.invalid_credentials",
+        Kind::Counter,
+        REQUEST,
+    ),
+    ("tokenserver.get", Kind::Histogram, REQUEST),
+    ("tokenserver.purge.batches", Kind::Counter, &["dry_run"]),
+    ("tokenserver.purge.bsos", Kind::Counter, &["dry_run"]),
+    ("tokenserver.purge.records", Kind::Counter, &["dry_run"]),
+    ("tokenserver.purge.skipped", Kind::Counter, &["dry_run"]),
+    (
+        "tokenserver.purge.user_collections",
+        Kind::Counter,
+        &["dry_run"],
+    ),
+    ("tokenserver.token_issued", Kind::Counter, REQUEST),
+];
+
+/// Prometheus counters, gauges and histograms mirroring the statsd counters,
+/// gauges and timers (of the same names and tags) emitted via the
+/// `StatsdClient`, for the `/__metrics__` endpoint.
+///
+/// The metrics (see `METRICS`) are all registered upfront: recording one is
+/// then a lookup of its (thread-safe) collector.
+#[derive(Clone)]
+pub struct PrometheusMetrics {
+    registry: Registry,
+    /// The collector and label names of each metric, by its statsd name
+    /// (including the prefix)
+    collectors: Arc<HashMap<String, (Collector, &'static [&'static str])>>,
+}
+
+impl std::fmt::Debug for PrometheusMetrics {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("PrometheusMetrics").finish()
+    }
+}
+
+/// Replace the characters Prometheus disallows in names
+fn prometheus_name(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+impl PrometheusMetrics {
+    /// Register the `METRICS` emitted with the statsd `prefix`
+    pub fn new(prefix: &str) -> Self {
+        let registry = Registry::new();
+        let mut collectors = HashMap::new();
+        for (name, kind, labels) in METRICS {
+            let statsd_name = if prefix.is_empty() {
+                (*name).to_owned()
+            } else {
+                format!("{}.{}", prefix, name)
+            };
+            let name = prometheus_name(&statsd_name);
+            let help = format!("statsd metric {}", statsd_name);
+            let collector = match kind {
+                Kind::Counter => {
+                    CounterVec::new(Opts::new(&name, &help), labels).map(Collector::Counter)
+                }
+                Kind::Gauge => GaugeVec::new(Opts::new(&name, &help), labels).map(Collector::Gauge),
+                Kind::Histogram => HistogramVec::new(
+                    HistogramOpts::new(&name, &help).buckets(TIMER_BUCKETS.to_vec()),
+                    labels,
+                )
+                .map(Collector::Histogram),
+            };
+            let registered = collector.and_then(|collector| {
+                match &collector {
+                    Collector::Counter(c) => registry.register(Box::new(c.clone())),
+                    Collector::Gauge(g) => registry.register(Box::new(g.clone())),
+                    Collector::Histogram(h) => registry.register(Box::new(h.clone())),
+                }
+                .map(|_| collector)
+            });
+            match registered {
+                Ok(collector) => {
+                    collectors.insert(statsd_name, (collector, *labels));
+                }
+                Err(e) => warn!("⚠️ Prometheus metric {} error: {:?}", name, e),
+            }
+        }
+        Self {
+            registry,
+            collectors: Arc::new(collectors),
+        }
+    }
+
+    /// Record a statsd line, e.g. `syncstorage.request.get_bso:1|c|#tag:value`
+    pub fn record(&self, line: &str) {
+        let mut parts = line.split('|');
+        let (name, value) = match parts.next().and_then(|metric| {
+            let colon = metric.rfind(':')?;
+            Some((&metric[..colon], metric[colon + 1..].parse::<f64>().ok()?))
+        }) {
+            Some(metric) => metric,
+            None => return,
+        };
+        let (collector, label_names) = match self.collectors.get(name) {
+            Some(collector) => collector,
+            None => {
+                trace!("Prometheus metric {} not declared", name);
+                return;
+            }
+        };
+        let kind = parts.next().unwrap_or_default();
+        let tags: HashMap<String, &str> = parts
+            .find(|part| part.starts_with('#'))
+            .map(|tags| {
+                tags[1..]
+                    .split(',')
+                    .filter(|tag| !tag.is_empty())
+                    .map(|tag| {
+                        let mut tag = tag.splitn(2, ':');
+                        let key = tag.next().unwrap_or_default();
+                        (prometheus_name(key), tag.next().unwrap_or_default())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let values: Vec<_> = label_names
+            .iter()
+            .map(|label| tags.get(*label).copied().unwrap_or_default())
+            .collect();
+        match (collector, kind) {
+            // Prometheus counters can't be decremented
+            (Collector::Counter(c), "c") if value >= 0.0 => {
+                c.with_label_values(&values).inc_by(value)
+            }
+            (Collector::Gauge(g), "g") => g.with_label_values(&values).set(value),
+            (Collector::Histogram(h), "ms") | (Collector::Histogram(h), "h") => {
+                h.with_label_values(&values).observe(value)
+            }
+            _ => warn!("⚠️ Prometheus metric {} not recorded: {:?}", name, line),
+        }
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> Result<String, ApiError> {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| ApiErrorKind::Internal(format!("Prometheus encode error: {}", e)))?;
+        String::from_utf8(buffer)
+            .map_err(|e| ApiErrorKind::Internal(format!("Prometheus encode error: {}", e)).into())
+    }
+}
+
+/// Sends the metrics on to another sink after recording them for Prometheus
+struct PrometheusSink<T: MetricSink> {
+    inner: T,
+    prometheus: PrometheusMetrics,
+}
+
+impl<T: MetricSink> MetricSink for PrometheusSink<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.prometheus.record(metric);
+        self.inner.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn prometheus_mirrors_statsd() {
+        use cadence::Gauged;
+
+        let prometheus = PrometheusMetrics::new("syncstorage");
+        let client = StatsdClient::builder(
+            "syncstorage",
+            PrometheusSink {
+                inner: NopMetricSink,
+                prometheus: prometheus.clone(),
+            },
+        )
+        .build();
+        let mut tags = Tags::default();
+        tags.tags.insert("ua.name".to_owned(), "Firefox".to_owned());
+        tags.tags
+            .insert("ua.browser.ver".to_owned(), "72.0".to_owned());
+        let metrics = Metrics {
+            client: Some(client.clone()),
+            tags: Some(tags),
+            timer: None,
+        };
+        metrics.incr("request.get_bso");
+        metrics.incr("request.undeclared");
+        metrics.count("storage.reaper.bsos", 3);
+        {
+            let mut metrics = Metrics::from(&client);
+            metrics.start_timer("storage.quota.update_existing_totals", None);
+        }
+        client.gauge("storage.pool.connections.idle", 2).unwrap();
+
+        // A metric first emitted without tags still records them later
+        let untagged = Metrics::from(&client);
+        untagged.incr("storage.quota.at_limit");
+        let mut tags = Tags::default();
+        tags.tags.insert("ua.name".to_owned(), "Firefox".to_owned());
+        tags.tags
+            .insert("collection".to_owned(), "bookmarks".to_owned());
+        untagged.incr_with_tags("storage.quota.at_limit", Some(tags));
+
+        let rendered = prometheus.render().unwrap();
+        assert_eq!(
+            sample(
+                &rendered,
+                "syncstorage_request_get_bso",
+                &[r#"ua_name="Firefox""#]
+            ),
+            Some("1")
+        );
+        assert_eq!(
+            sample(
+                &rendered,
+                "syncstorage_storage_reaper_bsos",
+                &[r#"ua_name="Firefox""#]
+            ),
+            Some("3")
+        );
+        assert_eq!(
+            sample(
+                &rendered,
+                "syncstorage_storage_quota_update_existing_totals_count",
+                &[]
+            ),
+            Some("1")
+        );
+        assert_eq!(
+            sample(
+                &rendered,
+                "syncstorage_storage_pool_connections_idle",
+                &[r#"hostname="""#]
+            ),
+            Some("2")
+        );
+        assert_eq!(
+            sample(
+                &rendered,
+                "syncstorage_storage_quota_at_limit",
+                &[r#"collection="""#, r#"ua_name="""#]
+            ),
+            Some("1")
+        );
+        assert_eq!(
+            sample(
+                &rendered,
+                "syncstorage_storage_quota_at_limit",
+                &[r#"collection="bookmarks""#, r#"ua_name="Firefox""#]
+            ),
+            Some("1")
+        );
+        assert!(!rendered.contains("ua_browser_ver"));
+        assert!(!rendered.contains("undeclared"));
+    }
+
+    /// The value of the rendered sample of `name` with all of `labels`
+    fn sample<'a>(rendered: &'a str, name: &str, labels: &[&str]) -> Option<&'a str> {
+        rendered.lines().find_map(|line| {
+            let (series, value) = {
+                let space = line.rfind(' ')?;
+                (&line[..space], &line[space + 1..])
+            };
+            let series_labels = series.strip_prefix(name)?;
+            if !(series_labels.is_empty() || series_labels.starts_with('{')) {
+                return None;
+            }
+            let series_labels: Vec<_> = series_labels
+                .trim_matches(|c| c == '{' || c == '}')
+                .split(',')
+                .collect();
+            if labels.iter().all(|label| series_labels.contains(label)) {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+
     #[test]
     fn test_tags() {
         use actix_web::dev::RequestHead;
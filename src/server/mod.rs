@@ -9,20 +9,26 @@ use actix_web::{
 };
 use cadence::StatsdClient;
 
-use crate::db::{pool_from_settings, spawn_pool_periodic_reporter, DbPool};
+use crate::db::{
+    check_schema_version, pool_from_settings, spawn_expiry_reaper, spawn_pool_periodic_reporter,
+    DbPool,
+};
 use crate::error::ApiError;
-use crate::server::metrics::Metrics;
+use crate::server::metrics::{Metrics, PrometheusMetrics};
 use crate::settings::{Secrets, ServerLimits, Settings};
-use crate::web::{handlers, middleware, tokenserver};
+use crate::tokenserver::TokenserverState;
+use crate::web::{admin, handlers, middleware, middleware::ratelimit::RateLimiter, tokenserver};
 
 pub const BSO_ID_REGEX: &str = r"[ -~]{1,64}";
 pub const COLLECTION_ID_REGEX: &str = r"[a-zA-Z0-9._-]{1,32}";
 const MYSQL_UID_REGEX: &str = r"[0-9]{1,10}";
 const SYNC_VERSION_PATH: &str = "1.5";
+const ADMIN_PATH: &str = "__admin__";
 
 pub mod metrics;
 #[cfg(test)]
 mod test;
+pub mod tracing;
 pub mod user_agent;
 
 /// This is the global HTTP state object that will be made available to all
@@ -45,6 +51,18 @@ pub struct ServerState {
     pub port: u16,
 
     pub quota_enabled: bool,
+
+    /// The built-in Tokenserver, when enabled.
+    pub tokenserver: Option<TokenserverState>,
+
+    /// Secret guarding the admin API, when enabled.
+    pub admin_secret: Option<String>,
+
+    /// The metrics served at `/__metrics__`, when enabled.
+    pub prometheus: Option<PrometheusMetrics>,
+
+    /// Per-user limiter of writes, when enabled.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 pub fn cfg_path(path: &str) -> String {
@@ -57,6 +75,15 @@ pub fn cfg_path(path: &str) -> String {
     format!("/{}/{{uid:{}}}{}", SYNC_VERSION_PATH, MYSQL_UID_REGEX, path)
 }
 
+/// Path of an admin API resource, e.g. `/__admin__/{uid}/collections`
+pub fn admin_path(path: &str) -> String {
+    let path = path.replace(
+        "{collection}",
+        &format!("{{collection:{}}}", COLLECTION_ID_REGEX),
+    );
+    format!("/{}/{{uid:{}}}{}", ADMIN_PATH, MYSQL_UID_REGEX, path)
+}
+
 pub struct Server;
 
 #[macro_export]
@@ -69,8 +96,10 @@ macro_rules! build_app {
             .wrap(ErrorHandlers::new().handler(StatusCode::NOT_FOUND, ApiError::render_404))
             // These are our wrappers
             // .wrap(middleware::db::DbTransaction::new())
+            .wrap(middleware::ratelimit::RateLimit::default())
             .wrap(middleware::weave::WeaveTimestamp::new())
             .wrap(middleware::sentry::SentryWrapper::default())
+            .wrap(middleware::tracing::TracingWrapper::default())
             .wrap(middleware::rejectua::RejectUA::default())
             // Followed by the "official middleware" so they run first.
             .wrap(Cors::default())
@@ -95,7 +124,14 @@ macro_rules! build_app {
             )
             .service(web::resource(&cfg_path("")).route(web::delete().to(handlers::delete_all)))
             .service(
-                web::resource(&cfg_path("/storage")).route(web::delete().to(handlers::delete_all)),
+                web::resource(&cfg_path("/storage"))
+                    .app_data(
+                        web::JsonConfig::default()
+                            .limit($limits.max_request_bytes as usize)
+                            .content_type(|ct| ct == mime::TEXT_PLAIN),
+                    )
+                    .route(web::delete().to(handlers::delete_all))
+                    .route(web::post().to(handlers::post_storage_batch)),
             )
             .service(
                 web::resource(&cfg_path("/storage/{collection}"))
@@ -127,8 +163,23 @@ macro_rules! build_app {
                     .route(web::put().to(handlers::put_bso)),
             )
             // Tokenserver
+            .service(web::resource("/1.0/sync/1.5").route(web::get().to(tokenserver::get)))
+            // Admin API
+            .service(
+                web::resource(&admin_path("/collections"))
+                    .route(web::get().to(admin::get_collections)),
+            )
+            .service(
+                web::resource(&admin_path("/storage"))
+                    .route(web::delete().to(admin::delete_storage)),
+            )
+            .service(
+                web::resource(&admin_path("/storage/{collection}"))
+                    .route(web::get().to(admin::get_collection)),
+            )
             .service(
-                web::resource(&cfg_path("/1.0/sync/1.5")).route(web::get().to(tokenserver::get)),
+                web::resource(&admin_path("/batches"))
+                    .route(web::delete().to(admin::delete_batches)),
             )
             // Dockerflow
             // Remember to update .::web::middleware::DOCKER_FLOW_ENDPOINTS
@@ -152,21 +203,33 @@ macro_rules! build_app {
                 })),
             )
             .service(web::resource("/__error__").route(web::get().to(handlers::test_error)))
+            .service(web::resource("/__metrics__").route(web::get().to(handlers::prometheus)))
     };
 }
 
 impl Server {
     pub async fn with_settings(settings: Settings) -> Result<dev::Server, ApiError> {
-        let metrics = metrics::metrics_from_opts(&settings)?;
+        let prometheus = if settings.enable_prometheus {
+            Some(PrometheusMetrics::new(&settings.statsd_label))
+        } else {
+            None
+        };
+        let metrics = metrics::metrics_from_opts_with_prometheus(&settings, prometheus.as_ref())?;
         let db_pool = pool_from_settings(&settings, &Metrics::from(&metrics)).await?;
+        check_schema_version(db_pool.as_ref()).await?;
         let limits = Arc::new(settings.limits);
         let limits_json =
             serde_json::to_string(&*limits).expect("ServerLimits failed to serialize");
         let secrets = Arc::new(settings.master_secret);
         let port = settings.port;
         let quota_enabled = settings.enable_quota;
+        let admin_secret = settings.admin_secret.clone();
+        let rate_limiter = RateLimiter::from_settings(&settings.rate_limit).map(Arc::new);
+        let tokenserver =
+            TokenserverState::from_settings(&settings.tokenserver, db_pool.as_ref()).await?;
 
         spawn_pool_periodic_reporter(Duration::from_secs(10), metrics.clone(), db_pool.clone())?;
+        spawn_expiry_reaper(&settings.reaper, metrics.clone(), db_pool.clone())?;
 
         let mut server = HttpServer::new(move || {
             // Setup the server state
@@ -178,6 +241,10 @@ impl Server {
                 metrics: Box::new(metrics.clone()),
                 port,
                 quota_enabled,
+                tokenserver: tokenserver.clone(),
+                admin_secret: admin_secret.clone(),
+                prometheus: prometheus.clone(),
+                rate_limiter: rate_limiter.clone(),
             };
 
             build_app!(state, limits)
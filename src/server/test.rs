@@ -12,7 +12,7 @@ use hkdf::Hkdf;
 use hmac::{Hmac, Mac, NewMac};
 use lazy_static::lazy_static;
 use serde::de::DeserializeOwned;
-use serde_json::json;
+use serde_json::{json, Value};
 use sha2::Sha256;
 use std::str::FromStr;
 
@@ -22,8 +22,17 @@ use crate::db::params;
 use crate::db::pool_from_settings;
 use crate::db::results::{DeleteBso, GetBso, PostBsos, PutBso};
 use crate::db::util::SyncTimestamp;
-use crate::settings::{test_settings, Secrets, ServerLimits};
-use crate::web::{auth::HawkPayload, extractors::BsoBody, X_LAST_MODIFIED};
+use crate::settings::{test_settings, CollectionLimits, RateLimitSettings, Secrets, ServerLimits};
+use crate::tokenserver::{
+    verify::{MockVerifier, VerifyOutput},
+    TokenserverState,
+};
+use crate::web::{
+    auth::HawkPayload,
+    extractors::{BsoBody, Offset},
+    middleware::ratelimit::MemoryRateLimitStore,
+    X_LAST_MODIFIED,
+};
 
 lazy_static! {
     static ref SERVER_LIMITS: Arc<ServerLimits> = Arc::new(ServerLimits::default());
@@ -66,6 +75,10 @@ async fn get_test_state(settings: &Settings) -> ServerState {
         metrics: Box::new(metrics),
         port: settings.port,
         quota_enabled: settings.enable_quota,
+        tokenserver: None,
+        admin_secret: settings.admin_secret.clone(),
+        prometheus: None,
+        rate_limiter: None,
     }
 }
 
@@ -614,3 +627,656 @@ async fn info_configuration_xlm() {
         "0.00"
     );
 }
+
+fn get_tokenserver_test_settings() -> Settings {
+    let mut settings = get_test_settings();
+    // Test transactions are per connection: share one so the node registered
+    // on startup is visible to the requests
+    settings.database_pool_max_size = Some(1);
+    settings.tokenserver.enabled = true;
+    settings.tokenserver.node_url = format!("http://{}:{}", TEST_HOST, settings.port);
+    settings.tokenserver.token_duration = 300;
+    settings
+}
+
+async fn tokenserver_app_state(settings: &Settings, output: Option<VerifyOutput>) -> ServerState {
+    let mut state = get_test_state(settings).await;
+    let mut tokenserver =
+        TokenserverState::from_settings(&settings.tokenserver, state.db_pool.as_ref())
+            .await
+            .expect("Could not get tokenserver in tokenserver_app_state")
+            .expect("Tokenserver not enabled in tokenserver_app_state");
+    tokenserver.verifier = Box::new(MockVerifier { output });
+    state.tokenserver = Some(tokenserver);
+    state
+}
+
+fn tokenserver_request(auth: &str) -> test::TestRequest {
+    tokenserver_request_with_key_id(auth, "1234-YWFhYQ")
+}
+
+fn tokenserver_request_with_key_id(auth: &str, key_id: &str) -> test::TestRequest {
+    test::TestRequest::with_uri("/1.0/sync/1.5")
+        .header("Authorization", auth)
+        .header("X-KeyID", key_id)
+}
+
+#[actix_rt::test]
+async fn tokenserver_credentials_authenticate() {
+    let settings = get_tokenserver_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let output = VerifyOutput {
+        fxa_uid: "deadbeef".to_owned(),
+        ..Default::default()
+    };
+    let mut app = test::init_service(build_app!(
+        tokenserver_app_state(&settings, Some(output)).await,
+        limits
+    ))
+    .await;
+
+    let req = tokenserver_request("Bearer wibble").to_request();
+    let result: serde_json::Value = test::read_response_json(&mut app, req).await;
+    let uid = result["uid"].as_u64().unwrap();
+    assert_eq!(
+        result["api_endpoint"],
+        format!("http://{}:{}/1.5/{}", TEST_HOST, settings.port, uid)
+    );
+    assert_eq!(result["duration"], 300);
+
+    let path = format!("/1.5/{}/info/collections", uid);
+    let credentials = Credentials {
+        id: result["id"].as_str().unwrap().to_owned(),
+        key: Key::new(
+            result["key"].as_str().unwrap().as_bytes(),
+            hawk::DigestAlgorithm::Sha256,
+        )
+        .unwrap(),
+    };
+    let header = RequestBuilder::new("GET", TEST_HOST, settings.port, &path)
+        .request()
+        .make_header(&credentials)
+        .unwrap();
+    let req = test::TestRequest::with_uri(&path)
+        .header("Authorization", format!("Hawk {}", header))
+        .header("Accept", "application/json")
+        .to_request();
+    let response = app.call(req).await.unwrap();
+    assert!(response.status().is_success());
+    let body = test::read_body(response).await;
+    assert_eq!(body, "{}".as_bytes());
+}
+
+#[actix_rt::test]
+async fn tokenserver_invalid_credentials() {
+    let settings = get_tokenserver_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(
+        tokenserver_app_state(&settings, None).await,
+        limits
+    ))
+    .await;
+
+    let req = tokenserver_request("Bearer wibble").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let req = tokenserver_request("Basic wibble").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn tokenserver_disabled() {
+    let mut app = init_app!().await;
+    let req = tokenserver_request("Bearer wibble").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn tokenserver_client_state_change() {
+    let settings = get_tokenserver_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let output = VerifyOutput {
+        fxa_uid: "cafebabe".to_owned(),
+        ..Default::default()
+    };
+    let mut app = test::init_service(build_app!(
+        tokenserver_app_state(&settings, Some(output)).await,
+        limits
+    ))
+    .await;
+
+    let uid = |result: serde_json::Value| result["uid"].as_u64().unwrap();
+    let req = tokenserver_request("Bearer wibble").to_request();
+    let first = uid(test::read_response_json(&mut app, req).await);
+    let req = tokenserver_request("Bearer wibble").to_request();
+    assert_eq!(uid(test::read_response_json(&mut app, req).await), first);
+
+    // New keys are a new user as far as storage is concerned
+    let req = tokenserver_request_with_key_id("Bearer wibble", "1235-YmJiYg").to_request();
+    let second = uid(test::read_response_json(&mut app, req).await);
+    assert_ne!(second, first);
+
+    // Which can't go back to the old keys
+    let req = tokenserver_request_with_key_id("Bearer wibble", "1236-YWFhYQ").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Nor change keys without a newer keys_changed_at
+    let req = tokenserver_request_with_key_id("Bearer wibble", "1235-Y2NjYw").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+const ADMIN_SECRET: &str = "wibble-admin";
+// Spanner keys the test user's data by their fxa_uid/fxa_kid
+const ADMIN_QUERY: &str = "?fxa_uid=xxx_test&fxa_kid=xxx_test";
+
+fn get_admin_test_settings() -> Settings {
+    let mut settings = get_test_settings();
+    // Test transactions are per connection: share one so the data written
+    // via the storage API is visible to the admin API
+    settings.database_pool_max_size = Some(1);
+    settings.admin_secret = Some(ADMIN_SECRET.to_owned());
+    settings
+}
+
+fn admin_request(method: http::Method, path: &str, secret: &str) -> test::TestRequest {
+    test::TestRequest::with_uri(&format!("{}{}", path, ADMIN_QUERY))
+        .method(method)
+        .header("Authorization", format!("Bearer {}", secret))
+}
+
+#[actix_rt::test]
+async fn admin_inspect_and_delete_storage() {
+    let settings = get_admin_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let bso = json!({"id": "wibble", "payload": "SomePayload"});
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(bso),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert!(response.status().is_success());
+
+    let req =
+        admin_request(http::Method::GET, "/__admin__/42/collections", ADMIN_SECRET).to_request();
+    let result: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert!(result["bookmarks"]["modified"].is_number());
+    assert_eq!(result["bookmarks"]["usage"], 11);
+
+    let req = admin_request(
+        http::Method::GET,
+        "/__admin__/42/storage/bookmarks",
+        ADMIN_SECRET,
+    )
+    .to_request();
+    let result: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(result["items"][0]["id"], "wibble");
+    assert_eq!(result["items"][0]["payload_size"], 11);
+    assert!(result["items"][0].get("payload").is_none());
+
+    let req =
+        admin_request(http::Method::DELETE, "/__admin__/42/batches", ADMIN_SECRET).to_request();
+    let result: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(result["expired"], 0);
+
+    let req =
+        admin_request(http::Method::DELETE, "/__admin__/42/storage", ADMIN_SECRET).to_request();
+    let response = app.call(req).await.unwrap();
+    assert!(response.status().is_success());
+
+    let req =
+        admin_request(http::Method::GET, "/__admin__/42/collections", ADMIN_SECRET).to_request();
+    let result: serde_json::Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(result, json!({}));
+}
+
+#[actix_rt::test]
+async fn admin_invalid_credentials() {
+    let settings = get_admin_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let req = admin_request(http::Method::GET, "/__admin__/42/collections", "wibble").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Nor do Hawk credentials grant access
+    let req =
+        create_request(http::Method::GET, "/__admin__/42/collections", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn admin_disabled() {
+    let mut settings = get_test_settings();
+    settings.admin_secret = None;
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+    let req =
+        admin_request(http::Method::GET, "/__admin__/42/collections", ADMIN_SECRET).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn prometheus_metrics() {
+    let mut settings = get_test_settings();
+    settings.statsd_host = None;
+    let limits = Arc::new(settings.limits.clone());
+    let mut state = get_test_state(&settings).await;
+    let prometheus = metrics::PrometheusMetrics::new(&settings.statsd_label);
+    state.metrics =
+        Box::new(metrics::metrics_from_opts_with_prometheus(&settings, Some(&prometheus)).unwrap());
+    state.prometheus = Some(prometheus);
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Served without Hawk credentials
+    let req = test::TestRequest::with_uri("/__metrics__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+    assert!(body.contains("request_get_collections"));
+}
+
+#[actix_rt::test]
+async fn prometheus_disabled() {
+    let mut app = init_app!().await;
+    let req = test::TestRequest::with_uri("/__metrics__").to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn tracing_spans() {
+    let path = std::env::temp_dir().join(format!(
+        "syncstorage-traces-{}.json",
+        Utc::now().timestamp_nanos()
+    ));
+    let mut settings = get_test_settings();
+    settings.tracing.exporter = Some("file".to_owned());
+    settings.tracing.file = path.to_string_lossy().into_owned();
+    let guard = tracing::init_tracing(&settings.tracing).unwrap();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let mut headers = HashMap::new();
+    headers.insert(
+        "traceparent",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_owned(),
+    );
+    let req = create_request(
+        http::Method::GET,
+        "/1.5/42/info/collections",
+        Some(headers),
+        None,
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // Export the pending batch of spans
+    drop(guard);
+
+    let spans: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    std::fs::remove_file(&path).unwrap();
+    // The request's span continues the trace of its traceparent
+    let request = spans
+        .iter()
+        .find(|span| span["parent_span_id"] == "00f067aa0ba902b7")
+        .expect("No span of the request");
+    assert_eq!(request["trace_id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert!(request["name"].as_str().unwrap().starts_with("GET /1.5/"));
+    assert_eq!(request["attributes"]["ua.name"], "Firefox");
+    assert_eq!(request["attributes"]["http.status_code"], "200");
+    assert_eq!(
+        request["attributes"]["http.route"],
+        request["name"].as_str().unwrap()["GET ".len()..]
+    );
+    assert!(!request["attributes"]
+        .as_object()
+        .unwrap()
+        .values()
+        .any(|value| value.as_str().map_or(false, |value| value.contains("/42/"))));
+    // with a child span for its db call
+    assert!(spans.iter().any(|span| {
+        span["name"] == "db.get_collection_timestamps"
+            && span["parent_span_id"] == request["span_id"]
+    }));
+}
+
+#[actix_rt::test]
+async fn rate_limited_writes() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut state = get_test_state(&settings).await;
+    let rate_limit = RateLimitSettings {
+        writes_per_second: 0.001,
+        burst: 1,
+        per_collection: false,
+    };
+    state.rate_limiter = Some(Arc::new(RateLimiter::new(
+        &rate_limit,
+        Box::new(MemoryRateLimitStore::default()),
+    )));
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let bso = json!({"payload": "wibble"});
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(bso.clone()),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(bso),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key("retry-after"));
+
+    // Reads aren't limited
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn collection_limits() {
+    let settings = get_test_settings();
+    let mut limits = ServerLimits::default();
+    limits.collections.insert(
+        "tabs".to_owned(),
+        CollectionLimits {
+            max_record_payload_bytes: Some(10),
+            ..Default::default()
+        },
+    );
+    let mut state = get_test_state(&settings).await;
+    state.limits_json = serde_json::to_string(&limits).unwrap();
+    state.limits = Arc::new(limits);
+    let limits = Arc::clone(&state.limits);
+    let mut app = test::init_service(build_app!(state, limits)).await;
+
+    let bso = json!({"payload": "x".repeat(20)});
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/tabs/wibble",
+        None,
+        Some(bso.clone()),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let req = create_request(
+        http::Method::PUT,
+        "/1.5/42/storage/bookmarks/wibble",
+        None,
+        Some(bso),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/configuration", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(
+        body["collections"]["tabs"],
+        json!({"max_record_payload_bytes": 10})
+    );
+}
+
+#[actix_rt::test]
+async fn stream_full_collection() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let bsos = json!([
+        {"id": "a", "sortindex": 3, "payload": "line\nbreak"},
+        {"id": "b", "sortindex": 2, "payload": "b"},
+        {"id": "c", "sortindex": 1, "payload": "c"},
+    ]);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/addresses",
+        None,
+        Some(bsos),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let path = "/1.5/42/storage/addresses?full=1&sort=index&limit=2";
+    let req = create_request(http::Method::GET, path, None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // The pages streamed aren't of one snapshot to count them by
+    assert!(!response.headers().contains_key("X-Weave-Records"));
+    let next_offset = response.headers().get("X-Weave-Next-Offset").unwrap();
+    let next_offset = next_offset.to_str().unwrap().to_owned();
+    let offset = Offset::from_token(&next_offset, &SECRETS.master_secret).unwrap();
+    assert_eq!(offset.offset, 2);
+    assert!(response.headers().contains_key(X_LAST_MODIFIED));
+    let body: Vec<GetBso> = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let ids: Vec<_> = body.iter().map(|bso| bso.id.as_str()).collect();
+    assert_eq!(ids, vec!["a", "b"]);
+    assert_eq!(body[0].payload, "line\nbreak");
+
+    let mut headers = HashMap::new();
+    headers.insert("Accept", "application/newlines".to_owned());
+    let path = format!(
+        "/1.5/42/storage/addresses?full=1&sort=index&offset={}",
+        next_offset
+    );
+    let req = create_request(http::Method::GET, &path, Some(headers), None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("X-Weave-Next-Offset"));
+    let body = test::read_body(response).await;
+    let lines: Vec<_> = body.split(|b| *b == b'\n').collect();
+    assert_eq!(lines.len(), 2);
+    let bso: GetBso = serde_json::from_slice(lines[0]).unwrap();
+    assert_eq!(bso.id, "c");
+}
+
+#[actix_rt::test]
+async fn inspect_and_abandon_batch() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let bsos = json!([
+        {"id": "b", "payload": "b"},
+        {"id": "a", "payload": "a"},
+    ]);
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage/forms?batch=true",
+        None,
+        Some(bsos),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let batch = body["batch"].as_str().unwrap().to_owned();
+    assert_eq!(body["total_records"], 2);
+
+    let path = format!("/1.5/42/storage/forms?batch={}", batch);
+    let req = create_request(http::Method::GET, &path, None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("X-Weave-Records").unwrap(), "2");
+    let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(body["batch"], batch.as_str());
+    assert_eq!(body["count"], 2);
+    assert_eq!(body["ids"], json!(["a", "b"]));
+    assert!(body["expiry"].as_f64().unwrap() > 0.0);
+
+    let req = create_request(http::Method::DELETE, &path, None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let req = create_request(http::Method::GET, &path, None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn commit_storage_batch() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    let mut batches = serde_json::Map::new();
+    for coll in &["passwords", "prefs"] {
+        let req = create_request(
+            http::Method::POST,
+            &format!("/1.5/42/storage/{}?batch=true", coll),
+            None,
+            Some(json!([{"id": "a", "payload": "a"}])),
+        )
+        .to_request();
+        let response = app.call(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+        batches.insert((*coll).to_owned(), body["batch"].clone());
+    }
+
+    // Nothing's committed when any of the batches is unknown
+    let mut unknown = batches.clone();
+    unknown.insert("tabs".to_owned(), batches["prefs"].clone());
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage",
+        None,
+        Some(Value::Object(unknown)),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let req =
+        create_request(http::Method::GET, "/1.5/42/storage/passwords", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    let body: Vec<String> = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert!(body.is_empty());
+
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage",
+        None,
+        Some(Value::Object(batches)),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(body["collections"]["passwords"]["records"], 1);
+    assert_eq!(body["collections"]["prefs"]["records"], 1);
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    let collections: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(collections["passwords"], body["modified"]);
+    assert_eq!(collections["prefs"], body["modified"]);
+}
+
+#[actix_rt::test]
+async fn stage_storage_batch() {
+    let settings = get_test_settings();
+    let limits = Arc::new(settings.limits.clone());
+    let mut app = test::init_service(build_app!(get_test_state(&settings).await, limits)).await;
+
+    // Records of several collections are staged in new batches
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage?commit=false",
+        None,
+        Some(json!({
+            "bookmarks": {"bsos": [{"id": "a", "payload": "a"}]},
+            "meta": {"bsos": [{"id": "global", "payload": "m"}]},
+        })),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    let bookmarks = body["batches"]["bookmarks"].as_str().unwrap().to_owned();
+    let meta = body["batches"]["meta"].as_str().unwrap().to_owned();
+    let req =
+        create_request(http::Method::GET, "/1.5/42/storage/bookmarks", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    let body: Vec<String> = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert!(body.is_empty());
+
+    // Invalid records fail the request
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage",
+        None,
+        Some(json!({"bookmarks": {"batch": bookmarks, "bsos": [{"id": "b", "bogus": 1}]}})),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Then appended to and committed together
+    let req = create_request(
+        http::Method::POST,
+        "/1.5/42/storage",
+        None,
+        Some(json!({
+            "bookmarks": {"batch": bookmarks, "bsos": [{"id": "b", "payload": "b"}]},
+            "meta": meta,
+        })),
+    )
+    .to_request();
+    let response = app.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(body["collections"]["bookmarks"]["records"], 2);
+    assert_eq!(body["collections"]["meta"]["records"], 1);
+
+    let req =
+        create_request(http::Method::GET, "/1.5/42/storage/bookmarks", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    let ids: Vec<String> = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(ids, vec!["a", "b"]);
+    let req =
+        create_request(http::Method::GET, "/1.5/42/info/collections", None, None).to_request();
+    let response = app.call(req).await.unwrap();
+    let collections: Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+    assert_eq!(collections["bookmarks"], body["modified"]);
+    assert_eq!(collections["meta"], body["modified"]);
+}
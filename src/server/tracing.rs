@@ -0,0 +1,177 @@
+//! OpenTelemetry tracing of requests and the db calls made on their behalf.
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{LineWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::HeaderMap;
+use async_trait::async_trait;
+use opentelemetry::{
+    global::{self, BoxedSpan, BoxedTracer, TracerProviderGuard},
+    propagation::{Extractor, TextMapPropagator},
+    sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        propagation::TraceContextPropagator,
+        trace::{self as sdktrace, TracerProvider},
+        Resource,
+    },
+    trace::{FutureExt, Span, StatusCode, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::{Exporter as OtlpExporter, ExporterConfig as OtlpExporterConfig};
+use serde_json::{json, Map, Value};
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::settings::TracingSettings;
+use crate::web::tags::Tags;
+
+const TRACER_NAME: &str = "syncstorage";
+
+/// Install the exporter configured in `settings` (if any) as the global
+/// tracer provider, which remains installed for as long as the returned guard
+/// is held.
+///
+/// Spans are exported in batches by a task of the tokio runtime the server
+/// runs on (which must be current), away from the requests' own tasks.
+/// Dropping the guard exports those still pending.
+pub fn init_tracing(settings: &TracingSettings) -> ApiResult<Option<TracerProviderGuard>> {
+    let exporter = match settings.exporter.as_deref() {
+        Some(exporter) => exporter,
+        None => return Ok(None),
+    };
+    let builder = TracerProvider::builder().with_config(sdktrace::config().with_resource(
+        Resource::new(vec![
+            KeyValue::new("service.name", TRACER_NAME),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ]),
+    ));
+    let builder = match exporter {
+        "otlp" => builder.with_exporter(OtlpExporter::new(OtlpExporterConfig {
+            endpoint: settings.otlp_endpoint.clone(),
+            ..Default::default()
+        })),
+        "file" => builder.with_exporter(FileExporter::new(&settings.file)?),
+        _ => Err(ApiErrorKind::Internal(format!(
+            "Invalid tracing.exporter: {}",
+            exporter
+        )))?,
+    };
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some(global::set_tracer_provider(builder.build())))
+}
+
+/// The tracer of all spans (a no-op until `init_tracing` installs an exporter)
+pub fn tracer() -> BoxedTracer {
+    global::tracer(TRACER_NAME)
+}
+
+/// Start a span as a child of the current one
+pub fn start_span(name: &'static str, attributes: Vec<KeyValue>) -> BoxedSpan {
+    let span = tracer().start(name);
+    for attribute in attributes {
+        span.set_attribute(attribute);
+    }
+    span
+}
+
+/// Run `future` within a new child span of the current one (so that it's the
+/// parent of any spans the future starts), marking the span as failed should
+/// the future fail.
+pub async fn in_span<F, T, E>(
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+    future: F,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: ToString,
+{
+    let cx = Context::current_with_span(start_span(name, attributes));
+    let result = future.with_context(cx.clone()).await;
+    if let Err(e) = &result {
+        cx.span().set_status(StatusCode::Error, e.to_string());
+    }
+    result
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// The remote parent context of a request from its W3C `traceparent` (and
+/// `tracestate`) headers, if any
+pub fn extract_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Span attributes for the request's `Tags`
+pub fn tag_attributes(tags: &Tags) -> Vec<KeyValue> {
+    tags.tags
+        .iter()
+        .map(|(k, v)| KeyValue::new(k.clone(), v.clone()))
+        .collect()
+}
+
+/// Appends finished spans to a file, one JSON object per line.
+#[derive(Debug)]
+pub struct FileExporter {
+    file: LineWriter<File>,
+}
+
+impl FileExporter {
+    pub fn new(path: &str) -> ApiResult<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                ApiErrorKind::Internal(format!("Couldn't open tracing.file {}: {}", path, e))
+            })?;
+        Ok(Self {
+            file: LineWriter::new(file),
+        })
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn span_to_json(span: &SpanData) -> Value {
+    let attributes: Map<String, Value> = span
+        .attributes
+        .iter()
+        .map(|(k, v)| (k.as_str().to_owned(), json!(v.as_str())))
+        .collect();
+    json!({
+        "trace_id": span.span_context.trace_id().to_hex(),
+        "span_id": span.span_context.span_id().to_hex(),
+        "parent_span_id": span.parent_span_id.to_hex(),
+        "name": span.name,
+        "start_time_unix_nano": unix_nanos(span.start_time),
+        "end_time_unix_nano": unix_nanos(span.end_time),
+        "status": format!("{:?}", span.status_code),
+        "status_message": span.status_message,
+        "attributes": attributes,
+    })
+}
+
+#[async_trait]
+impl SpanExporter for FileExporter {
+    async fn export(&mut self, batch: Vec<SpanData>) -> ExportResult {
+        for span in batch {
+            writeln!(self.file, "{}", span_to_json(&span))?;
+        }
+        Ok(())
+    }
+}
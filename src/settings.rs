@@ -1,5 +1,5 @@
 //! Application settings objects and initialization
-use std::{cmp::min, env};
+use std::{cmp::min, collections::BTreeMap, env};
 
 use config::{Config, ConfigError, Environment, File};
 use serde::{de::Deserializer, Deserialize, Serialize};
@@ -23,6 +23,12 @@ static DEFAULT_MAX_TOTAL_RECORDS: u32 = 100 * DEFAULT_MAX_POST_RECORDS;
 // Hard spanner limit is 4GB per split (items under a unique index).
 // This gives us more than a bit of wiggle room.
 static DEFAULT_MAX_QUOTA_LIMIT: u32 = 2 * GIGABYTE;
+static DEFAULT_TOKEN_DURATION: u64 = 60 * 60;
+static DEFAULT_VERIFIER_TIMEOUT: u64 = 5;
+static DEFAULT_NODE_CAPACITY: i32 = 100_000;
+static DEFAULT_REAPER_PARTITIONS: u32 = 64;
+static DEFAULT_REAPER_LIMIT: u32 = 1000;
+static DEFAULT_RATE_LIMIT_BURST: u32 = 100;
 static PREFIX: &str = "sync";
 
 #[derive(Clone, Debug, Deserialize)]
@@ -36,6 +42,16 @@ pub struct Settings {
     pub database_pool_min_idle: Option<u32>,
     #[cfg(test)]
     pub database_use_test_transactions: bool,
+    /// Apply any pending database migrations on startup (otherwise refusing to
+    /// start when the schema is behind).
+    pub database_auto_migrate: bool,
+    /// Host:port of a Cloud Spanner emulator to connect to (insecurely)
+    /// instead of Spanner itself. Defaults to `SPANNER_EMULATOR_HOST`.
+    pub spanner_emulator_host: Option<String>,
+    /// The exact staleness (in seconds) of the snapshot Spanner serves
+    /// read-only requests from (bounded staleness is limited to single-use
+    /// transactions). 0 makes every read a strong read.
+    pub spanner_exact_staleness: u32,
 
     pub actix_keep_alive: Option<u32>,
 
@@ -46,6 +62,9 @@ pub struct Settings {
     /// the signing secret and token secret
     /// that are used during Hawk authentication.
     pub master_secret: Secrets,
+    /// Secret (sent as `Authorization: Bearer <secret>`) protecting the
+    /// `/__admin__` API, which is disabled when unset.
+    pub admin_secret: Option<String>,
     pub human_logs: bool,
 
     pub statsd_host: Option<String>,
@@ -53,6 +72,22 @@ pub struct Settings {
     pub statsd_label: String,
 
     pub enable_quota: bool,
+
+    /// Serve the metrics sent to statsd in the Prometheus format at
+    /// `/__metrics__` as well.
+    pub enable_prometheus: bool,
+
+    /// Settings for the built-in Tokenserver.
+    pub tokenserver: TokenserverSettings,
+
+    /// Settings for the background purge of expired bsos and batches.
+    pub reaper: ReaperSettings,
+
+    /// Settings for the OpenTelemetry tracing of requests and db calls.
+    pub tracing: TracingSettings,
+
+    /// Settings for the per-user rate limiting of writes.
+    pub rate_limit: RateLimitSettings,
 }
 
 impl Default for Settings {
@@ -66,14 +101,23 @@ impl Default for Settings {
             database_pool_min_idle: None,
             #[cfg(test)]
             database_use_test_transactions: false,
+            database_auto_migrate: false,
+            spanner_emulator_host: None,
+            spanner_exact_staleness: 0,
             actix_keep_alive: None,
             limits: ServerLimits::default(),
             master_secret: Secrets::default(),
+            admin_secret: None,
             statsd_host: None,
             statsd_port: 8125,
             statsd_label: "syncstorage".to_string(),
             human_logs: false,
             enable_quota: false,
+            enable_prometheus: false,
+            tokenserver: TokenserverSettings::default(),
+            reaper: ReaperSettings::default(),
+            tracing: TracingSettings::default(),
+            rate_limit: RateLimitSettings::default(),
         }
     }
 }
@@ -90,6 +134,8 @@ impl Settings {
         s.set_default("human_logs", false)?;
         #[cfg(test)]
         s.set_default("database_use_test_transactions", false)?;
+        s.set_default("database_auto_migrate", false)?;
+        s.set_default("spanner_exact_staleness", 0)?;
         s.set_default("master_secret", "")?;
         s.set_default("limits.max_post_bytes", i64::from(DEFAULT_MAX_POST_BYTES))?;
         s.set_default(
@@ -115,6 +161,54 @@ impl Settings {
         s.set_default("statsd_port", 8125)?;
         s.set_default("statsd_label", "syncstorage")?;
         s.set_default("enable_quota", false)?;
+        s.set_default("enable_prometheus", false)?;
+
+        let tokenserver = TokenserverSettings::default();
+        s.set_default("tokenserver.enabled", tokenserver.enabled)?;
+        s.set_default("tokenserver.node_url", tokenserver.node_url)?;
+        s.set_default(
+            "tokenserver.node_capacity",
+            i64::from(tokenserver.node_capacity),
+        )?;
+        s.set_default(
+            "tokenserver.token_duration",
+            tokenserver.token_duration as i64,
+        )?;
+        s.set_default(
+            "tokenserver.fxa_metrics_hash_secret",
+            tokenserver.fxa_metrics_hash_secret,
+        )?;
+        s.set_default("tokenserver.fxa_email_domain", tokenserver.fxa_email_domain)?;
+        s.set_default(
+            "tokenserver.fxa_browserid_audience",
+            tokenserver.fxa_browserid_audience,
+        )?;
+        s.set_default(
+            "tokenserver.fxa_browserid_server_url",
+            tokenserver.fxa_browserid_server_url,
+        )?;
+        s.set_default(
+            "tokenserver.fxa_oauth_server_url",
+            tokenserver.fxa_oauth_server_url,
+        )?;
+        s.set_default(
+            "tokenserver.verifier_timeout",
+            tokenserver.verifier_timeout as i64,
+        )?;
+
+        let reaper = ReaperSettings::default();
+        s.set_default("reaper.interval", reaper.interval as i64)?;
+        s.set_default("reaper.partitions", i64::from(reaper.partitions))?;
+        s.set_default("reaper.limit", i64::from(reaper.limit))?;
+
+        let tracing = TracingSettings::default();
+        s.set_default("tracing.otlp_endpoint", tracing.otlp_endpoint)?;
+        s.set_default("tracing.file", tracing.file)?;
+
+        let rate_limit = RateLimitSettings::default();
+        s.set_default("rate_limit.writes_per_second", rate_limit.writes_per_second)?;
+        s.set_default("rate_limit.burst", i64::from(rate_limit.burst))?;
+        s.set_default("rate_limit.per_collection", rate_limit.per_collection)?;
 
         // Merge the config file if supplied
         if let Some(config_filename) = filename {
@@ -131,6 +225,11 @@ impl Settings {
 
         Ok(match s.try_into::<Self>() {
             Ok(mut s) => {
+                if s.spanner_emulator_host.is_none() {
+                    // The variable the Cloud Spanner client libraries honor
+                    s.spanner_emulator_host = env::var("SPANNER_EMULATOR_HOST").ok();
+                }
+
                 // Adjust the max values if required.
                 if s.uses_spanner() {
                     let mut ms = s;
@@ -149,11 +248,8 @@ impl Settings {
                             env::set_var("ACTIX_THREADPOOL", database_pool_max_size.to_string());
                         }
                     }
-                    // No quotas for stand alone servers
-                    s.limits.max_quota_limit = 0;
-                    s.enable_quota = false;
                 }
-                if s.limits.max_quota_limit == 0 {
+                if !s.limits.has_quotas() {
                     s.enable_quota = false
                 }
                 s
@@ -183,6 +279,23 @@ impl Settings {
         self.database_url.as_str().starts_with("spanner://")
     }
 
+    pub fn uses_mysql(&self) -> bool {
+        self.database_url.as_str().starts_with("mysql:")
+    }
+
+    pub fn uses_memory(&self) -> bool {
+        self.database_url.as_str().starts_with("memory:")
+    }
+
+    pub fn uses_sqlite(&self) -> bool {
+        self.database_url.as_str().starts_with("sqlite:")
+    }
+
+    pub fn uses_postgres(&self) -> bool {
+        let url = self.database_url.as_str();
+        url.starts_with("postgres:") || url.starts_with("postgresql:")
+    }
+
     pub fn spanner_database_name(&self) -> Option<&str> {
         if !self.uses_spanner() {
             None
@@ -231,7 +344,14 @@ pub struct ServerLimits {
 
     /// Maximum BSO count across a batch upload.
     pub max_total_records: u32,
+
+    /// Maximum combined size of BSO payloads in a collection, in bytes (0 for
+    /// no quota).
     pub max_quota_limit: u32,
+
+    /// Overrides of the above for individual collections.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub collections: BTreeMap<String, CollectionLimits>,
 }
 
 impl Default for ServerLimits {
@@ -245,6 +365,215 @@ impl Default for ServerLimits {
             max_total_bytes: DEFAULT_MAX_TOTAL_BYTES,
             max_total_records: DEFAULT_MAX_TOTAL_RECORDS,
             max_quota_limit: DEFAULT_MAX_QUOTA_LIMIT,
+            collections: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerLimits {
+    /// The quota of `collection` in bytes, `None` when it's unlimited
+    pub fn quota_for(&self, collection: &str) -> Option<u32> {
+        let limit = self
+            .collections
+            .get(collection)
+            .and_then(|limits| limits.max_quota_limit)
+            .unwrap_or(self.max_quota_limit);
+        if limit == 0 {
+            None
+        } else {
+            Some(limit)
+        }
+    }
+
+    /// The largest BSO payload allowed in `collection`, in bytes
+    pub fn max_record_payload_bytes_for(&self, collection: &str) -> u32 {
+        self.collections
+            .get(collection)
+            .and_then(|limits| limits.max_record_payload_bytes)
+            .unwrap_or(self.max_record_payload_bytes)
+    }
+
+    /// The most BSOs allowed in a batch upload to `collection`
+    pub fn max_total_records_for(&self, collection: &str) -> u32 {
+        self.collections
+            .get(collection)
+            .and_then(|limits| limits.max_total_records)
+            .unwrap_or(self.max_total_records)
+    }
+
+    /// Whether any collection has a quota
+    pub fn has_quotas(&self) -> bool {
+        self.max_quota_limit > 0
+            || self
+                .collections
+                .values()
+                .any(|limits| limits.max_quota_limit.unwrap_or(0) > 0)
+    }
+}
+
+/// Overrides of the `ServerLimits` for a collection, those unset falling back
+/// to the server's.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct CollectionLimits {
+    /// Quota of the collection in bytes (0 for no quota).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_quota_limit: Option<u32>,
+
+    /// Maximum size of an individual BSO payload, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_record_payload_bytes: Option<u32>,
+
+    /// Maximum BSO count across a batch upload.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_total_records: Option<u32>,
+}
+
+/// Settings for the built-in Tokenserver, which exchanges Firefox Accounts
+/// credentials for Hawk credentials valid against this storage node.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TokenserverSettings {
+    /// Whether the `/1.0/sync/1.5` endpoint is served at all.
+    pub enabled: bool,
+
+    /// Base URI of this storage node, registered with the Tokenserver's
+    /// node database on startup.
+    pub node_url: String,
+
+    /// The number of users `node_url` is registered to hold.
+    pub node_capacity: i32,
+
+    /// Lifetime of the issued Hawk credentials, in seconds.
+    pub token_duration: u64,
+
+    /// Secret used to hash the fxa_uid and device id for metrics purposes.
+    pub fxa_metrics_hash_secret: String,
+
+    /// Domain of the email addresses asserted by BrowserID, e.g.
+    /// `<fxa_uid>@api.accounts.firefox.com`.
+    pub fxa_email_domain: String,
+
+    /// The audience BrowserID assertions must be issued for.
+    pub fxa_browserid_audience: String,
+
+    /// URL of the remote BrowserID verifier.
+    pub fxa_browserid_server_url: String,
+
+    /// URL of the Firefox Accounts OAuth server, used to verify bearer tokens.
+    pub fxa_oauth_server_url: String,
+
+    /// Timeout for requests to the verifiers, in seconds.
+    pub verifier_timeout: u64,
+}
+
+impl Default for TokenserverSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            node_url: format!("http://localhost:{}", DEFAULT_PORT),
+            node_capacity: DEFAULT_NODE_CAPACITY,
+            token_duration: DEFAULT_TOKEN_DURATION,
+            fxa_metrics_hash_secret: "".to_owned(),
+            fxa_email_domain: "api.accounts.firefox.com".to_owned(),
+            fxa_browserid_audience: "https://token.services.mozilla.com".to_owned(),
+            fxa_browserid_server_url: "https://verifier.accounts.firefox.com/v2".to_owned(),
+            fxa_oauth_server_url: "https://oauth.accounts.firefox.com".to_owned(),
+            verifier_timeout: DEFAULT_VERIFIER_TIMEOUT,
+        }
+    }
+}
+
+/// Settings for the in-process reaper, which periodically deletes expired
+/// bsos and batches (in lieu of, or alongside, the `purge_ttl` job).
+///
+/// Each sweep purges a randomly chosen one of `partitions` ranges of the user
+/// ids, so that any number of server instances may run it without
+/// coordinating: they rarely sweep the same range at once, and it's harmless
+/// when they do.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReaperSettings {
+    /// Average number of seconds between sweeps (each delayed by a random
+    /// 50-150% of it). 0 disables the reaper.
+    pub interval: u64,
+
+    /// The number of user id ranges the sweeps are split between.
+    pub partitions: u32,
+
+    /// The most expired bsos (and, separately, batches) a sweep deletes.
+    pub limit: u32,
+}
+
+impl Default for ReaperSettings {
+    fn default() -> Self {
+        Self {
+            interval: 0,
+            partitions: DEFAULT_REAPER_PARTITIONS,
+            limit: DEFAULT_REAPER_LIMIT,
+        }
+    }
+}
+
+/// Settings for the OpenTelemetry tracing of requests and the db calls made
+/// on their behalf.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TracingSettings {
+    /// Where finished spans are exported: "otlp" (to `otlp_endpoint`) or
+    /// "file" (appended to `file`, one JSON object per line). Tracing is
+    /// disabled when unset.
+    pub exporter: Option<String>,
+
+    /// The OTLP collector's gRPC endpoint.
+    pub otlp_endpoint: String,
+
+    /// The file spans are written to by the "file" exporter.
+    pub file: String,
+}
+
+impl TracingSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.exporter.is_some()
+    }
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        Self {
+            exporter: None,
+            otlp_endpoint: "localhost:4317".to_owned(),
+            file: "traces.json".to_owned(),
+        }
+    }
+}
+
+/// Settings for the per-user rate limiting of writes (`POST`, `PUT` and
+/// `DELETE` requests).
+///
+/// Each user (or user and collection) has a bucket of `burst` tokens, refilled
+/// at `writes_per_second`: each write takes a token, and writes finding the
+/// bucket empty are rejected.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RateLimitSettings {
+    /// The sustained rate of writes allowed. 0 disables rate limiting.
+    pub writes_per_second: f64,
+
+    /// The most writes allowed in a burst.
+    pub burst: u32,
+
+    /// Limit each collection of a user separately.
+    pub per_collection: bool,
+}
+
+impl RateLimitSettings {
+    pub fn is_enabled(&self) -> bool {
+        self.writes_per_second > 0.0
+    }
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        Self {
+            writes_per_second: 0.0,
+            burst: DEFAULT_RATE_LIMIT_BURST,
+            per_collection: false,
         }
     }
 }
@@ -309,5 +638,6 @@ pub fn test_settings() -> Settings {
     settings.port = 8000;
     settings.database_pool_max_size = Some(1);
     settings.database_use_test_transactions = true;
+    settings.database_auto_migrate = true;
     settings
 }
@@ -0,0 +1,162 @@
+//! A self-contained Tokenserver.
+//!
+//! Exchanges Firefox Accounts credentials (BrowserID assertions or OAuth
+//! bearer tokens) for Hawk credentials valid against this storage node.
+//! Matches the [Python logic](https://github.com/mozilla-services/tokenserver).
+pub mod purge;
+pub mod users;
+pub mod verify;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use self::verify::{RemoteVerifier, VerifyToken};
+use crate::db::{params, DbPool};
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
+use crate::settings::TokenserverSettings;
+
+/// The name Sync 1.5 is registered under in the `services` table.
+pub const SYNC_SERVICE: &str = "sync-1.5";
+
+/// The API endpoint handed out for Sync 1.5, matching the Python
+/// Tokenserver's `services.pattern`.
+pub const SYNC_SERVICE_PATTERN: &str = "{node}/1.5/{uid}";
+
+/// Tokenserver specific server state.
+#[derive(Clone, Debug)]
+pub struct TokenserverState {
+    pub verifier: Box<dyn VerifyToken>,
+
+    /// The id of the Sync 1.5 service in the `services` table.
+    pub service_id: i32,
+
+    /// Users are recorded by their `<fxa_uid>@<fxa_email_domain>` email.
+    pub fxa_email_domain: String,
+
+    /// Lifetime of the issued Hawk credentials, in seconds.
+    pub token_duration: u64,
+
+    pub fxa_metrics_hash_secret: String,
+}
+
+impl TokenserverState {
+    /// Build the state from settings, when the Tokenserver is enabled.
+    ///
+    /// Registers the Sync service and this storage node (`node_url`) in the
+    /// node database, so there's somewhere to allocate users to.
+    pub async fn from_settings(
+        settings: &TokenserverSettings,
+        db_pool: &dyn DbPool,
+    ) -> ApiResult<Option<Self>> {
+        if !settings.enabled {
+            return Ok(None);
+        }
+
+        let db = db_pool.get().await?;
+        db.begin(true).await?;
+        let result = async {
+            let service_id = db
+                .post_service(params::PostService {
+                    service: SYNC_SERVICE.to_owned(),
+                    pattern: SYNC_SERVICE_PATTERN.to_owned(),
+                })
+                .await?;
+            db.post_node(params::PostNode {
+                service_id,
+                node: settings.node_url.trim_end_matches('/').to_owned(),
+                capacity: settings.node_capacity,
+            })
+            .await?;
+            Ok::<_, ApiError>(service_id)
+        }
+        .await;
+        let service_id = match result {
+            Ok(service_id) => service_id,
+            Err(e) => {
+                db.rollback().await?;
+                return Err(e);
+            }
+        };
+        db.commit().await?;
+
+        Ok(Some(Self {
+            verifier: Box::new(RemoteVerifier::new(settings)),
+            service_id,
+            fxa_email_domain: settings.fxa_email_domain.clone(),
+            token_duration: settings.token_duration,
+            fxa_metrics_hash_secret: settings.fxa_metrics_hash_secret.clone(),
+        }))
+    }
+}
+
+/// Hex encode bytes.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// HMAC a value for metrics purposes, so that the raw FxA identifiers never
+/// leave the server.
+pub fn fxa_metrics_hash(value: &str, secret: &str) -> ApiResult<String> {
+    let mut hmac = Hmac::<Sha256>::new_varkey(secret.as_bytes())?;
+    hmac.update(value.as_bytes());
+    Ok(to_hex(&hmac.finalize().into_bytes()))
+}
+
+/// Format the `fxa_kid` identifying a user's current set of sync keys:
+/// the zero padded `keys_changed_at` timestamp followed by the unpadded
+/// base64url encoded client state.
+pub fn format_key_id(keys_changed_at: i64, client_state: &str) -> ApiResult<String> {
+    let client_state = decode_client_state(client_state)?;
+    Ok(format!(
+        "{:013}-{}",
+        keys_changed_at,
+        base64::encode_config(&client_state, base64::URL_SAFE_NO_PAD)
+    ))
+}
+
+/// Decode a hex encoded client state, at most 16 bytes as stored.
+pub fn decode_client_state(client_state: &str) -> ApiResult<Vec<u8>> {
+    if client_state.len() % 2 != 0 || client_state.len() > 32 {
+        Err(ApiErrorKind::InvalidCredentials(
+            "Invalid client state".to_owned(),
+        ))?;
+    }
+    (0..client_state.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&client_state[i..i + 2], 16).map_err(|_| {
+                ApiErrorKind::InvalidCredentials("Invalid client state".to_owned()).into()
+            })
+        })
+        .collect()
+}
+
+/// Hex encode a client state.
+pub fn encode_client_state(client_state: &[u8]) -> String {
+    to_hex(client_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_id() {
+        assert_eq!(
+            format_key_id(1_234, "616161").unwrap(),
+            "0000000001234-YWFh"
+        );
+        assert_eq!(format_key_id(0, "").unwrap(), "0000000000000-");
+        assert!(format_key_id(0, "61616").is_err());
+        assert!(format_key_id(0, "zz").is_err());
+        assert!(format_key_id(0, &"ab".repeat(16)).is_ok());
+        assert!(format_key_id(0, &"ab".repeat(17)).is_err());
+    }
+
+    #[test]
+    fn client_state_round_trip() {
+        let client_state = "deadbeef00004be4ae957006c0ceb620";
+        let decoded = decode_client_state(client_state).unwrap();
+        assert_eq!(encode_client_state(&decoded), client_state);
+    }
+}
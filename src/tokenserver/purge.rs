@@ -0,0 +1,160 @@
+//! Purging of storage orphaned by superseded user records.
+//!
+//! Resetting sync keys changes a user's client state and thus their
+//! `fxa_kid`, starting them over on an empty storage. Their previous record
+//! is kept (marked `replaced_at`) along with its client state, so after a
+//! grace period the storage it identified can be purged and the record
+//! deleted. Modeled on the Python Tokenserver's `purge_old_records` script.
+use std::collections::HashMap;
+
+use super::format_key_id;
+use crate::db::{params, results, Db};
+use crate::error::ApiResult;
+use crate::server::metrics::Metrics;
+use crate::web::{extractors::HawkIdentifier, tags::Tags};
+
+#[derive(Clone, Debug)]
+pub struct PurgeParams {
+    pub service_id: i32,
+    /// Only purge records replaced at least this many seconds ago
+    pub grace_period: u64,
+    /// The maximum number of records to purge per run
+    pub max_records: i64,
+    /// Report what would be purged without deleting anything
+    pub dry_run: bool,
+    /// Whether storage is keyed by the legacy uid (every backend but
+    /// Spanner, which keys it by `fxa_uid` and `fxa_kid`)
+    pub storage_by_uid: bool,
+    /// The current time, in milliseconds since the epoch
+    pub timestamp: i64,
+}
+
+/// What was (or on a dry run, would be) purged.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PurgeStats {
+    /// Replaced user records processed
+    pub records: i64,
+    /// Records whose storage was still in use by the current record, so kept
+    pub skipped: i64,
+    pub bsos: i64,
+    pub user_collections: i64,
+    pub batches: i64,
+}
+
+/// Purge the storage of, then delete, the service's replaced user records.
+pub async fn purge_old_records(
+    db: &dyn Db<'_>,
+    metrics: &Metrics,
+    params: PurgeParams,
+) -> ApiResult<PurgeStats> {
+    let replaced_before = params.timestamp - (params.grace_period * 1000) as i64;
+    let users = db
+        .get_replaced_users(params::GetReplacedUsers {
+            service_id: params.service_id,
+            replaced_before,
+            limit: params.max_records,
+        })
+        .await?;
+
+    let mut stats = PurgeStats::default();
+    for user in users {
+        db.begin(!params.dry_run).await?;
+        match purge_user(db, &params, &user).await {
+            Ok(purged) => {
+                db.commit().await?;
+                stats.records += 1;
+                match purged {
+                    Some(purged) => {
+                        stats.bsos += purged.bsos;
+                        stats.user_collections += purged.user_collections;
+                        stats.batches += purged.batches;
+                    }
+                    None => stats.skipped += 1,
+                }
+            }
+            Err(e) => {
+                db.rollback().await?;
+                return Err(e);
+            }
+        }
+    }
+
+    let mut tags = HashMap::new();
+    tags.insert("dry_run".to_owned(), params.dry_run.to_string());
+    let tags = Some(Tags::with_tags(tags));
+    metrics.count_with_tags("tokenserver.purge.records", stats.records, tags.clone());
+    metrics.count_with_tags("tokenserver.purge.skipped", stats.skipped, tags.clone());
+    metrics.count_with_tags("tokenserver.purge.bsos", stats.bsos, tags.clone());
+    metrics.count_with_tags(
+        "tokenserver.purge.user_collections",
+        stats.user_collections,
+        tags.clone(),
+    );
+    metrics.count_with_tags("tokenserver.purge.batches", stats.batches, tags);
+    info!(
+        "Purged replaced Tokenserver records";
+        "dry_run" => params.dry_run,
+        "records" => stats.records,
+        "skipped" => stats.skipped,
+        "bsos" => stats.bsos,
+        "user_collections" => stats.user_collections,
+        "batches" => stats.batches,
+    );
+    Ok(stats)
+}
+
+/// Purge a replaced record's storage, unless it's still in use by the
+/// user's current record, then delete the record.
+pub(crate) async fn purge_user(
+    db: &dyn Db<'_>,
+    params: &PurgeParams,
+    user: &results::ReplacedUser,
+) -> ApiResult<Option<results::PurgeStorage>> {
+    let fxa_kid = format_key_id(
+        user.keys_changed_at.unwrap_or(user.generation),
+        &user.client_state,
+    )?;
+    let current = db
+        .get_users(params::GetUsers {
+            service_id: params.service_id,
+            email: user.email.clone(),
+        })
+        .await?
+        .into_iter()
+        .find(|current| current.replaced_at.is_none());
+    // Only skip the purge when both records point at the same storage. On
+    // Spanner that's e.g. a node reassignment without a change of keys:
+    // elsewhere storage is keyed by the uid, which differs per record
+    let shared_storage = match current {
+        Some(current) if params.storage_by_uid => current.uid == user.uid,
+        Some(current) => {
+            format_key_id(
+                current.keys_changed_at.unwrap_or(current.generation),
+                &current.client_state,
+            )? == fxa_kid
+        }
+        None => false,
+    };
+
+    let purged = if shared_storage {
+        None
+    } else {
+        let fxa_uid = user.email.split('@').next().unwrap_or_default();
+        Some(
+            db.purge_storage(params::PurgeStorage {
+                user_id: HawkIdentifier {
+                    legacy_id: user.uid as u64,
+                    fxa_uid: fxa_uid.to_owned(),
+                    fxa_kid,
+                },
+                dry_run: params.dry_run,
+            })
+            .await?,
+        )
+    };
+
+    if !params.dry_run {
+        db.delete_user(params::DeleteUser { uid: user.uid }).await?;
+    }
+    Ok(purged)
+}
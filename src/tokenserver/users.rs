@@ -0,0 +1,154 @@
+//! Mapping of Firefox Accounts users to storage nodes.
+//!
+//! Follows the Python Tokenserver: each user has a current record holding
+//! their legacy `uid`, `generation`, `keys_changed_at`, `client_state` and
+//! assigned node. A change of client state (or a lost node) supersedes that
+//! record with a new one, and so a new `uid` and an empty storage.
+use crate::db::{params, results::TokenserverUser, Db};
+use crate::error::{ApiError, ApiErrorKind, ApiResult};
+
+/// What a verified request tells us about a user.
+#[derive(Clone, Debug)]
+pub struct UserParams {
+    pub service_id: i32,
+    pub email: String,
+    pub generation: i64,
+    pub keys_changed_at: i64,
+    pub client_state: String,
+    /// The current time, in milliseconds since the epoch
+    pub timestamp: i64,
+}
+
+fn invalid(reason: &str) -> ApiError {
+    ApiErrorKind::InvalidCredentials(reason.to_owned()).into()
+}
+
+/// Return the user's current record, allocating or updating it as needed.
+pub async fn get_or_create_user(db: &dyn Db<'_>, params: UserParams) -> ApiResult<TokenserverUser> {
+    db.begin(true).await?;
+    match update_user(db, params).await {
+        Ok(user) => {
+            db.commit().await?;
+            Ok(user)
+        }
+        Err(e) => {
+            db.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+async fn update_user(db: &dyn Db<'_>, params: UserParams) -> ApiResult<TokenserverUser> {
+    let users = db
+        .get_users(params::GetUsers {
+            service_id: params.service_id,
+            email: params.email.clone(),
+        })
+        .await?;
+    let user = match users.first() {
+        // Retired records (replaced with no successor) start over too
+        Some(user) if user.replaced_at.is_none() => user.clone(),
+        _ => return allocate_user(db, &params, None).await,
+    };
+
+    if params.generation < user.generation {
+        return Err(invalid("invalid-generation"));
+    }
+    if params.keys_changed_at < user.keys_changed_at.unwrap_or(0) {
+        return Err(invalid("invalid-keysChangedAt"));
+    }
+    // A keys_changed_at bump means new keys, so a new client state
+    if matches!(user.keys_changed_at, Some(keys_changed_at) if params.keys_changed_at > keys_changed_at)
+        && params.client_state == user.client_state
+    {
+        return Err(invalid("invalid-keysChangedAt"));
+    }
+
+    if params.client_state != user.client_state {
+        // New keys must come with a newer keys_changed_at (or generation)
+        // and never revert to a previous set
+        if users
+            .iter()
+            .any(|user| user.client_state == params.client_state)
+        {
+            return Err(invalid("invalid-client-state"));
+        }
+        if params.keys_changed_at <= user.keys_changed_at.unwrap_or(0)
+            && params.generation <= user.generation
+        {
+            return Err(invalid("invalid-client-state"));
+        }
+        let node = user.node.clone().map(|node| (user.node_id, node));
+        return allocate_user(db, &params, node).await;
+    }
+
+    if user.node.is_none() {
+        // The node's been downed or removed: move the user elsewhere
+        return allocate_user(db, &params, None).await;
+    }
+
+    if params.generation > user.generation
+        || params.keys_changed_at > user.keys_changed_at.unwrap_or(0)
+    {
+        db.put_user(params::PutUser {
+            service_id: params.service_id,
+            email: params.email.clone(),
+            generation: params.generation,
+            keys_changed_at: Some(params.keys_changed_at),
+        })
+        .await?;
+        return Ok(TokenserverUser {
+            generation: params.generation,
+            keys_changed_at: Some(params.keys_changed_at),
+            ..user
+        });
+    }
+    Ok(user)
+}
+
+/// Create a new current record for the user, on the given node or a newly
+/// allocated one, superseding any previous records.
+async fn allocate_user(
+    db: &dyn Db<'_>,
+    params: &UserParams,
+    node: Option<(i64, String)>,
+) -> ApiResult<TokenserverUser> {
+    let (node_id, node) = match node {
+        Some(node) => node,
+        None => {
+            let node = db
+                .allocate_node(params::AllocateNode {
+                    service_id: params.service_id,
+                })
+                .await?;
+            (node.id, node.node)
+        }
+    };
+    let uid = db
+        .post_user(params::PostUser {
+            service_id: params.service_id,
+            email: params.email.clone(),
+            generation: params.generation,
+            keys_changed_at: Some(params.keys_changed_at),
+            client_state: params.client_state.clone(),
+            node_id,
+            created_at: params.timestamp,
+        })
+        .await?;
+    db.replace_users(params::ReplaceUsers {
+        service_id: params.service_id,
+        email: params.email.clone(),
+        replaced_at: params.timestamp,
+    })
+    .await?;
+    Ok(TokenserverUser {
+        uid,
+        node_id,
+        node: Some(node),
+        generation: params.generation,
+        keys_changed_at: Some(params.keys_changed_at),
+        client_state: params.client_state.clone(),
+        created_at: params.timestamp,
+        replaced_at: None,
+    })
+}
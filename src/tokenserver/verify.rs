@@ -0,0 +1,219 @@
+//! Verification of Firefox Accounts credentials presented to the Tokenserver.
+use std::{fmt::Debug, time::Duration};
+
+use actix_web::client::Client;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::settings::TokenserverSettings;
+
+/// The OAuth scope granting access to Sync.
+pub const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
+
+/// The credential presented in the `Authorization` header.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenserverAuth {
+    /// A BrowserID assertion (`Authorization: BrowserID <assertion>`)
+    BrowserId(String),
+    /// An OAuth access token (`Authorization: Bearer <token>`)
+    OAuth(String),
+}
+
+/// The user information vouched for by a verifier.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct VerifyOutput {
+    pub fxa_uid: String,
+    pub generation: Option<i64>,
+    pub keys_changed_at: Option<i64>,
+    pub device_id: Option<String>,
+}
+
+#[async_trait(?Send)]
+pub trait VerifyToken: Sync + Send + Debug {
+    /// Verify the credential, returning the user it belongs to.
+    async fn verify(&self, auth: &TokenserverAuth) -> ApiResult<VerifyOutput>;
+
+    fn box_clone(&self) -> Box<dyn VerifyToken>;
+}
+
+impl Clone for Box<dyn VerifyToken> {
+    fn clone(&self) -> Box<dyn VerifyToken> {
+        self.box_clone()
+    }
+}
+
+fn invalid_credentials(reason: &str) -> ApiErrorKind {
+    ApiErrorKind::InvalidCredentials(reason.to_owned())
+}
+
+/// Verifies credentials against the remote Firefox Accounts verifiers.
+#[derive(Clone, Debug)]
+pub struct RemoteVerifier {
+    browserid_audience: String,
+    browserid_server_url: String,
+    oauth_server_url: String,
+    email_domain: String,
+    timeout: Duration,
+}
+
+impl RemoteVerifier {
+    pub fn new(settings: &TokenserverSettings) -> Self {
+        Self {
+            browserid_audience: settings.fxa_browserid_audience.clone(),
+            browserid_server_url: settings.fxa_browserid_server_url.clone(),
+            oauth_server_url: settings.fxa_oauth_server_url.clone(),
+            email_domain: settings.fxa_email_domain.clone(),
+            timeout: Duration::from_secs(settings.verifier_timeout),
+        }
+    }
+
+    async fn verify_browserid(&self, assertion: &str) -> ApiResult<VerifyOutput> {
+        #[derive(Deserialize)]
+        struct IdpClaims {
+            #[serde(rename = "fxa-generation")]
+            generation: Option<i64>,
+            #[serde(rename = "fxa-keysChangedAt")]
+            keys_changed_at: Option<i64>,
+            #[serde(rename = "fxa-deviceId")]
+            device_id: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        struct BrowserIdResponse {
+            status: String,
+            email: Option<String>,
+            issuer: Option<String>,
+            #[serde(rename = "idpClaims")]
+            idp_claims: Option<IdpClaims>,
+        }
+
+        let body = json!({
+            "audience": self.browserid_audience,
+            "assertion": assertion,
+        });
+        let client = Client::builder().timeout(self.timeout).finish();
+        let mut response = client
+            .post(&self.browserid_server_url)
+            .send_json(&body)
+            .await
+            .map_err(|e| ApiErrorKind::Internal(format!("BrowserID verifier error: {}", e)))?;
+        if response.status().is_server_error() {
+            Err(ApiErrorKind::Internal(format!(
+                "BrowserID verifier error: {}",
+                response.status()
+            )))?;
+        }
+        let result: BrowserIdResponse = response
+            .json()
+            .await
+            .map_err(|e| ApiErrorKind::Internal(format!("BrowserID verifier error: {}", e)))?;
+
+        if result.status != "okay" {
+            Err(invalid_credentials("Unverified assertion"))?;
+        }
+        if result.issuer.as_deref() != Some(self.email_domain.as_str()) {
+            Err(invalid_credentials("Unexpected issuer"))?;
+        }
+        let email = result
+            .email
+            .ok_or_else(|| invalid_credentials("Missing email"))?;
+        let fxa_uid = email
+            .strip_suffix(&format!("@{}", self.email_domain))
+            .filter(|fxa_uid| !fxa_uid.is_empty())
+            .ok_or_else(|| invalid_credentials("Unexpected email domain"))?
+            .to_owned();
+
+        let (generation, keys_changed_at, device_id) = match result.idp_claims {
+            Some(claims) => (claims.generation, claims.keys_changed_at, claims.device_id),
+            None => (None, None, None),
+        };
+        Ok(VerifyOutput {
+            fxa_uid,
+            generation,
+            keys_changed_at,
+            device_id,
+        })
+    }
+
+    async fn verify_oauth(&self, token: &str) -> ApiResult<VerifyOutput> {
+        #[derive(Deserialize)]
+        struct OAuthResponse {
+            user: String,
+            #[serde(default)]
+            scope: Vec<String>,
+            generation: Option<i64>,
+        }
+
+        let client = Client::builder().timeout(self.timeout).finish();
+        let mut response = client
+            .post(format!(
+                "{}/v1/verify",
+                self.oauth_server_url.trim_end_matches('/')
+            ))
+            .send_json(&json!({ "token": token }))
+            .await
+            .map_err(|e| ApiErrorKind::Internal(format!("OAuth verifier error: {}", e)))?;
+        if response.status().is_server_error() {
+            Err(ApiErrorKind::Internal(format!(
+                "OAuth verifier error: {}",
+                response.status()
+            )))?;
+        }
+        if !response.status().is_success() {
+            Err(invalid_credentials("Unverified token"))?;
+        }
+        let result: OAuthResponse = response
+            .json()
+            .await
+            .map_err(|e| ApiErrorKind::Internal(format!("OAuth verifier error: {}", e)))?;
+
+        if !result.scope.iter().any(|scope| scope == SYNC_SCOPE) {
+            Err(invalid_credentials("Token lacks the sync scope"))?;
+        }
+        Ok(VerifyOutput {
+            fxa_uid: result.user,
+            generation: result.generation,
+            keys_changed_at: None,
+            device_id: None,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl VerifyToken for RemoteVerifier {
+    async fn verify(&self, auth: &TokenserverAuth) -> ApiResult<VerifyOutput> {
+        match auth {
+            TokenserverAuth::BrowserId(assertion) => self.verify_browserid(assertion).await,
+            TokenserverAuth::OAuth(token) => self.verify_oauth(token).await,
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn VerifyToken> {
+        Box::new(self.clone())
+    }
+}
+
+/// Accepts any credential as belonging to the given user (or rejects all of
+/// them when there's none).
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+pub struct MockVerifier {
+    pub output: Option<VerifyOutput>,
+}
+
+#[cfg(test)]
+#[async_trait(?Send)]
+impl VerifyToken for MockVerifier {
+    async fn verify(&self, _auth: &TokenserverAuth) -> ApiResult<VerifyOutput> {
+        Ok(self
+            .output
+            .clone()
+            .ok_or_else(|| invalid_credentials("Unverified token"))?)
+    }
+
+    fn box_clone(&self) -> Box<dyn VerifyToken> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,136 @@
+//! Admin API handlers.
+//!
+//! Support operations against a single user's storage, authenticated by the
+//! `admin_secret` (instead of Hawk) and audit logged.
+use std::{collections::HashMap, future::Future};
+
+use actix_web::{
+    web::{Data, Path},
+    Error, HttpResponse,
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::db::{params, util::SyncTimestamp, Db};
+use crate::error::ApiResult;
+use crate::server::ServerState;
+use crate::web::extractors::{AdminRequest, BsoQueryParams};
+
+/// A collection's last modified time and the size of its BSOs' payloads
+#[derive(Debug, Default, Serialize)]
+pub struct CollectionSummary {
+    modified: Option<SyncTimestamp>,
+    usage: i64,
+}
+
+/// Everything but a BSO's (encrypted) payload
+#[derive(Debug, Serialize)]
+pub struct BsoMetadata {
+    id: String,
+    modified: SyncTimestamp,
+    sortindex: Option<i32>,
+    expiry: i64,
+    payload_size: usize,
+}
+
+/// Run the action within a transaction, committing it on success (the action
+/// must not start its work before it's first polled)
+async fn transaction<'a, R>(
+    db: &dyn Db<'a>,
+    for_write: bool,
+    action: impl Future<Output = ApiResult<R>>,
+) -> ApiResult<R> {
+    db.begin(for_write).await?;
+    match action.await {
+        Ok(result) => {
+            db.commit().await?;
+            Ok(result)
+        }
+        Err(e) => {
+            db.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// List the user's collections with their usage
+pub async fn get_collections(request: AdminRequest) -> Result<HttpResponse, Error> {
+    request.metrics.incr("admin.get_collections");
+    request.audit("get_collections");
+    let db = request.db_pool.get().await?;
+    let user_id = request.user_id.clone();
+    let summaries = transaction(db.as_ref(), false, async {
+        let mut summaries: HashMap<String, CollectionSummary> = HashMap::new();
+        for (collection, modified) in db.get_collection_timestamps(user_id.clone()).await? {
+            summaries.entry(collection).or_default().modified = Some(modified);
+        }
+        for (collection, usage) in db.get_collection_usage(user_id).await? {
+            summaries.entry(collection).or_default().usage = usage;
+        }
+        Ok(summaries)
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(summaries))
+}
+
+/// Dump the metadata of a collection's BSOs
+pub async fn get_collection(
+    request: AdminRequest,
+    path: Path<(u64, String)>,
+    query: BsoQueryParams,
+    state: Data<ServerState>,
+) -> Result<HttpResponse, Error> {
+    request.metrics.incr("admin.get_collection");
+    let (_, collection) = path.into_inner();
+    request.audit(&format!("get_collection {}", collection));
+    let db = request.db_pool.get().await?;
+    let params = params::GetBsos {
+        user_id: request.user_id.clone(),
+        collection,
+        params: query,
+    };
+    let result = transaction(db.as_ref(), false, async { db.get_bsos(params).await }).await?;
+    let items: Vec<_> = result
+        .items
+        .into_iter()
+        .map(|bso| BsoMetadata {
+            id: bso.id,
+            modified: bso.modified,
+            sortindex: bso.sortindex,
+            expiry: bso.expiry,
+            payload_size: bso.payload.len(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(json!({
+        "items": items,
+        "offset": result
+            .offset
+            .map(|offset| offset.to_token(&state.secrets.master_secret)),
+    })))
+}
+
+/// Delete all of the user's storage
+pub async fn delete_storage(request: AdminRequest) -> Result<HttpResponse, Error> {
+    request.metrics.incr("admin.delete_storage");
+    request.audit("delete_storage");
+    let db = request.db_pool.get().await?;
+    let user_id = request.user_id.clone();
+    transaction(db.as_ref(), true, async {
+        db.delete_storage(user_id).await
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({})))
+}
+
+/// Discard the user's outstanding batches
+pub async fn delete_batches(request: AdminRequest) -> Result<HttpResponse, Error> {
+    request.metrics.incr("admin.delete_batches");
+    request.audit("delete_batches");
+    let db = request.db_pool.get().await?;
+    let user_id = request.user_id.clone();
+    let expired = transaction(db.as_ref(), true, async {
+        db.expire_batches(user_id).await
+    })
+    .await?;
+    Ok(HttpResponse::Ok().json(json!({ "expired": expired })))
+}
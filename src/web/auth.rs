@@ -143,6 +143,28 @@ impl HawkPayload {
         }
     }
 
+    /// Sign the payload and derive its token secret,
+    /// the inverse of `extract_and_validate`.
+    ///
+    /// Returns the Hawk `id` and `key` to be handed to the client.
+    pub fn sign(&self, secrets: &Secrets) -> ApiResult<(String, String)> {
+        let payload = serde_json::to_vec(self)?;
+        let mut hmac = Hmac::<Sha256>::new_varkey(&secrets.signing_secret)?;
+        hmac.update(&payload);
+
+        let mut id = payload;
+        id.extend_from_slice(&hmac.finalize().into_bytes());
+        let id = base64::encode_config(&id, base64::URL_SAFE);
+
+        let token_secret = hkdf_expand_32(
+            format!("services.mozilla.com/tokenlib/v1/derive/{}", id).as_bytes(),
+            Some(self.salt.as_bytes()),
+            &secrets.master_secret,
+        )?;
+        let key = base64::encode_config(&token_secret, base64::URL_SAFE);
+        Ok((id, key))
+    }
+
     #[cfg(test)]
     pub fn test_default(user_id: u64) -> Self {
         HawkPayload {
@@ -483,6 +505,27 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn signed_payload_round_trip() {
+        let fixture = TestFixture::new();
+        let secrets = &fixture.settings.master_secret;
+
+        let (id, _key) = fixture.expected.sign(secrets).unwrap();
+        let result = HawkPayload::extract_and_validate(
+            &id,
+            secrets,
+            fixture.expected.expires.round() as u64 - 1,
+        );
+        assert_eq!(result.unwrap(), fixture.expected);
+
+        let result = HawkPayload::extract_and_validate(
+            &id,
+            &Secrets::new("wibble").unwrap(),
+            fixture.expected.expires.round() as u64 - 1,
+        );
+        assert!(result.is_err());
+    }
+
     #[derive(Debug)]
     struct TestFixture {
         pub header: HawkHeader,
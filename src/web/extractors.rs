@@ -2,7 +2,12 @@
 //!
 //! Handles ensuring the header's, body, and query parameters are correct, extraction to
 //! relevant types, and failing correctly with the appropriate errors if issues arise.
-use std::{self, collections::HashMap, num::ParseIntError, str::FromStr};
+use std::{
+    self,
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Arc,
+};
 
 use actix_web::{
     dev::{ConnectionInfo, Extensions, Payload, RequestHead},
@@ -16,6 +21,7 @@ use actix_web::{
 
 use futures::future::{self, FutureExt, LocalBoxFuture, Ready, TryFutureExt};
 
+use hmac::{Hmac, Mac, NewMac};
 use lazy_static::lazy_static;
 use mime::STAR_STAR;
 use regex::Regex;
@@ -24,6 +30,7 @@ use serde::{
     Deserialize, Serialize,
 };
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use validator::{Validate, ValidationError};
 
 use crate::db::transaction::DbTransactionPool;
@@ -31,6 +38,7 @@ use crate::db::{util::SyncTimestamp, DbPool, Sorting};
 use crate::error::{ApiError, ApiErrorKind};
 use crate::server::{metrics, ServerState, BSO_ID_REGEX, COLLECTION_ID_REGEX};
 use crate::settings::Secrets;
+use crate::tokenserver::{self, verify::TokenserverAuth, TokenserverState};
 use crate::web::{
     auth::HawkPayload,
     error::{HawkErrorKind, ValidationErrorKind},
@@ -242,7 +250,8 @@ impl FromRequest for BsoBodies {
             }
         };
 
-        let max_payload_size = state.limits.max_record_payload_bytes as usize;
+        let collection = req.match_info().get("collection").unwrap_or_default();
+        let max_payload_size = state.limits.max_record_payload_bytes_for(collection) as usize;
         let max_post_bytes = state.limits.max_post_bytes as usize;
 
         let fut = fut.and_then(move |body| {
@@ -415,7 +424,8 @@ impl FromRequest for BsoBody {
             }
         };
 
-        let max_payload_size = state.limits.max_record_payload_bytes as usize;
+        let collection = req.match_info().get("collection").unwrap_or_default();
+        let max_payload_size = state.limits.max_record_payload_bytes_for(collection) as usize;
 
         let fut = <Json<BsoBody>>::from_request(&req, payload)
             .map_err(|e| {
@@ -692,6 +702,192 @@ impl FromRequest for MetaRequest {
     }
 }
 
+/// Storage Batch extractor
+///
+/// Extracts/validates the batches of several collections to be staged in
+/// and committed together, given as a JSON object of collection names to
+/// either the id of a pending batch or `{"batch": <id>, "bsos": [...]}`: the
+/// records to stage in that pending batch (or a new one, without an id).
+pub struct StorageBatchRequest {
+    pub user_id: HawkIdentifier,
+    /// Ordered by collection name: the order their collections are locked in
+    pub batches: BTreeMap<String, StorageBatch>,
+    /// Whether to commit the batches once staged (the default), as opposed
+    /// to leaving them pending
+    pub commit: bool,
+    pub metrics: metrics::Metrics,
+}
+
+/// A collection's part of a `StorageBatchRequest`
+#[derive(Clone, Debug, Default)]
+pub struct StorageBatch {
+    /// The pending batch, `None` for a new one
+    pub id: Option<String>,
+    /// Records to stage in the batch
+    pub bsos: Vec<BatchBsoBody>,
+}
+
+/// A collection's part of a `StorageBatchRequest`'s body
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StorageBatchBody {
+    Id(String),
+    Staged {
+        batch: Option<String>,
+        #[serde(default)]
+        bsos: Vec<Value>,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct StorageBatchParams {
+    commit: Option<bool>,
+}
+
+impl FromRequest for StorageBatchRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let mut payload = payload.take();
+        async move {
+            let tags = Tags::from_request(&req, &mut Payload::None).await?;
+            let user_id = HawkIdentifier::from_request(&req, &mut Payload::None).await?;
+            let invalid = |description: String, tags: &Tags, metric_label: &'static str| {
+                let err: ApiError = ValidationErrorKind::FromDetails(
+                    description,
+                    RequestErrorLocation::Body,
+                    Some("batches".to_owned()),
+                    Some(tags.clone()),
+                    label!(metric_label),
+                )
+                .into();
+                err
+            };
+            let commit = Query::<StorageBatchParams>::from_request(&req, &mut Payload::None)
+                .await
+                .map_err(|e| {
+                    let err: ApiError = ValidationErrorKind::FromDetails(
+                        e.to_string(),
+                        RequestErrorLocation::QueryString,
+                        Some("commit".to_owned()),
+                        Some(tags.clone()),
+                        None,
+                    )
+                    .into();
+                    err
+                })?
+                .into_inner()
+                .commit
+                .unwrap_or(true);
+            let body = <Json<BTreeMap<String, StorageBatchBody>>>::from_request(&req, &mut payload)
+                .await
+                .map_err(|e| invalid(e.to_string(), &tags, "request.validate.bad_storage_batch"))?
+                .into_inner();
+            if body.is_empty() {
+                return Err(invalid(
+                    "No batches to stage or commit".to_owned(),
+                    &tags,
+                    "request.validate.bad_storage_batch",
+                )
+                .into());
+            }
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        Some(tags),
+                        None,
+                    )
+                    .into());
+                }
+            };
+
+            let pool = DbTransactionPool::extract(&req).await?.get_pool()?;
+            let mut batches = BTreeMap::new();
+            for (collection, batch) in body {
+                if !VALID_COLLECTION_ID_REGEX.is_match(&collection) {
+                    return Err(invalid(
+                        format!(r#"Invalid collection: "{}""#, collection),
+                        &tags,
+                        "request.process.invalid_collection",
+                    )
+                    .into());
+                }
+                let (id, raw_bsos) = match batch {
+                    StorageBatchBody::Id(id) => (Some(id), vec![]),
+                    StorageBatchBody::Staged { batch, bsos } => (batch, bsos),
+                };
+                if let Some(id) = &id {
+                    if pool.validate_batch_id(id.clone()).is_err() {
+                        return Err(invalid(
+                            format!(r#"Invalid batch ID: "{}""#, id),
+                            &tags,
+                            "request.validate.batch.invalid_id",
+                        )
+                        .into());
+                    }
+                }
+                // Everything's staged atomically: a single invalid record
+                // fails the request (instead of being reported as `failed`)
+                if raw_bsos.len() > state.limits.max_post_records as usize {
+                    return Err(invalid(
+                        format!("Too many records for {}", collection),
+                        &tags,
+                        "request.validate.bad_storage_batch",
+                    )
+                    .into());
+                }
+                let max_payload_size =
+                    state.limits.max_record_payload_bytes_for(&collection) as usize;
+                let mut bsos: Vec<BatchBsoBody> = vec![];
+                for raw_bso in raw_bsos {
+                    let bso = BatchBsoBody::from_raw_bso(&raw_bso).map_err(|e| {
+                        invalid(
+                            format!("{} in {}", e, collection),
+                            &tags,
+                            "request.validate.bad_storage_batch",
+                        )
+                    })?;
+                    if bsos.iter().any(|other| other.id == bso.id) {
+                        return Err(invalid(
+                            "Input BSO has duplicate ID".to_owned(),
+                            &tags,
+                            "request.store.duplicate_bso_id",
+                        )
+                        .into());
+                    }
+                    if bso.payload.as_ref().map_or(0, String::len) > max_payload_size {
+                        return Err(invalid(
+                            format!("Payload of {} too large", bso.id),
+                            &tags,
+                            "request.validate.bad_storage_batch",
+                        )
+                        .into());
+                    }
+                    bsos.push(bso);
+                }
+                batches.insert(collection, StorageBatch { id, bsos });
+            }
+
+            Ok(StorageBatchRequest {
+                user_id,
+                batches,
+                commit,
+                metrics: metrics::Metrics::from(&req),
+            })
+        }
+        .boxed_local()
+    }
+}
+
 /// Desired reply format for a Collection Get request
 #[derive(Copy, Clone, Debug)]
 pub enum ReplyFormat {
@@ -706,6 +902,8 @@ pub struct CollectionRequest {
     pub collection: String,
     pub user_id: HawkIdentifier,
     pub query: BsoQueryParams,
+    /// A pending batch to inspect or abandon instead of the collection
+    pub batch: Option<BatchRequest>,
     pub reply: ReplyFormat,
     pub metrics: metrics::Metrics,
     pub tags: Option<Tags>,
@@ -748,11 +946,13 @@ impl FromRequest for CollectionRequest {
                     .into());
                 }
             };
+            let batch = BatchRequestOpt::extract(&req).await?.opt;
 
             Ok(CollectionRequest {
                 collection,
                 user_id,
                 query,
+                batch,
                 reply,
                 metrics: metrics::Metrics::from(&req),
                 tags: Some(tags),
@@ -849,6 +1049,9 @@ impl FromRequest for CollectionPostRequest {
 
             // XXX: let's not use extract here (maybe convert to extrude?)
             let batch = BatchRequestOpt::extract(&req).await?;
+            // Unlimited collections have no quota to report
+            let quota_enabled =
+                state.quota_enabled && state.limits.quota_for(&collection).is_some();
             Ok(CollectionPostRequest {
                 collection,
                 user_id,
@@ -856,7 +1059,7 @@ impl FromRequest for CollectionPostRequest {
                 bsos,
                 batch: batch.opt,
                 metrics: metrics::Metrics::from(&req),
-                quota_enabled: state.quota_enabled,
+                quota_enabled,
             })
         })
     }
@@ -1207,50 +1410,144 @@ impl From<u32> for HawkIdentifier {
     }
 }
 
-#[derive(Debug, Default, Clone, Deserialize, Validate)]
-#[serde(default)]
+/// Where a paginated listing of BSOs resumes from: the `X-Weave-Next-Offset`
+/// of the previous page.
+///
+/// Those are either a plain number of BSOs to skip (as always issued by
+/// older servers) or an opaque, signed token of the sort key of the previous
+/// page's last BSO, resuming right after it (keyset pagination) instead of
+/// rescanning all the preceding BSOs.
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Offset {
-    pub timestamp: Option<SyncTimestamp>,
+    /// The number of BSOs preceding this page
     pub offset: u64,
+    /// The sort key of the BSO preceding this page
+    pub key: Option<OffsetKey>,
 }
 
-impl ToString for Offset {
-    fn to_string(&self) -> String {
-        match self.timestamp {
-            None => format!("{}", self.offset),
-            Some(ts) => format!("{}:{}", ts.as_i64(), self.offset),
+/// The sort key of a BSO, per the ordering of a `Sorting`: its `modified`
+/// (or `sortindex` when sorting by index) then its id
+#[derive(Debug, Clone, PartialEq)]
+pub struct OffsetKey {
+    pub sort: Sorting,
+    /// `modified` in milliseconds, its `sortindex` for `Sorting::Index` or
+    /// `None` for `Sorting::None` (ordered by id only)
+    pub value: Option<i64>,
+    pub id: String,
+}
+
+const OFFSET_TOKEN_VERSION: &str = "k1";
+
+impl Offset {
+    /// The offset of the page following a page of `len` BSOs (itself
+    /// beginning at `self`) and ending with the BSO of the given sort key
+    pub fn next(
+        &self,
+        len: usize,
+        sort: Sorting,
+        last: Option<(&str, SyncTimestamp, Option<i32>)>,
+    ) -> Offset {
+        let key = match last {
+            Some((id, modified, sortindex)) => Some(OffsetKey {
+                sort,
+                value: match sort {
+                    Sorting::None => None,
+                    Sorting::Newest | Sorting::Oldest => Some(modified.as_i64()),
+                    Sorting::Index => sortindex.map(i64::from),
+                },
+                id: id.to_owned(),
+            }),
+            // An empty page resumes where it began
+            None => self.key.clone(),
+        };
+        Offset {
+            offset: self.offset + len as u64,
+            key,
         }
     }
-}
 
-impl FromStr for Offset {
-    type Err = ParseIntError;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // issue559: Disable ':' support for now: simply parse as i64 as
-        // previously (it was u64 previously but i64's close enough)
-        let result = Offset {
-            timestamp: None,
-            offset: s.parse::<u64>()?,
+    /// The signature of a token's payload: an HMAC keyed with the master
+    /// secret, so that only tokens issued by the server are accepted
+    fn signer(secret: &[u8], payload: &str) -> Hmac<Sha256> {
+        let mut hmac = Hmac::<Sha256>::new_varkey(secret).expect("HMAC takes keys of any size");
+        hmac.update(OFFSET_TOKEN_VERSION.as_bytes());
+        hmac.update(payload.as_bytes());
+        hmac
+    }
+
+    /// Encode as an `X-Weave-Next-Offset`: the plain number of BSOs to skip
+    /// or a token signed with `secret`
+    pub fn to_token(&self, secret: &[u8]) -> String {
+        let key = match &self.key {
+            None => return self.offset.to_string(),
+            Some(key) => key,
         };
-        /*
-        let result = match s.chars().position(|c| c == ':') {
-            None => Offset {
-                timestamp: None,
-                offset: s.parse::<u64>()?,
-            },
-            Some(_colon_position) => {
-                let mut parts = s.split(':');
-                let timestamp_string = parts.next().unwrap_or("0");
-                let timestamp = SyncTimestamp::from_milliseconds(timestamp_string.parse::<u64>()?);
-                let offset = parts.next().unwrap_or("0").parse::<u64>()?;
-                Offset {
-                    timestamp: Some(timestamp),
-                    offset,
-                }
+        let sort = match key.sort {
+            Sorting::None => "none",
+            Sorting::Newest => "newest",
+            Sorting::Oldest => "oldest",
+            Sorting::Index => "index",
+        };
+        let value = key.value.map(|value| value.to_string()).unwrap_or_default();
+        let payload = base64::encode_config(
+            format!("{}:{}:{}:{}", sort, value, self.offset, key.id),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let signature = Self::signer(secret, &payload).finalize().into_bytes();
+        format!(
+            "{}.{}.{}",
+            OFFSET_TOKEN_VERSION,
+            payload,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Decode an `offset` query parameter, rejecting tokens not signed with
+    /// `secret`
+    pub fn from_token(s: &str, secret: &[u8]) -> Result<Self, String> {
+        if let Ok(offset) = s.parse::<u64>() {
+            return Ok(Offset { offset, key: None });
+        }
+        let invalid = || format!("Invalid offset: {}", s);
+        let mut parts = s.split('.');
+        let (payload, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(OFFSET_TOKEN_VERSION), Some(payload), Some(signature), None) => {
+                (payload, signature)
             }
+            _ => return Err(invalid()),
         };
-        */
-        Ok(result)
+        let signature =
+            base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        Self::signer(secret, payload)
+            .verify(&signature)
+            .map_err(|_| invalid())?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|payload| String::from_utf8(payload).ok())
+            .ok_or_else(invalid)?;
+
+        let mut fields = payload.splitn(4, ':');
+        let sort = match fields.next() {
+            Some("none") => Sorting::None,
+            Some("newest") => Sorting::Newest,
+            Some("oldest") => Sorting::Oldest,
+            Some("index") => Sorting::Index,
+            _ => return Err(invalid()),
+        };
+        let value = match fields.next() {
+            Some("") => None,
+            Some(value) => Some(value.parse::<i64>().map_err(|_| invalid())?),
+            None => return Err(invalid()),
+        };
+        let offset = fields
+            .next()
+            .and_then(|offset| offset.parse::<u64>().ok())
+            .ok_or_else(invalid)?;
+        let id = fields.next().ok_or_else(invalid)?.to_owned();
+        Ok(Offset {
+            offset,
+            key: Some(OffsetKey { sort, value, id }),
+        })
     }
 }
 
@@ -1276,8 +1573,9 @@ pub struct BsoQueryParams {
     /// maximum number of items to return (integer)
     pub limit: Option<u32>,
 
-    /// position at which to restart search (string)
-    #[serde(deserialize_with = "deserialize_offset")]
+    /// position at which to restart search (string, read by `from_request`
+    /// as verifying it requires the master secret)
+    #[serde(skip)]
     pub offset: Option<Offset>,
 
     /// a comma-separated list of BSO ids (list of strings)
@@ -1302,7 +1600,7 @@ impl FromRequest for BsoQueryParams {
         Box::pin(async move {
             let tags = Tags::from_request(&req, &mut payload).await?;
 
-            let params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
+            let mut params = Query::<BsoQueryParams>::from_request(&req, &mut payload)
                 .map_err(|e| {
                     ValidationErrorKind::FromDetails(
                         e.to_string(),
@@ -1322,41 +1620,65 @@ impl FromRequest for BsoQueryParams {
                     None,
                 )
             })?;
-            // issue559: Dead code (timestamp always None)
-            /*
-               if params.sort != Sorting::Index {
-                   if let Some(timestamp) = params.offset.as_ref().and_then(|offset| offset.timestamp)
-                   {
-                       let bound = timestamp.as_i64();
-                       if let Some(newer) = params.newer {
-                           if bound < newer.as_i64() {
-                               return Err(ValidationErrorKind::FromDetails(
-                                   format!("Invalid Offset {} {}", bound, newer.as_i64()),
-                                   RequestErrorLocation::QueryString,
-                                   Some("newer".to_owned()),
-                                   None,
-                               )
-                               .into());
-                           }
-                       } else if let Some(older) = params.older {
-                           if bound > older.as_i64() {
-                               return Err(ValidationErrorKind::FromDetails(
-                                   "Invalid Offset".to_owned(),
-                                   RequestErrorLocation::QueryString,
-                                   Some("older".to_owned()),
-                                   None,
-                               )
-                               .into());
-                           }
-                       }
-                   }
-               }
-            */
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        Some(tags),
+                        None,
+                    )
+                    .into());
+                }
+            };
+            let offset = Query::<OffsetParams>::from_request(&req, &mut payload)
+                .await
+                .ok()
+                .and_then(|query| query.into_inner().offset);
+            if let Some(offset) = offset {
+                let offset =
+                    Offset::from_token(&offset, &state.secrets.master_secret).map_err(|e| {
+                        ValidationErrorKind::FromDetails(
+                            e,
+                            RequestErrorLocation::QueryString,
+                            Some("offset".to_owned()),
+                            Some(tags.clone()),
+                            None,
+                        )
+                    })?;
+                params.offset = Some(offset);
+            }
+            // Offset tokens are only valid for the ordering they were issued for
+            let key_sort = params
+                .offset
+                .as_ref()
+                .and_then(|offset| offset.key.as_ref())
+                .map(|key| key.sort);
+            if key_sort.map_or(false, |sort| sort != params.sort) {
+                return Err(ValidationErrorKind::FromDetails(
+                    "Invalid offset for the requested sort".to_owned(),
+                    RequestErrorLocation::QueryString,
+                    Some("offset".to_owned()),
+                    Some(tags),
+                    None,
+                )
+                .into());
+            }
             Ok(params)
         })
     }
 }
 
+/// The `offset` of a BSO search's query string
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct OffsetParams {
+    offset: Option<String>,
+}
+
 #[derive(Debug, Default, Clone, Deserialize, Validate)]
 #[serde(default)]
 pub struct BatchParams {
@@ -1416,11 +1738,15 @@ impl FromRequest for BatchRequestOpt {
             };
 
             let limits = &state.limits;
+            let collection = req.match_info().get("collection").unwrap_or_default();
 
             let checks = [
                 (X_WEAVE_RECORDS, limits.max_post_records),
                 ("X-Weave-Bytes", limits.max_post_bytes),
-                ("X-Weave-Total-Records", limits.max_total_records),
+                (
+                    "X-Weave-Total-Records",
+                    limits.max_total_records_for(collection),
+                ),
                 ("X-Weave-Total-Bytes", limits.max_total_bytes),
             ];
             for (header, limit) in &checks {
@@ -1747,21 +2073,82 @@ where
     }
 }
 
-fn deserialize_offset<'de, D>(deserializer: D) -> Result<Option<Offset>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let maybe_str: Option<String> = Deserialize::deserialize(deserializer)?;
-    if let Some(val) = maybe_str {
-        return Ok(Some(Offset::from_str(&val).map_err(SerdeError::custom)?));
-    }
-    Ok(None)
-}
-
 // Tokenserver extractor
-#[derive(Debug, Default, Clone, Deserialize)]
+#[derive(Clone, Debug)]
 pub struct TokenServerRequest {
-    // TODO extract required headers from the request into this struct.
+    pub auth: TokenserverAuth,
+    /// The `keys_changed_at` the client claims via `X-KeyID`, if any
+    pub keys_changed_at: Option<i64>,
+    /// The hex encoded client state
+    pub client_state: String,
+    pub tokenserver: TokenserverState,
+    pub db_pool: Box<dyn DbPool>,
+    pub secrets: Arc<Secrets>,
+    pub metrics: metrics::Metrics,
+}
+
+impl TokenServerRequest {
+    fn parse_auth(headers: &HeaderMap) -> Result<TokenserverAuth, ApiError> {
+        let header = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| {
+                ApiErrorKind::InvalidCredentials("Missing Authorization header".to_owned())
+            })?;
+        let mut parts = header.splitn(2, ' ');
+        let scheme = parts.next().unwrap_or_default().to_lowercase();
+        let credential = parts.next().unwrap_or_default().trim();
+        if credential.is_empty() {
+            Err(ApiErrorKind::InvalidCredentials(
+                "Invalid Authorization header".to_owned(),
+            ))?;
+        }
+        match scheme.as_str() {
+            "browserid" => Ok(TokenserverAuth::BrowserId(credential.to_owned())),
+            "bearer" => Ok(TokenserverAuth::OAuth(credential.to_owned())),
+            _ => Err(ApiErrorKind::InvalidCredentials(
+                "Unsupported Authorization scheme".to_owned(),
+            )
+            .into()),
+        }
+    }
+
+    /// Parse `X-KeyID: <keys_changed_at>-<base64url client state>` (sent
+    /// alongside OAuth tokens) or the legacy `X-Client-State: <hex>`.
+    fn parse_key_id(headers: &HeaderMap) -> Result<(Option<i64>, String), ApiError> {
+        let invalid = |reason: &str| -> ApiError {
+            ApiErrorKind::InvalidCredentials(reason.to_owned()).into()
+        };
+        let (keys_changed_at, client_state) = if let Some(key_id) = headers.get("x-keyid") {
+            let key_id = key_id.to_str().map_err(|_| invalid("Invalid X-KeyID"))?;
+            let mut parts = key_id.splitn(2, '-');
+            let keys_changed_at = parts
+                .next()
+                .and_then(|v| v.parse::<i64>().ok())
+                .ok_or_else(|| invalid("Invalid X-KeyID"))?;
+            let client_state = parts
+                .next()
+                .and_then(|v| base64::decode_config(v, base64::URL_SAFE_NO_PAD).ok())
+                .ok_or_else(|| invalid("Invalid X-KeyID"))?;
+            (
+                Some(keys_changed_at),
+                tokenserver::encode_client_state(&client_state),
+            )
+        } else {
+            let client_state = match headers.get("x-client-state") {
+                Some(value) => value
+                    .to_str()
+                    .map_err(|_| invalid("Invalid X-Client-State"))?
+                    .to_lowercase(),
+                None => "".to_owned(),
+            };
+            (None, client_state)
+        };
+        // Validate the hex encoding and length up front, whichever header
+        // the client state came from
+        tokenserver::decode_client_state(&client_state)?;
+        Ok((keys_changed_at, client_state))
+    }
 }
 
 impl FromRequest for TokenServerRequest {
@@ -1769,9 +2156,175 @@ impl FromRequest for TokenServerRequest {
     type Error = Error;
     type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
-    /// Extract and validate the precondition headers
-    fn from_request(_req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        Box::pin(async move { Ok(Self {}) })
+    /// Extract the Authorization, X-KeyID and X-Client-State headers
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        None,
+                        None,
+                    )
+                    .into());
+                }
+            };
+            let tokenserver = match &state.tokenserver {
+                Some(tokenserver) => tokenserver.clone(),
+                None => return Err(actix_web::error::ErrorNotFound("Not Found")),
+            };
+            let headers = req.headers();
+            let auth = Self::parse_auth(headers)?;
+            let (keys_changed_at, client_state) = Self::parse_key_id(headers)?;
+            Ok(Self {
+                auth,
+                keys_changed_at,
+                client_state,
+                tokenserver,
+                db_pool: state.db_pool.clone(),
+                secrets: Arc::clone(&state.secrets),
+                metrics: metrics::Metrics::from(&req),
+            })
+        })
+    }
+}
+
+/// The user's `fxa_uid`/`fxa_kid` (which only Spanner keys its data by),
+/// from the admin API's query string
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AdminUserParams {
+    fxa_uid: String,
+    fxa_kid: String,
+}
+
+// Admin API extractor
+#[derive(Clone, Debug)]
+pub struct AdminRequest {
+    pub user_id: HawkIdentifier,
+    pub db_pool: Box<dyn DbPool>,
+    pub metrics: metrics::Metrics,
+    /// Identifies the request (and its user) in the audit log
+    pub tags: Tags,
+}
+
+impl AdminRequest {
+    /// Whether the `Authorization: Bearer <secret>` header matches the admin
+    /// secret
+    fn authorized(headers: &HeaderMap, secret: &str) -> bool {
+        let credential = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                let mut parts = value.splitn(2, ' ');
+                match parts.next() {
+                    Some(scheme) if scheme.eq_ignore_ascii_case("bearer") => parts.next(),
+                    _ => None,
+                }
+            })
+            .map(str::trim)
+            .unwrap_or_default();
+        // Compare digests, so the comparison's timing reveals nothing about
+        // the secret itself
+        !credential.is_empty()
+            && Sha256::digest(credential.as_bytes()) == Sha256::digest(secret.as_bytes())
+    }
+
+    /// Record an action taken against the user in the audit log
+    pub fn audit(&self, action: &str) {
+        let mut tags = self.tags.clone();
+        tags.tags
+            .insert("admin.action".to_owned(), action.to_owned());
+        info!("🔧 Admin action: {}", action; tags);
+    }
+}
+
+impl FromRequest for AdminRequest {
+    type Config = ();
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    /// Authenticate the request and extract the user from its path/query
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let state = match req.app_data::<Data<ServerState>>() {
+                Some(s) => s,
+                None => {
+                    error!("⚠️ Could not load the app state");
+                    return Err(ValidationErrorKind::FromDetails(
+                        "Internal error".to_owned(),
+                        RequestErrorLocation::Unknown,
+                        Some("state".to_owned()),
+                        None,
+                        None,
+                    )
+                    .into());
+                }
+            };
+            let secret = match &state.admin_secret {
+                Some(secret) => secret,
+                None => return Err(actix_web::error::ErrorNotFound("Not Found")),
+            };
+            let mut tags = Tags::from_request_head(req.head());
+            if let Some(remote) = req.connection_info().realip_remote_addr() {
+                tags.tags
+                    .insert("admin.remote".to_owned(), remote.to_owned());
+            }
+            if !Self::authorized(req.headers(), secret) {
+                warn!("⚠️ Unauthorized admin request"; tags);
+                return Err(ApiError::from(ApiErrorKind::InvalidCredentials(
+                    "Invalid admin credentials".to_owned(),
+                ))
+                .into());
+            }
+
+            let legacy_id = req
+                .match_info()
+                .get("uid")
+                .and_then(|uid| u64::from_str(uid).ok())
+                .ok_or_else(|| {
+                    ValidationErrorKind::FromDetails(
+                        "Invalid UID".to_owned(),
+                        RequestErrorLocation::Path,
+                        Some("uid".to_owned()),
+                        Some(tags.clone()),
+                        None,
+                    )
+                })?;
+            let params = Query::<AdminUserParams>::from_query(req.query_string())
+                .map_err(|e| {
+                    ValidationErrorKind::FromDetails(
+                        e.to_string(),
+                        RequestErrorLocation::QueryString,
+                        None,
+                        Some(tags.clone()),
+                        None,
+                    )
+                })?
+                .into_inner();
+            tags.tags
+                .insert("admin.uid".to_owned(), legacy_id.to_string());
+            if !params.fxa_uid.is_empty() {
+                tags.tags
+                    .insert("admin.fxa_uid".to_owned(), params.fxa_uid.clone());
+            }
+            Ok(Self {
+                user_id: HawkIdentifier {
+                    legacy_id,
+                    fxa_uid: params.fxa_uid,
+                    fxa_kid: params.fxa_kid,
+                },
+                db_pool: state.db_pool.clone(),
+                metrics: metrics::Metrics::from(&req),
+                tags,
+            })
+        })
     }
 }
 
@@ -1836,6 +2389,10 @@ mod tests {
             port: 8000,
             metrics: Box::new(metrics::metrics_from_opts(&settings).unwrap()),
             quota_enabled: settings.enable_quota,
+            tokenserver: None,
+            admin_secret: None,
+            prometheus: None,
+            rate_limiter: None,
         }
     }
 
@@ -1986,6 +2543,50 @@ mod tests {
         assert_eq!(result.full, true);
     }
 
+    #[test]
+    fn test_offset_tokens() {
+        let secret = &SECRETS.master_secret;
+        // Numeric offsets of older servers remain valid
+        let offset = Offset::from_token("20", secret).unwrap();
+        assert_eq!(offset.offset, 20);
+        assert_eq!(offset.key, None);
+        assert_eq!(offset.to_token(secret), "20");
+
+        let last = Some(("a:b", SyncTimestamp::from_seconds(2.43), Some(3)));
+        let next = offset.next(10, Sorting::Index, last);
+        let token = next.to_token(secret);
+        let parsed = Offset::from_token(&token, secret).unwrap();
+        assert_eq!(parsed, next);
+        assert_eq!(parsed.offset, 30);
+        assert_eq!(
+            parsed.key,
+            Some(OffsetKey {
+                sort: Sorting::Index,
+                value: Some(3),
+                id: "a:b".to_owned(),
+            })
+        );
+
+        // Tampered or forged tokens are rejected
+        let mut tampered = token.clone();
+        tampered.insert(4, 'A');
+        assert!(Offset::from_token(&tampered, secret).is_err());
+        assert!(Offset::from_token(&token.replace("k1.", "k2."), secret).is_err());
+        assert!(Offset::from_token("10:20", secret).is_err());
+        assert!(Offset::from_token(&token, b"another secret").is_err());
+
+        // And only valid for the sort they were issued for
+        let req = TestRequest::with_uri(&format!("/?sort=newest&offset={}", token))
+            .data(make_state())
+            .to_http_request();
+        assert!(block_on(BsoQueryParams::extract(&req)).is_err());
+        let req = TestRequest::with_uri(&format!("/?sort=index&offset={}", token))
+            .data(make_state())
+            .to_http_request();
+        let result = block_on(BsoQueryParams::extract(&req)).unwrap();
+        assert_eq!(result.offset, Some(next));
+    }
+
     #[test]
     fn test_valid_bso_request() {
         let payload = HawkPayload::test_default(*USER_ID);
@@ -2400,6 +3001,23 @@ mod tests {
         */
     }
 
+    #[test]
+    fn key_id_client_state_length() {
+        let parse = |name: &str, value: &str| {
+            let req = TestRequest::default().header(name, value).to_http_request();
+            TokenServerRequest::parse_key_id(req.headers())
+        };
+        let client_state = base64::encode_config(&[0xab; 16], base64::URL_SAFE_NO_PAD);
+        let (keys_changed_at, hex) = parse("X-KeyID", &format!("1234-{}", client_state)).unwrap();
+        assert_eq!(keys_changed_at, Some(1234));
+        assert_eq!(hex, "ab".repeat(16));
+
+        // Too long for the users table, however it's sent
+        let client_state = base64::encode_config(&[0xab; 17], base64::URL_SAFE_NO_PAD);
+        assert!(parse("X-KeyID", &format!("1234-{}", client_state)).is_err());
+        assert!(parse("X-Client-State", &"ab".repeat(17)).is_err());
+    }
+
     #[actix_rt::test]
     async fn test_max_ttl() {
         let bso_body = json!([
@@ -1,14 +1,16 @@
 //! API Handlers
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use actix_web::{http::StatusCode, web::Data, Error, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::{channel::mpsc, future, stream, FutureExt, SinkExt, StreamExt};
 use serde::Serialize;
 use serde_json::{json, Value};
 
 use crate::{
     db::{
         params,
-        results::{CreateBatch, Paginated},
+        results::{BatchTotals, CreateBatch, Paginated},
         transaction::DbTransactionPool,
         util::SyncTimestamp,
         Db, DbError, DbErrorKind,
@@ -17,8 +19,9 @@ use crate::{
     server::ServerState,
     web::{
         extractors::{
-            BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest, HeartbeatRequest,
-            MetaRequest, ReplyFormat, TestErrorRequest,
+            BatchRequest, BsoPutRequest, BsoRequest, CollectionPostRequest, CollectionRequest,
+            HeartbeatRequest, MetaRequest, Offset, ReplyFormat, StorageBatchRequest,
+            TestErrorRequest,
         },
         X_LAST_MODIFIED, X_WEAVE_NEXT_OFFSET, X_WEAVE_RECORDS,
     },
@@ -26,6 +29,14 @@ use crate::{
 
 pub const ONE_KB: f64 = 1024.0;
 
+/// Serialized BSOs buffered ahead of a slow client when streaming a
+/// collection
+const STREAM_BUFFER: usize = 16;
+
+/// Most BSOs read per page (and so per transaction) when streaming a
+/// collection
+const STREAM_PAGE_SIZE: usize = 1000;
+
 pub async fn get_collections(
     meta: MetaRequest,
     db_pool: DbTransactionPool,
@@ -82,12 +93,20 @@ pub async fn get_collection_usage(
 pub async fn get_quota(
     meta: MetaRequest,
     db_pool: DbTransactionPool,
+    state: Data<ServerState>,
 ) -> Result<HttpResponse, Error> {
+    // The protocol's response has room for a single quota: report that of
+    // collections without their own (listed in `/info/configuration`)
+    let quota = if state.quota_enabled && state.limits.max_quota_limit > 0 {
+        Some(f64::from(state.limits.max_quota_limit) / ONE_KB)
+    } else {
+        None
+    };
     db_pool
         .transaction_http(|db| async move {
             meta.metrics.incr("request.get_quota");
             let usage = db.get_storage_usage(meta.user_id).await?;
-            Ok(HttpResponse::Ok().json(vec![Some(usage as f64 / ONE_KB), None]))
+            Ok(HttpResponse::Ok().json(vec![Some(usage as f64 / ONE_KB), quota]))
         })
         .await
 }
@@ -104,10 +123,104 @@ pub async fn delete_all(
         .await
 }
 
+/// Stage records in the batches of several collections then, unless asked
+/// not to, commit all of the batches atomically: every record is written
+/// with the same timestamp, or none are.
+pub async fn post_storage_batch(
+    sbreq: StorageBatchRequest,
+    db_pool: DbTransactionPool,
+) -> Result<HttpResponse, Error> {
+    db_pool
+        .transaction_http(|db| async move {
+            sbreq.metrics.incr("request.post_storage_batch");
+            // Lock all of the collections up front, in a deterministic
+            // (sorted) order so concurrent commits can't deadlock
+            for collection in sbreq.batches.keys() {
+                db.lock_for_write(params::LockCollection {
+                    user_id: sbreq.user_id.clone(),
+                    collection: collection.clone(),
+                })
+                .await?;
+            }
+
+            let mut ids = BTreeMap::new();
+            for (collection, batch) in sbreq.batches {
+                let bsos: Vec<_> = batch.bsos.into_iter().map(From::from).collect();
+                let id = match batch.id {
+                    Some(id) => {
+                        let exists = db
+                            .validate_batch(params::ValidateBatch {
+                                user_id: sbreq.user_id.clone(),
+                                collection: collection.clone(),
+                                id: id.clone(),
+                            })
+                            .await?;
+                        if !exists {
+                            let err: DbError = DbErrorKind::BatchNotFound.into();
+                            return Err(ApiError::from(err).into());
+                        }
+                        if !bsos.is_empty() {
+                            db.append_to_batch(params::AppendToBatch {
+                                user_id: sbreq.user_id.clone(),
+                                collection: collection.clone(),
+                                batch: CreateBatch {
+                                    id: id.clone(),
+                                    size: None,
+                                },
+                                bsos,
+                            })
+                            .await?;
+                        }
+                        id
+                    }
+                    None => {
+                        db.create_batch(params::CreateBatch {
+                            user_id: sbreq.user_id.clone(),
+                            collection: collection.clone(),
+                            bsos,
+                        })
+                        .await?
+                        .id
+                    }
+                };
+                ids.insert(collection, id);
+            }
+            if !sbreq.commit {
+                return Ok(HttpResponse::Accepted().json(json!({ "batches": ids })));
+            }
+
+            let mut modified = SyncTimestamp::default();
+            let mut totals = HashMap::new();
+            for (collection, id) in ids {
+                let result = db
+                    .commit_batch(params::CommitBatch {
+                        user_id: sbreq.user_id.clone(),
+                        collection: collection.clone(),
+                        batch: params::Batch { id },
+                        pending: BatchTotals::default(),
+                    })
+                    .await?;
+                modified = result.modified;
+                totals.insert(collection, result.totals);
+            }
+
+            Ok(HttpResponse::build(StatusCode::OK)
+                .header(X_LAST_MODIFIED, modified.as_header())
+                .json(json!({
+                    "modified": modified,
+                    "collections": totals,
+                })))
+        })
+        .await
+}
+
 pub async fn delete_collection(
     coll: CollectionRequest,
     db_pool: DbTransactionPool,
 ) -> Result<HttpResponse, Error> {
+    if let Some(breq) = coll.batch.clone() {
+        return delete_collection_batch(coll, breq, db_pool).await;
+    }
     db_pool
         .transaction_http(|db| async move {
             let delete_bsos = !coll.query.ids.is_empty();
@@ -149,10 +262,52 @@ pub async fn delete_collection(
         .await
 }
 
+/// Abandon a pending batch, discarding whatever was staged in it
+async fn delete_collection_batch(
+    coll: CollectionRequest,
+    breq: BatchRequest,
+    db_pool: DbTransactionPool,
+) -> Result<HttpResponse, Error> {
+    let id = pending_batch_id(breq)?;
+    db_pool
+        .transaction_http(|db| async move {
+            coll.metrics.clone().incr("request.delete_collection_batch");
+            let batch = db
+                .get_batch(params::GetBatch {
+                    user_id: coll.user_id.clone(),
+                    collection: coll.collection.clone(),
+                    id,
+                })
+                .await?;
+            let batch = match batch {
+                Some(batch) => batch,
+                None => {
+                    let err: DbError = DbErrorKind::BatchNotFound.into();
+                    return Err(ApiError::from(err).into());
+                }
+            };
+            db.delete_batch(params::DeleteBatch {
+                user_id: coll.user_id.clone(),
+                collection: coll.collection.clone(),
+                id: batch.id,
+            })
+            .await?;
+            Ok(HttpResponse::NoContent().finish())
+        })
+        .await
+}
+
 pub async fn get_collection(
     coll: CollectionRequest,
     db_pool: DbTransactionPool,
+    state: Data<ServerState>,
 ) -> Result<HttpResponse, Error> {
+    if let Some(breq) = coll.batch.clone() {
+        return get_collection_batch(coll, breq, db_pool).await;
+    }
+    if coll.query.full {
+        return stream_collection(coll, db_pool, state).await;
+    }
     db_pool
         .transaction_http(|db| async move {
             coll.metrics.clone().incr("request.get_collection");
@@ -162,24 +317,258 @@ pub async fn get_collection(
                 collection: coll.collection.clone(),
             };
 
-            let response = if coll.query.full {
-                let result = db.get_bsos(params).await;
-                finish_get_collection(&coll, db, result).await?
-            } else {
-                // Changed to be a Paginated list of BSOs, need to extract IDs from them.
-                let result = db.get_bso_ids(params).await;
-                finish_get_collection(&coll, db, result).await?
-            };
+            // Changed to be a Paginated list of BSOs, need to extract IDs from them.
+            let result = db.get_bso_ids(params).await;
+            finish_get_collection(&coll, db, result, &state).await
+        })
+        .await
+}
 
-            Ok(response)
+/// Describe a pending batch: when it expires and the ids staged in it so far
+async fn get_collection_batch(
+    coll: CollectionRequest,
+    breq: BatchRequest,
+    db_pool: DbTransactionPool,
+) -> Result<HttpResponse, Error> {
+    let id = pending_batch_id(breq)?;
+    db_pool
+        .transaction_http(|db| async move {
+            coll.metrics.clone().incr("request.get_collection_batch");
+            let items = db
+                .get_batch_items(params::GetBatchItems {
+                    user_id: coll.user_id.clone(),
+                    collection: coll.collection.clone(),
+                    id: id.clone(),
+                })
+                .await?;
+            let items = match items {
+                Some(items) => items,
+                None => {
+                    let err: DbError = DbErrorKind::BatchNotFound.into();
+                    return Err(ApiError::from(err).into());
+                }
+            };
+            Ok(HttpResponse::build(StatusCode::OK)
+                .header(X_WEAVE_RECORDS, items.count.to_string())
+                .json(json!({
+                    "batch": id,
+                    "expiry": items.expiry,
+                    "count": items.count,
+                    "ids": items.ids,
+                })))
         })
         .await
 }
 
+/// The id of an existing batch named by a GET/DELETE (which can't create one)
+fn pending_batch_id(breq: BatchRequest) -> Result<String, Error> {
+    breq.id.ok_or_else(|| {
+        let err: DbError = DbErrorKind::BatchNotFound.into();
+        ApiError::from(err).into()
+    })
+}
+
+/// What's known of a streamed collection response before its body
+struct StreamedHead {
+    ts: SyncTimestamp,
+    /// The ids of a limited page
+    ids: Option<Vec<String>>,
+    offset: Option<Offset>,
+}
+
+/// The BSOs sent of one page of a streamed collection
+#[derive(Default)]
+struct SentPage {
+    len: usize,
+    /// The sort key of the last one
+    last: Option<(String, SyncTimestamp, Option<i32>)>,
+}
+
+/// Respond to a `full` collection GET, writing out the BSOs as they're read
+/// from the db instead of buffering all of them first.
+///
+/// A first transaction checks the preconditions and determines the
+/// response's headers. The BSOs are then read a page at a time, each from its
+/// own transaction, so that no connection's held for the whole of a slow
+/// download: a limited page's by the ids determined upfront (so matching its
+/// `X-Weave-Next-Offset`), otherwise resuming after the previous page's last
+/// BSO. Those pages aren't of one snapshot, so `X-Weave-Records` is omitted.
+///
+/// The pages are read in a "pump" future that's polled alongside the
+/// response body, handing over the serialized BSOs through a bounded channel.
+async fn stream_collection(
+    coll: CollectionRequest,
+    db_pool: DbTransactionPool,
+    state: Data<ServerState>,
+) -> Result<HttpResponse, Error> {
+    coll.metrics.clone().incr("request.get_collection");
+    let mut head = None;
+    let resp = {
+        let (coll, head) = (&coll, &mut head);
+        db_pool
+            .transaction_http(|db| async move {
+                let ts = db
+                    .extract_resource(coll.user_id.clone(), Some(coll.collection.clone()), None)
+                    .await?;
+                let (ids, offset) = if coll.query.limit.is_some() {
+                    let params = params::GetBsos {
+                        user_id: coll.user_id.clone(),
+                        params: coll.query.clone(),
+                        collection: coll.collection.clone(),
+                    };
+                    let result = db.get_bso_ids(params).await.or_else(|e| {
+                        if e.is_collection_not_found() {
+                            // For b/w compat, non-existent collections must
+                            // return an empty list
+                            Ok(Paginated::default())
+                        } else {
+                            Err(e)
+                        }
+                    })?;
+                    (Some(result.items), result.offset)
+                } else {
+                    (None, None)
+                };
+                *head = Some(StreamedHead { ts, ids, offset });
+                Ok(HttpResponse::Ok().finish())
+            })
+            .await?
+    };
+    let head = match head {
+        Some(head) => head,
+        // A precondition failed
+        None => return Ok(resp),
+    };
+
+    let pool = db_pool.get_pool()?;
+    let reply = coll.reply;
+    let (mut body_tx, body_rx) = mpsc::channel(STREAM_BUFFER);
+    let mut ids = head.ids.map(Vec::into_iter);
+    let pump = async move {
+        let sort = coll.query.sort;
+        let mut offset = coll.query.offset.clone();
+        let mut first = true;
+        loop {
+            let mut params = params::GetBsos {
+                user_id: coll.user_id.clone(),
+                params: coll.query.clone(),
+                collection: coll.collection.clone(),
+            };
+            match ids.as_mut() {
+                Some(ids) => {
+                    let page: Vec<_> = ids.take(STREAM_PAGE_SIZE).collect();
+                    if page.is_empty() {
+                        break;
+                    }
+                    params.params.limit = Some(page.len() as u32);
+                    params.params.offset = None;
+                    params.params.ids = page;
+                }
+                None => {
+                    params.params.limit = Some(STREAM_PAGE_SIZE as u32);
+                    params.params.offset = offset.clone();
+                }
+            }
+
+            let db = pool.get().await?;
+            db.begin(false).await?;
+            let sent = match send_page(db.as_ref(), params, reply, &mut first, &mut body_tx).await {
+                Ok(sent) => {
+                    db.commit().await?;
+                    sent
+                }
+                Err(e) => {
+                    db.rollback().await?;
+                    return Err(e);
+                }
+            };
+            let sent = match sent {
+                Some(sent) => sent,
+                // The client went away
+                None => return Ok(()),
+            };
+            if ids.is_none() {
+                if sent.len < STREAM_PAGE_SIZE {
+                    break;
+                }
+                let last = sent
+                    .last
+                    .as_ref()
+                    .map(|(id, modified, sortindex)| (id.as_str(), *modified, *sortindex));
+                offset = Some(offset.unwrap_or_default().next(sent.len, sort, last));
+            }
+        }
+        let tail = match reply {
+            ReplyFormat::Json if first => "[]",
+            ReplyFormat::Json => "]",
+            ReplyFormat::Newlines => return Ok(()),
+        };
+        let _ = body_tx.send(Bytes::from(tail)).await;
+        Ok::<_, ApiError>(())
+    };
+
+    // Errors past this point can only abort the response
+    let errors = pump
+        .into_stream()
+        .filter_map(|result| future::ready(result.err().map(|e| Err(Error::from(e)))));
+    let body = stream::select(body_rx.map(Ok), errors).boxed_local();
+
+    let mut builder = HttpResponse::build(StatusCode::OK);
+    builder
+        .header(X_LAST_MODIFIED, head.ts.as_header())
+        .if_some(head.offset, |offset, resp| {
+            resp.header(
+                X_WEAVE_NEXT_OFFSET,
+                offset.to_token(&state.secrets.master_secret),
+            );
+        });
+    match reply {
+        ReplyFormat::Json => builder.content_type("application/json"),
+        ReplyFormat::Newlines => builder.content_type("application/newlines"),
+    };
+    Ok(builder.streaming::<_, Error>(body))
+}
+
+/// Send the BSOs of one page of a streamed collection through `body_tx`,
+/// returning None should the client have gone away
+async fn send_page(
+    db: &dyn Db<'_>,
+    params: params::GetBsos,
+    reply: ReplyFormat,
+    first: &mut bool,
+    body_tx: &mut mpsc::Sender<Bytes>,
+) -> ApiResult<Option<SentPage>> {
+    let mut items = match db.get_bsos_stream(params).await {
+        Ok(items) => items,
+        // For b/w compat, non-existent collections must return an empty list
+        Err(e) if e.is_collection_not_found() => return Ok(Some(SentPage::default())),
+        Err(e) => return Err(e),
+    };
+    let mut sent = SentPage::default();
+    while let Some(bso) = items.next().await {
+        let bso = bso?;
+        let json =
+            serde_json::to_string(&bso).map_err(|e| ApiErrorKind::Internal(e.to_string()))?;
+        let chunk = match reply {
+            ReplyFormat::Json if *first => format!("[{}", json),
+            ReplyFormat::Json => format!(",{}", json),
+            ReplyFormat::Newlines => json.replace("\n", "\\u000a") + "\n",
+        };
+        *first = false;
+        if body_tx.send(Bytes::from(chunk)).await.is_err() {
+            return Ok(None);
+        }
+        sent.len += 1;
+        sent.last = Some((bso.id, bso.modified, bso.sortindex));
+    }
+    Ok(Some(sent))
+}
+
 async fn finish_get_collection<T>(
     coll: &CollectionRequest,
     db: Box<dyn Db<'_> + '_>,
     result: Result<Paginated<T>, ApiError>,
+    state: &ServerState,
 ) -> Result<HttpResponse, Error>
 where
     T: Serialize + Default + 'static,
@@ -203,7 +592,10 @@ where
         .header(X_LAST_MODIFIED, ts.as_header())
         .header(X_WEAVE_RECORDS, result.items.len().to_string())
         .if_some(result.offset, |offset, resp| {
-            resp.header(X_WEAVE_NEXT_OFFSET, offset);
+            resp.header(
+                X_WEAVE_NEXT_OFFSET,
+                offset.to_token(&state.secrets.master_secret),
+            );
         });
 
     match coll.reply {
@@ -324,6 +716,9 @@ pub async fn post_collection_batch(
     let mut failed = coll.bsos.invalid;
     let bso_ids: Vec<_> = coll.bsos.valid.iter().map(|bso| bso.id.clone()).collect();
 
+    // Totals of the items written directly to bsos, counted towards the
+    // batch's limits at commit time
+    let mut pending = BatchTotals::default();
     let result = if commit && !coll.bsos.valid.is_empty() {
         // There's pending items to append to the batch but since we're
         // committing, write them to bsos immediately. Otherwise under
@@ -331,26 +726,41 @@ pub async fn post_collection_batch(
         // items (once writing them to to batch_bsos, then again
         // writing them to bsos)
         trace!("Batch: Committing {}", &new_batch.id);
+        // XXX: why does BatchBsoBody exist (it's the same struct as
+        // PostCollectionBso)?
+        let bsos: Vec<_> = coll
+            .bsos
+            .valid
+            .into_iter()
+            .map(|batch_bso| params::PostCollectionBso {
+                id: batch_bso.id,
+                sortindex: batch_bso.sortindex,
+                payload: batch_bso.payload,
+                ttl: batch_bso.ttl,
+            })
+            .collect();
+        // Records already staged in the batch are counted by its totals
+        let staged: HashSet<_> = match &breq.id {
+            Some(id) => db
+                .get_batch_items(params::GetBatchItems {
+                    user_id: coll.user_id.clone(),
+                    collection: coll.collection.clone(),
+                    id: id.clone(),
+                })
+                .await?
+                .map(|items| items.ids.into_iter().collect())
+                .unwrap_or_default(),
+            None => HashSet::new(),
+        };
+        pending = BatchTotals::staging(bsos.iter().filter(|bso| !staged.contains(&bso.id)));
         db.post_bsos(params::PostBsos {
             user_id: coll.user_id.clone(),
             collection: coll.collection.clone(),
-            // XXX: why does BatchBsoBody exist (it's the same struct
-            // as PostCollectionBso)?
-            bsos: coll
-                .bsos
-                .valid
-                .into_iter()
-                .map(|batch_bso| params::PostCollectionBso {
-                    id: batch_bso.id,
-                    sortindex: batch_bso.sortindex,
-                    payload: batch_bso.payload,
-                    ttl: batch_bso.ttl,
-                })
-                .collect(),
+            bsos,
             failed: Default::default(),
         })
         .await
-        .map(|_| ())
+        .map(|_| None)
     } else {
         // We're not yet to commit the accumulated batch, but there are some
         // additional records we need to add.
@@ -362,21 +772,28 @@ pub async fn post_collection_batch(
             bsos: coll.bsos.valid.into_iter().map(From::from).collect(),
         })
         .await
+        .map(Some)
     };
 
     // collect up the successful and failed bso_ids into a response.
-    match result {
-        Ok(_) => success.extend(bso_ids),
+    let totals = match result {
+        Ok(totals) => {
+            success.extend(bso_ids);
+            totals
+        }
         Err(e) if e.is_conflict() => return Err(e.into()),
         Err(apperr) => {
             if let ApiErrorKind::Db(dberr) = apperr.kind() {
-                // If we're over quota, return immediately with a 403 to let the client know.
-                // Otherwise the client will simply keep retrying records.
-                if let DbErrorKind::Quota = dberr.kind() {
+                // If we're over quota or the batch limits, return immediately
+                // to let the client know. Otherwise the client will simply
+                // keep retrying records.
+                if let DbErrorKind::Quota | DbErrorKind::BatchTooLarge = dberr.kind() {
                     return Err(apperr.into());
                 }
             };
-            failed.extend(bso_ids.into_iter().map(|id| (id, "db error".to_owned())))
+            pending = BatchTotals::default();
+            failed.extend(bso_ids.into_iter().map(|id| (id, "db error".to_owned())));
+            None
         }
     };
 
@@ -387,6 +804,10 @@ pub async fn post_collection_batch(
 
     if !breq.commit {
         resp["batch"] = json!(&new_batch.id);
+        if let Some(totals) = totals {
+            resp["total_records"] = json!(totals.records);
+            resp["total_bytes"] = json!(totals.bytes);
+        }
         return Ok(HttpResponse::Accepted().json(resp));
     }
 
@@ -399,13 +820,12 @@ pub async fn post_collection_batch(
         })
         .await?;
 
-    // TODO: validate *actual* sizes of the batch items
-    // (max_total_records, max_total_bytes)
     let result = if let Some(batch) = batch {
         db.commit_batch(params::CommitBatch {
             user_id: user_id.clone(),
             collection: collection.clone(),
             batch,
+            pending,
         })
         .await?
     } else {
@@ -414,6 +834,8 @@ pub async fn post_collection_batch(
     };
 
     resp["modified"] = json!(result.modified);
+    resp["total_records"] = json!(result.totals.records);
+    resp["total_bytes"] = json!(result.totals.bytes);
     trace!("Batch: Returning result: {}", &resp);
     Ok(HttpResponse::build(StatusCode::OK)
         .header(X_LAST_MODIFIED, result.modified.as_header())
@@ -500,6 +922,16 @@ pub fn get_configuration(state: Data<ServerState>) -> HttpResponse {
         .body(&state.limits_json)
 }
 
+/// The metrics in the Prometheus text format, when enabled
+pub async fn prometheus(state: Data<ServerState>) -> Result<HttpResponse, Error> {
+    match &state.prometheus {
+        Some(prometheus) => Ok(HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(prometheus.render()?)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
 /** Returns a status message indicating the state of the current server
  *
  */
@@ -522,6 +954,15 @@ pub async fn heartbeat(hb: HeartbeatRequest) -> Result<HttpResponse, Error> {
                     Value::from("check failed without error"),
                 );
             };
+            match db.schema_version().await {
+                Ok(version) => {
+                    checklist.insert("schema_version".to_owned(), Value::from(version.current));
+                }
+                Err(e) => {
+                    error!("Heartbeat schema version error: {:?}", e);
+                    checklist.insert("schema_version".to_owned(), Value::from("Unknown"));
+                }
+            }
             let status = if result { "Ok" } else { "Err" };
             checklist.insert("status".to_owned(), Value::from(status));
             Ok(HttpResponse::Ok().json(checklist))
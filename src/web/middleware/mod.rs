@@ -1,6 +1,8 @@
 // pub mod db;
+pub mod ratelimit;
 pub mod rejectua;
 pub mod sentry;
+pub mod tracing;
 pub mod weave;
 
 // # Web Middleware
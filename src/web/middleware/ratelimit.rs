@@ -0,0 +1,359 @@
+//! Per-user rate limiting of writes.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::{cell::RefCell, rc::Rc};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    error::ResponseError,
+    http::Method,
+    web::Data,
+    Error,
+};
+use async_trait::async_trait;
+use futures::future::{self, LocalBoxFuture};
+use futures::FutureExt;
+
+use crate::error::{ApiErrorKind, ApiResult};
+use crate::server::{metrics::Metrics, ServerState};
+use crate::settings::RateLimitSettings;
+use crate::web::{
+    extractors::{CollectionParam, HawkIdentifier},
+    middleware::SyncServerRequest,
+    tags::Tags,
+};
+
+/// Storage of the token buckets.
+///
+/// `MemoryRateLimitStore` keeps each server's buckets to itself, while a
+/// store shared between servers could implement this as well.
+#[async_trait(?Send)]
+pub trait RateLimitStore: Debug + Send + Sync {
+    /// Take a token from the bucket of `key` (holding up to `burst` tokens,
+    /// refilled at `rate` per second). When it's empty, return how long until
+    /// a token is available instead.
+    async fn take(&self, key: &str, rate: f64, burst: u32) -> ApiResult<Option<Duration>>;
+}
+
+/// Beyond this many buckets, the least recently used are dropped (granting
+/// their keys a full burst again).
+const MAX_BUCKETS: usize = 100_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Orders the buckets updated at the same instant
+    seq: u64,
+}
+
+impl Bucket {
+    /// Add the tokens refilled since last updated, returning the total
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = burst.min(self.tokens + elapsed * rate);
+        self.updated = now;
+        self.tokens
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    /// The keys of `buckets` by when they were last updated, least recently
+    /// first
+    lru: BTreeMap<(Instant, u64), String>,
+    seq: u64,
+}
+
+impl Buckets {
+    /// Drop the least recently updated bucket
+    fn evict_oldest(&mut self) {
+        let oldest = self.lru.keys().next().copied();
+        if let Some(key) = oldest.and_then(|oldest| self.lru.remove(&oldest)) {
+            self.buckets.remove(&key);
+        }
+    }
+
+    /// When the least recently updated bucket was
+    fn oldest_update(&self) -> Option<Instant> {
+        self.lru.keys().next().map(|(updated, _)| *updated)
+    }
+}
+
+#[derive(Debug)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<Buckets>,
+    max_buckets: usize,
+}
+
+impl Default for MemoryRateLimitStore {
+    fn default() -> Self {
+        Self::new(MAX_BUCKETS)
+    }
+}
+
+impl MemoryRateLimitStore {
+    pub fn new(max_buckets: usize) -> Self {
+        Self {
+            buckets: Mutex::new(Buckets::default()),
+            max_buckets,
+        }
+    }
+
+    fn take_at(
+        &self,
+        key: &str,
+        rate: f64,
+        burst: u32,
+        now: Instant,
+    ) -> ApiResult<Option<Duration>> {
+        let burst = f64::from(burst);
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| ApiErrorKind::Internal("Rate limit buckets lock poisoned".to_owned()))?;
+
+        // Buckets untouched for long enough to have refilled are full, no
+        // different than absent ones: sweep them from the old end
+        let refill_time = Duration::from_secs_f64(burst / rate);
+        while let Some(updated) = buckets.oldest_update() {
+            if now.saturating_duration_since(updated) < refill_time {
+                break;
+            }
+            buckets.evict_oldest();
+        }
+
+        let mut bucket = match buckets.buckets.remove(key) {
+            Some(bucket) => {
+                buckets.lru.remove(&(bucket.updated, bucket.seq));
+                bucket
+            }
+            None => {
+                while buckets.buckets.len() >= self.max_buckets.max(1) {
+                    buckets.evict_oldest();
+                }
+                Bucket {
+                    tokens: burst,
+                    updated: now,
+                    seq: 0,
+                }
+            }
+        };
+        let tokens = bucket.refill(now, rate, burst);
+        let wait = if tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - tokens) / rate))
+        };
+
+        buckets.seq += 1;
+        bucket.seq = buckets.seq;
+        buckets
+            .lru
+            .insert((bucket.updated, bucket.seq), key.to_owned());
+        buckets.buckets.insert(key.to_owned(), bucket);
+        Ok(wait)
+    }
+}
+
+#[async_trait(?Send)]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn take(&self, key: &str, rate: f64, burst: u32) -> ApiResult<Option<Duration>> {
+        self.take_at(key, rate, burst, Instant::now())
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    store: Box<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &RateLimitSettings, store: Box<dyn RateLimitStore>) -> Self {
+        Self {
+            settings: settings.clone(),
+            store,
+        }
+    }
+
+    /// A limiter keeping its buckets in memory, when enabled
+    pub fn from_settings(settings: &RateLimitSettings) -> Option<Self> {
+        if settings.is_enabled() {
+            Some(Self::new(
+                settings,
+                Box::new(MemoryRateLimitStore::default()),
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Take a write from the bucket of the user (or of their collection),
+    /// failing with `RateLimited` when it's empty
+    pub async fn check(&self, user_id: &HawkIdentifier, collection: Option<&str>) -> ApiResult<()> {
+        let mut key = format!("{}:{}", user_id.legacy_id, user_id.fxa_uid);
+        if self.settings.per_collection {
+            if let Some(collection) = collection {
+                key = format!("{}:{}", key, collection);
+            }
+        }
+        let wait = self
+            .store
+            .take(&key, self.settings.writes_per_second, self.settings.burst)
+            .await?;
+        match wait {
+            None => Ok(()),
+            Some(wait) => {
+                let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                Err(ApiErrorKind::RateLimited(retry_after).into())
+            }
+        }
+    }
+}
+
+/// Rejects writes of users exceeding the rate limit with a 503 (and a
+/// `Retry-After`), as legacy clients expect of an overloaded server.
+#[derive(Debug, Default)]
+pub struct RateLimit;
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub struct RateLimitMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let mut service = Rc::clone(&self.service);
+        async move {
+            let is_write = matches!(*sreq.method(), Method::POST | Method::PUT | Method::DELETE);
+            let state = match sreq.app_data::<Data<ServerState>>().cloned() {
+                Some(state)
+                    if is_write
+                        && state.rate_limiter.is_some()
+                        && sreq.path().starts_with("/1.5/") =>
+                {
+                    state
+                }
+                _ => return service.call(sreq).await,
+            };
+            // Requests failing authentication are left to the handlers to
+            // reject
+            let user_id = match sreq.get_hawk_id() {
+                Ok(user_id) => user_id,
+                Err(_) => return service.call(sreq).await,
+            };
+            let mut tags = Tags::from_request_head(sreq.head());
+            let collection =
+                match CollectionParam::extrude(&sreq.uri(), &mut sreq.extensions_mut(), &tags) {
+                    Ok(collection) => collection.map(|collection| collection.collection),
+                    Err(_) => None,
+                };
+
+            let limiter = state.rate_limiter.as_ref().expect("No rate_limiter");
+            if let Err(e) = limiter.check(&user_id, collection.as_deref()).await {
+                if let ApiErrorKind::RateLimited(_) = e.kind() {
+                    if let Some(collection) = collection {
+                        tags.tags.insert("collection".to_owned(), collection);
+                    }
+                    Metrics::from(state.get_ref())
+                        .incr_with_tags("storage.rate_limited", Some(tags));
+                }
+                return Ok(sreq.into_response(e.error_response().into_body()));
+            }
+            service.call(sreq).await
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills() {
+        let store = MemoryRateLimitStore::default();
+        let now = Instant::now();
+        // A burst of 2, refilled at one every 2 seconds
+        assert_eq!(store.take_at("a", 0.5, 2, now).unwrap(), None);
+        assert_eq!(store.take_at("a", 0.5, 2, now).unwrap(), None);
+        assert_eq!(
+            store.take_at("a", 0.5, 2, now).unwrap(),
+            Some(Duration::from_secs(2))
+        );
+        // Other keys have their own buckets
+        assert_eq!(store.take_at("b", 0.5, 2, now).unwrap(), None);
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            store.take_at("a", 0.5, 2, later).unwrap(),
+            Some(Duration::from_secs(1))
+        );
+        let later = now + Duration::from_secs(2);
+        assert_eq!(store.take_at("a", 0.5, 2, later).unwrap(), None);
+    }
+
+    #[test]
+    fn test_bucket_limit() {
+        let store = MemoryRateLimitStore::new(2);
+        let now = Instant::now();
+        let buckets = || store.buckets.lock().unwrap().buckets.len();
+        // A burst of 1, refilled at one every 10 seconds
+        assert_eq!(store.take_at("a", 0.1, 1, now).unwrap(), None);
+        assert_eq!(store.take_at("b", 0.1, 1, now).unwrap(), None);
+        assert!(store.take_at("a", 0.1, 1, now).unwrap().is_some());
+        assert_eq!(buckets(), 2);
+
+        // The least recently used bucket ("b") makes way for a new one
+        assert_eq!(store.take_at("c", 0.1, 1, now).unwrap(), None);
+        assert_eq!(buckets(), 2);
+        assert!(store.take_at("a", 0.1, 1, now).unwrap().is_some());
+        assert_eq!(store.take_at("b", 0.1, 1, now).unwrap(), None);
+        assert_eq!(buckets(), 2);
+
+        // Refilled (full) buckets are swept
+        let later = now + Duration::from_secs(10);
+        assert_eq!(store.take_at("d", 0.1, 1, later).unwrap(), None);
+        assert_eq!(buckets(), 1);
+    }
+}
@@ -0,0 +1,118 @@
+use std::task::Context;
+use std::{cell::RefCell, rc::Rc};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpMessage,
+};
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use opentelemetry::{
+    trace::{FutureExt as _, Span, StatusCode, TraceContextExt, Tracer},
+    Context as OtelContext, KeyValue,
+};
+use std::task::Poll;
+
+use crate::server::tracing::{extract_context, tag_attributes, tracer};
+use crate::web::tags::Tags;
+
+/// Traces each request in a span, continuing the trace of any W3C
+/// `traceparent` header.
+pub struct TracingWrapper;
+
+impl TracingWrapper {
+    pub fn new() -> Self {
+        TracingWrapper::default()
+    }
+}
+
+impl Default for TracingWrapper {
+    fn default() -> Self {
+        Self
+    }
+}
+
+impl<S, B> Transform<S> for TracingWrapper
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TracingWrapperMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        Box::pin(future::ok(TracingWrapperMiddleware {
+            service: Rc::new(RefCell::new(service)),
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub struct TracingWrapperMiddleware<S> {
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for TracingWrapperMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, sreq: ServiceRequest) -> Self::Future {
+        let method = sreq.method().to_string();
+        // Named after (and attributed with) the matched route on
+        // completion: the path itself includes the uid
+        let span = tracer().start_with_context(&method, extract_context(sreq.headers()));
+        span.set_attribute(KeyValue::new("http.method", method.clone()));
+        let cx = OtelContext::current_with_span(span);
+
+        let fut = self.service.call(sreq).with_context(cx.clone());
+        Box::pin(fut.map(move |result| {
+            let span = cx.span();
+            match &result {
+                Ok(sresp) => {
+                    // Tags (e.g. the collection) may have been added by the
+                    // handler: check both the request and response as
+                    // SentryWrapper does
+                    let mut tags = Tags::default();
+                    if let Some(t) = sresp.request().extensions().get::<Tags>() {
+                        tags.extend(t.tags.clone());
+                    }
+                    if let Some(t) = sresp.response().extensions().get::<Tags>() {
+                        tags.extend(t.tags.clone());
+                    }
+                    for attribute in tag_attributes(&tags) {
+                        span.set_attribute(attribute);
+                    }
+                    if let Some(pattern) = sresp.request().match_pattern() {
+                        span.update_name(format!("{} {}", method, pattern));
+                        span.set_attribute(KeyValue::new("http.route", pattern));
+                    }
+                    let status = sresp.status();
+                    span.set_attribute(KeyValue::new(
+                        "http.status_code",
+                        i64::from(status.as_u16()),
+                    ));
+                    if status.is_server_error() {
+                        span.set_status(StatusCode::Error, status.to_string());
+                    }
+                }
+                Err(e) => span.set_status(StatusCode::Error, e.to_string()),
+            }
+            result
+        }))
+    }
+}
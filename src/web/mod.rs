@@ -1,4 +1,5 @@
 //! Web authentication, handlers, and middleware
+pub mod admin;
 pub mod auth;
 pub mod error;
 pub mod extractors;
@@ -14,9 +15,10 @@ pub static X_WEAVE_NEXT_OFFSET: &str = "x-weave-next-offset";
 pub static X_WEAVE_RECORDS: &str = "x-weave-records";
 
 // Known DockerFlow commands for Ops callbacks
-pub const DOCKER_FLOW_ENDPOINTS: [&str; 4] = [
+pub const DOCKER_FLOW_ENDPOINTS: [&str; 5] = [
     "/__heartbeat__",
     "/__lbheartbeat__",
     "/__version__",
     "/__error__",
+    "/__metrics__",
 ];
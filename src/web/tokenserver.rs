@@ -1,26 +1,106 @@
-use actix_web::error::BlockingError;
-use actix_web::web::block;
-use actix_web::HttpResponse;
-
-use futures::future::{Future, TryFutureExt};
-
-use crate::error::ApiError;
-use crate::web::extractors::TokenServerRequest;
-
-pub struct TokenServerResult {}
-
-pub fn get(
-    request: TokenServerRequest,
-) -> impl Future<Output = Result<HttpResponse, BlockingError<ApiError>>> {
-    block(move || get_sync(request).map_err(Into::into)).map_ok(move |_result| {
-        // TODO turn _result into a json response.
-        HttpResponse::Ok()
-            .content_type("application/json")
-            .body("{}")
-    })
+//! Tokenserver handler.
+use actix_web::{Error, HttpResponse};
+use chrono::offset::Utc;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+
+use crate::error::{ApiError, ApiErrorKind};
+use crate::tokenserver::{
+    self,
+    users::{get_or_create_user, UserParams},
+    verify::TokenserverAuth,
+    SYNC_SERVICE_PATTERN,
+};
+use crate::web::{auth::HawkPayload, extractors::TokenServerRequest};
+
+/// The credentials handed back to a client.
+#[derive(Debug, Serialize)]
+pub struct TokenServerResult {
+    id: String,
+    key: String,
+    uid: u64,
+    hashed_fxa_uid: String,
+    api_endpoint: String,
+    duration: u64,
+    hashalg: &'static str,
 }
 
-pub fn get_sync(_request: TokenServerRequest) -> Result<TokenServerResult, ApiError> {
-    // TODO Perform any blocking calls needed to respond to the tokenserver request.
-    Ok(TokenServerResult {})
+pub async fn get(mut request: TokenServerRequest) -> Result<HttpResponse, Error> {
+    request.metrics.start_timer("tokenserver.get", None);
+    let state = &request.tokenserver;
+    let verified = state.verifier.verify(&request.auth).await?;
+
+    if let TokenserverAuth::OAuth(_) = request.auth {
+        if request.keys_changed_at.is_none() {
+            Err(ApiError::from(ApiErrorKind::InvalidCredentials(
+                "Missing X-KeyID header".to_owned(),
+            )))?;
+        }
+    }
+    if let (Some(claimed), Some(expected)) = (request.keys_changed_at, verified.keys_changed_at) {
+        if claimed != expected {
+            Err(ApiError::from(ApiErrorKind::InvalidCredentials(
+                "Mismatched keys_changed_at".to_owned(),
+            )))?;
+        }
+    }
+    let keys_changed_at = request
+        .keys_changed_at
+        .or(verified.keys_changed_at)
+        .or(verified.generation)
+        .unwrap_or(0);
+
+    // Validate the client state before touching the db
+    let fxa_kid = tokenserver::format_key_id(keys_changed_at, &request.client_state)?;
+    let db = request.db_pool.get().await?;
+    let user = get_or_create_user(
+        db.as_ref(),
+        UserParams {
+            service_id: state.service_id,
+            email: format!("{}@{}", verified.fxa_uid, state.fxa_email_domain),
+            generation: verified.generation.unwrap_or(0),
+            keys_changed_at,
+            client_state: request.client_state.clone(),
+            timestamp: Utc::now().timestamp_millis(),
+        },
+    )
+    .await?;
+    // get_or_create_user always yields an allocated node
+    let node = user.node.unwrap_or_default();
+    let uid = user.uid as u64;
+
+    let hashed_fxa_uid =
+        tokenserver::fxa_metrics_hash(&verified.fxa_uid, &state.fxa_metrics_hash_secret)?;
+    let hashed_device_id = tokenserver::fxa_metrics_hash(
+        &format!(
+            "{}{}",
+            verified.fxa_uid,
+            verified.device_id.as_deref().unwrap_or("none")
+        ),
+        &state.fxa_metrics_hash_secret,
+    )?;
+
+    let payload = HawkPayload {
+        expires: (Utc::now().timestamp() as u64 + state.token_duration) as f64,
+        node: node.clone(),
+        salt: tokenserver::to_hex(&thread_rng().gen::<[u8; 16]>()),
+        user_id: uid,
+        fxa_uid: verified.fxa_uid,
+        fxa_kid,
+        device_id: hashed_device_id,
+    };
+    let (id, key) = payload.sign(&request.secrets)?;
+
+    request.metrics.incr("tokenserver.token_issued");
+    Ok(HttpResponse::Ok().json(TokenServerResult {
+        id,
+        key,
+        uid,
+        hashed_fxa_uid,
+        api_endpoint: SYNC_SERVICE_PATTERN
+            .replace("{node}", &node)
+            .replace("{uid}", &uid.to_string()),
+        duration: state.token_duration,
+        hashalg: "sha256",
+    }))
 }